@@ -0,0 +1,147 @@
+//! Arena maps (linux 6.9+).
+//!
+//! An arena is a region of memory shared with user space that probes
+//! allocate pages from, which allows building linked data structures without
+//! a map entry per node. Nodes reference each other through [`ArenaPtr`]s,
+//! which are valid in the probe and in the user space mapping of the arena.
+use crate::map::RawMap;
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::mem;
+
+pub const PAGE_SIZE: usize = 4096;
+
+const BPF_MAP_TYPE_ARENA: u32 = 33;
+const NUMA_NO_NODE: i32 = -1;
+
+/// Arena map, the maximum number of entries is the size in pages.
+///
+/// The map has to be created with [`flags::MMAPABLE`](crate::flags::MMAPABLE).
+pub type Arena = RawMap<(), (), BPF_MAP_TYPE_ARENA>;
+
+crate::kfunc! {
+    fn bpf_arena_alloc_pages(
+        map: *mut c_void,
+        addr: *mut c_void,
+        page_cnt: u32,
+        node_id: i32,
+        flags: u64,
+    ) -> *mut c_void;
+    fn bpf_arena_free_pages(map: *mut c_void, ptr: *mut c_void, page_cnt: u32);
+}
+
+impl Arena {
+    /// Allocates `page_cnt` zeroed pages.
+    #[inline(always)]
+    pub fn alloc_pages(&self, page_cnt: u32) -> Option<*mut u8> {
+        let ptr = unsafe {
+            bpf_arena_alloc_pages(
+                self.as_ptr(),
+                core::ptr::null_mut(),
+                page_cnt,
+                NUMA_NO_NODE,
+                0,
+            )
+        };
+        if ptr.is_null() {
+            return None;
+        }
+        Some(ptr as *mut u8)
+    }
+
+    /// Returns pages allocated by [`alloc_pages`](Self::alloc_pages).
+    #[inline(always)]
+    pub fn free_pages(&self, ptr: *mut u8, page_cnt: u32) {
+        unsafe { bpf_arena_free_pages(self.as_ptr(), ptr as *mut _, page_cnt) }
+    }
+}
+
+/// Bump allocator handing out objects from arena pages.
+///
+/// Objects are never freed individually, the pages are released when the
+/// arena is destroyed.
+pub struct ArenaAlloc<'a> {
+    arena: &'a Arena,
+    page: *mut u8,
+    used: usize,
+}
+
+impl<'a> ArenaAlloc<'a> {
+    #[inline(always)]
+    pub fn new(arena: &'a Arena) -> Self {
+        Self {
+            arena,
+            page: core::ptr::null_mut(),
+            used: PAGE_SIZE,
+        }
+    }
+
+    /// Allocates a zeroed `T`, which must fit in a page.
+    #[inline(always)]
+    pub fn alloc<T>(&mut self) -> Option<&'a mut T> {
+        let size = mem::size_of::<T>();
+        let align = mem::align_of::<T>();
+        if size > PAGE_SIZE {
+            return None;
+        }
+        let mut offset = (self.used + align - 1) & !(align - 1);
+        if offset + size > PAGE_SIZE {
+            self.page = self.arena.alloc_pages(1)?;
+            offset = 0;
+        }
+        self.used = offset + size;
+        Some(unsafe { &mut *(self.page.add(offset) as *mut T) })
+    }
+}
+
+/// Pointer into an arena.
+///
+/// Stores the lower 32 bits of the address, which are the same in the kernel
+/// and the user space mapping since an arena never crosses a 4GiB boundary.
+#[derive(Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct ArenaPtr<T> {
+    addr: u32,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for ArenaPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ArenaPtr<T> {}
+
+impl<T> ArenaPtr<T> {
+    pub const NULL: Self = Self {
+        addr: 0,
+        _marker: PhantomData,
+    };
+
+    #[inline(always)]
+    pub fn new(ptr: *mut T) -> Self {
+        Self {
+            addr: ptr as usize as u32,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_null(self) -> bool {
+        self.addr == 0
+    }
+
+    /// Returns the pointer, `anchor` is any pointer into the same arena.
+    ///
+    /// The verifier only treats pointers derived from an arena allocation as
+    /// arena pointers, so the address is rebuilt from one.
+    #[inline(always)]
+    pub fn get(self, anchor: *mut u8) -> Option<*mut T> {
+        if self.is_null() {
+            return None;
+        }
+        let base = anchor.wrapping_sub(anchor as usize & 0xffff_ffff);
+        Some(base.wrapping_add(self.addr as usize) as *mut T)
+    }
+}
@@ -0,0 +1,133 @@
+//! Connection tracking lookups.
+//!
+//! The lookups are kfuncs of `nf_conntrack` (linux 5.18+), the loader
+//! resolves them against the kernel btf. A found entry holds a reference that
+//! is released when the [`Conn`] is dropped, which the verifier requires on
+//! every path.
+use crate::tc::__sk_buff;
+use crate::xdp::xdp_md;
+use core::mem;
+
+/// Look up the entry in the network namespace of the program.
+pub const BPF_F_CURRENT_NETNS: i32 = -1;
+
+/// Opaque `struct nf_conn`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct nf_conn {
+    _private: [u8; 0],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct bpf_ct_opts {
+    pub netns_id: i32,
+    pub error: i32,
+    pub l4proto: u8,
+    pub dir: u8,
+    pub reserved: [u8; 2],
+}
+
+/// Ipv4 variant of `struct bpf_sock_tuple`, addresses and ports in network
+/// byte order.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct SockTupleV4 {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+crate::kfunc! {
+    fn bpf_skb_ct_lookup(
+        skb: *mut __sk_buff,
+        tuple: *mut SockTupleV4,
+        tuple_sz: u32,
+        opts: *mut bpf_ct_opts,
+        opts_sz: u32,
+    ) -> *mut nf_conn;
+    fn bpf_xdp_ct_lookup(
+        xdp: *mut xdp_md,
+        tuple: *mut SockTupleV4,
+        tuple_sz: u32,
+        opts: *mut bpf_ct_opts,
+        opts_sz: u32,
+    ) -> *mut nf_conn;
+    fn bpf_ct_release(ct: *mut nf_conn);
+}
+
+/// Reference to a connection tracking entry.
+pub struct Conn(*mut nf_conn);
+
+impl Conn {
+    #[inline(always)]
+    pub fn as_ptr(&self) -> *mut nf_conn {
+        self.0
+    }
+}
+
+impl Drop for Conn {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe { bpf_ct_release(self.0) }
+    }
+}
+
+#[inline(always)]
+fn opts(l4proto: u8) -> bpf_ct_opts {
+    bpf_ct_opts {
+        netns_id: BPF_F_CURRENT_NETNS,
+        error: 0,
+        l4proto,
+        dir: 0,
+        reserved: [0; 2],
+    }
+}
+
+#[inline(always)]
+fn conn(ct: *mut nf_conn, opts: &bpf_ct_opts) -> Result<Option<Conn>, i32> {
+    if !ct.is_null() {
+        return Ok(Some(Conn(ct)));
+    }
+    // -ENOENT means there is no entry for the tuple.
+    match opts.error {
+        -2 => Ok(None),
+        err => Err(err),
+    }
+}
+
+/// Looks up the entry of `tuple` for protocol `l4proto` (`IPPROTO_TCP` or
+/// `IPPROTO_UDP`).
+#[inline(always)]
+pub fn skb_lookup(skb: &__sk_buff, tuple: &SockTupleV4, l4proto: u8) -> Result<Option<Conn>, i32> {
+    let mut tuple = *tuple;
+    let mut opts = opts(l4proto);
+    let ct = unsafe {
+        bpf_skb_ct_lookup(
+            skb as *const _ as *mut _,
+            &mut tuple,
+            mem::size_of::<SockTupleV4>() as _,
+            &mut opts,
+            mem::size_of::<bpf_ct_opts>() as _,
+        )
+    };
+    conn(ct, &opts)
+}
+
+/// Xdp version of [`skb_lookup`].
+#[inline(always)]
+pub fn xdp_lookup(xdp: &xdp_md, tuple: &SockTupleV4, l4proto: u8) -> Result<Option<Conn>, i32> {
+    let mut tuple = *tuple;
+    let mut opts = opts(l4proto);
+    let ct = unsafe {
+        bpf_xdp_ct_lookup(
+            xdp as *const _ as *mut _,
+            &mut tuple,
+            mem::size_of::<SockTupleV4>() as _,
+            &mut opts,
+            mem::size_of::<bpf_ct_opts>() as _,
+        )
+    };
+    conn(ct, &opts)
+}
@@ -0,0 +1,93 @@
+//! Writes to the memory of the traced process.
+//!
+//! `bpf_probe_write_user` changes the memory of whatever process is current
+//! when the probe runs, misuse corrupts it. The kernel logs a warning when a
+//! program using it is loaded. It's meant for fault injection and tests and
+//! needs the `dangerous` feature.
+//!
+//! ```ignore
+//! // uprobes are kprobe programs, this one is attached with
+//! // `uprobe:<binary>:<function>` to a function taking a `*mut i64` out
+//! // parameter.
+//! #[entry("kprobe")]
+//! fn fail_read(args: &pt_regs) {
+//!     let res = UserPtr::<i64>::new(args.rdi);
+//!     // -EIO
+//!     unsafe { res.write(&-5).ok() };
+//! }
+//! ```
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+
+/// Pointer into the memory of the current user space process.
+#[derive(Debug, Eq, PartialEq)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    #[inline(always)]
+    pub fn new(addr: u64) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Reads the value with `bpf_probe_read_user`.
+    #[inline(always)]
+    pub fn read(&self) -> Result<T, i32> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let ret = unsafe {
+            let f: unsafe extern "C" fn(
+                dst: *mut cty::c_void,
+                size: u32,
+                unsafe_ptr: *const cty::c_void,
+            ) -> cty::c_long = mem::transmute(112usize);
+            f(
+                value.as_mut_ptr() as *mut _,
+                mem::size_of::<T>() as _,
+                self.addr as *const _,
+            )
+        };
+        if ret < 0 {
+            return Err(ret as i32);
+        }
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Writes the value with `bpf_probe_write_user`, failing if the page
+    /// isn't mapped writable.
+    ///
+    /// # Safety
+    ///
+    /// The kernel only checks that the memory is writable user memory. The
+    /// caller has to make sure it holds a `T` of the current process, the
+    /// process sees the value as if written by another thread.
+    #[inline(always)]
+    pub unsafe fn write(&self, value: &T) -> Result<(), i32> {
+        let ret = bpf_helpers_sys::bpf_probe_write_user(
+            self.addr as *mut _,
+            value as *const T as *const _,
+            mem::size_of::<T>() as _,
+        );
+        if ret < 0 {
+            return Err(ret as i32);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Error counters of the map wrappers.
+//!
+//! With the `debug-counters` feature the map wrappers count failed updates,
+//! array lookups out of bounds and deletes of missing keys in the per cpu
+//! `BPF_DIAG` map, which the loader reports with `Bpf::diag_counts`. Without
+//! the feature nothing is recorded.
+
+/// Update of a full map.
+pub const UPDATE_FULL: u32 = 0;
+/// Update that failed to allocate an element.
+pub const UPDATE_NOMEM: u32 = 1;
+pub const UPDATE_FAILED: u32 = 2;
+/// Lookup of an array index out of bounds. Hash map lookups of missing keys
+/// are expected and not counted.
+pub const LOOKUP_MISS: u32 = 3;
+pub const DELETE_MISS: u32 = 4;
+/// Run skipped by a `RecursionGuard`.
+pub const RECURSION: u32 = 5;
+pub const MAX_COUNTERS: usize = 6;
+
+const E2BIG: i32 = 7;
+const ENOMEM: i32 = 12;
+
+#[cfg(feature = "debug-counters")]
+#[bpf_macros::map]
+static BPF_DIAG: crate::map::PercpuArray<u64> =
+    crate::map::PercpuArray::with_max_entries(MAX_COUNTERS);
+
+#[inline(always)]
+pub fn count(_counter: u32) {
+    #[cfg(feature = "debug-counters")]
+    unsafe {
+        if let Some(count) = BPF_DIAG.lookup(&_counter).as_mut() {
+            *count += 1;
+        }
+    }
+}
+
+/// Counts the error returned by `bpf_map_update_elem`.
+#[inline(always)]
+pub fn count_update(ret: i32) {
+    match -ret {
+        0 => {}
+        E2BIG => count(UPDATE_FULL),
+        ENOMEM => count(UPDATE_NOMEM),
+        _ => count(UPDATE_FAILED),
+    }
+}
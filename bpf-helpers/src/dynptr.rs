@@ -0,0 +1,260 @@
+//! Dynamic pointers.
+//!
+//! A dynptr is a pointer to memory of a size only known at runtime, every
+//! access is bounds checked by the helpers. They are created from memory,
+//! ring buffer reservations (linux 5.19+) or packets (linux 6.4+).
+use crate::map::RingBuf;
+use crate::tc::SkBuff;
+use crate::xdp::xdp_md;
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use cty::c_long;
+
+/// Opaque `struct bpf_dynptr`.
+#[allow(non_camel_case_types)]
+#[repr(C, align(8))]
+pub struct bpf_dynptr {
+    _opaque: [u64; 2],
+}
+
+crate::kfunc! {
+    fn bpf_dynptr_from_skb(skb: *mut c_void, flags: u64, ptr: *mut bpf_dynptr) -> i32;
+    fn bpf_dynptr_from_xdp(xdp: *mut xdp_md, flags: u64, ptr: *mut bpf_dynptr) -> i32;
+    fn bpf_dynptr_size(ptr: *const bpf_dynptr) -> u32;
+}
+
+/// Dynamic pointer borrowing the memory it was created from.
+pub struct Dynptr<'a> {
+    raw: bpf_dynptr,
+    _marker: PhantomData<&'a mut ()>,
+}
+
+impl<'a> Dynptr<'a> {
+    #[inline(always)]
+    fn uninit() -> Self {
+        Self {
+            raw: bpf_dynptr { _opaque: [0; 2] },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Dynptr to `data`.
+    ///
+    /// # Safety
+    ///
+    /// `data` has to be a map value or a global. The verifier doesn't
+    /// reject every other kind of memory, a dynptr to the stack of a
+    /// subprogram for instance stays usable after the subprogram returned.
+    #[inline(always)]
+    pub unsafe fn from_mem(data: &'a mut [u8]) -> Result<Self, i32> {
+        let mut ptr = Self::uninit();
+        let f: unsafe extern "C" fn(
+            data: *mut c_void,
+            size: u32,
+            flags: u64,
+            ptr: *mut bpf_dynptr,
+        ) -> c_long = mem::transmute(197usize);
+        let ret = f(
+            data.as_mut_ptr() as *mut _,
+            data.len() as _,
+            0,
+            &mut ptr.raw,
+        );
+        to_result(ret as _)?;
+        Ok(ptr)
+    }
+
+    /// Dynptr to the whole packet including paged data.
+    #[inline(always)]
+    pub fn from_skb(skb: &'a mut SkBuff) -> Result<Self, i32> {
+        let mut ptr = Self::uninit();
+        let ret = unsafe { bpf_dynptr_from_skb(skb.raw() as *const _ as *mut _, 0, &mut ptr.raw) };
+        to_result(ret)?;
+        Ok(ptr)
+    }
+
+    /// Dynptr to the whole packet including the fragments of multi buffer
+    /// xdp.
+    #[inline(always)]
+    pub fn from_xdp(xdp: &'a mut xdp_md) -> Result<Self, i32> {
+        let mut ptr = Self::uninit();
+        let ret = unsafe { bpf_dynptr_from_xdp(xdp, 0, &mut ptr.raw) };
+        to_result(ret)?;
+        Ok(ptr)
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> u32 {
+        unsafe { bpf_dynptr_size(&self.raw) }
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies `buf.len()` bytes at `offset` into `buf`.
+    #[inline(always)]
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), i32> {
+        let ret = unsafe {
+            let f: unsafe extern "C" fn(
+                dst: *mut c_void,
+                len: u32,
+                src: *const bpf_dynptr,
+                offset: u32,
+                flags: u64,
+            ) -> c_long = mem::transmute(201usize);
+            f(
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+                &self.raw,
+                offset,
+                0,
+            )
+        };
+        to_result(ret as _)
+    }
+
+    /// Reads the `T` at `offset`.
+    #[inline(always)]
+    pub fn read_value<T: Copy>(&self, offset: u32) -> Result<T, i32> {
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>())
+        };
+        self.read(offset, buf)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copies `data` to `offset`.
+    #[inline(always)]
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), i32> {
+        let ret = unsafe {
+            let f: unsafe extern "C" fn(
+                dst: *const bpf_dynptr,
+                offset: u32,
+                src: *const c_void,
+                len: u32,
+                flags: u64,
+            ) -> c_long = mem::transmute(202usize);
+            f(
+                &self.raw,
+                offset,
+                data.as_ptr() as *const _,
+                data.len() as _,
+                0,
+            )
+        };
+        to_result(ret as _)
+    }
+
+    /// Writes `value` at `offset`.
+    #[inline(always)]
+    pub fn write_value<T>(&mut self, offset: u32, value: &T) -> Result<(), i32> {
+        let data = unsafe {
+            core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.write(offset, data)
+    }
+
+    /// Returns a direct reference to the `T` at `offset`.
+    ///
+    /// Not supported for packet dynptrs, use [`read_value`](Self::read_value)
+    /// for those.
+    #[inline(always)]
+    pub fn get_mut<T>(&mut self, offset: u32) -> Option<&mut T> {
+        let ptr = unsafe {
+            let f: unsafe extern "C" fn(
+                ptr: *const bpf_dynptr,
+                offset: u32,
+                len: u32,
+            ) -> *mut c_void = mem::transmute(203usize);
+            f(&self.raw, offset, mem::size_of::<T>() as _)
+        };
+        unsafe { (ptr as *mut T).as_mut() }
+    }
+}
+
+/// Ring buffer record of a runtime size.
+///
+/// Dropping the record discards it.
+pub struct RingBufRecord<'a> {
+    ptr: Dynptr<'a>,
+}
+
+impl<'a> RingBufRecord<'a> {
+    /// Makes the record visible to user space, see `RingBuf::NO_WAKEUP` and
+    /// `RingBuf::FORCE_WAKEUP`.
+    #[inline(always)]
+    pub fn submit(mut self, flags: u64) {
+        unsafe {
+            let f: unsafe extern "C" fn(ptr: *mut bpf_dynptr, flags: u64) =
+                mem::transmute(199usize);
+            f(&mut self.ptr.raw, flags);
+        }
+        mem::forget(self);
+    }
+}
+
+impl<'a> Deref for RingBufRecord<'a> {
+    type Target = Dynptr<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl<'a> DerefMut for RingBufRecord<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ptr
+    }
+}
+
+impl<'a> Drop for RingBufRecord<'a> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            let f: unsafe extern "C" fn(ptr: *mut bpf_dynptr, flags: u64) =
+                mem::transmute(200usize);
+            f(&mut self.ptr.raw, 0);
+        }
+    }
+}
+
+impl RingBuf {
+    /// Skips notifying user space of the new record.
+    pub const NO_WAKEUP: u64 = 1 << 0;
+    /// Notifies user space regardless of whether it is behind.
+    pub const FORCE_WAKEUP: u64 = 1 << 1;
+
+    /// Reserves a record of `size` bytes.
+    #[inline(always)]
+    pub fn reserve_dynptr(&self, size: u32) -> Result<RingBufRecord<'_>, i32> {
+        let mut record = RingBufRecord {
+            ptr: Dynptr::uninit(),
+        };
+        let ret = unsafe {
+            let f: unsafe extern "C" fn(
+                map: *mut c_void,
+                size: u32,
+                flags: u64,
+                ptr: *mut bpf_dynptr,
+            ) -> c_long = mem::transmute(198usize);
+            f(self.as_ptr(), size, 0, &mut record.ptr.raw)
+        };
+        // The verifier requires a failed reservation to be discarded as well,
+        // which dropping the record does.
+        to_result(ret as _)?;
+        Ok(record)
+    }
+}
+
+#[inline(always)]
+fn to_result(ret: i32) -> Result<(), i32> {
+    if ret < 0 {
+        return Err(ret);
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Standard header of events sent to userspace.
+//!
+//! The layout matches `bpf::event::EventHeader`, which decodes it.
+
+pub const TASK_COMM_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct EventHeader {
+    pub ktime_ns: u64,
+    /// Thread id.
+    pub pid: u32,
+    /// Process id.
+    pub tgid: u32,
+    pub cpu: u32,
+    _pad: u32,
+    pub comm: [u8; TASK_COMM_LEN],
+}
+
+impl EventHeader {
+    /// Fills the header from the current task.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let pid_tgid = unsafe { bpf_helpers_sys::bpf_get_current_pid_tgid() };
+        let mut header = Self {
+            ktime_ns: unsafe { bpf_helpers_sys::bpf_ktime_get_ns() },
+            pid: pid_tgid as u32,
+            tgid: (pid_tgid >> 32) as u32,
+            cpu: unsafe { bpf_helpers_sys::bpf_get_smp_processor_id() },
+            _pad: 0,
+            comm: [0; TASK_COMM_LEN],
+        };
+        unsafe {
+            bpf_helpers_sys::bpf_get_current_comm(
+                header.comm.as_mut_ptr() as *mut _,
+                TASK_COMM_LEN as u32,
+            )
+        };
+        header
+    }
+}
+
+/// A payload with the header prepended.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Event<T> {
+    pub header: EventHeader,
+    pub payload: T,
+}
+
+impl<T> Event<T> {
+    #[inline(always)]
+    pub fn new(payload: T) -> Self {
+        Self {
+            header: EventHeader::capture(),
+            payload,
+        }
+    }
+}
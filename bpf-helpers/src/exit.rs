@@ -0,0 +1,116 @@
+//! Early exits from probes.
+//!
+//! Entry points may return `Result<T, Exit>`, which lets nested functions
+//! leave the program with `?` and [`bail!`](crate::bail) instead of checking
+//! every lookup in an `if let`. The resulting control flow is a plain return
+//! chain the verifier accepts.
+use crate::tc::TcAction;
+use crate::xdp::XdpAction;
+
+/// Value returned to the kernel by an entry point.
+pub trait ProgramReturn {
+    fn into_return(self) -> i32;
+}
+
+impl ProgramReturn for () {
+    #[inline(always)]
+    fn into_return(self) -> i32 {
+        0
+    }
+}
+
+impl ProgramReturn for i32 {
+    #[inline(always)]
+    fn into_return(self) -> i32 {
+        self
+    }
+}
+
+impl ProgramReturn for XdpAction {
+    #[inline(always)]
+    fn into_return(self) -> i32 {
+        self as i32
+    }
+}
+
+impl ProgramReturn for TcAction {
+    #[inline(always)]
+    fn into_return(self) -> i32 {
+        self as i32
+    }
+}
+
+impl<T: ProgramReturn> ProgramReturn for Result<T, Exit> {
+    #[inline(always)]
+    fn into_return(self) -> i32 {
+        match self {
+            Ok(value) => value.into_return(),
+            Err(exit) => exit.0,
+        }
+    }
+}
+
+/// Terminates the program with a return code, `0` by default.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Exit(pub i32);
+
+impl Exit {
+    #[inline(always)]
+    pub fn with<T: ProgramReturn>(ret: T) -> Self {
+        Self(ret.into_return())
+    }
+}
+
+/// Helper errors exit with the default return code.
+impl From<i32> for Exit {
+    #[inline(always)]
+    fn from(_: i32) -> Self {
+        Self::default()
+    }
+}
+
+/// Turns a failed lookup into an [`Exit`].
+pub trait OrExit<T> {
+    fn or_exit(self) -> Result<T, Exit>;
+}
+
+impl<T> OrExit<T> for Option<T> {
+    #[inline(always)]
+    fn or_exit(self) -> Result<T, Exit> {
+        self.ok_or_else(Exit::default)
+    }
+}
+
+impl<T, E> OrExit<T> for Result<T, E> {
+    #[inline(always)]
+    fn or_exit(self) -> Result<T, Exit> {
+        self.map_err(|_| Exit::default())
+    }
+}
+
+/// Returns an [`Exit`] from the current function, with the given return
+/// value or `0`.
+#[macro_export]
+macro_rules! bail {
+    () => {
+        return Err($crate::Exit::default())
+    };
+    ($ret:expr) => {
+        return Err($crate::Exit::with($ret))
+    };
+}
+
+/// Calls [`bail!`](crate::bail) unless the condition holds.
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr) => {
+        if !$cond {
+            $crate::bail!();
+        }
+    };
+    ($cond:expr, $ret:expr) => {
+        if !$cond {
+            $crate::bail!($ret);
+        }
+    };
+}
@@ -0,0 +1,10 @@
+//! Log2 histograms of latencies, decoded by `bpf::hist::Log2Histogram`.
+
+/// Number of buckets, bucket `i` counts values in `[2^(i-1), 2^i)`.
+pub const LOG2_BUCKETS: usize = 65;
+
+/// Bucket of a value, 0 only counts the value 0.
+#[inline(always)]
+pub fn log2_bucket(value: u64) -> u32 {
+    64 - value.leading_zeros()
+}
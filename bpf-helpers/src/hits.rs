@@ -0,0 +1,37 @@
+//! Branch hit counters for debug builds.
+//!
+//! `hit_counters!()` defines the `BPF_HITS` map at the crate root, `hit!()`
+//! counts how often the line it is placed on runs. Both expand to nothing in
+//! release builds. The loader reports the counts with `Bpf::hit_counts`.
+use crate::map::PercpuArray;
+
+/// Largest line number that can be counted.
+pub const MAX_LINES: usize = 4096;
+
+#[doc(hidden)]
+#[inline(always)]
+pub fn hit(map: &PercpuArray<u64>, line: u32) {
+    unsafe {
+        if let Some(count) = map.lookup(&line).as_mut() {
+            *count += 1;
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! hit_counters {
+    () => {
+        #[cfg(debug_assertions)]
+        #[$crate::map]
+        static BPF_HITS: $crate::PercpuArray<u64> =
+            $crate::PercpuArray::with_max_entries($crate::hits::MAX_LINES);
+    };
+}
+
+#[macro_export]
+macro_rules! hit {
+    () => {
+        #[cfg(debug_assertions)]
+        $crate::hits::hit(&crate::BPF_HITS, line!());
+    };
+}
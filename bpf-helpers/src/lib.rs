@@ -1,9 +1,25 @@
 #![no_std]
+pub mod arena;
+pub mod ct;
+#[cfg(feature = "dangerous")]
+pub mod dangerous;
+pub mod diag;
+pub mod dynptr;
+pub mod event;
+mod exit;
+pub mod hist;
+pub mod hits;
 #[allow(clippy::missing_safety_doc)]
 mod map;
+pub mod net;
 mod pid;
+pub mod recursion;
+pub mod regs;
+pub mod tc;
 mod time;
+pub mod xdp;
 
+pub use crate::exit::*;
 pub use crate::map::*;
 pub use crate::pid::*;
 pub use crate::time::*;
@@ -11,6 +27,10 @@ pub use bpf_helpers_sys as sys;
 pub use bpf_macros::*;
 pub use cty;
 
+/// Version of programs that don't check the kernel version, kernels before 5.0
+/// reject kprobes with it.
+pub const LINUX_VERSION_CODE_ANY: u32 = 0xFFFF_FFFE;
+
 #[inline]
 pub fn bpf_trace_printk(msg: &[u8]) -> usize {
     unsafe {
@@ -20,14 +40,35 @@ pub fn bpf_trace_printk(msg: &[u8]) -> usize {
     }
 }
 
+/// Declares kernel functions callable from probes.
+///
+/// Kfuncs are emitted as calls to undefined symbols which the loader resolves
+/// against the kernel btf, so the names have to match the kernel exactly.
+///
+/// ```ignore
+/// kfunc! {
+///     fn bpf_ct_release(ct: *mut nf_conn);
+/// }
+/// ```
+#[macro_export]
+macro_rules! kfunc {
+    ($($vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        extern "C" {
+            $($vis fn $name($($arg: $ty),*) $(-> $ret)?;)*
+        }
+    };
+}
+
 pub mod kprobe {
-    pub use bpf_helpers_sys::pt_regs;
+    pub use crate::regs::{pt_regs, Regs};
 }
 
-pub mod tracepoint {}
+pub mod tracepoint {
+    pub use core::ffi::c_void;
+}
 
 pub mod perf_event {
-    pub use bpf_helpers_sys::bpf_perf_event_data;
+    pub use crate::regs::{bpf_perf_event_data, PerfEventData, Regs};
 }
 
 pub mod raw_tracepoint {}
@@ -3,11 +3,28 @@
 //! Maps are a generic data structure for storage of different types of data.
 //! They allow sharing of data between eBPF kernel programs, and also between
 //! kernel and user-space code.
+use crate::xdp::XdpAction;
 use core::ffi::c_void;
 use core::marker::PhantomData;
 use core::mem;
 use cty::c_int;
 
+/// Map creation flags.
+///
+/// `RDONLY` and `WRONLY` restrict access through the `bpf` syscall, ie. from
+/// user space. `RDONLY_PROG` and `WRONLY_PROG` restrict access from bpf
+/// programs.
+pub mod flags {
+    pub const NO_PREALLOC: u32 = 1 << 0;
+    pub const NO_COMMON_LRU: u32 = 1 << 1;
+    pub const NUMA_NODE: u32 = 1 << 2;
+    pub const RDONLY: u32 = 1 << 3;
+    pub const WRONLY: u32 = 1 << 4;
+    pub const RDONLY_PROG: u32 = 1 << 7;
+    pub const WRONLY_PROG: u32 = 1 << 8;
+    pub const MMAPABLE: u32 = 1 << 10;
+}
+
 #[repr(transparent)]
 pub struct RawMap<K, V, const T: u32> {
     def: bpf_helpers_sys::bpf_map_def,
@@ -29,6 +46,33 @@ impl<K, V, const T: u32> RawMap<K, V, T> {
         }
     }
 
+    /// Overrides the maximum number of elements, used by `#[map(max_entries)]`.
+    pub const fn set_max_entries(self, max_entries: usize) -> Self {
+        Self {
+            def: bpf_helpers_sys::bpf_map_def {
+                max_entries: max_entries as u32,
+                ..self.def
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the map creation flags, see [`flags`].
+    pub const fn with_flags(self, flags: u32) -> Self {
+        Self {
+            def: bpf_helpers_sys::bpf_map_def {
+                map_flags: flags,
+                ..self.def
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        &self.def as *const _ as *mut c_void
+    }
+
     /// Returns a reference to the value corresponding to the key.
     ///
     /// To pass bpf validation the returned reference can be used only once.
@@ -43,21 +87,25 @@ impl<K, V, const T: u32> RawMap<K, V, T> {
     /// Set the `value` in the map for `key`
     #[inline(always)]
     pub unsafe fn update(&self, key: &K, value: &V) {
-        bpf_helpers_sys::bpf_map_update_elem(
+        let ret = bpf_helpers_sys::bpf_map_update_elem(
             &self.def as *const _ as *mut c_void,
             key as *const _ as *const c_void,
             value as *const _ as *const c_void,
             bpf_helpers_sys::BPF_ANY.into(),
         );
+        crate::diag::count_update(ret as _);
     }
 
     /// Delete the entry indexed by `key`
     #[inline(always)]
     pub unsafe fn delete(&self, key: &K) {
-        bpf_helpers_sys::bpf_map_delete_elem(
+        let ret = bpf_helpers_sys::bpf_map_delete_elem(
             &self.def as *const _ as *mut c_void,
             key as *const _ as *const c_void,
         );
+        if ret < 0 {
+            crate::diag::count(crate::diag::DELETE_MISS);
+        }
     }
 }
 
@@ -74,6 +122,7 @@ macro_rules! impl_hash_map {
             /// Returns a reference to the value corresponding to the key.
             #[inline(always)]
             pub fn get(&self, key: &K) -> Option<V> {
+                // missing keys are how hash maps are used, they aren't counted.
                 let ptr = unsafe { self.lookup(key) };
                 if ptr.is_null() {
                     None
@@ -109,11 +158,13 @@ pub type PercpuArray<V> =
 macro_rules! impl_hash_map {
     ($ty:ident) => {
         impl<V: Copy> $ty<V> {
-            /// Returns a reference to the value corresponding to the key.
+            /// Returns a reference to the value corresponding to the key,
+            /// `None` if the key is out of bounds.
             #[inline(always)]
             pub fn get(&self, key: u32) -> Option<V> {
                 let ptr = unsafe { self.lookup(&key) };
                 if ptr.is_null() {
+                    crate::diag::count(crate::diag::LOOKUP_MISS);
                     None
                 } else {
                     Some(unsafe { *ptr })
@@ -139,7 +190,11 @@ impl_hash_map!(PercpuArray);
 /// `BPF_MAP_TYPE_PERF_EVENT_ARRAY`.
 pub type PerfEventArray =
     RawMap<u32, u32, { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY }>;
-pub type RingBuf = RawMap<u32, u32, { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_RINGBUF }>;
+/// Ring buffer shared by all cpus (linux 5.8+).
+///
+/// The number of entries is the size of the buffer in bytes, a power of two
+/// multiple of the page size. Ring buffers have no keys or values.
+pub type RingBuf = RawMap<(), (), { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_RINGBUF }>;
 
 macro_rules! impl_perf_event {
     ($ty:ident) => {
@@ -166,6 +221,39 @@ macro_rules! impl_perf_event {
 impl_perf_event!(PerfEventArray);
 impl_perf_event!(RingBuf);
 
+impl PerfEventArray {
+    /// Flags of `perf_event_output` selecting the perf event of the current
+    /// cpu.
+    pub const CURRENT_CPU: u64 = 0xffff_ffff;
+}
+
+impl RingBuf {
+    /// Copies `data` into a new record, see `RingBuf::NO_WAKEUP` and
+    /// `RingBuf::FORCE_WAKEUP`.
+    #[inline(always)]
+    pub fn output<T>(&self, data: &T, flags: u64) -> Result<(), c_int> {
+        let ret = unsafe {
+            let f: unsafe extern "C" fn(
+                map: *mut c_void,
+                data: *mut c_void,
+                size: u64,
+                flags: u64,
+            ) -> cty::c_long = mem::transmute(130usize);
+            f(
+                self.as_ptr(),
+                data as *const _ as *mut _,
+                mem::size_of::<T>() as u64,
+                flags,
+            )
+        };
+        if ret < 0 {
+            Err(ret as _)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 // TODO Use PERF_MAX_STACK_DEPTH
 pub const BPF_MAX_STACK_DEPTH: usize = 127;
 
@@ -201,6 +289,66 @@ impl StackTrace {
     }
 }
 
+/// Device map.
+///
+/// Maps keys to interface indices, xdp programs redirect packets to the
+/// interfaces with `redirect`.
+pub type DevMap = RawMap<u32, u32, { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP }>;
+/// Device map with sparse keys.
+pub type DevMapHash = RawMap<u32, u32, { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP_HASH }>;
+/// Cpu map.
+///
+/// Maps cpus to queue sizes, xdp programs hand packets to the network stack on
+/// another cpu with `redirect`.
+pub type CpuMap = RawMap<u32, u32, { bpf_helpers_sys::bpf_map_type_BPF_MAP_TYPE_CPUMAP }>;
+
+macro_rules! impl_redirect_map {
+    ($ty:ident, $flags:expr) => {
+        impl $ty {
+            /// Flags besides the lookup failure action the map accepts.
+            const REDIRECT_FLAGS: u64 = $flags;
+
+            /// Redirects the packet to the entry at `key`.
+            ///
+            /// The lower two bits of `flags` are the action returned when the
+            /// lookup fails, see [`XdpAction`]. Flags the map type doesn't
+            /// support abort.
+            #[inline(always)]
+            pub fn redirect(&self, key: u32, flags: u64) -> XdpAction {
+                if flags & !(Self::REDIRECT_FLAGS | 0b11) != 0 {
+                    return XdpAction::Aborted;
+                }
+                let ret = unsafe {
+                    bpf_helpers_sys::bpf_redirect_map(
+                        &self.def as *const _ as *mut c_void,
+                        key,
+                        flags,
+                    )
+                };
+                XdpAction::from_raw(ret as _)
+            }
+        }
+    };
+}
+
+macro_rules! impl_broadcast {
+    ($ty:ident) => {
+        impl $ty {
+            /// Sends the packet to all entries of the map, `key` is ignored.
+            pub const BROADCAST: u64 = 1 << 3;
+            /// Skips the interface the packet was received on when
+            /// broadcasting.
+            pub const EXCLUDE_INGRESS: u64 = 1 << 4;
+        }
+        impl_redirect_map!($ty, Self::BROADCAST | Self::EXCLUDE_INGRESS);
+    };
+}
+
+impl_broadcast!(DevMap);
+impl_broadcast!(DevMapHash);
+// the kernel only broadcasts to devmaps.
+impl_redirect_map!(CpuMap, 0);
+
 /// Program array map.
 ///
 /// An array of eBPF programs that can be used as a jump table.
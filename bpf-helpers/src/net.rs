@@ -0,0 +1,160 @@
+//! Packet headers and checksums.
+//!
+//! The headers are packed since packet data has no alignment guarantees,
+//! multi byte fields are in network byte order.
+use core::ptr;
+
+pub const ETH_P_IP: u16 = 0x0800;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+
+pub const ETH_HLEN: usize = 14;
+pub const IPV4_HLEN: usize = 20;
+pub const TCP_HLEN: usize = 20;
+pub const ICMP_HLEN: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct EthHdr {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    pub proto: u16,
+}
+
+impl EthHdr {
+    #[inline(always)]
+    pub fn swap_addrs(&mut self) {
+        let src = self.src;
+        self.src = self.dst;
+        self.dst = src;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Ipv4Hdr {
+    pub version_ihl: u8,
+    pub tos: u8,
+    pub tot_len: u16,
+    pub id: u16,
+    pub frag_off: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub check: u16,
+    pub saddr: u32,
+    pub daddr: u32,
+}
+
+impl Ipv4Hdr {
+    /// Header length in bytes.
+    #[inline(always)]
+    pub fn header_len(&self) -> usize {
+        (self.version_ihl & 0xf) as usize * 4
+    }
+
+    /// Header without options replying to `other`.
+    #[inline(always)]
+    pub fn reply(other: &Ipv4Hdr, protocol: u8, payload_len: usize) -> Self {
+        let mut hdr = Self {
+            version_ihl: 0x45,
+            tos: 0,
+            tot_len: ((IPV4_HLEN + payload_len) as u16).to_be(),
+            id: 0,
+            frag_off: 0,
+            ttl: 64,
+            protocol,
+            check: 0,
+            saddr: other.daddr,
+            daddr: other.saddr,
+        };
+        hdr.check = csum_fold(csum(&hdr, 0));
+        hdr
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct TcpHdr {
+    pub source: u16,
+    pub dest: u16,
+    pub seq: u32,
+    pub ack_seq: u32,
+    /// Data offset in the upper four bits.
+    pub doff: u8,
+    pub flags: u8,
+    pub window: u16,
+    pub check: u16,
+    pub urg_ptr: u16,
+}
+
+impl TcpHdr {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+
+    /// Header length in bytes.
+    #[inline(always)]
+    pub fn header_len(&self) -> usize {
+        (self.doff >> 4) as usize * 4
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct IcmpHdr {
+    pub ty: u8,
+    pub code: u8,
+    pub check: u16,
+    pub unused: u32,
+}
+
+impl IcmpHdr {
+    pub const DEST_UNREACH: u8 = 3;
+
+    pub const NET_UNREACH: u8 = 0;
+    pub const HOST_UNREACH: u8 = 1;
+    pub const PROT_UNREACH: u8 = 2;
+    pub const PORT_UNREACH: u8 = 3;
+    pub const NET_ANO: u8 = 9;
+    pub const HOST_ANO: u8 = 10;
+    pub const PKT_FILTERED: u8 = 13;
+}
+
+/// Adds the bytes of `data` to the one's complement sum `seed`.
+///
+/// `T` must be a multiple of four bytes long.
+#[inline(always)]
+pub fn csum<T>(data: &T, seed: u32) -> u32 {
+    unsafe {
+        bpf_helpers_sys::bpf_csum_diff(
+            ptr::null_mut(),
+            0,
+            data as *const T as *mut _,
+            core::mem::size_of::<T>() as _,
+            seed,
+        ) as u32
+    }
+}
+
+/// Folds a 32 bit sum into the 16 bit checksum.
+#[inline(always)]
+pub fn csum_fold(csum: u32) -> u16 {
+    let mut csum = csum as u64;
+    csum = (csum & 0xffff) + (csum >> 16);
+    csum = (csum & 0xffff) + (csum >> 16);
+    !(csum as u16)
+}
+
+/// Sum of the tcp/udp pseudo header.
+#[inline(always)]
+pub fn pseudo_csum(ip: &Ipv4Hdr, protocol: u8, len: u16) -> u32 {
+    let len = len.to_be_bytes();
+    let pseudo = [
+        ip.saddr,
+        ip.daddr,
+        u32::from_ne_bytes([0, protocol, len[0], len[1]]),
+    ];
+    csum(&pseudo, 0)
+}
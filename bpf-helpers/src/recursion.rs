@@ -0,0 +1,68 @@
+//! Reentrancy guard for probes.
+//!
+//! The kernel doesn't nest runs of kprobe and tracepoint programs on a cpu,
+//! but a perf event in an nmi can interrupt any of them, and a program
+//! attached to a function its own helpers call triggers itself. Programs of
+//! a probe that share maps take a [`RecursionGuard`] first, which fails while
+//! another guarded program runs on the same cpu, so a nested run exits
+//! instead of updating the maps halfway through another update.
+//!
+//! `recursion_guard!()` defines the per cpu `BPF_RECURSION` map at the crate
+//! root and `guard!()` enters it. Skipped runs are counted as `recursion` by
+//! the `debug-counters` feature, see the diag module.
+//!
+//! ```ignore
+//! recursion_guard!();
+//!
+//! #[entry("kprobe")]
+//! fn kprobe(args: &pt_regs) -> Result<(), Exit> {
+//!     let _guard = guard!()?;
+//!     ...
+//! }
+//! ```
+use crate::map::PercpuArray;
+use crate::Exit;
+
+/// Marks the current cpu as running a guarded program until dropped.
+pub struct RecursionGuard<'a> {
+    active: &'a mut u32,
+}
+
+impl<'a> RecursionGuard<'a> {
+    /// Enters the guard of `map`, failing if it is held on the current cpu.
+    #[inline(always)]
+    pub fn enter(map: &'a PercpuArray<u32>) -> Result<Self, Exit> {
+        let active = match unsafe { map.lookup(&0).as_mut() } {
+            Some(active) => active,
+            None => return Err(Exit::default()),
+        };
+        if *active != 0 {
+            crate::diag::count(crate::diag::RECURSION);
+            return Err(Exit::default());
+        }
+        *active = 1;
+        Ok(Self { active })
+    }
+}
+
+impl<'a> Drop for RecursionGuard<'a> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        *self.active = 0;
+    }
+}
+
+#[macro_export]
+macro_rules! recursion_guard {
+    () => {
+        #[$crate::map]
+        static BPF_RECURSION: $crate::PercpuArray<u32> = $crate::PercpuArray::with_max_entries(1);
+    };
+}
+
+#[macro_export]
+macro_rules! guard {
+    () => {
+        $crate::recursion::RecursionGuard::enter(&crate::BPF_RECURSION)
+    };
+}
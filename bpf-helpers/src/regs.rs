@@ -0,0 +1,127 @@
+//! Typed access to sampled registers.
+//!
+//! [`PerfEventData`] wraps the context of `perf_event` programs and [`Regs`]
+//! the registers of it and of kprobes, so probes don't depend on the field
+//! names of `pt_regs`. Only x86_64 is supported.
+pub use bpf_helpers_sys::{bpf_perf_event_data, pt_regs};
+
+/// Registers of the interrupted context, user registers for perf events of
+/// a process.
+#[derive(Clone, Copy)]
+pub struct Regs<'a> {
+    regs: &'a pt_regs,
+}
+
+impl<'a> Regs<'a> {
+    #[inline(always)]
+    pub fn new(regs: &'a pt_regs) -> Self {
+        Self { regs }
+    }
+
+    #[inline(always)]
+    pub fn raw(&self) -> &'a pt_regs {
+        self.regs
+    }
+
+    /// Instruction pointer.
+    #[inline(always)]
+    pub fn ip(&self) -> u64 {
+        self.regs.rip
+    }
+
+    /// Stack pointer.
+    #[inline(always)]
+    pub fn sp(&self) -> u64 {
+        self.regs.rsp
+    }
+
+    /// Frame pointer.
+    #[inline(always)]
+    pub fn fp(&self) -> u64 {
+        self.regs.rbp
+    }
+
+    /// Return value in a kretprobe.
+    #[inline(always)]
+    pub fn rc(&self) -> u64 {
+        self.regs.rax
+    }
+
+    /// Argument `n` of the probed function in a kprobe, `None` past the
+    /// sixth.
+    #[inline(always)]
+    pub fn arg(&self, n: usize) -> Option<u64> {
+        match n {
+            0 => Some(self.regs.rdi),
+            1 => Some(self.regs.rsi),
+            2 => Some(self.regs.rdx),
+            3 => Some(self.regs.rcx),
+            4 => Some(self.regs.r8),
+            5 => Some(self.regs.r9),
+            _ => None,
+        }
+    }
+
+    /// Register by its dwarf number, 16 is the return address column which
+    /// holds `rip`.
+    #[inline(always)]
+    pub fn dwarf(&self, reg: u16) -> Option<u64> {
+        Some(match reg {
+            0 => self.regs.rax,
+            1 => self.regs.rdx,
+            2 => self.regs.rcx,
+            3 => self.regs.rbx,
+            4 => self.regs.rsi,
+            5 => self.regs.rdi,
+            6 => self.regs.rbp,
+            7 => self.regs.rsp,
+            8 => self.regs.r8,
+            9 => self.regs.r9,
+            10 => self.regs.r10,
+            11 => self.regs.r11,
+            12 => self.regs.r12,
+            13 => self.regs.r13,
+            14 => self.regs.r14,
+            15 => self.regs.r15,
+            16 => self.regs.rip,
+            _ => return None,
+        })
+    }
+}
+
+/// Context of a `perf_event` program.
+#[derive(Clone, Copy)]
+pub struct PerfEventData<'a> {
+    data: &'a bpf_perf_event_data,
+}
+
+impl<'a> PerfEventData<'a> {
+    #[inline(always)]
+    pub fn new(data: &'a bpf_perf_event_data) -> Self {
+        Self { data }
+    }
+
+    #[inline(always)]
+    pub fn raw(&self) -> &'a bpf_perf_event_data {
+        self.data
+    }
+
+    /// Registers at the time of the sample.
+    #[inline(always)]
+    pub fn regs(&self) -> Regs<'a> {
+        Regs::new(&self.data.regs)
+    }
+
+    /// Events or nanoseconds between samples.
+    #[inline(always)]
+    pub fn sample_period(&self) -> u64 {
+        self.data.sample_period
+    }
+
+    /// Data address of the sample, set by breakpoints and precise memory
+    /// events.
+    #[inline(always)]
+    pub fn addr(&self) -> u64 {
+        self.data.addr
+    }
+}
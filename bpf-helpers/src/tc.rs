@@ -0,0 +1,358 @@
+//! Traffic control classifier programs.
+//!
+//! Resizing the packet invalidates every pointer into it. [`SkBuff`] only
+//! hands out references borrowed from itself, and the resizing methods take
+//! `&mut self`, so a stale header reference is a compile error instead of a
+//! verifier rejection.
+use crate::net::*;
+pub use bpf_helpers_sys::__sk_buff;
+use core::mem;
+use cty::{c_int, c_long};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum TcAction {
+    Unspec = -1,
+    Ok = 0,
+    Reclassify = 1,
+    Shot = 2,
+    Pipe = 3,
+    Stolen = 4,
+    Redirect = 7,
+}
+
+/// Grow or shrink at the network layer, after the ip header.
+pub const BPF_ADJ_ROOM_NET: u32 = 0;
+/// Grow or shrink at the mac layer, after the ethernet header.
+pub const BPF_ADJ_ROOM_MAC: u32 = 1;
+
+pub const BPF_F_ADJ_ROOM_FIXED_GSO: u64 = 1 << 0;
+pub const BPF_F_ADJ_ROOM_ENCAP_L3_IPV4: u64 = 1 << 1;
+pub const BPF_F_ADJ_ROOM_ENCAP_L3_IPV6: u64 = 1 << 2;
+pub const BPF_F_ADJ_ROOM_ENCAP_L4_GRE: u64 = 1 << 3;
+pub const BPF_F_ADJ_ROOM_ENCAP_L4_UDP: u64 = 1 << 4;
+pub const BPF_F_ADJ_ROOM_NO_CSUM_RESET: u64 = 1 << 5;
+
+/// Check the segments of a gso packet instead of the packet length.
+pub const BPF_MTU_CHK_SEGS: u64 = 1;
+
+/// Redirect to the ingress instead of the egress of an interface.
+pub const BPF_F_INGRESS: u64 = 1;
+
+/// Hook a tc program is attached to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Packets received on the interface.
+    Ingress,
+    /// Packets sent out of the interface.
+    Egress,
+}
+
+/// Result of [`check_mtu`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mtu {
+    /// The packet fits, the mtu of the interface.
+    Ok(u32),
+    /// The packet needs fragmentation, the mtu of the interface.
+    FragNeeded(u32),
+    /// A gso segment exceeds the mtu.
+    SegsTooBig,
+}
+
+/// Checks whether the packet with `len_diff` bytes added fits the mtu of
+/// `ifindex`, `0` means the interface the packet is on.
+///
+/// `ctx` is a `__sk_buff` or an `xdp_md`.
+#[inline(always)]
+pub fn check_mtu<C>(ctx: &C, ifindex: u32, len_diff: i32, flags: u64) -> Result<Mtu, i32> {
+    let mut mtu_len = 0u32;
+    let ret = unsafe {
+        let f: unsafe extern "C" fn(
+            ctx: *mut cty::c_void,
+            ifindex: u32,
+            mtu_len: *mut u32,
+            len_diff: i32,
+            flags: u64,
+        ) -> c_long = mem::transmute(163usize);
+        f(
+            ctx as *const _ as *mut _,
+            ifindex,
+            &mut mtu_len,
+            len_diff,
+            flags,
+        )
+    };
+    match ret {
+        0 => Ok(Mtu::Ok(mtu_len)),
+        1 => Ok(Mtu::FragNeeded(mtu_len)),
+        2 => Ok(Mtu::SegsTooBig),
+        err => Err(err as _),
+    }
+}
+
+/// Packet of a tc program.
+pub struct SkBuff<'a> {
+    skb: &'a __sk_buff,
+}
+
+impl<'a> SkBuff<'a> {
+    #[inline(always)]
+    pub fn new(skb: &'a __sk_buff) -> Self {
+        Self { skb }
+    }
+
+    #[inline(always)]
+    pub fn raw(&self) -> &__sk_buff {
+        self.skb
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> u32 {
+        self.skb.len
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The pointers are reloaded from the context on every access, which is
+    // what the verifier requires after the packet was resized.
+    #[inline(always)]
+    fn bounds(&self) -> (usize, usize) {
+        unsafe {
+            let data = core::ptr::read_volatile(&self.skb.data);
+            let data_end = core::ptr::read_volatile(&self.skb.data_end);
+            (data as usize, data_end as usize)
+        }
+    }
+
+    /// Returns the `T` at `offset` if it lies within the linear packet data.
+    #[inline(always)]
+    pub fn get<T>(&self, offset: usize) -> Option<&T> {
+        let (data, data_end) = self.bounds();
+        let start = data + offset;
+        if start + mem::size_of::<T>() > data_end {
+            return None;
+        }
+        Some(unsafe { &*(start as *const T) })
+    }
+
+    /// Mutable version of [`get`](Self::get).
+    #[inline(always)]
+    pub fn get_mut<T>(&mut self, offset: usize) -> Option<&mut T> {
+        let (data, data_end) = self.bounds();
+        let start = data + offset;
+        if start + mem::size_of::<T>() > data_end {
+            return None;
+        }
+        Some(unsafe { &mut *(start as *mut T) })
+    }
+
+    /// Writes `value` at `offset`, works for paged data as well.
+    #[inline(always)]
+    pub fn store<T>(&mut self, offset: usize, value: &T) -> Result<(), i32> {
+        let ret = unsafe {
+            bpf_helpers_sys::bpf_skb_store_bytes(
+                self.ctx(),
+                offset as _,
+                value as *const T as *const _,
+                mem::size_of::<T>() as _,
+                0,
+            )
+        };
+        to_result(ret)
+    }
+
+    /// Sends a reply rewritten from the packet back where the packet came
+    /// from, `direction` is the hook the program is attached to.
+    ///
+    /// Replies to received packets go out of the interface, replies to sent
+    /// packets are delivered to the local stack through the ingress of the
+    /// interface.
+    #[inline(always)]
+    pub fn redirect_back(&self, direction: Direction) -> TcAction {
+        let flags = match direction {
+            Direction::Ingress => 0,
+            Direction::Egress => BPF_F_INGRESS,
+        };
+        let ret = unsafe { bpf_helpers_sys::bpf_redirect(self.skb.ifindex, flags) };
+        if ret == TcAction::Redirect as c_int {
+            TcAction::Redirect
+        } else {
+            TcAction::Shot
+        }
+    }
+
+    /// Pulls `len` bytes into the linear packet data, needed before `get`
+    /// when the headers are in paged data.
+    #[inline(always)]
+    pub fn pull_data(&mut self, len: u32) -> Result<(), i32> {
+        let ret = unsafe { bpf_helpers_sys::bpf_skb_pull_data(self.ctx(), len) };
+        to_result(ret)
+    }
+
+    /// Grows or shrinks the packet by `len_diff` bytes at the layer given by
+    /// `mode`, see `BPF_ADJ_ROOM_*` and `BPF_F_ADJ_ROOM_*`.
+    #[inline(always)]
+    pub fn adjust_room(&mut self, len_diff: i32, mode: u32, flags: u64) -> Result<(), i32> {
+        let ret =
+            unsafe { bpf_helpers_sys::bpf_skb_adjust_room(self.ctx(), len_diff, mode, flags) };
+        to_result(ret)
+    }
+
+    /// Resizes the packet to `len` bytes by growing or trimming the tail.
+    #[inline(always)]
+    pub fn change_tail(&mut self, len: u32, flags: u64) -> Result<(), i32> {
+        let ret = unsafe { bpf_helpers_sys::bpf_skb_change_tail(self.ctx(), len, flags) };
+        to_result(ret)
+    }
+
+    /// Like [`adjust_room`](Self::adjust_room) but only if the result still
+    /// fits the mtu of the interface.
+    #[inline(always)]
+    pub fn grow_room(&mut self, len_diff: i32, mode: u32, flags: u64) -> Result<(), i32> {
+        match check_mtu(self.skb, 0, len_diff, 0)? {
+            Mtu::Ok(_) => self.adjust_room(len_diff, mode, flags),
+            _ => Err(-(EMSGSIZE as i32)),
+        }
+    }
+
+    #[inline(always)]
+    fn ctx(&self) -> *mut __sk_buff {
+        self.skb as *const _ as *mut _
+    }
+}
+
+/// Rewrites a tcp/ipv4 packet into a reset answering it and sends it back,
+/// see [`SkBuff::redirect_back`].
+///
+/// Resets aren't answered, they are dropped.
+#[inline(always)]
+pub fn send_tcp_reset(skb: &mut SkBuff, direction: Direction) -> Result<TcAction, i32> {
+    let (eth, ip) = ipv4_headers(skb)?;
+    if ip.protocol != IPPROTO_TCP {
+        return Err(-EPROTONOSUPPORT);
+    }
+    let tcp: TcpHdr = *skb.get(ETH_HLEN + IPV4_HLEN).ok_or(-EINVAL)?;
+    if tcp.flags & TcpHdr::RST != 0 {
+        return Ok(TcAction::Shot);
+    }
+    // rfc 793: a reset takes its sequence number from the ack field of the
+    // offending segment, otherwise it acks everything the segment occupied.
+    let (seq, ack_seq, flags) = if tcp.flags & TcpHdr::ACK != 0 {
+        (tcp.ack_seq, 0, TcpHdr::RST)
+    } else {
+        let mut len =
+            (u16::from_be(ip.tot_len) as usize).saturating_sub(IPV4_HLEN + tcp.header_len()) as u32;
+        if tcp.flags & TcpHdr::SYN != 0 {
+            len += 1;
+        }
+        if tcp.flags & TcpHdr::FIN != 0 {
+            len += 1;
+        }
+        let ack_seq = u32::from_be(tcp.seq).wrapping_add(len).to_be();
+        (0, ack_seq, TcpHdr::RST | TcpHdr::ACK)
+    };
+
+    skb.change_tail((ETH_HLEN + IPV4_HLEN + TCP_HLEN) as _, 0)?;
+
+    let mut reply_eth = eth;
+    reply_eth.swap_addrs();
+    let reply_ip = Ipv4Hdr::reply(&ip, IPPROTO_TCP, TCP_HLEN);
+    let mut reply_tcp = TcpHdr {
+        source: tcp.dest,
+        dest: tcp.source,
+        seq,
+        ack_seq,
+        doff: ((TCP_HLEN / 4) as u8) << 4,
+        flags,
+        window: 0,
+        check: 0,
+        urg_ptr: 0,
+    };
+    let seed = pseudo_csum(&reply_ip, IPPROTO_TCP, TCP_HLEN as _);
+    reply_tcp.check = csum_fold(csum(&reply_tcp, seed));
+
+    skb.store(0, &reply_eth)?;
+    skb.store(ETH_HLEN, &reply_ip)?;
+    skb.store(ETH_HLEN + IPV4_HLEN, &reply_tcp)?;
+    Ok(skb.redirect_back(direction))
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct IcmpError {
+    icmp: IcmpHdr,
+    ip: Ipv4Hdr,
+    data: [u8; 8],
+}
+
+/// Rewrites an ipv4 packet into an icmp destination unreachable error with
+/// `code` (see `IcmpHdr::*_UNREACH`) and sends it back, see
+/// [`SkBuff::redirect_back`].
+///
+/// Icmp packets aren't answered, they are dropped.
+#[inline(always)]
+pub fn send_icmp_unreachable(
+    skb: &mut SkBuff,
+    code: u8,
+    direction: Direction,
+) -> Result<TcAction, i32> {
+    let (eth, ip) = ipv4_headers(skb)?;
+    if ip.protocol == IPPROTO_ICMP {
+        return Ok(TcAction::Shot);
+    }
+    let data: [u8; 8] = skb.get(ETH_HLEN + IPV4_HLEN).copied().unwrap_or_default();
+
+    // the error quotes the ip header and the first 8 bytes of the payload.
+    skb.adjust_room((IPV4_HLEN + ICMP_HLEN) as _, BPF_ADJ_ROOM_MAC, 0)?;
+    let len = ETH_HLEN + IPV4_HLEN + mem::size_of::<IcmpError>();
+    skb.change_tail(len as _, 0)?;
+
+    let mut reply_eth = eth;
+    reply_eth.swap_addrs();
+    let reply_ip = Ipv4Hdr::reply(&ip, IPPROTO_ICMP, mem::size_of::<IcmpError>());
+    let mut error = IcmpError {
+        icmp: IcmpHdr {
+            ty: IcmpHdr::DEST_UNREACH,
+            code,
+            check: 0,
+            unused: 0,
+        },
+        ip,
+        data,
+    };
+    error.icmp.check = csum_fold(csum(&error, 0));
+
+    skb.store(0, &reply_eth)?;
+    skb.store(ETH_HLEN, &reply_ip)?;
+    skb.store(ETH_HLEN + IPV4_HLEN, &error)?;
+    Ok(skb.redirect_back(direction))
+}
+
+#[inline(always)]
+fn ipv4_headers(skb: &SkBuff) -> Result<(EthHdr, Ipv4Hdr), i32> {
+    let eth: EthHdr = *skb.get(0).ok_or(-EINVAL)?;
+    if eth.proto != ETH_P_IP.to_be() {
+        return Err(-EPROTONOSUPPORT);
+    }
+    let ip: Ipv4Hdr = *skb.get(ETH_HLEN).ok_or(-EINVAL)?;
+    if ip.header_len() != IPV4_HLEN {
+        return Err(-EPROTONOSUPPORT);
+    }
+    Ok((eth, ip))
+}
+
+const EINVAL: i32 = 22;
+const EMSGSIZE: c_int = 90;
+const EPROTONOSUPPORT: i32 = 93;
+
+#[inline(always)]
+fn to_result(ret: c_int) -> Result<(), i32> {
+    if ret < 0 {
+        Err(ret as _)
+    } else {
+        Ok(())
+    }
+}
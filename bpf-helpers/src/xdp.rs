@@ -0,0 +1,34 @@
+//! XDP programs.
+//!
+//! An xdp entry point returns an [`XdpAction`] deciding the fate of the packet.
+pub use bpf_helpers_sys::xdp_md;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum XdpAction {
+    Aborted = 0,
+    Drop = 1,
+    Pass = 2,
+    Tx = 3,
+    Redirect = 4,
+}
+
+impl XdpAction {
+    /// Converts a helper return value, unknown values abort.
+    #[inline(always)]
+    pub fn from_raw(action: i32) -> Self {
+        match action {
+            1 => Self::Drop,
+            2 => Self::Pass,
+            3 => Self::Tx,
+            4 => Self::Redirect,
+            _ => Self::Aborted,
+        }
+    }
+}
+
+/// Start and end of the packet data.
+#[inline(always)]
+pub fn packet(ctx: &xdp_md) -> (usize, usize) {
+    (ctx.data as usize, ctx.data_end as usize)
+}
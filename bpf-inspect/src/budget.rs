@@ -0,0 +1,303 @@
+//! Size and complexity budgets for bpf programs.
+//!
+//! The numbers are estimates computed from the object file, they are meant to
+//! catch growth of probe code at build time rather than to predict the exact
+//! verifier behaviour.
+use crate::disasm::{disassemble, Insn, BPF_ALU64, BPF_LDX, BPF_ST, BPF_STX};
+use crate::{BpfObject, ProgramInfo, BPF_COMPLEXITY_LIMIT_INSNS};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Maximum stack size of a bpf program.
+pub const MAX_BPF_STACK: usize = 512;
+
+const FRAME_POINTER: u8 = 10;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProgramStats {
+    pub name: String,
+    pub instructions: usize,
+    pub branches: usize,
+    pub helper_calls: usize,
+    /// Instructions multiplied by the number of conditional branches plus one.
+    ///
+    /// The verifier explores both sides of every branch it can't prune, so
+    /// this grows much like the number of processed instructions does.
+    pub complexity: usize,
+    /// Worst case bytes of stack used, including the frames of called
+    /// functions.
+    pub stack_size: usize,
+}
+
+impl ProgramStats {
+    pub fn new(prog: &ProgramInfo) -> Self {
+        let insns = disassemble(&prog.code);
+        let branches = insns
+            .iter()
+            .filter(|(_, insn)| insn.is_jump() && insn.op() != 0)
+            .count();
+        let helper_calls = insns
+            .iter()
+            .filter(|(_, insn)| insn.helper().is_some())
+            .count();
+        Self {
+            name: prog.name.clone(),
+            instructions: prog.instructions,
+            branches,
+            helper_calls,
+            complexity: prog.instructions.saturating_mul(branches + 1),
+            stack_size: stack_size(insns.iter().map(|(_, insn)| insn)),
+        }
+    }
+}
+
+/// Returns the deepest frame pointer relative stack access.
+///
+/// Registers derived from `r10` by moves and constant additions are tracked
+/// in a single linear pass.
+pub fn stack_size<'a>(insns: impl Iterator<Item = &'a Insn>) -> usize {
+    let mut fp: [Option<i64>; 11] = Default::default();
+    fp[FRAME_POINTER as usize] = Some(0);
+    let mut depth = 0;
+    let mut access = |base: Option<i64>, off: i16| {
+        if let Some(base) = base {
+            depth = depth.max(-(base + off as i64));
+        }
+    };
+    for insn in insns {
+        let dst = insn.dst as usize % 11;
+        let src = insn.src as usize % 11;
+        match insn.class() {
+            BPF_LDX => {
+                access(fp[src], insn.off);
+                fp[dst] = None;
+            }
+            BPF_STX | BPF_ST => access(fp[dst], insn.off),
+            BPF_ALU64 if insn.op() == 0xb0 && insn.code & 0x08 != 0 => fp[dst] = fp[src],
+            BPF_ALU64 if insn.op() == 0x00 && insn.code & 0x08 == 0 => {
+                fp[dst] = fp[dst].map(|off| off + insn.imm)
+            }
+            BPF_ALU64 if insn.op() == 0x10 && insn.code & 0x08 == 0 => {
+                fp[dst] = fp[dst].map(|off| off - insn.imm)
+            }
+            _ if insn.is_call() => {
+                for reg in fp.iter_mut().take(6) {
+                    *reg = None;
+                }
+            }
+            _ if insn.is_jump() || insn.is_exit() => {}
+            _ => fp[dst] = None,
+        }
+        if dst == FRAME_POINTER as usize {
+            fp[dst] = Some(0);
+        }
+    }
+    depth.max(0) as usize
+}
+
+/// Limits a probe has to stay within.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Budget {
+    pub max_instructions: usize,
+    /// The complexity is a rough estimate that doesn't account for pruning,
+    /// it's only checked when a limit is set.
+    pub max_complexity: Option<usize>,
+    pub max_stack_size: usize,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            max_instructions: BPF_COMPLEXITY_LIMIT_INSNS,
+            max_complexity: None,
+            max_stack_size: MAX_BPF_STACK,
+        }
+    }
+}
+
+impl Budget {
+    pub const ENV_INSTRUCTIONS: &'static str = "BPF_BUDGET_INSNS";
+    pub const ENV_COMPLEXITY: &'static str = "BPF_BUDGET_COMPLEXITY";
+    pub const ENV_STACK_SIZE: &'static str = "BPF_BUDGET_STACK";
+
+    /// Default budget overridden by the `BPF_BUDGET_*` environment variables.
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+        Ok(Self {
+            max_instructions: env_limit(Self::ENV_INSTRUCTIONS, default.max_instructions)?,
+            max_complexity: match std::env::var(Self::ENV_COMPLEXITY) {
+                Ok(value) => Some(value.parse()?),
+                Err(_) => default.max_complexity,
+            },
+            max_stack_size: env_limit(Self::ENV_STACK_SIZE, default.max_stack_size)?,
+        })
+    }
+
+    pub fn violations(&self, stats: &ProgramStats) -> Vec<String> {
+        let mut violations = vec![];
+        let limits = [
+            ("instructions", stats.instructions, self.max_instructions),
+            ("stack size", stats.stack_size, self.max_stack_size),
+        ];
+        for (what, value, limit) in limits.iter() {
+            if value > limit {
+                violations.push(exceeds(stats, what, *value, *limit));
+            }
+        }
+        match self.max_complexity {
+            Some(limit) if stats.complexity > limit => {
+                violations.push(exceeds(stats, "complexity", stats.complexity, limit));
+            }
+            _ => {}
+        }
+        violations
+    }
+}
+
+fn exceeds(stats: &ProgramStats, what: &str, value: usize, limit: usize) -> String {
+    format!(
+        "program `{}` {} {} exceeds budget of {}",
+        stats.name, what, value, limit
+    )
+}
+
+fn env_limit(var: &str, default: usize) -> Result<usize> {
+    match std::env::var(var) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+pub struct Report<'a>(pub &'a [ProgramStats]);
+
+impl<'a> std::fmt::Display for Report<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:24} {:>8} {:>8} {:>8} {:>12} {:>6}",
+            "name", "insns", "branches", "helpers", "complexity", "stack"
+        )?;
+        for stats in self.0 {
+            writeln!(
+                f,
+                "{:24} {:>8} {:>8} {:>8} {:>12} {:>6}",
+                stats.name,
+                stats.instructions,
+                stats.branches,
+                stats.helper_calls,
+                stats.complexity,
+                stats.stack_size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl BpfObject {
+    /// Stats of the programs as loaded, with the functions they call.
+    pub fn stats(&self) -> Vec<ProgramStats> {
+        self.programs()
+            .iter()
+            .map(|prog| {
+                let stats = ProgramStats::new(prog);
+                let instructions = self.instructions(prog);
+                ProgramStats {
+                    instructions,
+                    complexity: instructions.saturating_mul(stats.branches + 1),
+                    stack_size: self.stack_usage(prog).depth,
+                    ..stats
+                }
+            })
+            .collect()
+    }
+}
+
+/// Checks a probe from a build script against the budget configured in the
+/// environment.
+///
+/// The report is written to the build script output, programs near the
+/// stack limit are emitted as cargo warnings. An error is returned if any
+/// program exceeds its budget.
+pub fn check_build<T: AsRef<Path>>(path: T) -> Result<()> {
+    println!("cargo:rerun-if-env-changed={}", Budget::ENV_INSTRUCTIONS);
+    println!("cargo:rerun-if-env-changed={}", Budget::ENV_COMPLEXITY);
+    println!("cargo:rerun-if-env-changed={}", Budget::ENV_STACK_SIZE);
+    check_build_with(path, &Budget::from_env()?)
+}
+
+/// Like [`check_build`], but checks against `budget`.
+pub fn check_build_with<T: AsRef<Path>>(path: T, budget: &Budget) -> Result<()> {
+    let obj = BpfObject::open(path)?;
+    let stats = obj.stats();
+    print!("{}", Report(&stats));
+    for prog in obj.programs() {
+        let usage = obj.stack_usage(prog);
+        if usage.is_near_limit() {
+            println!("cargo:warning=stack usage near the limit: {}", usage);
+        }
+    }
+    let violations: Vec<_> = stats
+        .iter()
+        .flat_map(|stats| budget.violations(stats))
+        .collect();
+    if !violations.is_empty() {
+        bail!("{}", violations.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i64) -> Insn {
+        Insn {
+            code,
+            dst,
+            src,
+            off,
+            imm,
+        }
+    }
+
+    #[test]
+    fn frame_pointer_stack_size() {
+        let insns = [
+            insn(0x7b, 10, 1, -8, 0),
+            insn(0xbf, 2, 10, 0, 0),
+            insn(0x07, 2, 0, 0, -64),
+            insn(0x7b, 2, 1, -8, 0),
+            insn(0x85, 0, 0, 0, 1),
+            insn(0x7b, 2, 1, -128, 0),
+            insn(0x95, 0, 0, 0, 0),
+        ];
+        assert_eq!(stack_size(insns.iter()), 72);
+    }
+
+    #[test]
+    fn complexity_checked_when_set() {
+        let stats = ProgramStats {
+            name: "probe".into(),
+            instructions: 2000,
+            branches: 600,
+            helper_calls: 10,
+            complexity: 2000 * 601,
+            stack_size: 256,
+        };
+        let budget = Budget::default();
+        assert!(budget.violations(&stats).is_empty());
+        let budget = Budget {
+            max_complexity: Some(2000 * 601),
+            ..budget
+        };
+        assert!(budget.violations(&stats).is_empty());
+        let budget = Budget {
+            max_complexity: Some(1_000_000),
+            ..budget
+        };
+        assert_eq!(
+            budget.violations(&stats),
+            vec!["program `probe` complexity 1202000 exceeds budget of 1000000".to_string()]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! BPF instruction decoder.
+//!
+//! The output follows the syntax used by `llvm-objdump` and the verifier log.
+use crate::helpers::helper_name;
+use crate::source::SourceMap;
+use object::SectionIndex;
+use std::convert::TryInto;
+
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+pub const BPF_ALU64: u8 = 0x07;
+
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+
+pub const BPF_PSEUDO_MAP_FD: u8 = 1;
+pub const BPF_PSEUDO_MAP_VALUE: u8 = 2;
+pub const BPF_PSEUDO_CALL: u8 = 1;
+pub const BPF_PSEUDO_KFUNC_CALL: u8 = 2;
+
+/// Decoded instruction.
+///
+/// `lddw` occupies two instruction slots, the upper half of the immediate is
+/// merged into `imm`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Insn {
+    pub code: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub off: i16,
+    pub imm: i64,
+}
+
+impl Insn {
+    /// Decodes the instruction at the start of `code`, returning it and the
+    /// number of bytes it occupies.
+    pub fn decode(code: &[u8]) -> Option<(Self, usize)> {
+        let bytes = code.get(..8)?;
+        let mut insn = Self {
+            code: bytes[0],
+            dst: bytes[1] & 0x0f,
+            src: bytes[1] >> 4,
+            off: i16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+            imm: i32::from_le_bytes(bytes[4..8].try_into().unwrap()) as i64,
+        };
+        if !insn.is_wide() {
+            return Some((insn, 8));
+        }
+        let next = code.get(8..16)?;
+        let hi = u32::from_le_bytes(next[4..8].try_into().unwrap()) as u64;
+        insn.imm = ((hi << 32) | (insn.imm as u32 as u64)) as i64;
+        Some((insn, 16))
+    }
+
+    #[inline(always)]
+    pub fn class(&self) -> u8 {
+        self.code & 0x07
+    }
+
+    #[inline(always)]
+    pub fn op(&self) -> u8 {
+        self.code & 0xf0
+    }
+
+    /// `lddw`
+    #[inline(always)]
+    pub fn is_wide(&self) -> bool {
+        self.code == 0x18
+    }
+
+    #[inline(always)]
+    fn is_src_reg(&self) -> bool {
+        self.code & 0x08 != 0
+    }
+
+    pub fn is_call(&self) -> bool {
+        self.class() == BPF_JMP && self.op() == BPF_CALL
+    }
+
+    pub fn is_exit(&self) -> bool {
+        self.class() == BPF_JMP && self.op() == BPF_EXIT
+    }
+
+    /// Returns the helper id if this is a call to a bpf helper.
+    pub fn helper(&self) -> Option<u32> {
+        if self.is_call() && self.src == 0 {
+            Some(self.imm as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` for conditional and unconditional jumps.
+    pub fn is_jump(&self) -> bool {
+        matches!(self.class(), BPF_JMP | BPF_JMP32) && !self.is_call() && !self.is_exit()
+    }
+
+    fn size(&self) -> &'static str {
+        match self.code & 0x18 {
+            0x00 => "u32",
+            0x08 => "u16",
+            0x10 => "u8",
+            _ => "u64",
+        }
+    }
+
+    fn fmt_alu(&self, f: &mut std::fmt::Formatter, reg: char) -> std::fmt::Result {
+        let src = if self.is_src_reg() {
+            format!("{}{}", reg, self.src)
+        } else {
+            self.imm.to_string()
+        };
+        let dst = self.dst;
+        let op = match self.op() {
+            0x00 => "+=",
+            0x10 => "-=",
+            0x20 => "*=",
+            0x30 => "/=",
+            0x40 => "|=",
+            0x50 => "&=",
+            0x60 => "<<=",
+            0x70 => ">>=",
+            0x80 => return write!(f, "{}{} = -{}{}", reg, dst, reg, dst),
+            0x90 => "%=",
+            0xa0 => "^=",
+            0xb0 => "=",
+            0xc0 => "s>>=",
+            0xd0 => {
+                let end = if self.is_src_reg() { "be" } else { "le" };
+                return write!(f, "r{} = {}{} r{}", dst, end, self.imm, dst);
+            }
+            _ => return write!(f, "invalid alu 0x{:02x}", self.code),
+        };
+        write!(f, "{}{} {} {}", reg, dst, op, src)
+    }
+
+    fn fmt_jmp(&self, f: &mut std::fmt::Formatter, reg: char) -> std::fmt::Result {
+        let src = if self.is_src_reg() {
+            format!("{}{}", reg, self.src)
+        } else {
+            self.imm.to_string()
+        };
+        let op = match self.op() {
+            0x00 => return write!(f, "goto {:+}", self.off),
+            0x10 => "==",
+            0x20 => ">",
+            0x30 => ">=",
+            0x40 => "&",
+            0x50 => "!=",
+            0x60 => "s>",
+            0x70 => "s>=",
+            BPF_CALL => {
+                return match self.src {
+                    BPF_PSEUDO_CALL => write!(f, "call pc{:+}", self.imm),
+                    BPF_PSEUDO_KFUNC_CALL => write!(f, "call kfunc#{}", self.imm),
+                    _ => match helper_name(self.imm as u32) {
+                        Some(name) => write!(f, "call {}#{}", name, self.imm),
+                        None => write!(f, "call {}", self.imm),
+                    },
+                }
+            }
+            BPF_EXIT => return write!(f, "exit"),
+            0xa0 => "<",
+            0xb0 => "<=",
+            0xc0 => "s<",
+            0xd0 => "s<=",
+            _ => return write!(f, "invalid jmp 0x{:02x}", self.code),
+        };
+        write!(
+            f,
+            "if {}{} {} {} goto {:+}",
+            reg, self.dst, op, src, self.off
+        )
+    }
+}
+
+impl std::fmt::Display for Insn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let off = Offset(self.off);
+        match self.class() {
+            BPF_ALU64 => self.fmt_alu(f, 'r'),
+            BPF_ALU => self.fmt_alu(f, 'w'),
+            BPF_JMP => self.fmt_jmp(f, 'r'),
+            BPF_JMP32 => self.fmt_jmp(f, 'w'),
+            BPF_LDX => write!(
+                f,
+                "r{} = *({} *)(r{}{})",
+                self.dst,
+                self.size(),
+                self.src,
+                off
+            ),
+            BPF_STX if self.code & 0xe0 == 0xc0 => write!(
+                f,
+                "lock *({} *)(r{}{}) += r{}",
+                self.size(),
+                self.dst,
+                off,
+                self.src
+            ),
+            BPF_STX => write!(
+                f,
+                "*({} *)(r{}{}) = r{}",
+                self.size(),
+                self.dst,
+                off,
+                self.src
+            ),
+            BPF_ST => write!(
+                f,
+                "*({} *)(r{}{}) = {}",
+                self.size(),
+                self.dst,
+                off,
+                self.imm
+            ),
+            BPF_LD if self.is_wide() => match self.src {
+                BPF_PSEUDO_MAP_FD => write!(f, "r{} = map[fd:{}] ll", self.dst, self.imm),
+                BPF_PSEUDO_MAP_VALUE => write!(f, "r{} = map_value[{}] ll", self.dst, self.imm),
+                _ => write!(f, "r{} = 0x{:x} ll", self.dst, self.imm),
+            },
+            BPF_LD => write!(f, "r0 = *({} *)skb[{}]", self.size(), self.imm),
+            _ => write!(f, "invalid 0x{:02x}", self.code),
+        }
+    }
+}
+
+struct Offset(i16);
+
+impl std::fmt::Display for Offset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0 < 0 {
+            write!(f, " - {}", -(self.0 as i32))
+        } else {
+            write!(f, " + {}", self.0)
+        }
+    }
+}
+
+/// Decodes all instructions in `code`, returning them with their slot index.
+pub fn disassemble(code: &[u8]) -> Vec<(usize, Insn)> {
+    let mut insns = vec![];
+    let mut offset = 0;
+    while let Some((insn, len)) = Insn::decode(&code[offset..]) {
+        insns.push((offset / 8, insn));
+        offset += len;
+    }
+    insns
+}
+
+/// Instruction listing of a program, annotated with source lines when debug
+/// info is available.
+pub struct Listing<'a> {
+    code: &'a [u8],
+    source: Option<(&'a SourceMap, SectionIndex)>,
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        Self { code, source: None }
+    }
+
+    pub fn with_source(mut self, source: &'a SourceMap, section: SectionIndex) -> Self {
+        self.source = Some((source, section));
+        self
+    }
+}
+
+impl<'a> std::fmt::Display for Listing<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut last_line = None;
+        for (i, insn) in disassemble(self.code) {
+            if let Some((source, section)) = self.source {
+                let line = source.line(section, i as u64 * 8).unwrap_or_default();
+                if let Some(text) = &line {
+                    if line != last_line {
+                        writeln!(f, "; {}", text)?;
+                        last_line = line;
+                    }
+                }
+            }
+            writeln!(f, "{:>6}: {}", i, insn)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u8, regs: u8, off: i16, imm: i32) -> Vec<u8> {
+        let mut bytes = vec![code, regs];
+        bytes.extend_from_slice(&off.to_le_bytes());
+        bytes.extend_from_slice(&imm.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn disassemble_insns() {
+        let mut code = vec![];
+        code.extend(insn(0xb7, 0x01, 0, 0));
+        code.extend(insn(0x7b, 0x1a, -8, 0));
+        code.extend(insn(0x18, 0x11, 0, 5));
+        code.extend(insn(0x00, 0x00, 0, 0));
+        code.extend(insn(0x85, 0x00, 0, 1));
+        code.extend(insn(0x55, 0x00, 2, 0));
+        code.extend(insn(0x61, 0x21, 4, 0));
+        code.extend(insn(0x95, 0x00, 0, 0));
+        let insns: Vec<_> = disassemble(&code)
+            .into_iter()
+            .map(|(i, insn)| (i, insn.to_string()))
+            .collect();
+        assert_eq!(
+            insns,
+            vec![
+                (0, "r1 = 0".to_string()),
+                (1, "*(u64 *)(r10 - 8) = r1".to_string()),
+                (2, "r1 = map[fd:5] ll".to_string()),
+                (4, "call bpf_map_lookup_elem#1".to_string()),
+                (5, "if r0 != 0 goto +2".to_string()),
+                (6, "r1 = *(u32 *)(r2 + 4)".to_string()),
+                (7, "exit".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! Inspection of compiled bpf object files.
+//!
+//! Lists the programs, maps and BTF types contained in an object, which is
+//! usually the first thing to look at when the loader or the verifier rejects
+//! it.
+use crate::disasm::Listing;
+use crate::source::SourceMap;
+use anyhow::Result;
+use bpf_utils::btf::Btf;
+use object::{
+    Object, ObjectSection, ObjectSymbol, RelocationTarget, SectionIndex, SectionKind, SymbolKind,
+};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+pub mod budget;
+pub mod disasm;
+pub mod helpers;
+pub mod license;
+mod source;
+pub mod stack;
+
+/// Maximum number of instructions the verifier accepts from privileged users.
+pub const BPF_COMPLEXITY_LIMIT_INSNS: usize = 1_000_000;
+/// Maximum number of instructions the verifier accepts from unprivileged users.
+pub const BPF_MAXINSNS: usize = 4096;
+
+static PROGRAM_SECTIONS: &[&str] = &[
+    "kprobe/",
+    "kretprobe/",
+    "uprobe",
+    "uretprobe",
+    "tracepoint/",
+    "tp/",
+    "raw_tracepoint/",
+    "raw_tp/",
+    "perf_event",
+    "xdp",
+    "classifier",
+    "action",
+    "tc",
+    "socket",
+    "sockops",
+    "sk_skb",
+    "sk_msg",
+    "cgroup",
+    "fentry/",
+    "fexit/",
+    "lsm/",
+    "iter/",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProgramInfo {
+    pub name: String,
+    pub section: String,
+    pub section_index: SectionIndex,
+    /// Number of 8 byte instruction slots (`lddw` occupies two).
+    pub instructions: usize,
+    pub code: Vec<u8>,
+}
+
+impl ProgramInfo {
+    pub fn prog_type(&self) -> &str {
+        self.section.split('/').next().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MapInfo {
+    pub name: String,
+    pub ty: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub flags: u32,
+}
+
+impl MapInfo {
+    pub fn type_name(&self) -> &'static str {
+        map_type_name(self.ty)
+    }
+}
+
+pub struct BpfObject {
+    path: PathBuf,
+    license: Option<String>,
+    version: Option<u32>,
+    programs: Vec<ProgramInfo>,
+    maps: Vec<MapInfo>,
+    btf: Option<Btf>,
+    kfuncs: Vec<String>,
+    functions: Vec<stack::Function>,
+    source: Option<SourceMap>,
+}
+
+impl BpfObject {
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let data = std::fs::read(path.as_ref())?;
+        Self::parse(path.as_ref(), &data)
+    }
+
+    pub fn parse(path: &Path, data: &[u8]) -> Result<Self> {
+        let file = object::File::parse(data)?;
+        let mut obj = Self {
+            path: path.to_owned(),
+            license: None,
+            version: None,
+            programs: vec![],
+            maps: vec![],
+            btf: None,
+            kfuncs: vec![],
+            functions: stack::functions(&file)?,
+            source: SourceMap::new(&file)?,
+        };
+        for section in file.sections() {
+            let name = section.name()?;
+            match name {
+                "license" => obj.license = Some(read_cstr(section.data()?)),
+                "version" => {
+                    obj.version = section
+                        .data()?
+                        .get(..4)
+                        .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()));
+                }
+                "maps" => obj.maps = parse_maps(&file, &section)?,
+                ".BTF" => obj.btf = Some(Btf::parse(section.data()?)?),
+                ".text" => {}
+                _ if section.kind() == SectionKind::Text && section.size() > 0 => {
+                    obj.kfuncs.extend(kfunc_calls(&file, &section)?);
+                    obj.programs.push(ProgramInfo {
+                        name: program_name(&file, &section),
+                        section: name.to_string(),
+                        section_index: section.index(),
+                        instructions: section.size() as usize / 8,
+                        code: section.data()?.to_vec(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        obj.kfuncs.sort();
+        obj.kfuncs.dedup();
+        Ok(obj)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn license(&self) -> Option<&str> {
+        self.license.as_deref()
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn programs(&self) -> &[ProgramInfo] {
+        &self.programs
+    }
+
+    pub fn maps(&self) -> &[MapInfo] {
+        &self.maps
+    }
+
+    pub fn btf(&self) -> Option<&Btf> {
+        self.btf.as_ref()
+    }
+
+    /// Kernel functions called by the programs.
+    pub fn kfuncs(&self) -> &[String] {
+        &self.kfuncs
+    }
+
+    pub fn program(&self, name: &str) -> Option<&ProgramInfo> {
+        self.programs
+            .iter()
+            .find(|prog| prog.name == name || prog.section == name)
+    }
+
+    /// Disassembly of a program, interleaved with the source lines if the
+    /// object was built with debug info.
+    pub fn listing<'a>(&'a self, prog: &'a ProgramInfo) -> Listing<'a> {
+        let listing = Listing::new(&prog.code);
+        match self.source.as_ref() {
+            Some(source) => listing.with_source(source, prog.section_index),
+            None => listing,
+        }
+    }
+
+    /// Common reasons for the loader or the verifier to reject the object.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.license.is_none() {
+            warnings.push("missing `license` section".to_string());
+        }
+        warnings.extend(self.license_violations());
+        for prog in &self.programs {
+            if !PROGRAM_SECTIONS
+                .iter()
+                .any(|prefix| prog.section.starts_with(prefix))
+            {
+                warnings.push(format!(
+                    "program `{}` in unknown section `{}`, the program type can't be inferred",
+                    prog.name, prog.section
+                ));
+            }
+            if prog.instructions > BPF_COMPLEXITY_LIMIT_INSNS {
+                warnings.push(format!(
+                    "program `{}` has {} instructions, the limit is {}",
+                    prog.name, prog.instructions, BPF_COMPLEXITY_LIMIT_INSNS
+                ));
+            } else if prog.instructions > BPF_MAXINSNS {
+                warnings.push(format!(
+                    "program `{}` has {} instructions, unprivileged users are limited to {}",
+                    prog.name, prog.instructions, BPF_MAXINSNS
+                ));
+            }
+        }
+        for map in &self.maps {
+            if map.max_entries == 0 {
+                warnings.push(format!("map `{}` has zero max entries", map.name));
+            }
+            if map.key_size == 0 && map.type_name().contains("hash") {
+                warnings.push(format!("map `{}` has zero key size", map.name));
+            }
+        }
+        warnings
+    }
+}
+
+impl std::fmt::Display for BpfObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", self.path.display())?;
+        writeln!(f, "license: {}", self.license().unwrap_or("none"))?;
+        match self.version {
+            Some(version) => writeln!(f, "version: 0x{:x}", version)?,
+            None => writeln!(f, "version: none")?,
+        }
+
+        writeln!(f, "\nprograms:")?;
+        writeln!(f, "{:24} {:32} {:>8}", "name", "section", "insns")?;
+        for prog in &self.programs {
+            writeln!(
+                f,
+                "{:24} {:32} {:>8}",
+                prog.name, prog.section, prog.instructions
+            )?;
+        }
+
+        writeln!(f, "\nmaps:")?;
+        writeln!(
+            f,
+            "{:24} {:20} {:>6} {:>6} {:>12} flags",
+            "name", "type", "key", "value", "max_entries"
+        )?;
+        for map in &self.maps {
+            writeln!(
+                f,
+                "{:24} {:20} {:>6} {:>6} {:>12} {}",
+                map.name,
+                map.type_name(),
+                map.key_size,
+                map.value_size,
+                map.max_entries,
+                MapFlags(map.flags)
+            )?;
+        }
+
+        if !self.kfuncs.is_empty() {
+            writeln!(f, "\nkfuncs:")?;
+            for kfunc in &self.kfuncs {
+                writeln!(f, "{}", kfunc)?;
+            }
+        }
+
+        writeln!(f, "\nbtf:")?;
+        match self.btf.as_ref() {
+            Some(btf) => {
+                for ty in btf.types() {
+                    writeln!(f, "[{}] {} '{}'", ty.id, ty.kind, ty.name)?;
+                }
+            }
+            None => writeln!(f, "none")?,
+        }
+
+        let warnings = self.warnings();
+        if !warnings.is_empty() {
+            writeln!(f, "\nwarnings:")?;
+            for warning in warnings {
+                writeln!(f, "{}", warning)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls to undefined symbols, which the loader resolves as kfuncs.
+fn kfunc_calls(file: &object::File<'_>, section: &object::Section<'_, '_>) -> Result<Vec<String>> {
+    let code = section.data()?;
+    let mut kfuncs = vec![];
+    for (offset, reloc) in section.relocations() {
+        let symbol = match reloc.target() {
+            RelocationTarget::Symbol(index) => file.symbol_by_index(index)?,
+            _ => continue,
+        };
+        if symbol.is_undefined() && code.get(offset as usize) == Some(&0x85) {
+            kfuncs.push(symbol.name()?.to_string());
+        }
+    }
+    Ok(kfuncs)
+}
+
+struct MapFlags(u32);
+
+impl std::fmt::Display for MapFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        static FLAGS: &[(u32, &str)] = &[
+            (1 << 0, "NO_PREALLOC"),
+            (1 << 1, "NO_COMMON_LRU"),
+            (1 << 2, "NUMA_NODE"),
+            (1 << 3, "RDONLY"),
+            (1 << 4, "WRONLY"),
+            (1 << 5, "STACK_BUILD_ID"),
+            (1 << 6, "ZERO_SEED"),
+            (1 << 7, "RDONLY_PROG"),
+            (1 << 8, "WRONLY_PROG"),
+            (1 << 9, "CLONE"),
+            (1 << 10, "MMAPABLE"),
+        ];
+        write!(f, "0x{:x}", self.0)?;
+        for (flag, name) in FLAGS {
+            if self.0 & flag != 0 {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn map_type_name(ty: u32) -> &'static str {
+    static TYPES: &[&str] = &[
+        "unspec",
+        "hash",
+        "array",
+        "prog_array",
+        "perf_event_array",
+        "percpu_hash",
+        "percpu_array",
+        "stack_trace",
+        "cgroup_array",
+        "lru_hash",
+        "lru_percpu_hash",
+        "lpm_trie",
+        "array_of_maps",
+        "hash_of_maps",
+        "devmap",
+        "sockmap",
+        "cpumap",
+        "xskmap",
+        "sockhash",
+        "cgroup_storage",
+        "reuseport_sockarray",
+        "percpu_cgroup_storage",
+        "queue",
+        "stack",
+        "sk_storage",
+        "devmap_hash",
+        "struct_ops",
+        "ringbuf",
+        "inode_storage",
+        "task_storage",
+        "bloom_filter",
+        "user_ringbuf",
+        "cgrp_storage",
+        "arena",
+    ];
+    TYPES.get(ty as usize).copied().unwrap_or("unknown")
+}
+
+fn parse_maps(file: &object::File<'_>, section: &object::Section<'_, '_>) -> Result<Vec<MapInfo>> {
+    let data = section.data()?;
+    let mut maps = vec![];
+    for symbol in file.symbols() {
+        if symbol.section_index() != Some(section.index()) || symbol.kind() == SymbolKind::Section {
+            continue;
+        }
+        let name = symbol.name()?;
+        if name.is_empty() {
+            continue;
+        }
+        let offset = symbol.address() as usize;
+        let def = data
+            .get(offset..offset + 20)
+            .ok_or_else(|| anyhow::anyhow!("map `{}` out of bounds", name))?;
+        let field = |i: usize| u32::from_ne_bytes(def[i * 4..i * 4 + 4].try_into().unwrap());
+        maps.push((
+            offset,
+            MapInfo {
+                name: name.to_string(),
+                ty: field(0),
+                key_size: field(1),
+                value_size: field(2),
+                max_entries: field(3),
+                flags: field(4),
+            },
+        ));
+    }
+    maps.sort_by_key(|(offset, _)| *offset);
+    Ok(maps.into_iter().map(|(_, map)| map).collect())
+}
+
+fn program_name(file: &object::File<'_>, section: &object::Section<'_, '_>) -> String {
+    for symbol in file.symbols() {
+        if symbol.section_index() == Some(section.index())
+            && symbol.kind() == SymbolKind::Text
+            && symbol.address() == 0
+        {
+            if let Ok(name) = symbol.name() {
+                return name.to_string();
+            }
+        }
+    }
+    let name = section.name().unwrap_or_default();
+    name.rsplit('/').next().unwrap_or(name).to_string()
+}
+
+fn read_cstr(data: &[u8]) -> String {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
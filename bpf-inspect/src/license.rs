@@ -0,0 +1,119 @@
+//! Helpers restricted to GPL compatible programs.
+//!
+//! The verifier rejects a call to a GPL-only helper with `EINVAL` when the
+//! `license` section of the object isn't GPL compatible. Checking the helper
+//! calls of the compiled object reports it when the probe is built instead.
+use crate::helpers::helper_name;
+use crate::BpfObject;
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Licenses the kernel considers GPL compatible, `license_is_gpl_compatible`.
+static GPL_COMPATIBLE: &[&str] = &[
+    "GPL",
+    "GPL v2",
+    "GPL and additional rights",
+    "Dual BSD/GPL",
+    "Dual MIT/GPL",
+    "Dual MPL/GPL",
+];
+
+/// Helpers with `gpl_only` set in their kernel prototype.
+static GPL_ONLY: &[&str] = &[
+    "bpf_probe_read",
+    "bpf_trace_printk",
+    "bpf_perf_event_read",
+    "bpf_perf_event_output",
+    "bpf_get_stackid",
+    "bpf_get_current_task",
+    "bpf_probe_write_user",
+    "bpf_probe_read_str",
+    "bpf_perf_event_read_value",
+    "bpf_perf_prog_read_value",
+    "bpf_override_return",
+    "bpf_get_stack",
+    "bpf_skb_output",
+    "bpf_probe_read_user",
+    "bpf_probe_read_kernel",
+    "bpf_probe_read_user_str",
+    "bpf_probe_read_kernel_str",
+    "bpf_read_branch_records",
+    "bpf_xdp_output",
+    "bpf_seq_printf",
+    "bpf_seq_write",
+    "bpf_seq_printf_btf",
+    "bpf_get_current_task_btf",
+    "bpf_snprintf",
+    "bpf_timer_init",
+    "bpf_timer_set_callback",
+    "bpf_timer_start",
+    "bpf_timer_cancel",
+    "bpf_task_pt_regs",
+    "bpf_get_branch_snapshot",
+    "bpf_trace_vprintk",
+];
+
+pub fn is_gpl_compatible(license: &str) -> bool {
+    GPL_COMPATIBLE.contains(&license)
+}
+
+pub fn is_gpl_only(helper: &str) -> bool {
+    GPL_ONLY.contains(&helper)
+}
+
+impl BpfObject {
+    /// Calls to GPL-only helpers the license of the object doesn't allow.
+    pub fn license_violations(&self) -> Vec<String> {
+        let license = self.license().unwrap_or_default();
+        if is_gpl_compatible(license) {
+            return vec![];
+        }
+        let mut violations = vec![];
+        for function in &self.functions {
+            for helper in function.helpers.iter().filter_map(|id| helper_name(*id)) {
+                if is_gpl_only(helper) {
+                    violations.push(format!(
+                        "`{}` calls the GPL-only helper `{}`, which the license {:?} doesn't allow",
+                        function.name(),
+                        helper,
+                        license
+                    ));
+                }
+            }
+        }
+        violations
+    }
+}
+
+/// Checks the license of a probe from a build script, an error is returned
+/// if a program calls a GPL-only helper it isn't allowed to.
+pub fn check_build<T: AsRef<Path>>(path: T) -> Result<()> {
+    let violations = BpfObject::open(path)?.license_violations();
+    if !violations.is_empty() {
+        bail!("{}", violations.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::helper_id;
+
+    #[test]
+    fn gpl_compatible() {
+        assert!(is_gpl_compatible("GPL"));
+        assert!(is_gpl_compatible("Dual MIT/GPL"));
+        assert!(!is_gpl_compatible("MIT"));
+        assert!(!is_gpl_compatible("gpl"));
+    }
+
+    #[test]
+    fn gpl_only_helpers() {
+        for helper in GPL_ONLY {
+            assert!(helper_id(helper).is_some(), "unknown helper {}", helper);
+        }
+        assert!(is_gpl_only("bpf_get_stackid"));
+        assert!(!is_gpl_only("bpf_map_lookup_elem"));
+    }
+}
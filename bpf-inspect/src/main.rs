@@ -0,0 +1,107 @@
+use anyhow::Result;
+use bpf_inspect::budget::{Budget, Report};
+use bpf_inspect::disasm::Listing;
+use bpf_inspect::BpfObject;
+use bpf_utils::sys;
+
+const USAGE: &str = "usage:
+    cargo bpf-inspect <object>
+    cargo bpf-inspect --disasm <object> [program]
+    cargo bpf-inspect --budget <object>
+    cargo bpf-inspect --xlated <prog id>
+    cargo bpf-inspect --jited <prog id>";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // cargo passes the name of the subcommand as the first argument
+    if args.first() == Some(&"bpf-inspect") {
+        args.remove(0);
+    }
+    match args.as_slice() {
+        ["--disasm", path] => disasm(path, None),
+        ["--disasm", path, prog] => disasm(path, Some(prog)),
+        ["--budget", path] => budget(path),
+        ["--xlated", id] => xlated(id.parse()?),
+        ["--jited", id] => jited(id.parse()?),
+        [path] if !path.starts_with('-') => {
+            print!("{}", BpfObject::open(path)?);
+            Ok(())
+        }
+        _ => usage(),
+    }
+}
+
+fn disasm(path: &str, prog: Option<&str>) -> Result<()> {
+    let obj = BpfObject::open(path)?;
+    let progs = match prog {
+        Some(name) => match obj.program(name) {
+            Some(prog) => vec![prog],
+            None => anyhow::bail!("no program `{}` in {}", name, path),
+        },
+        None => obj.programs().iter().collect(),
+    };
+    for prog in progs {
+        println!("{} ({}):", prog.name, prog.section);
+        println!("{}", obj.listing(prog));
+    }
+    Ok(())
+}
+
+fn budget(path: &str) -> Result<()> {
+    let budget = Budget::from_env()?;
+    let obj = BpfObject::open(path)?;
+    let stats = obj.stats();
+    print!("{}", Report(&stats));
+    println!("\nstack:");
+    for prog in obj.programs() {
+        println!("{}", obj.stack_usage(prog));
+    }
+    let violations: Vec<_> = stats.iter().flat_map(|s| budget.violations(s)).collect();
+    for violation in &violations {
+        println!("{}", violation);
+    }
+    if !violations.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn xlated(id: u32) -> Result<()> {
+    let fd = sys::prog_get_fd_by_id(id)?;
+    let info = sys::prog_info(fd)?;
+    let insns = sys::prog_xlated_insns(fd)?;
+    unsafe { libc::close(fd) };
+    println!(
+        "{} (id {}): {} xlated bytes, {} jited bytes",
+        info.name(),
+        info.id,
+        info.xlated_prog_len,
+        info.jited_prog_len
+    );
+    print!("{}", Listing::new(&insns));
+    Ok(())
+}
+
+fn jited(id: u32) -> Result<()> {
+    let fd = sys::prog_get_fd_by_id(id)?;
+    let info = sys::prog_info(fd)?;
+    let code = sys::prog_jited_insns(fd)?;
+    unsafe { libc::close(fd) };
+    println!(
+        "{} (id {}): {} jited bytes",
+        info.name(),
+        info.id,
+        code.len()
+    );
+    for (i, line) in code.chunks(16).enumerate() {
+        let bytes: Vec<_> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{:6x}: {}", i * 16, bytes.join(" "));
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Source line lookup for relocatable bpf objects.
+//!
+//! Every program lives in its own section starting at address zero, so the
+//! debug sections are relocated with a distinct base per section before the
+//! line tables are parsed.
+use addr2line::gimli::{self, RunTimeEndian};
+use addr2line::Context;
+use anyhow::Result;
+use object::{
+    Object, ObjectSection, ObjectSymbol, RelocationKind, RelocationTarget, SectionIndex,
+    SectionKind,
+};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+type Reader = gimli::EndianRcSlice<RunTimeEndian>;
+
+/// Address a program section is relocated to.
+pub fn section_base(index: SectionIndex) -> u64 {
+    (index.0 as u64) << 32
+}
+
+pub struct SourceMap {
+    ctx: Context<Reader>,
+}
+
+impl SourceMap {
+    /// Returns `None` when the object has no debug info.
+    pub fn new(file: &object::File<'_>) -> Result<Option<Self>> {
+        if file.section_by_name(".debug_info").is_none() {
+            return Ok(None);
+        }
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(
+            |id| -> Result<Reader> {
+                let data = match file.section_by_name(id.name()) {
+                    Some(section) => relocated_data(file, &section)?,
+                    None => vec![],
+                };
+                Ok(Reader::new(Rc::from(data.as_slice()), endian))
+            },
+            // probes have no supplementary object file.
+            |_| -> Result<Reader> { Ok(Reader::new(Rc::from(&[][..]), endian)) },
+        )?;
+        Ok(Some(Self {
+            ctx: Context::from_dwarf(dwarf)?,
+        }))
+    }
+
+    /// Returns `file:line` for the instruction at byte `offset` of a section.
+    pub fn line(&self, section: SectionIndex, offset: u64) -> Result<Option<String>> {
+        let location = match self.ctx.find_location(section_base(section) + offset)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        Ok(match (location.file, location.line) {
+            (Some(file), Some(line)) => Some(format!("{}:{}", file, line)),
+            (Some(file), None) => Some(file.to_string()),
+            _ => None,
+        })
+    }
+}
+
+fn relocated_data(file: &object::File<'_>, section: &object::Section<'_, '_>) -> Result<Vec<u8>> {
+    let mut data = match section.uncompressed_data()? {
+        Cow::Borrowed(data) => data.to_vec(),
+        Cow::Owned(data) => data,
+    };
+    for (offset, reloc) in section.relocations() {
+        if reloc.kind() != RelocationKind::Absolute {
+            continue;
+        }
+        let symbol = match reloc.target() {
+            RelocationTarget::Symbol(index) => file.symbol_by_index(index)?,
+            _ => continue,
+        };
+        let base = match symbol.section_index() {
+            Some(index) if is_text(file, index) => section_base(index),
+            _ => 0,
+        };
+        let offset = offset as usize;
+        let size = reloc.size() as usize / 8;
+        let bytes = match data.get_mut(offset..offset + size) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let implicit = if reloc.has_implicit_addend() {
+            match size {
+                4 => u32::from_le_bytes(bytes[..].try_into()?) as i64,
+                8 => u64::from_le_bytes(bytes[..].try_into()?) as i64,
+                _ => continue,
+            }
+        } else {
+            0
+        };
+        let value = (base + symbol.address()).wrapping_add((reloc.addend() + implicit) as u64);
+        match size {
+            4 => bytes.copy_from_slice(&(value as u32).to_le_bytes()),
+            8 => bytes.copy_from_slice(&value.to_le_bytes()),
+            _ => {}
+        }
+    }
+    Ok(data)
+}
+
+fn is_text(file: &object::File<'_>, index: SectionIndex) -> bool {
+    file.section_by_index(index)
+        .map(|section| section.kind() == SectionKind::Text)
+        .unwrap_or_default()
+}
@@ -0,0 +1,226 @@
+//! Worst case stack usage across bpf to bpf calls.
+//!
+//! Every function gets its own frame, which the verifier rounds up to 32
+//! bytes. The sum of the frames along the deepest call chain has to stay
+//! within the 512 byte limit.
+use crate::budget::{stack_size, MAX_BPF_STACK};
+use crate::disasm::{disassemble, BPF_PSEUDO_CALL};
+use crate::{BpfObject, ProgramInfo};
+use anyhow::Result;
+use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget, SectionIndex, SymbolKind};
+use std::collections::HashMap;
+
+/// Maximum depth of bpf to bpf calls.
+pub const MAX_CALL_FRAMES: usize = 8;
+/// Usage in percent of the limit above which the build warns.
+pub const WARN_PERCENT: usize = 90;
+
+const FRAME_ALIGN: usize = 32;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Function {
+    name: String,
+    section: SectionIndex,
+    /// Offset in the section in instructions.
+    start: usize,
+    /// Number of instruction slots.
+    len: usize,
+    frame: usize,
+    /// Section and instruction offset of the called functions.
+    calls: Vec<(SectionIndex, usize)>,
+    /// Ids of the called helpers.
+    pub(crate) helpers: Vec<u32>,
+}
+
+impl Function {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Collects the functions of all code sections with the calls they make.
+pub(crate) fn functions(file: &object::File<'_>) -> Result<Vec<Function>> {
+    let mut functions = vec![];
+    for section in file.sections() {
+        if section.kind() != object::SectionKind::Text || section.size() == 0 {
+            continue;
+        }
+        let code = section.data()?;
+        let relocs: HashMap<usize, (SectionIndex, usize)> = section
+            .relocations()
+            .filter_map(|(offset, reloc)| {
+                let symbol = match reloc.target() {
+                    RelocationTarget::Symbol(index) => file.symbol_by_index(index).ok()?,
+                    _ => return None,
+                };
+                Some((
+                    offset as usize / 8,
+                    (symbol.section_index()?, symbol.address() as usize / 8),
+                ))
+            })
+            .collect();
+        let mut ranges: Vec<(String, usize, usize)> = file
+            .symbols()
+            .filter(|symbol| {
+                symbol.section_index() == Some(section.index())
+                    && symbol.kind() == SymbolKind::Text
+                    && symbol.size() > 0
+            })
+            .map(|symbol| {
+                let start = symbol.address() as usize / 8;
+                let name = symbol.name().unwrap_or_default().to_string();
+                (name, start, start + symbol.size() as usize / 8)
+            })
+            .collect();
+        if ranges.is_empty() {
+            let name = section.name().unwrap_or_default().to_string();
+            ranges.push((name, 0, code.len() / 8));
+        }
+        for (name, start, end) in ranges {
+            let code = code.get(start * 8..end * 8).unwrap_or_default();
+            let insns = disassemble(code);
+            let calls = insns
+                .iter()
+                .filter(|(_, insn)| insn.is_call() && insn.src == BPF_PSEUDO_CALL)
+                .map(|(offset, insn)| {
+                    let offset = start + offset;
+                    // relocated calls are relative to the symbol, the others
+                    // to the next instruction.
+                    let (section, base) = match relocs.get(&offset) {
+                        Some((section, base)) => (*section, *base as i64),
+                        None => (section.index(), offset as i64),
+                    };
+                    (section, (base + insn.imm + 1) as usize)
+                })
+                .collect();
+            let mut helpers: Vec<u32> =
+                insns.iter().filter_map(|(_, insn)| insn.helper()).collect();
+            helpers.sort_unstable();
+            helpers.dedup();
+            functions.push(Function {
+                name,
+                section: section.index(),
+                start,
+                len: end - start,
+                frame: stack_size(insns.iter().map(|(_, insn)| insn)),
+                calls,
+                helpers,
+            });
+        }
+    }
+    Ok(functions)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StackUsage {
+    pub program: String,
+    /// Sum of the rounded frames along the deepest call chain.
+    pub depth: usize,
+    /// Functions of the deepest call chain with their frame sizes.
+    pub chain: Vec<(String, usize)>,
+}
+
+impl StackUsage {
+    pub fn is_near_limit(&self) -> bool {
+        self.depth * 100 >= MAX_BPF_STACK * WARN_PERCENT
+    }
+}
+
+impl std::fmt::Display for StackUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} bytes (", self.program, self.depth)?;
+        for (i, (name, frame)) in self.chain.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{} {}", name, frame)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn round_frame(frame: usize) -> usize {
+    let frame = frame.max(1);
+    frame.div_ceil(FRAME_ALIGN) * FRAME_ALIGN
+}
+
+impl BpfObject {
+    /// Worst case stack usage of a program including the functions it calls.
+    pub fn stack_usage(&self, prog: &ProgramInfo) -> StackUsage {
+        let chain = match self.function_at(prog.section_index, 0) {
+            Some(entry) => self.deepest_chain(entry, 0),
+            None => vec![],
+        };
+        StackUsage {
+            program: prog.name.clone(),
+            depth: chain
+                .iter()
+                .map(|i| round_frame(self.functions[*i].frame))
+                .sum(),
+            chain: chain
+                .iter()
+                .map(|i| (self.functions[*i].name.clone(), self.functions[*i].frame))
+                .collect(),
+        }
+    }
+
+    /// Instructions of a program including the functions it calls in other
+    /// sections, which the loader appends to the program once each.
+    pub fn instructions(&self, prog: &ProgramInfo) -> usize {
+        let mut called = vec![];
+        if let Some(entry) = self.function_at(prog.section_index, 0) {
+            self.called_functions(entry, &mut called);
+        }
+        prog.instructions
+            + called
+                .iter()
+                .map(|i| &self.functions[*i])
+                .filter(|func| func.section != prog.section_index)
+                .map(|func| func.len)
+                .sum::<usize>()
+    }
+
+    /// Adds the functions called by `func` directly or indirectly to `called`.
+    fn called_functions(&self, func: usize, called: &mut Vec<usize>) {
+        for (section, offset) in &self.functions[func].calls {
+            if let Some(callee) = self.function_at(*section, *offset) {
+                if !called.contains(&callee) {
+                    called.push(callee);
+                    self.called_functions(callee, called);
+                }
+            }
+        }
+    }
+
+    fn function_at(&self, section: SectionIndex, offset: usize) -> Option<usize> {
+        self.functions
+            .iter()
+            .position(|func| func.section == section && func.start == offset)
+    }
+
+    /// Returns the function indices of the deepest chain starting at `func`.
+    ///
+    /// Recursion isn't allowed by the verifier, the depth is capped to
+    /// terminate anyway.
+    fn deepest_chain(&self, func: usize, depth: usize) -> Vec<usize> {
+        let mut deepest = vec![];
+        let mut deepest_size = 0;
+        if depth < MAX_CALL_FRAMES {
+            for (section, offset) in &self.functions[func].calls {
+                if let Some(callee) = self.function_at(*section, *offset) {
+                    let chain = self.deepest_chain(callee, depth + 1);
+                    let size: usize = chain
+                        .iter()
+                        .map(|i| round_frame(self.functions[*i].frame))
+                        .sum();
+                    if size > deepest_size {
+                        deepest = chain;
+                        deepest_size = size;
+                    }
+                }
+            }
+        }
+        deepest.insert(0, func);
+        deepest
+    }
+}
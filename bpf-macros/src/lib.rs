@@ -9,7 +9,7 @@ use syn::{
     punctuated::Punctuated,
 };
 
-struct Args(Punctuated<syn::Lit, syn::token::Comma>);
+struct Args(Punctuated<syn::Expr, syn::token::Comma>);
 
 impl Parse for Args {
     fn parse(input: ParseStream) -> syn::Result<Args> {
@@ -19,9 +19,13 @@ impl Parse for Args {
 
 /// Generates program metadata.
 ///
-/// Takes two arguments, the `LINUX_VERSION_CODE` the program is compatible with,
-/// and the license. The special version code `0xFFFFFFFE` can be used to signify
-/// any kernel version.
+/// Takes the license and optionally the `LINUX_VERSION_CODE` the program is
+/// compatible with before it. Without a version the loader sets it to the
+/// version of the running kernel, which kernels before 5.0 require for kprobes.
+/// `bpf_helpers::LINUX_VERSION_CODE_ANY` is kept as is, for programs that don't
+/// check the version. Calling GPL-only helpers requires a GPL compatible
+/// license, which `bpf_inspect::license::check_build` checks after the probe
+/// is built.
 ///
 /// # Example
 ///
@@ -29,15 +33,24 @@ impl Parse for Args {
 /// #![no_std]
 /// #![no_main]
 /// # use bpf_macros::program;
-/// program!(0xFFFFFFFE, b"GPL");
+/// program!(b"GPL");
 /// ```
 #[proc_macro]
 pub fn program(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as Args);
-    let mut args = input.0.iter();
-    let version = args.next().expect("no version");
-    let license = args.next().expect("no license");
-    let len = quote!(lincense).to_string().len() - 5;
+    let args: Vec<_> = input.0.iter().collect();
+    let (version, license) = match args.as_slice() {
+        [license] => (quote!(0), *license),
+        [version, license] => (quote!(#version), *license),
+        _ => panic!("expected an optional version and a license"),
+    };
+    let len = match license {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::ByteStr(license),
+            ..
+        }) => license.value().len(),
+        _ => panic!("expected a byte string license"),
+    };
     let tokens = quote! {
         #[no_mangle]
         #[link_section = "license"]
@@ -57,13 +70,72 @@ pub fn program(input: TokenStream) -> TokenStream {
     tokens.into()
 }
 
+/// Places a map definition in the `maps` section.
+///
+/// The number of entries can be overridden at build time, with
+/// `max_entries = <n>` (for example behind a `cfg_attr`) or with
+/// `max_entries_env = "<VAR>"`, which reads the environment variable when the
+/// probe is built and takes precedence.
+///
+/// ```ignore
+/// #[map(max_entries_env = "STACK_MAP_SIZE")]
+/// static STACKS: HashMap<u64, u32> = HashMap::with_max_entries(1024);
+/// ```
 #[proc_macro_attribute]
-pub fn map(_: TokenStream, item: TokenStream) -> TokenStream {
-    let map = parse_macro_input!(item as syn::ItemStatic);
+pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let attrs = parse_macro_input!(attrs as syn::AttributeArgs);
+    let mut map = parse_macro_input!(item as syn::ItemStatic);
+    let mut max_entries = None;
+    let mut env_tracking = quote!();
+    for attr in attrs {
+        let (name, lit) = match attr {
+            syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) => match nv.path.get_ident() {
+                Some(ident) => (ident.to_string(), nv.lit),
+                None => panic!("unexpected map argument"),
+            },
+            _ => panic!("expected max_entries or max_entries_env"),
+        };
+        match (name.as_str(), lit) {
+            ("max_entries", syn::Lit::Int(n)) => {
+                let value = match n.base10_parse::<u32>() {
+                    Ok(value) => value as usize,
+                    Err(err) => return err.to_compile_error().into(),
+                };
+                if max_entries.is_none() {
+                    max_entries = Some(value);
+                }
+            }
+            ("max_entries_env", syn::Lit::Str(lit)) => {
+                let var = lit.value();
+                if let Ok(value) = std::env::var(&var) {
+                    // max_entries is a u32 in the kernel.
+                    let n = match value.parse::<u32>() {
+                        Ok(n) => n as usize,
+                        Err(_) => {
+                            let msg = format!("{}={} is not a number of entries", var, value);
+                            return syn::Error::new_spanned(lit, msg).to_compile_error().into();
+                        }
+                    };
+                    max_entries = Some(n);
+                }
+                // makes cargo rebuild the probe when the variable changes.
+                env_tracking = quote! {
+                    const _: Option<&str> = option_env!(#var);
+                };
+            }
+            (name, _) => panic!("unexpected map argument {}", name),
+        }
+    }
+    if let Some(n) = max_entries {
+        let expr = &map.expr;
+        map.expr = Box::new(syn::parse_quote!((#expr).set_max_entries(#n)));
+    }
     let tokens = quote! {
         #[no_mangle]
         #[link_section = "maps"]
         #map
+
+        #env_tracking
     };
     tokens.into()
 }
@@ -80,6 +152,10 @@ pub fn entry(attrs: TokenStream, item: TokenStream) -> TokenStream {
         "kprobe" => quote!(bpf_helpers::kprobe::pt_regs),
         "perf_event" => quote!(bpf_helpers::perf_event::bpf_perf_event_data),
         "tracing" => quote!(core::ffi::c_void),
+        "xdp" => quote!(bpf_helpers::xdp::xdp_md),
+        "tc" => quote!(bpf_helpers::tc::__sk_buff),
+        // tracepoints that may be missing at build time, like driver events.
+        "tracepoint" => quote!(core::ffi::c_void),
         //"raw_tracepoint" => quote!(u64),
         //"raw_tracepoint_writable" => quote!(u64),
         tracepoint => {
@@ -104,7 +180,12 @@ pub fn entry(attrs: TokenStream, item: TokenStream) -> TokenStream {
         }
     };
     let ident = &prog.sig.ident;
-    let section_name = format!("{}/{}", prog_type, ident.to_string());
+    // libbpf infers the program type of tc programs from the `classifier` prefix.
+    let section_prefix = match prog_type.as_str() {
+        "tc" => "classifier",
+        prog_type => prog_type,
+    };
+    let section_name = format!("{}/{}", section_prefix, ident.to_string());
     let prog_type = format_ident!("{}", prog_type);
     let tokens = quote! {
         #event
@@ -116,8 +197,8 @@ pub fn entry(attrs: TokenStream, item: TokenStream) -> TokenStream {
             #[inline(always)]
             #prog
             let arg = unsafe { &*(arg as *const #arg) };
-            #ident(arg);
-            0
+            // programs returning a value like an `XdpAction` pass it on to the kernel.
+            bpf_helpers::ProgramReturn::into_return(#ident(arg))
         }
     };
     tokens.into()
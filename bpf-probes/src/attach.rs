@@ -1,21 +1,31 @@
-use crate::{HardwareEvent, Interval, Mode, SoftwareEvent};
+use crate::tracefs::TraceEvent;
+use crate::{pmu, HardwareEvent, Interval, Mode, SoftwareEvent};
 use anyhow::{Context, Error, Result};
-use libbpf_rs::Program;
 use perf_event_open_sys::bindings::{self as sys, perf_event_attr};
 use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::str::FromStr;
 
 #[derive(Debug, Eq, PartialEq)]
-pub struct AttachedProbe(u32);
+pub struct AttachedProbe {
+    fd: u32,
+    /// Probe created in tracefs on kernels without the kprobe and uprobe
+    /// pmus, removed once the fd is closed.
+    event: Option<TraceEvent>,
+}
 
 impl AttachedProbe {
     pub fn kprobe(symbol: &str, offset: usize, pid: Option<u32>) -> Result<Self> {
+        if !pmu::exists("kprobe") {
+            let target = format!("{}+{}", symbol, offset);
+            return Self::trace_event("kprobe_events", 'p', &target, pid);
+        }
         let symbol = CString::new(symbol)?;
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
-        attr.type_ = pmu_type("kprobe")?;
+        attr.type_ = pmu::pmu_type("kprobe")?;
         attr.config = 0;
         attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 {
             kprobe_func: symbol.as_ptr() as _,
@@ -27,10 +37,13 @@ impl AttachedProbe {
     }
 
     pub fn kretprobe(symbol: &str, pid: Option<u32>) -> Result<Self> {
+        if !pmu::exists("kprobe") {
+            return Self::trace_event("kprobe_events", 'r', symbol, pid);
+        }
         let symbol = CString::new(symbol)?;
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
-        attr.type_ = pmu_type("kprobe")?;
+        attr.type_ = pmu::pmu_type("kprobe")?;
         attr.config = 1;
         attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 {
             kprobe_func: symbol.as_ptr() as _,
@@ -41,9 +54,13 @@ impl AttachedProbe {
 
     pub fn uprobe(path: &Path, address: usize, pid: Option<u32>) -> Result<Self> {
         log::trace!("attaching uprobe at address 0x{:x}", address);
+        if !pmu::exists("uprobe") {
+            let target = format!("{}:0x{:x}", path.display(), address);
+            return Self::trace_event("uprobe_events", 'p', &target, pid);
+        }
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
-        attr.type_ = pmu_type("uprobe")?;
+        attr.type_ = pmu::pmu_type("uprobe")?;
         attr.config = 0;
         attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 {
             uprobe_path: path.as_os_str().as_bytes().as_ptr() as _,
@@ -55,9 +72,13 @@ impl AttachedProbe {
     }
 
     pub fn uretprobe(path: &Path, address: usize, pid: Option<u32>) -> Result<Self> {
+        if !pmu::exists("uprobe") {
+            let target = format!("{}:0x{:x}", path.display(), address);
+            return Self::trace_event("uprobe_events", 'r', &target, pid);
+        }
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
-        attr.type_ = pmu_type("uprobe")?;
+        attr.type_ = pmu::pmu_type("uprobe")?;
         attr.config = 1;
         attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 {
             uprobe_path: path.as_os_str().as_bytes().as_ptr() as _,
@@ -76,12 +97,14 @@ impl AttachedProbe {
         let path = format!("/sys/kernel/debug/tracing/events/{}/{}/id", category, name);
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
-        attr.type_ = pmu_type("tracepoint")?;
+        attr.type_ = pmu::pmu_type("tracepoint")?;
         attr.config = read(&path)?;
         Self::open_for_any_cpu(&attr, pid)
     }
 
-    pub fn profile(interval: &Interval, pid: Option<u32>) -> Result<Vec<Self>> {
+    /// Samples every `interval`. An `inherit`ed event of a task also samples
+    /// the threads and children it starts after the event was opened.
+    pub fn profile(interval: &Interval, pid: Option<u32>, inherit: bool) -> Result<Vec<Self>> {
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
         attr.type_ = sys::perf_type_id_PERF_TYPE_SOFTWARE;
@@ -107,10 +130,11 @@ impl AttachedProbe {
                 attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 { sample_freq: *f };
             }
         }
+        attr.set_inherit(inherit as _);
         Self::open_for_every_cpu(&attr, pid)
     }
 
-    pub fn interval(interval: &Interval, pid: Option<u32>) -> Result<Self> {
+    pub fn interval(interval: &Interval, pid: Option<u32>, inherit: bool) -> Result<Self> {
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
         attr.type_ = sys::perf_type_id_PERF_TYPE_SOFTWARE;
@@ -136,10 +160,16 @@ impl AttachedProbe {
                 attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 { sample_freq: *f };
             }
         }
+        attr.set_inherit(inherit as _);
         Self::open_for_any_cpu(&attr, pid)
     }
 
-    pub fn software(event: SoftwareEvent, count: u64, pid: Option<u32>) -> Result<Self> {
+    pub fn software(
+        event: SoftwareEvent,
+        count: u64,
+        pid: Option<u32>,
+        inherit: bool,
+    ) -> Result<Self> {
         use SoftwareEvent::*;
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
@@ -160,10 +190,16 @@ impl AttachedProbe {
         attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 {
             sample_period: count,
         };
+        attr.set_inherit(inherit as _);
         Self::open_for_any_cpu(&attr, pid)
     }
 
-    pub fn hardware(event: HardwareEvent, count: u64, pid: Option<u32>) -> Result<Vec<Self>> {
+    pub fn hardware(
+        event: HardwareEvent,
+        count: u64,
+        pid: Option<u32>,
+        inherit: bool,
+    ) -> Result<Vec<Self>> {
         use HardwareEvent::*;
         let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
         attr.size = std::mem::size_of::<perf_event_attr>() as _;
@@ -183,6 +219,39 @@ impl AttachedProbe {
         attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 {
             sample_period: count,
         };
+        attr.set_inherit(inherit as _);
+        Self::open_for_every_cpu(&attr, pid)
+    }
+
+    /// Opens `event` of the pmu `device`, on every cpu of its `cpumask` for
+    /// pmus that only count system wide.
+    pub fn pmu(
+        device: &str,
+        event: &str,
+        count: u64,
+        pid: Option<u32>,
+        inherit: bool,
+    ) -> Result<Vec<Self>> {
+        let [config, config1, config2] = pmu::encode(device, event)?;
+        let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
+        attr.size = std::mem::size_of::<perf_event_attr>() as _;
+        attr.type_ = pmu::pmu_type(device)?;
+        attr.config = config;
+        attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 { config1 };
+        attr.__bindgen_anon_4 = sys::perf_event_attr__bindgen_ty_4 { config2 };
+        attr.__bindgen_anon_1 = sys::perf_event_attr__bindgen_ty_1 {
+            sample_period: count,
+        };
+        if let Some(cpus) = pmu::cpus(device) {
+            if pid.is_some() {
+                log::warn!("pmu {} counts system wide, not just the target", device);
+            }
+            return cpus
+                .into_iter()
+                .map(|cpu| Self::open_for_cpu(&attr, None, cpu as _))
+                .collect();
+        }
+        attr.set_inherit(inherit as _);
         Self::open_for_every_cpu(&attr, pid)
     }
 
@@ -203,7 +272,39 @@ impl AttachedProbe {
         todo!()
     }
 
+    /// Opens a probe added to the tracefs file `events`.
+    fn trace_event(
+        events: &'static str,
+        kind: char,
+        target: &str,
+        pid: Option<u32>,
+    ) -> Result<Self> {
+        log::debug!("no pmu, adding {}:{} to {}", kind, target, events);
+        let event = TraceEvent::create(events, kind, target)?;
+        let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
+        attr.size = std::mem::size_of::<perf_event_attr>() as _;
+        attr.type_ = sys::perf_type_id_PERF_TYPE_TRACEPOINT;
+        let probe = event.id().and_then(|id| {
+            attr.config = id;
+            Self::open_for_any_cpu(&attr, pid)
+        });
+        match probe {
+            Ok(mut probe) => {
+                probe.event = Some(event);
+                Ok(probe)
+            }
+            Err(err) => {
+                event.remove().ok();
+                Err(err)
+            }
+        }
+    }
+
     fn open_for_every_cpu(attr: &perf_event_attr, pid: Option<u32>) -> Result<Vec<Self>> {
+        // events of a task follow it to every cpu.
+        if pid.is_some() {
+            return Ok(vec![Self::open_for_any_cpu(attr, pid)?]);
+        }
         bpf_utils::cpu::online_cpu_ids()?
             .into_iter()
             .map(|cpu| Self::open_for_cpu(attr, pid, cpu as _))
@@ -211,7 +312,9 @@ impl AttachedProbe {
     }
 
     fn open_for_any_cpu(attr: &perf_event_attr, pid: Option<u32>) -> Result<Self> {
-        Self::open_for_cpu(attr, pid, 0)
+        // events of a task follow it to every cpu, system wide events need one.
+        let cpu = if pid.is_some() { -1 } else { 0 };
+        Self::open_for_cpu(attr, pid, cpu)
     }
 
     fn open_for_cpu(attr: &perf_event_attr, pid: Option<u32>, cpu: i32) -> Result<Self> {
@@ -229,11 +332,19 @@ impl AttachedProbe {
         if pfd < 0 {
             return Err(std::io::Error::last_os_error().into());
         }
-        Ok(Self(pfd as _))
+        Ok(Self {
+            fd: pfd as _,
+            event: None,
+        })
+    }
+
+    /// The perf event fd.
+    pub fn fd(&self) -> u32 {
+        self.fd
     }
 
     pub fn enable(&self) -> Result<()> {
-        if unsafe { perf_event_open_sys::ioctls::ENABLE(self.0 as _, 0) } != 0 {
+        if unsafe { perf_event_open_sys::ioctls::ENABLE(self.fd as _, 0) } != 0 {
             return Err(Error::from(std::io::Error::last_os_error()))
                 .context("ioctl(PERF_EVENT_IOC_ENABLE)");
         }
@@ -241,15 +352,16 @@ impl AttachedProbe {
     }
 
     pub fn disable(&self) -> Result<()> {
-        if unsafe { perf_event_open_sys::ioctls::DISABLE(self.0 as _, 0) } != 0 {
+        if unsafe { perf_event_open_sys::ioctls::DISABLE(self.fd as _, 0) } != 0 {
             return Err(Error::from(std::io::Error::last_os_error()))
                 .context("ioctl(PERF_EVENT_IOC_DISABLE)");
         }
         Ok(())
     }
 
-    pub fn set_bpf(&self, program: &Program) -> Result<()> {
-        if unsafe { perf_event_open_sys::ioctls::SET_BPF(self.0 as _, program.fd() as _) } != 0 {
+    /// Runs the loaded program `prog_fd` on every event.
+    pub fn set_bpf(&self, prog_fd: RawFd) -> Result<()> {
+        if unsafe { perf_event_open_sys::ioctls::SET_BPF(self.fd as _, prog_fd as _) } != 0 {
             return Err(Error::from(std::io::Error::last_os_error()))
                 .context("ioctl(PERF_EVENT_IOC_SET_BPF)");
         }
@@ -257,7 +369,7 @@ impl AttachedProbe {
     }
 
     fn close(&self) -> Result<()> {
-        if unsafe { libc::close(self.0 as _) } < 0 {
+        if unsafe { libc::close(self.fd as _) } < 0 {
             return Err(Error::from(std::io::Error::last_os_error()))
                 .context("close perf event FD failed");
         }
@@ -273,12 +385,53 @@ impl Drop for AttachedProbe {
         if let Err(err) = self.close() {
             log::warn!("{}", err);
         }
+        if let Some(event) = self.event.as_ref() {
+            if let Err(err) = event.remove() {
+                log::warn!("{}", err);
+            }
+        }
+    }
+}
+
+/// Thread ids of the process `pid`.
+pub(crate) fn threads(pid: u32) -> Result<Vec<u32>> {
+    let mut tids = vec![];
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Whether every thread of the process `pid` is stopped, a stopped thread
+/// can't start new ones.
+pub(crate) fn stopped(pid: u32) -> Result<bool> {
+    for tid in threads(pid)? {
+        let stat = match std::fs::read_to_string(format!("/proc/{}/task/{}/stat", pid, tid)) {
+            Ok(stat) => stat,
+            // the thread exited since it was listed.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // the state follows the command name, which may contain spaces.
+        let state = stat
+            .rsplit(')')
+            .next()
+            .and_then(|rest| rest.trim_start().chars().next());
+        if !matches!(state, Some('T') | Some('t')) {
+            return Ok(false);
+        }
     }
+    Ok(true)
 }
 
-fn pmu_type(event: &str) -> Result<u32> {
-    let path = format!("/sys/bus/event_source/devices/{}/type", event);
-    read(&path)
+/// Whether opening an event failed because the task exited.
+pub(crate) fn is_esrch(err: &Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .and_then(|err| err.raw_os_error())
+        == Some(libc::ESRCH)
 }
 
 fn read<P, T>(path: P) -> Result<T>
@@ -10,14 +10,20 @@
 use anyhow::Result;
 use bpf_utils::elf::Elf;
 pub use libbpf_rs::{Program, ProgramAttachType, ProgramType};
+use std::collections::HashSet;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use thiserror::Error;
 
 mod attach;
 mod parse;
+pub mod pmu;
+mod tracefs;
+pub mod xdp;
 
 pub use crate::attach::AttachedProbe;
+pub use crate::xdp::AttachedXdp;
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq)]
 pub enum Interval {
@@ -224,6 +230,12 @@ pub enum Probe {
         length: usize,
         mode: Mode,
     },
+    /// Event of a pmu in `/sys/bus/event_source/devices`, see the pmu module.
+    Pmu {
+        device: String,
+        event: String,
+        count: Option<u64>,
+    },
     Kfunc {
         func: String,
     },
@@ -293,6 +305,17 @@ impl std::fmt::Display for Probe {
                 length,
                 mode,
             } => write!(f, "watchpoint:{:x}:{}:{}", address, length, mode),
+            Pmu {
+                device,
+                event,
+                count,
+            } => {
+                write!(f, "pmu:{}:{}", device, event)?;
+                if let Some(count) = count {
+                    write!(f, ":{}", count)?;
+                }
+                Ok(())
+            }
             Kfunc { func } => write!(f, "kfunc:{}", func),
             Kretfunc { func } => write!(f, "kretfunc:{}", func),
         }
@@ -312,7 +335,8 @@ impl Probe {
             | Self::Interval { .. }
             | Self::Software { .. }
             | Self::Hardware { .. }
-            | Self::Watchpoint { .. } => ProgramType::PerfEvent,
+            | Self::Watchpoint { .. }
+            | Self::Pmu { .. } => ProgramType::PerfEvent,
             Self::Kfunc { .. } | Self::Kretfunc { .. } => ProgramType::Tracing,
         }
     }
@@ -338,9 +362,87 @@ impl Probe {
         }
     }
 
-    pub fn attach(&self, program: &mut Program, pid: Option<u32>) -> Result<Vec<AttachedProbe>> {
+    /// Attaches the loaded program `prog_fd`.
+    ///
+    /// Perf events of a `pid` are opened for each of its threads, see
+    /// `Probe::attach_every_thread`.
+    pub fn attach(&self, prog_fd: RawFd, pid: Option<u32>) -> Result<Vec<AttachedProbe>> {
         log::debug!("attaching {}", self);
-        let probes = match self {
+        let mut probes = vec![];
+        match pid {
+            Some(pid) if self.counts_task() => {
+                self.attach_every_thread(prog_fd, pid, &mut probes)?
+            }
+            _ => {
+                for probe in self.open(pid, false)? {
+                    probe.set_bpf(prog_fd)?;
+                    probe.enable()?;
+                    probes.push(probe);
+                }
+            }
+        }
+        Ok(probes)
+    }
+
+    /// Whether the probe is a perf event counting a task, pmus that only
+    /// count system wide ignore the pid.
+    fn counts_task(&self) -> bool {
+        match self {
+            Self::Pmu { device, .. } => pmu::cpus(device).is_none(),
+            _ => matches!(self.prog_type(), ProgramType::PerfEvent),
+        }
+    }
+
+    /// Attaches `prog_fd` to an event of every thread of `pid`.
+    ///
+    /// The events of a stopped process, like a child stopped before `exec`,
+    /// are inherited by the threads and children it starts later. A running
+    /// process may start threads while its events are opened, so its threads
+    /// are listed again until no new ones were started. Its events aren't
+    /// inherited, a thread started after the event of its parent was opened
+    /// would be sampled twice otherwise, and threads started after the probe
+    /// was attached aren't sampled.
+    ///
+    /// The program is attached to each event before the next one is opened,
+    /// so no inherited event is left without it.
+    fn attach_every_thread(
+        &self,
+        prog_fd: RawFd,
+        pid: u32,
+        probes: &mut Vec<AttachedProbe>,
+    ) -> Result<()> {
+        let inherit = attach::stopped(pid)?;
+        let mut attached = HashSet::new();
+        loop {
+            let tids: Vec<_> = attach::threads(pid)?
+                .into_iter()
+                .filter(|tid| attached.insert(*tid))
+                .collect();
+            if tids.is_empty() {
+                return Ok(());
+            }
+            for tid in tids {
+                let events = match self.open(Some(tid), inherit) {
+                    Ok(events) => events,
+                    // the thread exited since it was listed.
+                    Err(err) if attach::is_esrch(&err) => continue,
+                    Err(err) => return Err(err),
+                };
+                for event in events {
+                    event.set_bpf(prog_fd)?;
+                    event.enable()?;
+                    probes.push(event);
+                }
+            }
+            // threads started since have inherited the events.
+            if inherit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn open(&self, pid: Option<u32>, inherit: bool) -> Result<Vec<AttachedProbe>> {
+        Ok(match self {
             Self::Kprobe { symbol, offset } => vec![AttachedProbe::kprobe(symbol, *offset, pid)?],
             Self::Kretprobe { symbol } => vec![AttachedProbe::kretprobe(symbol, pid)?],
             Self::Uprobe {
@@ -370,29 +472,32 @@ impl Probe {
             Self::Tracepoint { category, name } => {
                 vec![AttachedProbe::tracepoint(category, name, pid)?]
             }
-            Self::Profile { interval } => AttachedProbe::profile(interval, pid)?,
-            Self::Interval { interval } => vec![AttachedProbe::interval(interval, pid)?],
+            Self::Profile { interval } => AttachedProbe::profile(interval, pid, inherit)?,
+            Self::Interval { interval } => vec![AttachedProbe::interval(interval, pid, inherit)?],
             Self::Software { event, count } => {
                 let count = count.unwrap_or_else(|| event.default_count());
-                vec![AttachedProbe::software(*event, count, pid)?]
+                vec![AttachedProbe::software(*event, count, pid, inherit)?]
             }
             Self::Hardware { event, count } => {
                 let count = count.unwrap_or_else(|| event.default_count());
-                AttachedProbe::hardware(*event, count, pid)?
+                AttachedProbe::hardware(*event, count, pid, inherit)?
             }
             Self::Watchpoint {
                 address,
                 length,
                 mode,
             } => vec![AttachedProbe::watchpoint(*address, *length, *mode, pid)?],
+            Self::Pmu {
+                device,
+                event,
+                count,
+            } => {
+                let count = count.unwrap_or_else(|| pmu::default_count(device));
+                AttachedProbe::pmu(device, event, count, pid, inherit)?
+            }
             Self::Kfunc { func } => vec![AttachedProbe::kfunc(func, pid)?],
             Self::Kretfunc { func } => vec![AttachedProbe::kretfunc(func, pid)?],
-        };
-        for probe in &probes {
-            probe.set_bpf(program)?;
-            probe.enable()?;
-        }
-        Ok(probes)
+        })
     }
 }
 
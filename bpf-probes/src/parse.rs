@@ -226,6 +226,17 @@ impl std::str::FromStr for Probe {
                     mode,
                 }
             }
+            "pmu" => {
+                let mut iter = probe_args.splitn(3, ':');
+                let device = iter.next().ok_or(Expected("pmu:device:event"))?;
+                let event = iter.next().ok_or(Expected("pmu:device:event"))?;
+                let count = iter.next().map(u64::from_str).transpose()?;
+                Self::Pmu {
+                    device: device.to_string(),
+                    event: event.to_string(),
+                    count,
+                }
+            }
             "kfunc" => Self::Kfunc {
                 func: probe_args.to_string(),
             },
@@ -308,6 +319,22 @@ mod tests {
                     interval: Interval::Hz(99),
                 },
             ),
+            (
+                "pmu:tracepoint:1234",
+                Probe::Pmu {
+                    device: "tracepoint".into(),
+                    event: "1234".into(),
+                    count: None,
+                },
+            ),
+            (
+                "pmu:cpu:event=0x3c,umask=0x1:100000",
+                Probe::Pmu {
+                    device: "cpu".into(),
+                    event: "event=0x3c,umask=0x1".into(),
+                    count: Some(100_000),
+                },
+            ),
             (
                 "watchpoint:0x10000:8:rwx",
                 Probe::Watchpoint {
@@ -0,0 +1,229 @@
+//! Dynamic pmus of `/sys/bus/event_source/devices`.
+//!
+//! Every pmu has a type for `perf_event_attr.type`. Its events are encoded
+//! into the `config`, `config1` and `config2` fields as described by the
+//! `format` directory, a term like `umask=0x1` is placed at the bits of
+//! `format/umask`, for example `config:8-15`. Named events in the `events`
+//! directory are lists of terms.
+//!
+//! An event is a number, which is used as `config` as is (a tracepoint id of
+//! the `tracepoint` pmu or a raw event), a list of terms like
+//! `event=0x3c,umask=0x1` or the name of an event.
+//!
+//! Pmus that can't sample, like the energy counters of `power`, are read
+//! with a [`Counter`] instead.
+use anyhow::{bail, Context, Result};
+use perf_event_open_sys::bindings::{self as sys, perf_event_attr};
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+const DEVICES: &str = "/sys/bus/event_source/devices";
+
+/// Default sample period of pmu events, `1` for tracepoints.
+pub fn default_count(device: &str) -> u64 {
+    match device {
+        "tracepoint" => 1,
+        _ => 1_000_000,
+    }
+}
+
+fn device_dir(device: &str) -> PathBuf {
+    PathBuf::from(DEVICES).join(device)
+}
+
+/// Whether the kernel has the pmu `device`, the `kprobe` and `uprobe` pmus
+/// were added in 4.17.
+pub fn exists(device: &str) -> bool {
+    device_dir(device).join("type").exists()
+}
+
+/// Value of `perf_event_attr.type` for the pmu `device`.
+pub fn pmu_type(device: &str) -> Result<u32> {
+    let path = device_dir(device).join("type");
+    let ty = std::fs::read_to_string(&path).with_context(|| format!("unknown pmu {}", device))?;
+    Ok(ty.trim().parse()?)
+}
+
+/// Cpus of a pmu that counts system wide, like an uncore pmu, `None` for
+/// pmus that count per task.
+pub fn cpus(device: &str) -> Option<Vec<u32>> {
+    let mask = std::fs::read_to_string(device_dir(device).join("cpumask")).ok()?;
+    Some(bpf_utils::cpu::parse_cpu_list(&mask))
+}
+
+/// Encodes `event` of the pmu `device` into `config`, `config1` and
+/// `config2`.
+pub fn encode(device: &str, event: &str) -> Result<[u64; 3]> {
+    if let Some(config) = parse_number(event) {
+        return Ok([config, 0, 0]);
+    }
+    let dir = device_dir(device);
+    let terms = if event.contains('=') {
+        event.to_string()
+    } else {
+        std::fs::read_to_string(dir.join("events").join(event))
+            .with_context(|| format!("unknown event {} of pmu {}", event, device))?
+    };
+    let mut formats = HashMap::new();
+    for term in terms.trim().split(',') {
+        let name = term.split('=').next().unwrap_or_default();
+        if let Ok(format) = std::fs::read_to_string(dir.join("format").join(name)) {
+            formats.insert(name.to_string(), format.trim().to_string());
+        }
+    }
+    encode_terms(&terms, &formats)
+}
+
+/// Places the terms at the bits of their formats.
+pub fn encode_terms(terms: &str, formats: &HashMap<String, String>) -> Result<[u64; 3]> {
+    let mut config = [0; 3];
+    for term in terms.trim().split(',').filter(|term| !term.is_empty()) {
+        let mut iter = term.splitn(2, '=');
+        let name = iter.next().unwrap_or_default();
+        // flags like `edge` are terms without a value.
+        let value = match iter.next() {
+            Some(value) => match parse_number(value) {
+                Some(value) => value,
+                None => bail!("invalid value in term {}", term),
+            },
+            None => 1,
+        };
+        let format = match formats.get(name) {
+            Some(format) => format,
+            None => bail!("unknown term {}", name),
+        };
+        let mut iter = format.splitn(2, ':');
+        let field = match iter.next() {
+            Some("config") => 0,
+            Some("config1") => 1,
+            Some("config2") => 2,
+            _ => bail!("invalid format {}", format),
+        };
+        let mut value = value;
+        for range in iter.next().unwrap_or_default().split(',') {
+            let mut bits = range.splitn(2, '-');
+            let start: u32 = bits.next().unwrap_or_default().parse()?;
+            let end: u32 = match bits.next() {
+                Some(end) => end.parse()?,
+                None => start,
+            };
+            if end < start || end > 63 {
+                bail!("invalid format {}", format);
+            }
+            let width = end - start + 1;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            config[field] |= (value & mask) << start;
+            value = value.checked_shr(width).unwrap_or_default();
+        }
+    }
+    Ok(config)
+}
+
+/// Counting event of a pmu, for pmus that can't sample like `power`.
+pub struct Counter {
+    fds: Vec<RawFd>,
+    /// Unit of a count, from `events/<event>.scale`.
+    scale: f64,
+}
+
+impl Counter {
+    /// Opens `event` of `device` on the cpus of its `cpumask`, or system wide
+    /// on every cpu.
+    pub fn open(device: &str, event: &str) -> Result<Self> {
+        let [config, config1, config2] = encode(device, event)?;
+        let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
+        attr.size = std::mem::size_of::<perf_event_attr>() as _;
+        attr.type_ = pmu_type(device)?;
+        attr.config = config;
+        attr.__bindgen_anon_3 = sys::perf_event_attr__bindgen_ty_3 { config1 };
+        attr.__bindgen_anon_4 = sys::perf_event_attr__bindgen_ty_4 { config2 };
+        let cpus = match cpus(device) {
+            Some(cpus) => cpus,
+            None => bpf_utils::cpu::online_cpu_ids()?,
+        };
+        let scale = std::fs::read_to_string(
+            device_dir(device)
+                .join("events")
+                .join(format!("{}.scale", event)),
+        )
+        .ok()
+        .and_then(|scale| scale.trim().parse().ok())
+        .unwrap_or(1.0);
+        let mut counter = Self { fds: vec![], scale };
+        for cpu in cpus {
+            let fd = unsafe {
+                perf_event_open_sys::perf_event_open(
+                    &mut attr,
+                    -1,
+                    cpu as _,
+                    -1,
+                    sys::PERF_FLAG_FD_CLOEXEC as _,
+                )
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("failed to open {}/{}", device, event));
+            }
+            counter.fds.push(fd);
+        }
+        Ok(counter)
+    }
+
+    /// Scaled count since the counter was opened.
+    pub fn read(&self) -> Result<f64> {
+        let mut sum = 0;
+        for fd in &self.fds {
+            let mut count = 0u64;
+            let len = std::mem::size_of::<u64>();
+            if unsafe { libc::read(*fd, &mut count as *mut _ as *mut _, len) } != len as isize {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            sum += count;
+        }
+        Ok(sum as f64 * self.scale)
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        for fd in &self.fds {
+            unsafe { libc::close(*fd) };
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_event_terms() {
+        let formats: HashMap<_, _> = [
+            ("event", "config:0-7,32-35"),
+            ("umask", "config:8-15"),
+            ("edge", "config:18"),
+            ("ldlat", "config1:0-15"),
+        ]
+        .iter()
+        .map(|(name, format)| (name.to_string(), format.to_string()))
+        .collect();
+        let config = encode_terms("event=0x1c2,umask=0x1,edge,ldlat=3", &formats).unwrap();
+        assert_eq!(config, [0x1_0004_01c2, 3, 0]);
+        assert!(encode_terms("cmask=1", &formats).is_err());
+        assert_eq!(parse_number("0x10"), Some(16));
+        assert_eq!(parse_number("42"), Some(42));
+    }
+}
@@ -0,0 +1,58 @@
+//! Kprobes and uprobes created in tracefs.
+//!
+//! Kernels before 4.17 have no `kprobe` and `uprobe` pmus, the probes are
+//! added to `kprobe_events` or `uprobe_events` instead and opened like a
+//! tracepoint. They outlive the tracer unless they're removed again.
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const TRACING: &str = "/sys/kernel/debug/tracing";
+const GROUP: &str = "bpf_probes";
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct TraceEvent {
+    events: &'static str,
+    name: String,
+}
+
+impl TraceEvent {
+    /// Adds a probe to `kprobe_events` or `uprobe_events`, `kind` is `p` for
+    /// probes and `r` for return probes, `target` is `symbol+offset` for
+    /// kprobes and `path:0xaddress` for uprobes.
+    pub fn create(events: &'static str, kind: char, target: &str) -> Result<Self> {
+        let name = format!(
+            "{}_{}_{}",
+            events.trim_end_matches("_events"),
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let event = Self { events, name };
+        event.write(&format!("{}:{}/{} {}", kind, GROUP, event.name, target))?;
+        Ok(event)
+    }
+
+    /// Value of `perf_event_attr.config` for the tracepoint pmu.
+    pub fn id(&self) -> Result<u64> {
+        let path = format!("{}/events/{}/{}/id", TRACING, GROUP, self.name);
+        let id = std::fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+        Ok(id.trim().parse()?)
+    }
+
+    /// Removes the probe, which fails while it's open.
+    pub fn remove(&self) -> Result<()> {
+        self.write(&format!("-:{}/{}", GROUP, self.name))
+    }
+
+    fn write(&self, line: &str) -> Result<()> {
+        let path = format!("{}/{}", TRACING, self.events);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path))?;
+        writeln!(file, "{}", line).with_context(|| format!("writing `{}` to {}", line, path))?;
+        Ok(())
+    }
+}
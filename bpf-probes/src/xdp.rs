@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+
+/// Skip attaching if a program is already attached.
+pub const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1 << 0;
+/// Generic xdp, works with every driver.
+pub const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+/// Native xdp, requires driver support.
+pub const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+/// Offload to the nic.
+pub const XDP_FLAGS_HW_MODE: u32 = 1 << 3;
+
+/// XDP program attached to an interface, detached on drop.
+#[derive(Debug)]
+pub struct AttachedXdp {
+    ifindex: i32,
+    flags: u32,
+}
+
+impl AttachedXdp {
+    /// Attaches the loaded program `prog_fd` to `interface`.
+    pub fn attach(prog_fd: RawFd, interface: &str, flags: u32) -> Result<Self> {
+        let ifindex = ifindex(interface)?;
+        log::debug!("attaching xdp to {} ({})", interface, ifindex);
+        set_link_xdp_fd(ifindex, prog_fd, flags)
+            .with_context(|| format!("attaching xdp program to `{}`", interface))?;
+        Ok(Self { ifindex, flags })
+    }
+
+    pub fn ifindex(&self) -> u32 {
+        self.ifindex as _
+    }
+}
+
+impl Drop for AttachedXdp {
+    fn drop(&mut self) {
+        let flags = self.flags & !XDP_FLAGS_UPDATE_IF_NOEXIST;
+        if let Err(err) = set_link_xdp_fd(self.ifindex, -1, flags) {
+            log::warn!("{}", err);
+        }
+    }
+}
+
+/// Returns the index of a network interface.
+pub fn ifindex(interface: &str) -> Result<i32> {
+    let name = CString::new(interface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("unknown interface `{}`", interface));
+    }
+    Ok(ifindex as _)
+}
+
+fn set_link_xdp_fd(ifindex: i32, fd: i32, flags: u32) -> Result<()> {
+    let ret = unsafe { libbpf_sys::bpf_set_link_xdp_fd(ifindex, fd, flags) };
+    if ret < 0 {
+        return Err(std::io::Error::from_raw_os_error(-ret).into());
+    }
+    Ok(())
+}
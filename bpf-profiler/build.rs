@@ -0,0 +1,52 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use bpf_inspect::budget::{self, Budget};
+use cargo_bpf_lib as cargo_bpf;
+
+fn main() {
+    let cargo = PathBuf::from(env::var("CARGO").unwrap());
+    let target = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let probes = Path::new("probe");
+
+    cargo_bpf::build(&cargo, &probes, &target.join("target"), Vec::new())
+        .expect("couldn't compile probes");
+    // kernels without ring buffers get the probe built with the compat feature.
+    let features = vec!["compat".to_string()];
+    cargo_bpf::build_with_features(
+        &cargo,
+        &probes,
+        &target.join("compat"),
+        Vec::new(),
+        &features,
+    )
+    .expect("couldn't compile compat probes");
+
+    let probe = |dir: &str| {
+        target
+            .join(dir)
+            .join("bpf/programs/cargo-trace-probe/cargo-trace-probe.elf")
+    };
+    budget::check_build(probe("target")).expect("probe exceeds its budget");
+    // kernels before 5.2 reject programs with more than 4096 instructions.
+    let configured = Budget::from_env().expect("invalid budget");
+    let old_kernels = Budget {
+        max_instructions: configured.max_instructions.min(bpf_inspect::BPF_MAXINSNS),
+        ..configured
+    };
+    budget::check_build_with(probe("compat"), &old_kernels)
+        .expect("compat probe exceeds the budget of old kernels");
+    for dir in &["target", "compat"] {
+        bpf_inspect::license::check_build(probe(dir)).expect("probe calls GPL-only helpers");
+    }
+
+    cargo_bpf::probe_files(&probes)
+        .expect("couldn't list probe files")
+        .iter()
+        .for_each(|file| {
+            println!("cargo:rerun-if-changed={}", file);
+        });
+    println!("cargo:rerun-if-changed=instruction/src");
+    // sizes USER_STACK, see `max_entries_env` of the map macro.
+    println!("cargo:rerun-if-env-changed=CARGO_TRACE_STACKS");
+}
@@ -0,0 +1,196 @@
+//! Unwind instructions of the probe.
+//!
+//! Every row of the unwind table has an instruction computing the cfa and
+//! one computing the return address. The table is encoded in user space and
+//! executed by the probe, this crate is the contract between the two and
+//! builds for both.
+//!
+//! The low 32 bits of the encoded op are the [`Op`], the high 32 bits the
+//! register operand of [`Op::Register`]. Registers are numbered like in
+//! dwarf, the values of registers other than `rip` and `rsp` are only known
+//! in the innermost frame, where they're read from the sampled registers.
+#![no_std]
+
+/// Largest offset of an instruction, larger ones are corrupt.
+pub const MAX_OFFSET: i64 = 1 << 20;
+
+/// Largest dwarf register number of x86_64 an instruction can use, the
+/// return address column.
+pub const MAX_REGISTER: u16 = 16;
+
+/// Operation of an instruction, the discriminant is its encoding.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum Op {
+    /// The value can't be computed, which ends the stack.
+    Undefined = 0,
+    /// Value stored at the cfa plus the offset.
+    CfaOffset = 1,
+    /// Value of `rip` plus the offset.
+    Rip = 2,
+    /// Value of `rsp` plus the offset.
+    Rsp = 3,
+    /// Value of the register operand plus the offset.
+    Register = 4,
+}
+
+impl Op {
+    pub const ALL: [Op; 5] = [Op::Undefined, Op::CfaOffset, Op::Rip, Op::Rsp, Op::Register];
+
+    #[inline(always)]
+    pub fn decode(op: u64) -> Option<Self> {
+        Some(match op {
+            0 => Self::Undefined,
+            1 => Self::CfaOffset,
+            2 => Self::Rip,
+            3 => Self::Rsp,
+            4 => Self::Register,
+            _ => return None,
+        })
+    }
+}
+
+/// Why an instruction is invalid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Invalid {
+    Op(u64),
+    Offset(i64),
+    Register(u16),
+}
+
+/// Encoded instruction as stored in the `RIP` and `RSP` maps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Instruction {
+    pub op: u64,
+    pub offset: i64,
+}
+
+impl Instruction {
+    #[inline(always)]
+    pub const fn new(op: Op, offset: i64) -> Self {
+        Self {
+            op: op as u64,
+            offset,
+        }
+    }
+
+    pub const fn undefined() -> Self {
+        Self::new(Op::Undefined, 0)
+    }
+
+    pub const fn cfa_offset(offset: i64) -> Self {
+        Self::new(Op::CfaOffset, offset)
+    }
+
+    pub const fn rip(offset: i64) -> Self {
+        Self::new(Op::Rip, offset)
+    }
+
+    pub const fn rsp(offset: i64) -> Self {
+        Self::new(Op::Rsp, offset)
+    }
+
+    /// Value of the dwarf register `reg` plus `offset`.
+    pub const fn register(reg: u16, offset: i64) -> Self {
+        Self {
+            op: Op::Register as u64 | (reg as u64) << 32,
+            offset,
+        }
+    }
+
+    /// The op, `None` if it's unknown or has an operand it doesn't take.
+    #[inline(always)]
+    pub fn op(&self) -> Option<Op> {
+        match Op::decode(self.op & 0xffff_ffff)? {
+            Op::Register => Some(Op::Register),
+            _ if self.op >> 32 != 0 => None,
+            op => Some(op),
+        }
+    }
+
+    /// Register operand of [`Op::Register`].
+    #[inline(always)]
+    pub fn reg(&self) -> u16 {
+        (self.op >> 32) as u16
+    }
+
+    /// Checks the op, the register and the bounds of the offset.
+    pub fn validate(&self) -> Result<Op, Invalid> {
+        let op = self.op().ok_or(Invalid::Op(self.op))?;
+        if op == Op::Register && self.op >> 32 > MAX_REGISTER as u64 {
+            return Err(Invalid::Register(self.reg()));
+        }
+        if op != Op::Undefined && !(-MAX_OFFSET..=MAX_OFFSET).contains(&self.offset) {
+            return Err(Invalid::Offset(self.offset));
+        }
+        Ok(op)
+    }
+
+    /// Computes the value, `register` looks up a dwarf register of the frame
+    /// and `read` reads a word of the target's memory. The cfa is only used
+    /// by [`Op::CfaOffset`].
+    #[inline(always)]
+    pub fn execute(
+        &self,
+        rip: u64,
+        rsp: u64,
+        cfa: u64,
+        register: impl FnOnce(u16) -> Option<u64>,
+        read: impl FnOnce(u64) -> Option<u64>,
+    ) -> Option<u64> {
+        match self.op()? {
+            Op::Undefined => None,
+            Op::CfaOffset => read((cfa as i64 + self.offset) as u64),
+            Op::Rip => Some((rip as i64 + self.offset) as u64),
+            Op::Rsp => Some((rsp as i64 + self.offset) as u64),
+            Op::Register => Some((register(self.reg())? as i64 + self.offset) as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        for op in &Op::ALL {
+            assert_eq!(Op::decode(*op as u64), Some(*op));
+        }
+        assert_eq!(Op::decode(5), None);
+        assert_eq!(Instruction::rsp(8).validate(), Ok(Op::Rsp));
+        let r13 = Instruction::register(13, 16);
+        assert_eq!((r13.op(), r13.reg()), (Some(Op::Register), 13));
+        assert_eq!(r13.validate(), Ok(Op::Register));
+        let invalid = Instruction { op: 7, offset: 0 };
+        assert_eq!(invalid.validate(), Err(Invalid::Op(7)));
+        let operand = Instruction {
+            op: Op::Rsp as u64 | 3 << 32,
+            offset: 0,
+        };
+        assert_eq!(operand.validate(), Err(Invalid::Op(operand.op)));
+        let register = Instruction::register(MAX_REGISTER + 1, 0);
+        assert_eq!(
+            register.validate(),
+            Err(Invalid::Register(MAX_REGISTER + 1))
+        );
+        let far = Instruction::cfa_offset(MAX_OFFSET + 1);
+        assert_eq!(far.validate(), Err(Invalid::Offset(MAX_OFFSET + 1)));
+    }
+
+    #[test]
+    fn execute() {
+        let read = |addr| if addr == 0x1008 { Some(0x42) } else { None };
+        let reg = |reg| if reg == 3 { Some(0x3000) } else { None };
+        let exec = |ins: Instruction, rip, rsp, cfa| ins.execute(rip, rsp, cfa, reg, read);
+        assert_eq!(exec(Instruction::rsp(16), 0, 0x1000, 0), Some(0x1010));
+        assert_eq!(exec(Instruction::rip(-1), 0x10, 0, 0), Some(0xf));
+        let ret = Instruction::cfa_offset(-8);
+        assert_eq!(exec(ret, 0, 0, 0x1010), Some(0x42));
+        assert_eq!(exec(ret, 0, 0, 0x2000), None);
+        assert_eq!(exec(Instruction::register(3, 8), 0, 0, 0), Some(0x3008));
+        assert_eq!(exec(Instruction::register(13, 8), 0, 0, 0), None);
+        assert_eq!(exec(Instruction::undefined(), 1, 1, 1), None);
+    }
+}
@@ -0,0 +1,633 @@
+#![no_std]
+#![no_main]
+
+use bpf_helpers::hist::{log2_bucket, LOG2_BUCKETS};
+use bpf_helpers::regs::{PerfEventData, Regs};
+#[cfg(feature = "compat")]
+use bpf_helpers::PerfEventArray;
+#[cfg(not(feature = "compat"))]
+use bpf_helpers::RingBuf;
+use bpf_helpers::{
+    bail, entry, flags, guard, hit, hit_counters, map, program, recursion_guard, sys, Array, Exit,
+    HashMap, Instant, OrExit, PidTgid, StackTrace,
+};
+use cargo_trace_instruction::{Instruction, Op};
+
+program!(b"GPL");
+
+// absolute maximum would be 512 byte stack size limit / 8 byte address = 64. but since
+// we need some stack for other variables this needs to be lower.
+const MAX_STACK_DEPTH: usize = 48;
+const MAX_BIN_SEARCH_DEPTH: usize = 24;
+const EHFRAME_ENTRIES: usize = 0xff_ffff;
+const MAX_SAMPLES: usize = 0xffff;
+const MEMORY_RECLAIM: u64 = 0;
+const MEMORY_MAJOR_FAULT: u64 = 1;
+const VM_FAULT_MAJOR: u64 = 0x4;
+const MAX_CPUS: usize = 1024;
+const MAX_THREADS: usize = 4096;
+const MAPPED: u64 = 0;
+const UNMAPPED: u64 = 1;
+const EXECED: u64 = 2;
+const MAX_ERRNO: u64 = 4095;
+const GPU_SUBMIT: u32 = 0;
+const GPU_COMPLETE: u32 = 1;
+const MAX_GPU_EVENTS: usize = 64;
+
+/// Number of unwind table rows, pid of the target, whether a stop marker
+/// paused sampling and whether stacks are tagged with the numa node.
+#[map]
+static CONFIG: Array<u32> = Array::with_max_entries(4);
+/// Pid of the target, set by the loader. Checked before `CONFIG[1]` so
+/// samples of other processes exit without a map lookup, the compat build
+/// has no global data and only checks `CONFIG[1]`.
+#[cfg(not(feature = "compat"))]
+#[no_mangle]
+#[link_section = ".rodata"]
+static TARGET_PID: u32 = 0;
+#[map]
+static PC: Array<u64> = Array::with_max_entries(EHFRAME_ENTRIES).with_flags(flags::RDONLY_PROG);
+#[map]
+static RIP: Array<Instruction> =
+    Array::with_max_entries(EHFRAME_ENTRIES).with_flags(flags::RDONLY_PROG);
+#[map]
+static RSP: Array<Instruction> =
+    Array::with_max_entries(EHFRAME_ENTRIES).with_flags(flags::RDONLY_PROG);
+
+/// Sampled instruction pointers while no unwind table is loaded.
+#[map]
+static SAMPLES: HashMap<u64, u32> = HashMap::with_max_entries(MAX_SAMPLES);
+
+hit_counters!();
+// a perf event can interrupt the kprobes while they update the maps.
+recursion_guard!();
+
+#[map(max_entries_env = "CARGO_TRACE_STACKS")]
+static USER_STACK: HashMap<[u64; MAX_STACK_DEPTH], u32> = HashMap::with_max_entries(1024);
+
+/// Numa node of every cpu.
+#[map]
+static CPU_NODE: Array<u32> = Array::with_max_entries(MAX_CPUS);
+/// Cpu of the last sample of every thread.
+#[map]
+static LAST_CPU: HashMap<u32, u32> = HashMap::with_max_entries(MAX_THREADS);
+/// Migrations between samples within a node and across nodes.
+#[map]
+static MIGRATIONS: Array<u64> = Array::with_max_entries(2);
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MemoryKey {
+    kind: u64,
+    stack: [u64; MAX_STACK_DEPTH],
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct MemoryTime {
+    count: u64,
+    time_ns: u64,
+}
+
+#[map]
+static RECLAIM_START: HashMap<u32, Instant> = HashMap::with_max_entries(1024);
+#[map]
+static FAULT_START: HashMap<u32, Instant> = HashMap::with_max_entries(1024);
+/// Time spent in direct reclaim and major faults by user stack. The stacks
+/// are unwound by the kernel and need frame pointers.
+#[map]
+static MEMORY: HashMap<MemoryKey, MemoryTime> = HashMap::with_max_entries(1024);
+/// Log2 histograms of the latency in ns of every kind.
+#[map]
+static MEMORY_HIST: Array<u64> = Array::with_max_entries(2 * LOG2_BUCKETS);
+
+/// File backed mapping or unmapping or exec of the target.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MappingEvent {
+    kind: u64,
+    start: u64,
+    len: u64,
+    /// File of a mapping.
+    fd: u64,
+}
+
+/// Mappings of threads inside `mmap`, the address is known on exit.
+#[map]
+static MMAP_ARGS: HashMap<u32, MappingEvent> = HashMap::with_max_entries(MAX_THREADS);
+/// Mapping changes streamed to user space, see the mappings module.
+#[cfg(not(feature = "compat"))]
+#[map]
+static MAPPINGS: RingBuf = RingBuf::with_max_entries(64 * 4096);
+#[cfg(feature = "compat")]
+#[map]
+static MAPPINGS: PerfEventArray = PerfEventArray::with_max_entries(MAX_CPUS);
+
+/// Submission or completion of a gpu job.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct GpuEvent {
+    time_ns: u64,
+    /// Value of the field correlating a submission with its completion.
+    key: u64,
+    /// User stack of a submission, negative if it's missing.
+    stack_id: i64,
+    kind: u32,
+    tid: u32,
+    /// Id of the tracepoint.
+    event: u32,
+    _pad: u32,
+}
+
+/// Field of a tracepoint correlating submissions and completions.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct GpuKey {
+    offset: u32,
+    size: u32,
+}
+
+/// Key field by tracepoint id, set by user space from the event formats.
+#[map]
+static GPU_KEYS: HashMap<u32, GpuKey> = HashMap::with_max_entries(MAX_GPU_EVENTS);
+/// User stacks of the submitting threads, unwound by the kernel with frame
+/// pointers.
+#[map]
+static GPU_STACKS: StackTrace = StackTrace::with_max_entries(4096);
+/// Gpu jobs streamed to user space, see the gpu module.
+#[cfg(not(feature = "compat"))]
+#[map]
+static GPU_EVENTS: RingBuf = RingBuf::with_max_entries(256 * 4096);
+#[cfg(feature = "compat")]
+#[map]
+static GPU_EVENTS: PerfEventArray = PerfEventArray::with_max_entries(MAX_CPUS);
+
+/// Streams a record to user space.
+#[cfg(not(feature = "compat"))]
+#[inline(always)]
+fn output<C, T>(map: &RingBuf, _ctx: &C, record: &T) {
+    map.output(record, 0).ok();
+}
+
+/// Streams a record to user space, kernels without ring buffers get a perf
+/// event array.
+#[cfg(feature = "compat")]
+#[inline(always)]
+fn output<C, T>(map: &PerfEventArray, ctx: &C, record: &T) {
+    map.perf_event_output(ctx, record, PerfEventArray::CURRENT_CPU);
+}
+
+#[entry("perf_event")]
+fn perf_event(args: &bpf_perf_event_data) -> Result<(), Exit> {
+    // samples of other processes, most of them when sampling all cpus, exit
+    // before taking the guard.
+    target_thread()?;
+    let _guard = guard!()?;
+    increment_stack_counter(PerfEventData::new(args).regs())
+}
+
+#[entry("kprobe")]
+fn kprobe(args: &pt_regs) -> Result<(), Exit> {
+    target_thread()?;
+    let _guard = guard!()?;
+    increment_stack_counter(Regs::new(args))
+}
+
+#[entry("kprobe")]
+fn start_marker(_args: &pt_regs) -> Result<(), Exit> {
+    set_paused(0)
+}
+
+#[entry("kprobe")]
+fn stop_marker(_args: &pt_regs) -> Result<(), Exit> {
+    set_paused(1)
+}
+
+#[entry("vmscan:mm_vmscan_direct_reclaim_begin")]
+fn reclaim_begin(_args: &MmVmscanDirectReclaimBegin) -> Result<(), Exit> {
+    let _guard = guard!()?;
+    RECLAIM_START.insert(&target_thread()?, &Instant::now());
+    Ok(())
+}
+
+#[entry("vmscan:mm_vmscan_direct_reclaim_end")]
+fn reclaim_end(args: &MmVmscanDirectReclaimEnd) -> Result<(), Exit> {
+    let _guard = guard!()?;
+    memory_end(args as *const _ as *const _, &RECLAIM_START, MEMORY_RECLAIM)
+}
+
+#[entry("kprobe")]
+fn fault_begin(_args: &pt_regs) -> Result<(), Exit> {
+    let _guard = guard!()?;
+    FAULT_START.insert(&target_thread()?, &Instant::now());
+    Ok(())
+}
+
+#[entry("kprobe")]
+fn fault_end(args: &pt_regs) -> Result<(), Exit> {
+    let _guard = guard!()?;
+    if Regs::new(args).rc() & VM_FAULT_MAJOR == 0 {
+        FAULT_START.remove(&target_thread()?);
+        bail!();
+    }
+    memory_end(
+        args as *const _ as *const _,
+        &FAULT_START,
+        MEMORY_MAJOR_FAULT,
+    )
+}
+
+#[entry("syscalls:sys_enter_mmap")]
+fn mmap_enter(args: &SysEnterMmap) -> Result<(), Exit> {
+    // anonymous mappings contain no code to unwind.
+    if (args.fd as i64) < 0 {
+        bail!();
+    }
+    let event = MappingEvent {
+        kind: MAPPED,
+        start: 0,
+        len: args.len,
+        fd: args.fd,
+    };
+    MMAP_ARGS.insert(&target_thread()?, &event);
+    Ok(())
+}
+
+#[entry("syscalls:sys_exit_mmap")]
+fn mmap_exit(args: &SysExitMmap) -> Result<(), Exit> {
+    let tid = target_thread()?;
+    let mut event = MMAP_ARGS.get(&tid).or_exit()?;
+    MMAP_ARGS.remove(&tid);
+    if args.ret as u64 > u64::MAX - MAX_ERRNO {
+        bail!();
+    }
+    event.start = args.ret as u64;
+    output(&MAPPINGS, args, &event);
+    Ok(())
+}
+
+#[entry("syscalls:sys_enter_munmap")]
+fn munmap_enter(args: &SysEnterMunmap) -> Result<(), Exit> {
+    target_thread()?;
+    let event = MappingEvent {
+        kind: UNMAPPED,
+        start: args.addr,
+        len: args.len,
+        fd: 0,
+    };
+    output(&MAPPINGS, args, &event);
+    Ok(())
+}
+
+#[entry("sched:sched_process_exec")]
+fn process_exec(args: &SchedProcessExec) -> Result<(), Exit> {
+    if args.pid as u32 != CONFIG.get(1).or_exit()? {
+        bail!();
+    }
+    let event = MappingEvent {
+        kind: EXECED,
+        start: 0,
+        len: 0,
+        fd: 0,
+    };
+    output(&MAPPINGS, args, &event);
+    Ok(())
+}
+
+#[entry("tracepoint")]
+fn gpu_submit(args: &c_void) -> Result<(), Exit> {
+    let tid = target_thread()?;
+    let stack_id = match GPU_STACKS.stack_id(args, StackTrace::USER_STACK) {
+        Ok(id) => id as i64,
+        Err(_) => -1,
+    };
+    gpu_event(args, GPU_SUBMIT, tid, stack_id)
+}
+
+/// Completions run in the context of the driver, they're matched with the
+/// submissions of the target in user space.
+#[entry("tracepoint")]
+fn gpu_complete(args: &c_void) -> Result<(), Exit> {
+    gpu_event(args, GPU_COMPLETE, 0, -1)
+}
+
+fn gpu_event(args: &c_void, kind: u32, tid: u32, stack_id: i64) -> Result<(), Exit> {
+    let ctx = args as *const _ as *const u8;
+    // the `common_type` field of a tracepoint is its id.
+    let event = unsafe { *(ctx as *const u16) } as u32;
+    let field = GPU_KEYS.get(&event).or_exit()?;
+    // user space only configures 4 and 8 byte keys.
+    let size = match field.size {
+        4 => 4,
+        8 => 8,
+        _ => bail!(),
+    };
+    let mut key = 0u64;
+    unsafe {
+        sys::bpf_probe_read(
+            &mut key as *mut _ as *mut _,
+            size,
+            ctx.add(field.offset as usize) as *const _,
+        )
+    };
+    let event = GpuEvent {
+        time_ns: unsafe { sys::bpf_ktime_get_ns() },
+        key,
+        stack_id,
+        kind,
+        tid,
+        event,
+        _pad: 0,
+    };
+    output(&GPU_EVENTS, args, &event);
+    Ok(())
+}
+
+/// Thread id of the current thread if it belongs to the target.
+fn target_thread() -> Result<u32, Exit> {
+    let pid = PidTgid::current().pid();
+    // read volatile so the loader's value isn't folded to zero.
+    #[cfg(not(feature = "compat"))]
+    if pid != unsafe { core::ptr::read_volatile(&TARGET_PID) } {
+        bail!();
+    }
+    // inactive or reloading probes ignore the target until it's written.
+    if pid != CONFIG.get(1).or_exit()? {
+        bail!();
+    }
+    Ok(unsafe { sys::bpf_get_current_pid_tgid() } as u32)
+}
+
+fn memory_end(
+    ctx: *const core::ffi::c_void,
+    start: &HashMap<u32, Instant>,
+    kind: u64,
+) -> Result<(), Exit> {
+    let tid = target_thread()?;
+    let elapsed = start.get(&tid).or_exit()?.elapsed();
+    start.remove(&tid);
+    let mut key = MemoryKey {
+        kind,
+        stack: [0; MAX_STACK_DEPTH],
+    };
+    unsafe {
+        sys::bpf_get_stack(
+            ctx as *mut _,
+            key.stack.as_mut_ptr() as *mut _,
+            (MAX_STACK_DEPTH * 8) as u32,
+            sys::BPF_F_USER_STACK as u64,
+        )
+    };
+    let i = kind as u32 * LOG2_BUCKETS as u32 + log2_bucket(elapsed.as_nanos());
+    let count = MEMORY_HIST.get(i).unwrap_or_default();
+    MEMORY_HIST.insert(i, &(count + 1));
+    let mut time = MEMORY.get(&key).unwrap_or_default();
+    time.count += 1;
+    time.time_ns += elapsed.as_nanos();
+    MEMORY.insert(&key, &time);
+    Ok(())
+}
+
+fn set_paused(paused: u32) -> Result<(), Exit> {
+    target_thread()?;
+    CONFIG.insert(2, &paused);
+    Ok(())
+}
+
+/// Entries of `CONFIG` the sampling programs read, looked up once per run
+/// instead of once per frame.
+struct Config {
+    rows: u32,
+    numa: bool,
+}
+
+impl Config {
+    /// Fails while a stop marker paused sampling.
+    #[inline(always)]
+    fn load() -> Result<Self, Exit> {
+        if CONFIG.get(2).unwrap_or_default() != 0 {
+            bail!();
+        }
+        Ok(Self {
+            rows: CONFIG.get(0).unwrap_or_default(),
+            numa: CONFIG.get(3).unwrap_or_default() != 0,
+        })
+    }
+}
+
+/// Counts the stack of a sample of the target, see `target_thread`.
+fn increment_stack_counter(regs: Regs) -> Result<(), Exit> {
+    let config = Config::load()?;
+    if config.rows == 0 {
+        let count = SAMPLES.get(&regs.ip()).unwrap_or_default();
+        SAMPLES.insert(&regs.ip(), &(count + 1));
+        return Ok(());
+    }
+    let mut stack = [0; MAX_STACK_DEPTH];
+    if !config.numa {
+        backtrace(regs, &mut stack, MAX_STACK_DEPTH, config.rows);
+    } else {
+        // the last frame is replaced by the node + 1.
+        backtrace(regs, &mut stack, MAX_STACK_DEPTH - 1, config.rows);
+        stack[MAX_STACK_DEPTH - 1] = sample_node() as u64 + 1;
+    }
+    let mut count = USER_STACK.get(&stack).unwrap_or_default();
+    count += 1;
+    USER_STACK.insert(&stack, &count);
+    Ok(())
+}
+
+/// Numa node of the current cpu, counting migrations of the thread since
+/// its last sample.
+fn sample_node() -> u32 {
+    let cpu = unsafe { sys::bpf_get_smp_processor_id() };
+    let node = CPU_NODE.get(cpu).unwrap_or_default();
+    let tid = unsafe { sys::bpf_get_current_pid_tgid() } as u32;
+    if let Some(last) = LAST_CPU.get(&tid) {
+        if last != cpu {
+            let i = (CPU_NODE.get(last).unwrap_or_default() != node) as u32;
+            let count = MIGRATIONS.get(i).unwrap_or_default();
+            MIGRATIONS.insert(i, &(count + 1));
+        }
+    }
+    LAST_CPU.insert(&tid, &cpu);
+    node
+}
+
+/// Unwinds with the first `rows` rows of the unwind table.
+#[cfg(not(feature = "compat"))]
+fn backtrace(regs: Regs, stack: &mut [u64; MAX_STACK_DEPTH], depth: usize, rows: u32) {
+    let mut rip = regs.ip();
+    let mut rsp = regs.sp();
+    for d in 0..MAX_STACK_DEPTH {
+        if !frame(d, &mut rip, &mut rsp, regs, stack, depth, rows) {
+            break;
+        }
+    }
+}
+
+/// The unwind without a loop, for kernels without bounded loops.
+#[cfg(feature = "compat")]
+#[allow(unused_assignments)]
+fn backtrace(regs: Regs, stack: &mut [u64; MAX_STACK_DEPTH], depth: usize, rows: u32) {
+    let mut rip = regs.ip();
+    let mut rsp = regs.sp();
+    let mut d = 0;
+    macro_rules! frame {
+        () => {
+            if !frame(d, &mut rip, &mut rsp, regs, stack, depth, rows) {
+                return;
+            }
+            d += 1;
+        };
+    }
+    macro_rules! frames {
+        () => {
+            frame!();
+            frame!();
+            frame!();
+            frame!();
+            frame!();
+            frame!();
+        };
+    }
+    const _: [(); 48] = [(); MAX_STACK_DEPTH];
+    frames!();
+    frames!();
+    frames!();
+    frames!();
+    frames!();
+    frames!();
+    frames!();
+    frames!();
+}
+
+/// Records frame `d` of at most `depth` and unwinds it, returning false at
+/// the end of the stack.
+#[inline(always)]
+fn frame(
+    d: usize,
+    rip: &mut u64,
+    rsp: &mut u64,
+    regs: Regs,
+    stack: &mut [u64; MAX_STACK_DEPTH],
+    depth: usize,
+    rows: u32,
+) -> bool {
+    if d >= depth {
+        return false;
+    }
+    stack[d] = *rip;
+    if *rip == 0 {
+        return false;
+    }
+    // the sampled registers other than rip and rsp are only the ones of
+    // the innermost frame.
+    let frame = if d == 0 { Some(regs) } else { None };
+    match step(rip, rsp, frame, rows) {
+        Ok(true) => true,
+        // a cfa based on an unknown register ends the stack.
+        Ok(false) => false,
+        Err(_) => {
+            hit!();
+            false
+        }
+    }
+}
+
+/// Unwinds one frame, `regs` are the registers of the frame if known.
+/// Returns false if the cfa is based on a register other than `rip` and
+/// `rsp` of an outer frame, which can't be unwound.
+///
+/// The compat build calls it as a bpf to bpf function, inlining it into
+/// every unrolled frame would exceed the 4096 instructions of old kernels.
+#[cfg_attr(feature = "compat", inline(never))]
+fn step(rip: &mut u64, rsp: &mut u64, regs: Option<Regs>, rows: u32) -> Result<bool, Exit> {
+    let i = binary_search(*rip, rows);
+    let ins = RSP.get(i).or_exit()?;
+    if regs.is_none() && ins.op() == Some(Op::Register) {
+        return Ok(false);
+    }
+    let cfa = execute_instruction(&ins, *rip, *rsp, 0, regs).or_exit()?;
+    let ins = RIP.get(i).or_exit()?;
+    *rip = execute_instruction(&ins, *rip, *rsp, cfa, regs).unwrap_or_default();
+    *rsp = cfa;
+    Ok(true)
+}
+
+#[cfg(not(feature = "compat"))]
+fn binary_search(rip: u64, rows: u32) -> u32 {
+    let mut left = 0;
+    let mut right = rows.saturating_sub(1);
+    let mut i = 0;
+    for _ in 0..MAX_BIN_SEARCH_DEPTH {
+        if left > right {
+            break;
+        }
+        i = (left + right) / 2;
+        let pc = PC.get(i).unwrap_or(u64::MAX);
+        if pc < rip {
+            left = i;
+        } else {
+            right = i;
+        }
+    }
+    i
+}
+
+/// The search without a loop, for kernels without bounded loops.
+#[cfg(feature = "compat")]
+#[allow(unused_assignments)]
+fn binary_search(rip: u64, rows: u32) -> u32 {
+    let mut left = 0;
+    let mut right = rows.saturating_sub(1);
+    let mut i = 0;
+    macro_rules! step {
+        () => {
+            if left <= right {
+                i = (left + right) / 2;
+                let pc = PC.get(i).unwrap_or(u64::MAX);
+                if pc < rip {
+                    left = i;
+                } else {
+                    right = i;
+                }
+            }
+        };
+    }
+    macro_rules! steps {
+        () => {
+            step!();
+            step!();
+            step!();
+            step!();
+            step!();
+            step!();
+        };
+    }
+    const _: [(); 24] = [(); MAX_BIN_SEARCH_DEPTH];
+    steps!();
+    steps!();
+    steps!();
+    steps!();
+    i
+}
+
+fn execute_instruction(
+    ins: &Instruction,
+    rip: u64,
+    rsp: u64,
+    cfa: u64,
+    regs: Option<Regs>,
+) -> Option<u64> {
+    let register = |reg| regs?.dwarf(reg);
+    ins.execute(rip, rsp, cfa, register, |addr| {
+        let mut res: u64 = 0;
+        let unsafe_ptr = addr as *const core::ffi::c_void;
+        if unsafe { sys::bpf_probe_read(&mut res as *mut _ as *mut _, 8, unsafe_ptr) } == 0 {
+            Some(res)
+        } else {
+            None
+        }
+    })
+}
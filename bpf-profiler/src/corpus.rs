@@ -0,0 +1,235 @@
+//! Corpus of captured stacks to test the unwinder.
+//!
+//! A snapshot holds the registers of a thread, its modules and a copy of its
+//! stack. [`check`] unwinds it with the unwind table the same way as the
+//! probe, see [`crate::unwind::backtrace`], and with the reference unwinder
+//! of the `ehframe` crate, which evaluates the `.eh_frame` rows with gimli.
+//! Changes to the encoding of the rows or to the interpreter of the probe
+//! that break stacks show up as differences between the two.
+//!
+//! Snapshots are text files:
+//!
+//! ```text
+//! cargo-trace unwind-snapshot 1
+//! regs 0x55d0c2a01234 0x7ffd3a2b1f00 0x7ffd3a2b1f40
+//! module 0x55d0c29f0000 0x55d0c2a40000 target/release/app
+//! stack 0x7ffd3a2b1f00 00a1b2...
+//! ```
+//!
+//! The registers are `rip`, `rsp` and `rbp`, the stack is hex encoded from
+//! the address of `rsp` on. The modules have to be at the same paths when
+//! the snapshot is checked.
+use crate::module_rows;
+use crate::unwind::{backtrace, MAX_STACK_DEPTH};
+use anyhow::Result;
+use bpf::utils::ehframe::Regs;
+use bpf::utils::Elf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub const HEADER: &str = "cargo-trace unwind-snapshot 1";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Module {
+    pub start: usize,
+    pub end: usize,
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Snapshot {
+    pub regs: Regs,
+    /// Modules sorted by address.
+    pub modules: Vec<Module>,
+    /// Address of the first byte of `stack`.
+    pub stack_addr: u64,
+    pub stack: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut s = String::new();
+        std::fs::File::open(path)?.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Reads a word of the copied stack.
+    pub fn read_word(&self, addr: u64) -> Option<u64> {
+        let offset = addr.checked_sub(self.stack_addr)? as usize;
+        let bytes = self.stack.get(offset..offset + 8)?;
+        let mut word = [0; 8];
+        word.copy_from_slice(bytes);
+        Some(u64::from_ne_bytes(word))
+    }
+}
+
+impl std::str::FromStr for Snapshot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            anyhow::bail!("not a cargo-trace unwind snapshot");
+        }
+        let mut snapshot = Self::default();
+        for line in lines {
+            let mut cols = line.splitn(4, ' ');
+            match (cols.next(), cols.next(), cols.next(), cols.next()) {
+                (Some("regs"), Some(rip), Some(rsp), Some(rbp)) => {
+                    snapshot.regs = Regs {
+                        rip: parse_hex(rip)?,
+                        rsp: parse_hex(rsp)?,
+                        rbp: parse_hex(rbp)?,
+                    };
+                }
+                (Some("module"), Some(start), Some(end), Some(path)) => {
+                    snapshot.modules.push(Module {
+                        start: parse_hex(start)? as usize,
+                        end: parse_hex(end)? as usize,
+                        path: path.into(),
+                    });
+                }
+                (Some("stack"), Some(addr), Some(bytes), None) => {
+                    snapshot.stack_addr = parse_hex(addr)?;
+                    snapshot.stack = decode_hex(bytes)?;
+                }
+                _ => anyhow::bail!("invalid line {}", line),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        let regs = &self.regs;
+        writeln!(f, "regs 0x{:x} 0x{:x} 0x{:x}", regs.rip, regs.rsp, regs.rbp)?;
+        for module in &self.modules {
+            writeln!(
+                f,
+                "module 0x{:x} 0x{:x} {}",
+                module.start,
+                module.end,
+                module.path.display()
+            )?;
+        }
+        write!(f, "stack 0x{:x} ", self.stack_addr)?;
+        for byte in &self.stack {
+            write!(f, "{:02x}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+fn parse_hex(s: &str) -> Result<u64> {
+    Ok(u64::from_str_radix(s.trim_start_matches("0x"), 16)?)
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        anyhow::bail!("odd number of hex digits");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Stacks of a snapshot unwound by both unwinders.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Outcome {
+    pub probe: Vec<u64>,
+    pub reference: Vec<u64>,
+}
+
+impl Outcome {
+    pub fn is_match(&self) -> bool {
+        self.probe == self.reference
+    }
+}
+
+/// Unwinds `snapshot` with the unwind table and the reference unwinder.
+pub fn check(snapshot: &Snapshot) -> Result<Outcome> {
+    let elfs = snapshot
+        .modules
+        .iter()
+        .map(|module| Elf::open(&module.path))
+        .collect::<Result<Vec<_>>>()?;
+    let mut rows = vec![];
+    for (i, (module, elf)) in snapshot.modules.iter().zip(elfs.iter()).enumerate() {
+        rows.extend(module_rows(i, module.start, elf)?);
+    }
+    let read = |addr| snapshot.read_word(addr);
+    // rbp is the only other register of a snapshot.
+    let register = |reg| match reg {
+        6 => Some(snapshot.regs.rbp),
+        _ => None,
+    };
+    let probe = backtrace(&rows, snapshot.regs.rip, snapshot.regs.rsp, register, read);
+
+    let unwinders = elfs
+        .iter()
+        .map(|elf| elf.unwinder().ok())
+        .collect::<Vec<_>>();
+    let mut reference = vec![];
+    let mut regs = snapshot.regs;
+    while regs.rip != 0 && reference.len() < MAX_STACK_DEPTH {
+        reference.push(regs.rip);
+        let i = match snapshot
+            .modules
+            .iter()
+            .position(|module| (module.start..module.end).contains(&(regs.rip as usize)))
+        {
+            Some(i) => i,
+            None => break,
+        };
+        let unwinder = match &unwinders[i] {
+            Some(unwinder) => unwinder,
+            None => break,
+        };
+        // return addresses point after the call, which may be the start of
+        // the next function.
+        let caller = (reference.len() > 1) as u64;
+        let addr = (regs.rip - snapshot.modules[i].start as u64).saturating_sub(caller);
+        regs = match unwinder.step(addr, &regs, read) {
+            Some(regs) => regs,
+            None => break,
+        };
+    }
+    Ok(Outcome { probe, reference })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let snapshot = Snapshot {
+            regs: Regs {
+                rip: 0x1010,
+                rsp: 0x2000,
+                rbp: 0x2040,
+            },
+            modules: vec![Module {
+                start: 0x1000,
+                end: 0x2000,
+                path: "target/release/my app".into(),
+            }],
+            stack_addr: 0x2000,
+            stack: vec![0x10, 0x11, 0, 0, 0, 0, 0, 0, 0xff],
+        };
+        let parsed: Snapshot = snapshot.to_string().parse().unwrap();
+        assert_eq!(parsed, snapshot);
+        assert_eq!(parsed.read_word(0x2000), Some(0x1110));
+        assert_eq!(parsed.read_word(0x2002), None);
+        assert_eq!(parsed.read_word(0x1ff8), None);
+        assert!("cargo-trace profile 1".parse::<Snapshot>().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Gpu jobs submitted by the target.
+//!
+//! Gpu drivers trace the submission and the completion of jobs, the `drm`
+//! scheduler for the drivers built on it like amdgpu, and amdgpu and i915
+//! have their own events. Their formats differ between kernel versions and
+//! drivers, so the `gpu_submit` and `gpu_complete` programs of the probe are
+//! attached to any tracepoint and read the field correlating a submission
+//! with its completion, like the fence of the job, at the offset user space
+//! looks up in the format of the event.
+//!
+//! Submissions are recorded for the threads of the target along with their
+//! user stack, which the kernel unwinds with frame pointers. Completions run
+//! in the context of the driver and are matched with the submissions here.
+//!
+//! A tracepoint is given as `<submit|complete>:<category>:<name>:<field>`,
+//! tracepoints that aren't present are skipped.
+use crate::probe_builder;
+use anyhow::{Context, Result};
+use bpf::utils::event::{self, FieldFormat};
+use bpf::{Bpf, I64, U32, U64};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
+
+/// Map name of the ring buffer.
+pub const GPU_EVENTS: &str = "GPU_EVENTS";
+
+/// Submissions and completions of the `drm` scheduler, amdgpu and i915.
+pub const DEFAULT_TRACEPOINTS: &str = "submit:gpu_scheduler:drm_sched_job:fence,\
+     complete:gpu_scheduler:drm_sched_process_job:fence,\
+     submit:amdgpu:amdgpu_cs_ioctl:fence,\
+     submit:i915:i915_request_add:seqno,\
+     complete:i915:i915_request_retire:seqno";
+
+const SUBMIT: u32 = 0;
+const COMPLETE: u32 = 1;
+
+const INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct GpuEvent {
+    time_ns: U64,
+    key: U64,
+    stack_id: I64,
+    kind: U32,
+    tid: U32,
+    event: U32,
+    _pad: U32,
+}
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct GpuKey {
+    offset: U32,
+    size: U32,
+}
+
+/// A tracepoint of a gpu driver.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GpuTracepoint {
+    pub submit: bool,
+    pub category: String,
+    pub name: String,
+    /// Field correlating submissions and completions.
+    pub key: String,
+}
+
+impl GpuTracepoint {
+    pub fn exists(&self) -> bool {
+        event::event_exists(&self.category, &self.name)
+    }
+}
+
+impl std::str::FromStr for GpuTracepoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<_> = s.trim().split(':').collect();
+        let submit = match parts.first() {
+            Some(&"submit") => true,
+            Some(&"complete") => false,
+            _ => anyhow::bail!("expected submit or complete in {}", s),
+        };
+        match parts[1..] {
+            [category, name, key] => Ok(Self {
+                submit,
+                category: category.to_string(),
+                name: name.to_string(),
+                key: key.to_string(),
+            }),
+            _ => anyhow::bail!(
+                "expected <submit|complete>:<category>:<name>:<field>, got {}",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for GpuTracepoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = if self.submit { "submit" } else { "complete" };
+        write!(f, "{}:{}:{}:{}", kind, self.category, self.name, self.key)
+    }
+}
+
+/// Parses a comma separated list of tracepoints.
+pub fn parse_tracepoints(s: &str) -> Result<Vec<GpuTracepoint>> {
+    s.split(',').map(|tp| tp.parse()).collect()
+}
+
+/// A job submitted by a thread of the target.
+#[derive(Clone, Debug)]
+pub struct GpuJob {
+    pub tid: u32,
+    /// Name of the submitting tracepoint.
+    pub tracepoint: String,
+    /// Monotonic clock time of the submission.
+    pub submit_ns: u64,
+    /// Missing for jobs that didn't complete while tracing.
+    pub complete_ns: Option<u64>,
+    /// User stack of the submitting thread, innermost frame first.
+    pub stack: Vec<u64>,
+}
+
+pub struct Recorder {
+    stop: Sender<()>,
+    handle: JoinHandle<Result<Vec<GpuJob>>>,
+}
+
+impl Recorder {
+    /// Records the jobs of `pid` on the present `tracepoints` until
+    /// [`Recorder::finish`], failing if none is present. `audit` logs the
+    /// setup like `Trace::audit`.
+    ///
+    /// The loaded programs aren't `Send`, so they're loaded and owned by the
+    /// recording thread.
+    pub fn spawn(pid: u32, tracepoints: Vec<GpuTracepoint>, audit: bool) -> Result<Self> {
+        let (present, missing): (Vec<_>, Vec<_>) =
+            tracepoints.into_iter().partition(|tp| tp.exists());
+        for tp in &missing {
+            log::debug!("skipping missing gpu tracepoint {}", tp);
+        }
+        if !present.iter().any(|tp| tp.submit) || !present.iter().any(|tp| !tp.submit) {
+            anyhow::bail!("no gpu submission and completion tracepoints are present");
+        }
+        let (stop, stopped) = mpsc::channel();
+        let handle = std::thread::spawn(move || -> Result<Vec<GpuJob>> {
+            let (mut bpf, names) = load(pid, &present, audit)?;
+            let mut events = vec![];
+            {
+                let mut ring = bpf.records(GPU_EVENTS)?;
+                let mut parse = |record: &[u8]| {
+                    // perf buffers pad the records to 8 bytes.
+                    if let Some((event, _)) =
+                        LayoutVerified::<_, GpuEvent>::new_unaligned_from_prefix(record)
+                    {
+                        events.push(*event.into_ref());
+                    }
+                };
+                loop {
+                    match stopped.recv_timeout(Duration::from_millis(0)) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => break,
+                    }
+                    ring.poll(INTERVAL, &mut parse)?;
+                }
+                ring.consume(&mut parse);
+            }
+            let stacks = bpf.stack_trace("GPU_STACKS")?;
+            let mut jobs: Vec<GpuJob> = vec![];
+            let mut pending = HashMap::new();
+            for event in events {
+                let key = event.key.get();
+                match event.kind.get() {
+                    SUBMIT => {
+                        let stack = match event.stack_id.get() {
+                            id if id >= 0 => stacks
+                                .raw_stack_trace(id as u32)?
+                                .map(|frames| frames.iter().collect())
+                                .unwrap_or_default(),
+                            _ => vec![],
+                        };
+                        // drivers on the drm scheduler trace a job twice.
+                        if pending.contains_key(&key) {
+                            continue;
+                        }
+                        pending.insert(key, jobs.len());
+                        let tracepoint = names.get(&event.event.get()).cloned();
+                        jobs.push(GpuJob {
+                            tid: event.tid.get(),
+                            tracepoint: tracepoint.unwrap_or_default(),
+                            submit_ns: event.time_ns.get(),
+                            complete_ns: None,
+                            stack,
+                        });
+                    }
+                    COMPLETE => {
+                        if let Some(i) = pending.remove(&key) {
+                            jobs[i].complete_ns = Some(event.time_ns.get());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // events of different cpus are reserved in the ring buffer in
+            // any order.
+            jobs.sort_by_key(|job| (job.submit_ns, job.tid));
+            Ok(jobs)
+        });
+        Ok(Self { stop, handle })
+    }
+
+    /// Stops recording and returns the jobs in the order of submission.
+    pub fn finish(self) -> Result<Vec<GpuJob>> {
+        self.stop.send(()).ok();
+        self.handle.join().unwrap()
+    }
+}
+
+/// Loads the gpu programs of the probe for `pid` and returns the names of
+/// the tracepoints by id.
+fn load(
+    pid: u32,
+    tracepoints: &[GpuTracepoint],
+    audit: bool,
+) -> Result<(Bpf, HashMap<u32, String>)> {
+    let empty = [("PC", 1), ("RIP", 1), ("RSP", 1), ("USER_STACK", 1)];
+    let mut builder = probe_builder(pid, &empty)?;
+    if audit {
+        builder.set_audit_hook(bpf::audit::log_hook());
+    }
+    for tp in tracepoints {
+        let probe = format!("tracepoint:{}:{}", tp.category, tp.name);
+        let entry = if tp.submit {
+            "gpu_submit"
+        } else {
+            "gpu_complete"
+        };
+        builder.attach_probe_str(&probe, entry)?;
+    }
+    let mut bpf = builder.load()?;
+    let mut config = bpf.array::<U32>("CONFIG")?;
+    config.insert(&U32::new(1), &U32::new(pid))?;
+    let mut names = HashMap::new();
+    let mut keys = bpf.hash_map::<U32, GpuKey>("GPU_KEYS")?;
+    for tp in tracepoints {
+        let id = event::event_id(&tp.category, &tp.name)?;
+        let format = event::event_format(&tp.category, &tp.name)?;
+        let (offset, size) = match format.offset(&tp.key) {
+            Some((offset, FieldFormat::Simple { size, .. })) if *size == 4 || *size == 8 => {
+                (offset, *size)
+            }
+            _ => anyhow::bail!("{} has no 4 or 8 byte field {}", tp.name, tp.key),
+        };
+        let key = GpuKey {
+            offset: U32::new(offset as u32),
+            size: U32::new(size as u32),
+        };
+        keys.insert(&U32::new(id), &key)
+            .with_context(|| format!("failed to set the key of {}", tp))?;
+        names.insert(id, format!("{}:{}", tp.category, tp.name));
+    }
+    Ok((bpf, names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_default_tracepoints() {
+        let tracepoints = parse_tracepoints(DEFAULT_TRACEPOINTS).unwrap();
+        assert_eq!(tracepoints.len(), 5);
+        assert_eq!(
+            tracepoints[1].to_string(),
+            "complete:gpu_scheduler:drm_sched_process_job:fence"
+        );
+        assert!("run:amdgpu:amdgpu_cs_ioctl:fence"
+            .parse::<GpuTracepoint>()
+            .is_err());
+        assert!("submit:amdgpu:fence".parse::<GpuTracepoint>().is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Growing of the stack map during long runs.
+//!
+//! The probe drops new stacks once `USER_STACK` is full. A watcher checks the
+//! fill level and loads a probe with twice the capacity once it passes
+//! `GROW_PERCENT`. The new probe is loaded paused and the probes are switched
+//! by updating the pid in their `CONFIG` maps. The stacks of all probes are
+//! merged at the end. The map doesn't grow beyond `MAX_STACKS`, a full map
+//! of that size is reported once.
+//!
+//! The perf events of a new probe are opened for every thread the target has
+//! at that time, see `Probe::attach`. The target is running by then, so the
+//! events aren't inherited and threads started after the map grew aren't
+//! sampled.
+//!
+//! When following the mappings, the stacks sampled before an exec of the
+//! target are dropped. They belong to the old binary, and the ones up to the
+//! reload were unwound with its table.
+use crate::numa::Migrations;
+use crate::{mappings, unwind_rows, Row, Stack, Trace};
+use anyhow::Result;
+use bpf::utils::{sys, BinaryInfo};
+use bpf::{Bpf, U32, U64};
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Fill level in percent at which the map grows.
+pub const GROW_PERCENT: usize = 90;
+/// Largest capacity the map grows to.
+pub const MAX_STACKS: usize = 1 << 20;
+
+const INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn needs_grow(len: usize, capacity: usize) -> bool {
+    capacity < MAX_STACKS && len * 100 >= capacity * GROW_PERCENT
+}
+
+/// Sums the counts of equal stacks.
+pub fn merge(stacks: impl IntoIterator<Item = Stack>) -> Vec<Stack> {
+    let mut merged: BTreeMap<[u64; 48], u32> = BTreeMap::new();
+    for (stack, count) in stacks {
+        let mut key = [0; 48];
+        for (key, ip) in key.iter_mut().zip(stack.iter()) {
+            *key = ip.get();
+        }
+        *merged.entry(key).or_default() += count.get();
+    }
+    merged
+        .into_iter()
+        .map(|(key, count)| {
+            let mut stack = [U64::new(0); 48];
+            for (ip, key) in stack.iter_mut().zip(key.iter()) {
+                *ip = U64::new(*key);
+            }
+            (stack, U32::new(count))
+        })
+        .collect()
+}
+
+pub struct Watcher {
+    stop: Sender<()>,
+    handle: JoinHandle<Result<(Vec<Stack>, Migrations)>>,
+}
+
+impl Watcher {
+    /// Watches the probe with the `USER_STACK` and `CONFIG` maps `stacks_fd`
+    /// and `config_fd`.
+    ///
+    /// The loaded probe isn't `Send`, so the bigger probes are loaded and
+    /// owned by the watcher thread.
+    pub fn spawn(trace: Trace, rows: Vec<Row>, stacks_fd: RawFd, config_fd: RawFd) -> Self {
+        Self::spawn_with(trace, rows, stacks_fd, config_fd, false)
+    }
+
+    /// Like [`Watcher::spawn`], but also loads a probe with a new unwind
+    /// table whenever the target maps or unmaps a module, see the mappings
+    /// module.
+    pub fn spawn_following_mappings(
+        trace: Trace,
+        rows: Vec<Row>,
+        stacks_fd: RawFd,
+        config_fd: RawFd,
+    ) -> Self {
+        Self::spawn_with(trace, rows, stacks_fd, config_fd, true)
+    }
+
+    fn spawn_with(
+        trace: Trace,
+        mut rows: Vec<Row>,
+        stacks_fd: RawFd,
+        config_fd: RawFd,
+        follow_mappings: bool,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = std::thread::spawn(move || -> Result<(Vec<Stack>, Migrations)> {
+            // the mapping programs are loaded before reading the modules, so
+            // no mapping is missed.
+            let mut aux = if follow_mappings {
+                Some(mappings::load(trace.pid, trace.audit)?)
+            } else {
+                None
+            };
+            let mut feed = match aux.as_mut() {
+                Some(bpf) => Some((
+                    bpf.records(mappings::MAPPINGS)?,
+                    BinaryInfo::attach(trace.pid)?,
+                )),
+                None => None,
+            };
+            let mut current = (stacks_fd, config_fd);
+            let mut capacity = sys::map_info(stacks_fd)?.max_entries as usize;
+            let mut probes: Vec<Bpf> = vec![];
+            let mut stacks = vec![];
+            let mut migrations = Migrations::default();
+            let mut warned = false;
+            loop {
+                // the ring buffer wakes up the watcher instead.
+                let timeout = if feed.is_some() {
+                    Duration::from_millis(0)
+                } else {
+                    INTERVAL
+                };
+                match stopped.recv_timeout(timeout) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+                let mut remapped = false;
+                let mut execed = false;
+                if let Some((ring, info)) = feed.as_mut() {
+                    let mut changes = vec![];
+                    ring.poll(INTERVAL, |record| {
+                        changes.extend(mappings::Change::parse(trace.pid, record))
+                    })?;
+                    for change in changes {
+                        log::debug!("{:?}", change);
+                        remapped |= change.apply(info)?;
+                        execed |= change == mappings::Change::Execed;
+                    }
+                    if remapped {
+                        rows = unwind_rows(info)?;
+                    }
+                }
+                let len = sys::map_len(current.0)?;
+                if !probes.is_empty() && capacity == MAX_STACKS && len >= capacity && !warned {
+                    log::warn!(
+                        "USER_STACK is full with {} stacks and can't grow further, \
+                         new stacks are dropped",
+                        capacity
+                    );
+                    warned = true;
+                }
+                let grow = needs_grow(len, capacity);
+                if !grow && !remapped {
+                    continue;
+                }
+                if grow {
+                    capacity = (capacity * 2).min(MAX_STACKS);
+                }
+                let mut bpf = trace.load_with(&rows, Some(capacity as u32), false)?;
+                let next = (bpf.map_fd("USER_STACK")?, bpf.map_fd("CONFIG")?);
+                // pauses the old probe and starts the new one, keeping the
+                // state of the markers.
+                let (pid, paused) = (1u32.to_ne_bytes(), 2u32.to_ne_bytes());
+                let mut value = [0; 4];
+                if sys::map_lookup_elem(current.1, &paused, &mut value)? {
+                    sys::map_update_elem(next.1, &paused, &value)?;
+                }
+                sys::map_update_elem(current.1, &pid, &u32::MAX.to_ne_bytes())?;
+                sys::map_update_elem(next.1, &pid, &trace.pid.to_ne_bytes())?;
+                if grow {
+                    log::info!("grew USER_STACK to {} entries", capacity);
+                }
+                if remapped {
+                    log::info!("reloaded the unwind table with {} rows", rows.len());
+                }
+                // probes loaded for every mapping change would add up, so
+                // the stacks of the replaced probe are read right away.
+                if let Some(mut prev) = probes.pop() {
+                    stacks.extend(prev.hash_map::<[U64; 48], U32>("USER_STACK")?.iter());
+                    migrations += trace.migrations(&mut prev)?;
+                }
+                if execed {
+                    log::info!("dropping the stacks sampled before the exec");
+                    stacks.clear();
+                }
+                probes.push(bpf);
+                current = next;
+            }
+            // the first probe is checked by `Trace::collect`.
+            if !probes.is_empty() && !warned {
+                crate::warn_if_full(sys::map_len(current.0)?, capacity);
+            }
+            for bpf in &mut probes {
+                stacks.extend(bpf.hash_map::<[U64; 48], U32>("USER_STACK")?.iter());
+                migrations += trace.migrations(bpf)?;
+            }
+            Ok((stacks, migrations))
+        });
+        Self { stop, handle }
+    }
+
+    /// Stops watching, returning the stacks and migrations of the probes
+    /// loaded by the watcher.
+    pub fn finish(self) -> Result<(Vec<Stack>, Migrations)> {
+        self.stop.send(()).ok();
+        self.handle.join().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_stacks() {
+        let stack = |ip| {
+            let mut stack = [U64::new(0); 48];
+            stack[0] = U64::new(ip);
+            stack
+        };
+        let mut merged = merge(vec![
+            (stack(1), U32::new(2)),
+            (stack(2), U32::new(1)),
+            (stack(1), U32::new(3)),
+        ]);
+        merged.sort_by_key(|(stack, _)| stack[0].get());
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].1.get(), 5);
+        assert_eq!(merged[1].1.get(), 1);
+    }
+
+    #[test]
+    fn grow_threshold() {
+        assert!(!needs_grow(900, 1024));
+        assert!(needs_grow(922, 1024));
+        assert!(!needs_grow(MAX_STACKS, MAX_STACKS));
+    }
+}
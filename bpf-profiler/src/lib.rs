@@ -0,0 +1,564 @@
+//! Stack sampling profiler for rust programs.
+//!
+//! The probe unwinds the user stack with the unwind tables of the traced
+//! process, which are generated from the `.eh_frame` sections of its modules
+//! and loaded into bpf maps. The sampled stacks are counted in a map and
+//! symbolized with the debug info of the modules.
+//!
+//! [`Profiler`] profiles a running process:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let mut profiler = bpf_profiler::Profiler::attach(1234, "profile:hz:99")?;
+//! std::thread::sleep(std::time::Duration::from_secs(10));
+//! for line in profiler.collapsed()? {
+//!     println!("{}", line);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A service can profile itself with [`Profiler::start_self`], which loads
+//! the probe in a privileged helper unless it runs as root:
+//!
+//! ```no_run
+//! fn main() -> anyhow::Result<()> {
+//!     bpf_profiler::privsep::run_if_helper();
+//!     let mut profiler = bpf_profiler::Profiler::start_self()?;
+//!     // serve requests, return `profiler.collapsed()` from an endpoint.
+//!     # Ok(())
+//! }
+//! ```
+//!
+//! [`Trace`] gives control over loading the probe, [`grow`] keeps the stack
+//! map from running full during long runs, [`trace_trimmed`] only loads the
+//! unwind table of the code that runs and [`privsep`] loads the probe in a
+//! privileged helper process. [`validate`] checks an unwind table before
+//! it's loaded.
+use crate::symbols::SymbolCache;
+use anyhow::Result;
+use bpf::features::Features;
+use bpf::utils::{ehframe, sys, BinaryInfo, Elf};
+use bpf::{Bpf, BpfBuilder, Probe, ProgramType, I64, U32, U64};
+use cargo_trace_instruction as instruction;
+use rayon::prelude::*;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc;
+use std::time::Duration;
+use zerocopy::{AsBytes, FromBytes, Unaligned};
+
+pub mod corpus;
+pub mod gpu;
+pub mod grow;
+pub mod mappings;
+pub mod numa;
+pub mod privsep;
+pub mod symbols;
+pub mod trim;
+pub mod unwind;
+pub mod validate;
+
+/// The compiled probe.
+pub static PROBE: &[u8] = include_bytes!(concat!(
+    env!("OUT_DIR"),
+    "/target/bpf/programs/cargo-trace-probe/cargo-trace-probe.elf",
+));
+
+/// The probe compiled for kernels without ring buffers, see
+/// `bpf::features`.
+pub static COMPAT_PROBE: &[u8] = include_bytes!(concat!(
+    env!("OUT_DIR"),
+    "/compat/bpf/programs/cargo-trace-probe/cargo-trace-probe.elf",
+));
+
+/// The probe for the features of the running kernel.
+pub fn probe() -> Result<&'static [u8]> {
+    if Features::detect()?.is_compat() {
+        Ok(COMPAT_PROBE)
+    } else {
+        Ok(PROBE)
+    }
+}
+
+/// Opens the probe for the process `pid`, see `BpfBuilder::with_globals`.
+/// The compat probe has no `TARGET_PID` and only filters on `CONFIG[1]`.
+pub fn probe_builder(pid: u32, max_entries: &[(&str, u32)]) -> Result<BpfBuilder> {
+    if Features::detect()?.is_compat() {
+        return BpfBuilder::with_max_entries(COMPAT_PROBE, max_entries);
+    }
+    let pid = pid.to_le_bytes();
+    BpfBuilder::with_globals(PROBE, max_entries, &[("TARGET_PID", &pid)])
+}
+
+/// Unwind instruction of a row as the probe reads it, see the
+/// `cargo-trace-instruction` crate.
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Instruction {
+    pub op: U64,
+    pub offset: I64,
+}
+
+impl Instruction {
+    pub fn decode(&self) -> instruction::Instruction {
+        instruction::Instruction {
+            op: self.op.get(),
+            offset: self.offset.get(),
+        }
+    }
+}
+
+impl From<instruction::Instruction> for Instruction {
+    fn from(ins: instruction::Instruction) -> Self {
+        Self {
+            op: U64::new(ins.op),
+            offset: I64::new(ins.offset),
+        }
+    }
+}
+
+impl From<ehframe::Instruction> for Instruction {
+    fn from(ins: ehframe::Instruction) -> Self {
+        let offset = ins.offset().unwrap_or_default();
+        match (ins.op(), ins.reg()) {
+            (ehframe::Op::CfaOffset, None) => instruction::Instruction::cfa_offset(offset),
+            (ehframe::Op::Register, Some(ehframe::Reg::Rip)) => {
+                instruction::Instruction::rip(offset)
+            }
+            (ehframe::Op::Register, Some(ehframe::Reg::Rsp)) => {
+                instruction::Instruction::rsp(offset)
+            }
+            (ehframe::Op::Register, Some(ehframe::Reg::Other(reg))) => {
+                instruction::Instruction::register(reg, offset)
+            }
+            _ => instruction::Instruction::undefined(),
+        }
+        .into()
+    }
+}
+
+/// Row of the unwind table of a process.
+#[derive(Clone, Copy)]
+pub struct Row {
+    /// First address the row applies to.
+    pub addr: usize,
+    /// Index of the module in the `BinaryInfo`.
+    pub module: usize,
+    pub rip: Instruction,
+    pub rsp: Instruction,
+}
+
+/// Unwind table of all modules of a process, sorted by address.
+pub fn unwind_rows(info: &BinaryInfo) -> Result<Vec<Row>> {
+    let mut rows = vec![];
+    for (module, binary) in info.iter().enumerate() {
+        rows.extend(module_rows(module, binary.start_addr, &binary.elf)?);
+    }
+    Ok(rows)
+}
+
+/// Rows of the module with index `module` loaded at `start_addr`.
+pub fn module_rows(module: usize, start_addr: usize, elf: &Elf) -> Result<Vec<Row>> {
+    let table = elf.unwind_table()?;
+    Ok(table
+        .rows
+        .iter()
+        .map(|row| Row {
+            addr: start_addr + row.start_address,
+            module,
+            rip: row.rip.into(),
+            rsp: row.rsp.into(),
+        })
+        .collect())
+}
+
+/// Instruction pointers of a stack, innermost first, and its sample count.
+pub type Stack = ([U64; 48], U32);
+
+pub const DEFAULT_MARKERS: &str = "cargo_trace_start,cargo_trace_stop";
+
+/// Parses `<start>,<stop>` into uprobes on the marker functions in `path`.
+pub fn marker_probes(markers: &str, path: &std::path::Path) -> Result<(Probe, Probe)> {
+    let mut symbols = markers.split(',');
+    match (symbols.next(), symbols.next(), symbols.next()) {
+        (Some(start), Some(stop), None) if !start.is_empty() && !stop.is_empty() => {
+            let probe = |symbol: &str| Probe::Uprobe {
+                path: Some(path.into()),
+                symbol: symbol.into(),
+                offset: 0,
+            };
+            Ok((probe(start), probe(stop)))
+        }
+        _ => anyhow::bail!("expected --markers=<start>,<stop>, got {}", markers),
+    }
+}
+
+/// Options of the probe, a probe can be loaded several times with the same
+/// options.
+#[derive(Clone)]
+pub struct Trace {
+    pub probe: Probe,
+    /// Program of the probe attached to `probe`.
+    pub entry: &'static str,
+    pub pid: u32,
+    /// Enables run time accounting and logs the stats of the probe.
+    pub probe_stats: bool,
+    /// Prints the kernel memory of the maps once loaded.
+    pub show_memory: bool,
+    /// Uprobes on the start and stop markers, sampling starts paused.
+    pub markers: Option<(Probe, Probe)>,
+    /// Tags the stacks with the numa node, see the numa module.
+    pub numa: bool,
+    /// Logs the maps, programs and probes set up by the loader, see
+    /// `bpf::audit`.
+    pub audit: bool,
+}
+
+impl Trace {
+    /// Parses a probe like `profile:hz:99` for the process of `info`.
+    pub fn new(probe: &str, info: &BinaryInfo) -> Result<Self> {
+        // TODO more convenience:
+        // uprobes: find path from libname
+        // tracepoint: convert to kprobes on syscalls
+        let mut probe: Probe = probe.parse()?;
+        let entry = match probe.prog_type() {
+            ProgramType::Kprobe => "kprobe",
+            ProgramType::PerfEvent => "perf_event",
+            _ => anyhow::bail!("unsupported probe {}", probe),
+        };
+        log::debug!("setting default path to {}", info.path().display());
+        probe.set_default_path(info.path());
+        Ok(Self {
+            probe,
+            entry,
+            pid: info.pid(),
+            probe_stats: false,
+            show_memory: false,
+            markers: None,
+            numa: false,
+            audit: false,
+        })
+    }
+
+    /// Loads the probe with unwind tables sized to `rows`.
+    pub fn load(&self, rows: &[Row]) -> Result<Bpf> {
+        self.load_with(rows, None, true)
+    }
+
+    /// Loads the probe, `stacks` overrides the capacity of `USER_STACK`. An
+    /// inactive probe ignores all events until the pid is written to
+    /// `CONFIG[1]`.
+    pub fn load_with(&self, rows: &[Row], stacks: Option<u32>, active: bool) -> Result<Bpf> {
+        let len = rows.len().max(1) as u32;
+        let mut max_entries = vec![("PC", len), ("RIP", len), ("RSP", len)];
+        if let Some(stacks) = stacks {
+            max_entries.push(("USER_STACK", stacks));
+        }
+        let mut builder = probe_builder(self.pid, &max_entries)?;
+        if self.audit {
+            builder.set_audit_hook(bpf::audit::log_hook());
+        }
+        if self.probe_stats {
+            builder.enable_stats();
+        }
+        if let ProgramType::PerfEvent = self.probe.prog_type() {
+            // without this we will get kernel regs instead of user regs.
+            builder.set_child_pid(self.pid);
+        }
+        builder.attach_probe(self.probe.clone(), self.entry)?;
+        if let Some((start, stop)) = &self.markers {
+            builder.attach_probe(start.clone(), "start_marker")?;
+            builder.attach_probe(stop.clone(), "stop_marker")?;
+        }
+        let mut bpf = builder.load()?;
+        log::debug!("loaded bpf program");
+
+        let mut pc = bpf.array::<U64>("PC")?;
+        for (i, row) in rows.iter().enumerate() {
+            pc.insert(&U32::new(i as _), &U64::new(row.addr as _))?;
+        }
+        let mut rip = bpf.array::<Instruction>("RIP")?;
+        for (i, row) in rows.iter().enumerate() {
+            rip.insert(&U32::new(i as _), &row.rip)?;
+        }
+        let mut rsp = bpf.array::<Instruction>("RSP")?;
+        for (i, row) in rows.iter().enumerate() {
+            rsp.insert(&U32::new(i as _), &row.rsp)?;
+        }
+        // the probe binary searches the pcs, which only works on sorted rows.
+        let pcs = bpf.array::<U64>("PC")?.range(0..rows.len() as u32)?;
+        let pcs: Vec<_> = pcs.iter().map(|pc| pc.get()).collect();
+        if let Some(i) = bpf::first_unsorted(&pcs) {
+            anyhow::bail!(
+                "unwind table isn't strictly sorted, row {} at 0x{:x} is followed by 0x{:x}",
+                i,
+                pcs[i],
+                pcs[i + 1]
+            );
+        }
+        for map in &["PC", "RIP", "RSP"] {
+            bpf.freeze(map)?;
+        }
+        let mut len = bpf.array::<U32>("CONFIG")?;
+        len.insert(&U32::new(0), &U32::new(rows.len() as _))?;
+        let pid = if active { self.pid } else { u32::MAX };
+        len.insert(&U32::new(1), &U32::new(pid))?;
+        let paused = self.markers.is_some() as u32;
+        len.insert(&U32::new(2), &U32::new(paused))?;
+        len.insert(&U32::new(3), &U32::new(self.numa as u32))?;
+        if self.numa {
+            let mut cpu_node = bpf.array::<U32>("CPU_NODE")?;
+            for (cpu, node) in numa::cpu_nodes()? {
+                cpu_node.insert(&U32::new(cpu), &U32::new(node))?;
+            }
+        }
+        if !active {
+            return Ok(bpf);
+        }
+
+        if self.show_memory {
+            println!("{} unwind table rows", rows.len());
+            print!("{}", bpf::memory::Report(&bpf.map_memory()?));
+        }
+        if self.probe_stats {
+            bpf.log_stats(Duration::from_secs(1))?;
+        }
+        Ok(bpf)
+    }
+
+    /// Stacks sampled so far.
+    pub fn collect(&self, bpf: &mut Bpf) -> Result<Vec<Stack>> {
+        self.log_probe_stats(bpf)?;
+        let user_stack = bpf.hash_map::<[U64; 48], U32>("USER_STACK")?;
+        let stacks: Vec<_> = user_stack.iter().collect();
+        warn_if_full(stacks.len(), user_stack.capacity()?);
+        Ok(stacks)
+    }
+
+    pub fn migrations(&self, bpf: &mut Bpf) -> Result<numa::Migrations> {
+        if !self.numa {
+            return Ok(Default::default());
+        }
+        numa::Migrations::read(bpf)
+    }
+
+    fn log_probe_stats(&self, bpf: &mut Bpf) -> Result<()> {
+        if self.probe_stats {
+            for stats in bpf.program_stats()? {
+                log::info!(target: "bpf::stats", "{}", stats);
+            }
+            for (line, count) in bpf.hit_counts()? {
+                log::info!(target: "bpf::stats", "probe line {}: {} hits", line, count);
+            }
+            for (counter, count) in bpf.diag_counts()? {
+                log::info!(target: "bpf::stats", "probe {}: {}", counter, count);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The probe drops new stacks once `USER_STACK` is full. Only probes
+/// replaced by a [`grow::Watcher`] get a bigger map.
+pub fn warn_if_full(stacks: usize, capacity: usize) {
+    if stacks >= capacity {
+        log::warn!(
+            "USER_STACK is full with {} stacks, new stacks were dropped",
+            capacity
+        );
+    }
+}
+
+/// Samples without unwinding for `delay`, then loads the probe again with
+/// the unwind table trimmed to the sampled code.
+///
+/// The traced program keeps running while the probe is reloaded, so the
+/// reload happens on a separate thread. `run` returns once the program should
+/// no longer be traced.
+pub fn trace_trimmed(
+    info: &mut BinaryInfo,
+    trace: Trace,
+    rows: Vec<Row>,
+    delay: Duration,
+    run: impl FnOnce(&mut BinaryInfo) -> Result<()>,
+) -> Result<(Vec<Stack>, numa::Migrations)> {
+    let mut coarse = trace.load(&[])?;
+    let samples_fd = coarse.map_fd("SAMPLES")?;
+    let config_fd = coarse.map_fd("CONFIG")?;
+    let modules: Vec<_> = info
+        .iter()
+        .map(|binary| (binary.start_addr, binary.end_addr))
+        .collect();
+    let (exited, exit) = mpsc::channel();
+    let handle = std::thread::spawn(move || -> Result<(Vec<Stack>, numa::Migrations)> {
+        if exit.recv_timeout(delay).is_ok() {
+            log::warn!("program exited before the unwind table was trimmed");
+            return Ok(Default::default());
+        }
+        let samples = read_samples(samples_fd)?;
+        // stops the coarse pass.
+        sys::map_update_elem(config_fd, &1u32.to_ne_bytes(), &0u32.to_ne_bytes())?;
+        let addrs: Vec<_> = rows.iter().map(|row| (row.addr, row.module)).collect();
+        let keep = trim::hot_rows(&addrs, &modules, &samples);
+        log::info!(
+            "trimmed unwind table from {} to {} rows using {} samples",
+            rows.len(),
+            keep.len(),
+            samples.len()
+        );
+        let rows: Vec<_> = keep
+            .into_iter()
+            .map(|row| match row {
+                trim::Trimmed::Row(i) => rows[i],
+                trim::Trimmed::End(i) => Row {
+                    rip: instruction::Instruction::undefined().into(),
+                    rsp: instruction::Instruction::undefined().into(),
+                    ..rows[i]
+                },
+            })
+            .collect();
+        let mut bpf = trace.load(&rows)?;
+        exit.recv().ok();
+        Ok((trace.collect(&mut bpf)?, trace.migrations(&mut bpf)?))
+    });
+    let res = run(info);
+    exited.send(()).ok();
+    let stacks = handle.join().unwrap()?;
+    drop(coarse);
+    res?;
+    Ok(stacks)
+}
+
+/// Instruction pointers sampled by the coarse pass.
+fn read_samples(fd: RawFd) -> Result<Vec<u64>> {
+    let mut samples = vec![];
+    let mut key = [0u8; 8];
+    let mut prev: Option<[u8; 8]> = None;
+    while sys::map_get_next_key(fd, prev.as_ref().map(|prev| &prev[..]), &mut key)? {
+        samples.push(u64::from_ne_bytes(key));
+        prev = Some(key);
+    }
+    Ok(samples)
+}
+
+/// Symbolizes the stacks into the collapsed format of inferno, one line per
+/// stack in the same order. The stacks are symbolized and folded on all
+/// cpus, see the symbols module.
+pub fn collapse(info: &BinaryInfo, iter: impl Iterator<Item = Stack>) -> Result<Vec<String>> {
+    let stacks: Vec<Stack> = iter.collect();
+    let ips = stacks
+        .iter()
+        .flat_map(|(stack, _)| stack.iter().map(|ip| ip.get() as usize))
+        .filter(|ip| *ip != 0);
+    let symbols = SymbolCache::resolve(info, ips)?;
+    Ok(stacks
+        .par_iter()
+        .map(|(stack, count)| {
+            let mut frames = Vec::with_capacity(stack.len());
+            for ip in stack.iter() {
+                let ip = ip.get() as usize;
+                if ip == 0 {
+                    break;
+                }
+                match symbols.get(ip) {
+                    Some(symbol) => frames.push(symbol),
+                    None => break,
+                }
+            }
+            frames.reverse();
+            let mut collapsed = frames.join(";");
+            collapsed.push(' ');
+            collapsed.push_str(&count.to_string());
+            collapsed
+        })
+        .collect())
+}
+
+/// Probe of [`Profiler::start_self`].
+pub const DEFAULT_PROBE: &str = "profile:hz:99";
+
+enum Loaded {
+    Bpf(Bpf),
+    /// The stack map passed by a helper, see the privsep module.
+    Helper(Option<privsep::Helper>, RawFd),
+}
+
+/// A probe profiling a process.
+pub struct Profiler {
+    info: BinaryInfo,
+    trace: Trace,
+    loaded: Loaded,
+}
+
+impl Profiler {
+    /// Profiles the running process `pid` with a probe like `profile:hz:99`.
+    pub fn attach(pid: u32, probe: &str) -> Result<Self> {
+        Self::start(BinaryInfo::attach(pid)?, probe)
+    }
+
+    /// Profiles the process of `info`.
+    pub fn start(info: BinaryInfo, probe: &str) -> Result<Self> {
+        let trace = Trace::new(probe, &info)?;
+        let bpf = trace.load(&unwind_rows(&info)?)?;
+        Ok(Self {
+            info,
+            trace,
+            loaded: Loaded::Bpf(bpf),
+        })
+    }
+
+    /// Profiles the calling process with `profile:hz:99`.
+    ///
+    /// As root the probe is loaded directly, otherwise by a privileged
+    /// helper, see [`privsep::Helper::spawn`]. Programs without a pre-granted
+    /// helper must call [`privsep::run_if_helper`] first thing in `main`.
+    pub fn start_self() -> Result<Self> {
+        Self::start_self_with(DEFAULT_PROBE)
+    }
+
+    /// Profiles the calling process with a probe like `profile:hz:99`.
+    pub fn start_self_with(probe: &str) -> Result<Self> {
+        let info = BinaryInfo::attach(std::process::id())?;
+        if unsafe { libc::geteuid() } == 0 {
+            return Self::start(info, probe);
+        }
+        let trace = Trace::new(probe, &info)?;
+        let mut helper = privsep::Helper::spawn()?;
+        let fd = helper.load(&trace, &unwind_rows(&info)?)?;
+        Ok(Self {
+            info,
+            trace,
+            loaded: Loaded::Helper(Some(helper), fd),
+        })
+    }
+
+    pub fn info(&self) -> &BinaryInfo {
+        &self.info
+    }
+
+    /// Stacks sampled so far.
+    pub fn stacks(&mut self) -> Result<Vec<Stack>> {
+        match &mut self.loaded {
+            Loaded::Bpf(bpf) => self.trace.collect(bpf),
+            Loaded::Helper(_, fd) => privsep::read_stacks(*fd),
+        }
+    }
+
+    /// Symbolized stacks sampled so far in the collapsed format of inferno,
+    /// sorted by stack.
+    pub fn collapsed(&mut self) -> Result<Vec<String>> {
+        let stacks = self.stacks()?;
+        Ok(symbols::fold(collapse(&self.info, stacks.into_iter())?))
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Loaded::Helper(helper, fd) = &mut self.loaded {
+            unsafe { libc::close(*fd) };
+            if let Some(Err(err)) = helper.take().map(|helper| helper.stop()) {
+                log::warn!("{}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Mapping changes of the target.
+//!
+//! The `mmap_enter`, `mmap_exit` and `munmap_enter` programs of the probe
+//! stream the file backed mappings and the unmappings of the target to the
+//! `MAPPINGS` ring buffer. Modules loaded or unloaded with `dlopen` and
+//! `dlclose` are applied to the `BinaryInfo` as they happen, without
+//! rereading `/proc/<pid>/maps`, see [`crate::grow::Watcher`] for the reload
+//! of the unwind table.
+//!
+//! The path of a mapping is resolved in `/proc/<pid>/map_files` once the
+//! event is read, falling back to the file descriptor it was mapped from.
+//! Mappings unmapped before that are dropped along with their unmapping.
+//!
+//! An exec of the target is streamed as well. The kernel maps the new binary
+//! and its dynamic linker without the `mmap` syscall, so the modules are
+//! read from `/proc/<pid>/maps` once, the libraries follow as mappings.
+use crate::probe_builder;
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use bpf::{Bpf, U32, U64};
+use std::path::PathBuf;
+use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
+
+/// Map name of the ring buffer.
+pub const MAPPINGS: &str = "MAPPINGS";
+
+const MAPPED: u64 = 0;
+const UNMAPPED: u64 = 1;
+const EXECED: u64 = 2;
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct MappingEvent {
+    kind: U64,
+    start: U64,
+    len: U64,
+    fd: U64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    Mapped {
+        start: usize,
+        end: usize,
+        path: PathBuf,
+    },
+    Unmapped {
+        start: usize,
+        end: usize,
+    },
+    /// The target execed another binary.
+    Execed,
+}
+
+impl Change {
+    /// Parses a record of the ring buffer, resolving the path of mappings of
+    /// `pid`.
+    pub fn parse(pid: u32, record: &[u8]) -> Option<Self> {
+        // perf buffers pad the records to 8 bytes.
+        let (event, _) = LayoutVerified::<_, MappingEvent>::new_unaligned_from_prefix(record)?;
+        let event = event.into_ref();
+        let start = event.start.get() as usize;
+        let end = start + page_align(event.len.get() as usize);
+        match event.kind.get() {
+            MAPPED => {
+                let path = resolve_path(pid, start, end, event.fd.get())?;
+                Some(Self::Mapped { start, end, path })
+            }
+            UNMAPPED => Some(Self::Unmapped { start, end }),
+            EXECED => Some(Self::Execed),
+            _ => None,
+        }
+    }
+
+    /// Applies the change to `info`, returning whether its modules changed.
+    pub fn apply(&self, info: &mut BinaryInfo) -> Result<bool> {
+        match self {
+            Self::Mapped { start, end, path } => info.map_module(path, *start, *end),
+            Self::Unmapped { start, end } => Ok(info.unmap_modules(*start, *end)),
+            Self::Execed => {
+                info.reload()?;
+                log::info!("target execed {}", info.path().display());
+                Ok(true)
+            }
+        }
+    }
+}
+
+fn page_align(len: usize) -> usize {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    (len + page_size - 1) & !(page_size - 1)
+}
+
+fn resolve_path(pid: u32, start: usize, end: usize, fd: u64) -> Option<PathBuf> {
+    let map_file = format!("/proc/{}/map_files/{:x}-{:x}", pid, start, end);
+    let path = std::fs::read_link(map_file)
+        .or_else(|_| std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)))
+        .ok()?;
+    if !path.exists() {
+        log::debug!("mapping of {} is gone", path.display());
+        return None;
+    }
+    Some(path)
+}
+
+/// Loads the mapping programs of the probe for `pid`, independent of the
+/// sampling probe so they keep running when it's reloaded. `audit` logs the
+/// setup like `Trace::audit`.
+pub fn load(pid: u32, audit: bool) -> Result<Bpf> {
+    let empty = [("PC", 1), ("RIP", 1), ("RSP", 1), ("USER_STACK", 1)];
+    let mut builder = probe_builder(pid, &empty)?;
+    if audit {
+        builder.set_audit_hook(bpf::audit::log_hook());
+    }
+    builder.attach_probe_str("tracepoint:syscalls:sys_enter_mmap", "mmap_enter")?;
+    builder.attach_probe_str("tracepoint:syscalls:sys_exit_mmap", "mmap_exit")?;
+    builder.attach_probe_str("tracepoint:syscalls:sys_enter_munmap", "munmap_enter")?;
+    builder.attach_probe_str("tracepoint:sched:sched_process_exec", "process_exec")?;
+    let mut bpf = builder.load()?;
+    let mut config = bpf.array::<U32>("CONFIG")?;
+    config.insert(&U32::new(1), &U32::new(pid))?;
+    Ok(bpf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_unmapped() {
+        let event = MappingEvent {
+            kind: U64::new(UNMAPPED),
+            start: U64::new(0x7f00_0000_0000),
+            len: U64::new(1),
+            fd: U64::new(0),
+        };
+        assert_eq!(
+            Change::parse(1, event.as_bytes()),
+            Some(Change::Unmapped {
+                start: 0x7f00_0000_0000,
+                end: 0x7f00_0000_0000 + page_align(1),
+            })
+        );
+        assert_eq!(Change::parse(1, &event.as_bytes()[..8]), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Numa nodes of the samples.
+//!
+//! With `--numa` the probe stores the node of the sampled cpu plus one in the
+//! last slot of every stack and counts the cpu migrations of the target's
+//! threads between two of their samples.
+use crate::{collapse, Stack};
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use bpf::{Bpf, U32, U64};
+use std::collections::BTreeMap;
+
+/// Slot of the stack holding the node.
+pub const NODE_SLOT: usize = 47;
+
+/// Node of every cpu, empty on machines without numa.
+pub fn cpu_nodes() -> Result<Vec<(u32, u32)>> {
+    let mut nodes = vec![];
+    let dir = match std::fs::read_dir("/sys/devices/system/node") {
+        Ok(dir) => dir,
+        Err(_) => return Ok(nodes),
+    };
+    for entry in dir {
+        let entry = entry?;
+        let name = entry.file_name();
+        let node = match name.to_string_lossy().strip_prefix("node") {
+            Some(node) => node.parse()?,
+            None => continue,
+        };
+        let cpulist = std::fs::read_to_string(entry.path().join("cpulist"))?;
+        for cpu in parse_cpulist(&cpulist)? {
+            nodes.push((cpu, node));
+        }
+    }
+    Ok(nodes)
+}
+
+/// Parses cpu lists like `0-3,8-11`.
+pub fn parse_cpulist(s: &str) -> Result<Vec<u32>> {
+    let mut cpus = vec![];
+    for group in s.trim().split(',').filter(|group| !group.is_empty()) {
+        let mut iter = group.split('-');
+        let start: u32 = iter.next().unwrap_or_default().parse()?;
+        let end = match iter.next() {
+            Some(end) => end.parse()?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// Removes the node from a stack.
+pub fn take_node(stack: &mut Stack) -> Option<u32> {
+    let tag = stack.0[NODE_SLOT].get();
+    stack.0[NODE_SLOT] = U64::new(0);
+    tag.checked_sub(1).map(|node| node as u32)
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Migrations {
+    pub same_node: u64,
+    pub cross_node: u64,
+}
+
+impl Migrations {
+    pub fn read(bpf: &mut Bpf) -> Result<Self> {
+        let migrations = bpf.array::<U64>("MIGRATIONS")?;
+        let get = |i| -> Result<u64> {
+            Ok(migrations
+                .get(&U32::new(i))?
+                .map(|count| count.get())
+                .unwrap_or_default())
+        };
+        Ok(Self {
+            same_node: get(0)?,
+            cross_node: get(1)?,
+        })
+    }
+}
+
+impl std::ops::AddAssign for Migrations {
+    fn add_assign(&mut self, other: Self) {
+        self.same_node += other.same_node;
+        self.cross_node += other.cross_node;
+    }
+}
+
+impl std::fmt::Display for Migrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} migrations within a node, {} across nodes",
+            self.same_node, self.cross_node
+        )
+    }
+}
+
+/// Samples per node.
+pub fn node_samples(stacks: &[Stack]) -> BTreeMap<u32, u64> {
+    let mut samples = BTreeMap::new();
+    for stack in stacks {
+        let node = stack.0[NODE_SLOT].get().saturating_sub(1) as u32;
+        *samples.entry(node).or_default() += stack.1.get() as u64;
+    }
+    samples
+}
+
+/// Collapses the stacks with the node as the root frame.
+pub fn collapse_by_node(info: &BinaryInfo, mut stacks: Vec<Stack>) -> Result<Vec<String>> {
+    let nodes: Vec<_> = stacks
+        .iter_mut()
+        .map(|stack| take_node(stack).unwrap_or_default())
+        .collect();
+    let lines = collapse(info, stacks.into_iter())?;
+    Ok(nodes
+        .into_iter()
+        .zip(lines)
+        .map(|(node, line)| {
+            let sep = if line.starts_with(' ') { "" } else { ";" };
+            format!("node {}{}{}", node, sep, line)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(parse_cpulist("0-3,8\n").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert!(parse_cpulist("\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn node_tag() {
+        let mut stack = ([U64::new(0); 48], U32::new(3));
+        stack.0[0] = U64::new(0x1000);
+        stack.0[NODE_SLOT] = U64::new(2);
+        assert_eq!(node_samples(&[stack]).get(&1), Some(&3));
+        assert_eq!(take_node(&mut stack), Some(1));
+        assert_eq!(stack.0[NODE_SLOT].get(), 0);
+        assert_eq!(take_node(&mut stack), None);
+    }
+}
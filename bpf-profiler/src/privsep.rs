@@ -0,0 +1,370 @@
+//! Privilege separation between loading and analysis.
+//!
+//! Only a small helper runs as root. It loads the probe, attaches it and
+//! passes the fd of the stack map over a unix socket. The traced program, the
+//! symbolization and the flamegraph stay unprivileged. The helper is the
+//! current executable started with [`HELPER_ARG`], which calls [`helper`].
+//!
+//! The socket is created in a directory only the user can access, and the
+//! helper only traces processes owned by the user connected to it.
+//!
+//! Reading the map through the passed fd needs Linux 6.5 or later when
+//! unprivileged bpf is disabled.
+use crate::{Instruction, Row, Stack, Trace};
+use anyhow::{bail, Result};
+use bpf::utils::{fdpass, sys};
+use bpf::{ProgramType, U32, U64};
+use std::convert::TryInto;
+use std::ffi::{CString, OsString};
+use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::Duration;
+use zerocopy::{AsBytes, LayoutVerified};
+
+/// Argument that runs the current executable as the helper, see [`helper`].
+pub const HELPER_ARG: &str = "__bpf-helper";
+/// Path of a pre-granted helper, an executable calling [`run_if_helper`] that
+/// has the `cap_bpf` and `cap_perfmon` file capabilities.
+pub const HELPER_ENV: &str = "BPF_PROFILER_HELPER";
+
+const ROW_SIZE: usize = 40;
+const STACK_SIZE: usize = 48 * 8;
+/// Maximum size of a message, the unwind tables of large binaries have a few
+/// million rows.
+const MAX_MSG: usize = 256 << 20;
+
+fn write_msg(stream: &mut UnixStream, msg: &[u8]) -> Result<()> {
+    stream.write_all(&(msg.len() as u32).to_ne_bytes())?;
+    stream.write_all(msg)?;
+    Ok(())
+}
+
+fn read_msg(stream: &mut UnixStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_ne_bytes(len) as usize;
+    if len > MAX_MSG {
+        bail!(
+            "message of {} bytes exceeds the maximum of {}",
+            len,
+            MAX_MSG
+        );
+    }
+    let mut msg = vec![0; len];
+    stream.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+/// Replies are short and sent with a single `sendmsg` so they can carry fds.
+fn read_reply(stream: &UnixStream) -> Result<Vec<RawFd>> {
+    let mut buf = [0; 4096];
+    let (len, fds) = fdpass::recv(stream, &mut buf)?;
+    let reply = std::str::from_utf8(&buf[..len])?;
+    if let Some(err) = reply.strip_prefix("error: ") {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+        bail!("bpf helper: {}", err);
+    }
+    Ok(fds)
+}
+
+fn encode_trace(trace: &Trace) -> String {
+    let mut s = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        trace.probe, trace.entry, trace.pid, trace.probe_stats, trace.show_memory, trace.audit
+    );
+    if let Some((start, stop)) = &trace.markers {
+        s.push_str(&format!("\n{}\n{}", start, stop));
+    }
+    s
+}
+
+fn decode_trace(s: &str) -> Result<Trace> {
+    let lines: Vec<_> = s.lines().collect();
+    if lines.len() != 6 && lines.len() != 8 {
+        bail!("invalid trace");
+    }
+    let probe: bpf::Probe = lines[0].parse()?;
+    let entry = match (lines[1], probe.prog_type()) {
+        ("kprobe", ProgramType::Kprobe) => "kprobe",
+        ("perf_event", ProgramType::PerfEvent) => "perf_event",
+        _ => bail!("unsupported probe {}", probe),
+    };
+    Ok(Trace {
+        probe,
+        entry,
+        pid: lines[2].parse()?,
+        probe_stats: lines[3].parse()?,
+        show_memory: lines[4].parse()?,
+        markers: match lines.get(6..8) {
+            Some(&[start, stop]) => Some((start.parse()?, stop.parse()?)),
+            _ => None,
+        },
+        numa: false,
+        audit: lines[5].parse()?,
+    })
+}
+
+fn encode_rows(rows: &[Row]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(rows.len() * ROW_SIZE);
+    for row in rows {
+        bytes.extend_from_slice(&(row.addr as u64).to_ne_bytes());
+        bytes.extend_from_slice(row.rip.as_bytes());
+        bytes.extend_from_slice(row.rsp.as_bytes());
+    }
+    bytes
+}
+
+fn instruction(bytes: &[u8]) -> Instruction {
+    *LayoutVerified::<_, Instruction>::new_unaligned(bytes).unwrap()
+}
+
+fn decode_rows(bytes: &[u8]) -> Result<Vec<Row>> {
+    if bytes.len() % ROW_SIZE != 0 {
+        bail!("invalid unwind table");
+    }
+    Ok(bytes
+        .chunks_exact(ROW_SIZE)
+        .map(|row| Row {
+            addr: u64::from_ne_bytes(row[..8].try_into().unwrap()) as usize,
+            module: 0,
+            rip: instruction(&row[8..24]),
+            rsp: instruction(&row[24..40]),
+        })
+        .collect())
+}
+
+/// Reads the stacks from the `USER_STACK` map.
+pub fn read_stacks(fd: RawFd) -> Result<Vec<Stack>> {
+    let mut stacks = vec![];
+    let mut key = [0u8; STACK_SIZE];
+    let mut prev: Option<[u8; STACK_SIZE]> = None;
+    let mut count = [0u8; 4];
+    while sys::map_get_next_key(fd, prev.as_ref().map(|prev| &prev[..]), &mut key)? {
+        if sys::map_lookup_elem(fd, &key, &mut count)? {
+            let stack = *LayoutVerified::<_, [U64; 48]>::new_unaligned(&key[..]).unwrap();
+            stacks.push((stack, U32::new(u32::from_ne_bytes(count))));
+        }
+        prev = Some(key);
+    }
+    crate::warn_if_full(stacks.len(), sys::map_info(fd)?.max_entries as usize);
+    Ok(stacks)
+}
+
+/// The unprivileged side of the helper.
+pub struct Helper {
+    child: Child,
+    stream: UnixStream,
+}
+
+impl Helper {
+    /// Starts the helper in `BPF_PROFILER_HELPER` or the current executable,
+    /// with sudo unless already running as root.
+    pub fn spawn() -> Result<Self> {
+        let dir = private_dir()?;
+        let path = dir.join("helper.sock");
+        let res = UnixListener::bind(&path)
+            .map_err(Into::into)
+            .and_then(|listener| Self::accept(&listener, &path));
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_dir(&dir).ok();
+        res
+    }
+
+    fn accept(listener: &UnixListener, path: &Path) -> Result<Self> {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        let exe = std::env::current_exe()?;
+        let mut cmd = match std::env::var_os(HELPER_ENV) {
+            Some(helper) => Command::new(helper),
+            None if unsafe { libc::getuid() } == 0 => Command::new(exe),
+            None => {
+                let mut cmd = Command::new("sudo");
+                cmd.arg("--preserve-env=RUST_LOG").arg(exe);
+                cmd
+            }
+        };
+        let mut child = cmd.arg(HELPER_ARG).arg(path).spawn()?;
+        // the helper may never connect if sudo fails.
+        listener.set_nonblocking(true)?;
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(Self { child, stream });
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(status) = child.try_wait()? {
+                bail!("bpf helper exited with {}", status);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Loads and attaches the probe, returning the fd of the stack map.
+    pub fn load(&mut self, trace: &Trace, rows: &[Row]) -> Result<RawFd> {
+        write_msg(&mut self.stream, encode_trace(trace).as_bytes())?;
+        write_msg(&mut self.stream, &encode_rows(rows))?;
+        match read_reply(&self.stream)?.as_slice() {
+            [fd] => Ok(*fd),
+            _ => bail!("bpf helper: expected one fd"),
+        }
+    }
+
+    /// Detaches the probe and waits for the helper to exit.
+    pub fn stop(mut self) -> Result<()> {
+        write_msg(&mut self.stream, b"stop")?;
+        read_reply(&self.stream)?;
+        let status = self.child.wait()?;
+        if !status.success() {
+            bail!("bpf helper exited with {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// Creates a new directory in the temporary directory with mode 0700.
+fn private_dir() -> Result<PathBuf> {
+    let template = std::env::temp_dir().join("bpf-profiler-XXXXXX");
+    let mut template = CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut _) }.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    template.pop();
+    Ok(OsString::from_vec(template).into())
+}
+
+/// Uid of the process connected to `stream`.
+fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(cred.uid)
+}
+
+/// Refuses to trace `pid` unless the peer owns it or is root.
+fn check_peer(stream: &UnixStream, pid: u32) -> Result<()> {
+    let uid = peer_uid(stream)?;
+    if uid == 0 {
+        return Ok(());
+    }
+    let owner = std::fs::metadata(format!("/proc/{}", pid))?.uid();
+    if owner != uid {
+        bail!("pid {} isn't owned by uid {}", pid, uid);
+    }
+    Ok(())
+}
+
+/// Runs the helper and exits if the process was started as one.
+pub fn run_if_helper() {
+    let mut args = std::env::args();
+    if args.nth(1).as_deref() != Some(HELPER_ARG) {
+        return;
+    }
+    let res = match args.next() {
+        Some(path) => helper(path.as_ref()),
+        None => Err(anyhow::anyhow!("missing socket path")),
+    };
+    if let Err(err) = res {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// Entry point of the privileged helper.
+pub fn helper(path: &Path) -> Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    let res = serve(&mut stream);
+    if let Err(err) = &res {
+        fdpass::send(&stream, format!("error: {}", err).as_bytes(), &[]).ok();
+    }
+    res
+}
+
+fn serve(stream: &mut UnixStream) -> Result<()> {
+    let trace = decode_trace(std::str::from_utf8(&read_msg(stream)?)?)?;
+    check_peer(stream, trace.pid)?;
+    let rows = decode_rows(&read_msg(stream)?)?;
+    let mut bpf = trace.load(&rows)?;
+    fdpass::send(stream, b"ok", &[bpf.map_fd("USER_STACK")?])?;
+    if read_msg(stream)? != b"stop" {
+        bail!("unexpected message");
+    }
+    trace.log_probe_stats(&mut bpf)?;
+    drop(bpf);
+    fdpass::send(stream, b"stopped", &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_roundtrip() {
+        let rows = vec![Row {
+            addr: 0x1000,
+            module: 0,
+            rip: cargo_trace_instruction::Instruction::cfa_offset(-8).into(),
+            rsp: cargo_trace_instruction::Instruction::rsp(16).into(),
+        }];
+        let decoded = decode_rows(&encode_rows(&rows)).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].addr, 0x1000);
+        assert_eq!(decoded[0].rip.as_bytes(), rows[0].rip.as_bytes());
+        assert_eq!(decoded[0].rsp.as_bytes(), rows[0].rsp.as_bytes());
+    }
+
+    #[test]
+    fn peer_owns_itself() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&a).unwrap(), unsafe { libc::geteuid() });
+        check_peer(&a, std::process::id()).unwrap();
+    }
+
+    #[test]
+    fn oversized_message() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        a.write_all(&(MAX_MSG as u32 + 1).to_ne_bytes()).unwrap();
+        assert!(read_msg(&mut b).is_err());
+    }
+
+    #[test]
+    fn trace_with_markers_roundtrip() {
+        let trace = Trace {
+            probe: "profile:hz:99".parse().unwrap(),
+            entry: "perf_event",
+            pid: 42,
+            probe_stats: false,
+            show_memory: true,
+            markers: Some(
+                crate::marker_probes(crate::DEFAULT_MARKERS, "/bin/app".as_ref()).unwrap(),
+            ),
+            numa: false,
+            audit: true,
+        };
+        let decoded = decode_trace(&encode_trace(&trace)).unwrap();
+        assert_eq!(decoded.pid, 42);
+        assert_eq!(decoded.markers, trace.markers);
+        assert!(decoded.audit);
+    }
+}
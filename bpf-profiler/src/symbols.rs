@@ -0,0 +1,123 @@
+//! Symbolization of the sampled stacks on all cpus.
+//!
+//! The stacks of long runs share most of their frames, so every distinct
+//! instruction pointer is resolved once into a [`SymbolCache`], which the
+//! threads folding the stacks share. The debug info of a module can't be
+//! shared between threads, so the modules are resolved in parallel, each by
+//! a thread with its own debug info.
+//!
+//! The stack map is iterated in the order of its hashes, which differs
+//! between runs, so reports [`fold`] the collapsed stacks into a sorted list
+//! for byte identical outputs of identical samples.
+use anyhow::Result;
+use bpf::utils::{resolve_symbol, BinaryInfo, Elf};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Functions of instruction pointers, the ones outside of the modules or
+/// without a symbol are missing.
+#[derive(Debug, Default)]
+pub struct SymbolCache {
+    symbols: HashMap<usize, String>,
+}
+
+impl SymbolCache {
+    /// Resolves the instruction pointers `ips` of the process of `info`.
+    pub fn resolve(info: &BinaryInfo, ips: impl Iterator<Item = usize>) -> Result<Self> {
+        let mut modules: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for ip in ips {
+            if let Some(module) = info.module(ip) {
+                modules.entry(module).or_default().push(ip);
+            }
+        }
+        let modules: Vec<_> = modules
+            .into_iter()
+            .map(|(module, mut ips)| {
+                ips.sort_unstable();
+                ips.dedup();
+                let binary = &info[module];
+                let task = Task {
+                    elf: binary.elf.clone(),
+                    start_addr: binary.start_addr,
+                    has_dwarf: binary.dwarf.is_some(),
+                };
+                (task, ips)
+            })
+            .collect();
+        let symbols = modules
+            .into_par_iter()
+            .map(|(task, ips)| task.resolve(&ips))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            symbols: symbols.into_iter().flatten().collect(),
+        })
+    }
+
+    pub fn get(&self, ip: usize) -> Option<&str> {
+        self.symbols.get(&ip).map(String::as_str)
+    }
+}
+
+/// Instruction pointers of a module resolved by one thread.
+struct Task {
+    elf: Elf,
+    start_addr: usize,
+    has_dwarf: bool,
+}
+
+impl Task {
+    fn resolve(&self, ips: &[usize]) -> Result<Vec<(usize, String)>> {
+        let dwarf = if self.has_dwarf {
+            self.elf.dwarf().ok()
+        } else {
+            None
+        };
+        let mut symbols = Vec::with_capacity(ips.len());
+        for ip in ips {
+            let offset = ip - self.start_addr;
+            if let Some(symbol) = resolve_symbol(&self.elf, dwarf.as_ref(), offset)? {
+                symbols.push((*ip, symbol));
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+/// Sums the counts of equal collapsed stacks and sorts them by stack.
+pub fn fold(lines: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for mut line in lines {
+        let count = match line.rfind(' ') {
+            Some(i) => match line[i + 1..].parse::<u64>() {
+                Ok(count) => {
+                    line.truncate(i);
+                    count
+                }
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        *stacks.entry(line).or_default() += count;
+    }
+    stacks
+        .into_iter()
+        .map(|(stack, count)| format!("{} {}", stack, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_lines() {
+        let lines = vec![
+            "main;work 3".to_string(),
+            " 1".to_string(),
+            "main;idle 2".to_string(),
+            "main;work 4".to_string(),
+            "invalid".to_string(),
+        ];
+        assert_eq!(fold(lines), vec![" 1", "main;idle 2", "main;work 7"]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Trimming of the unwind tables to the code that runs.
+//!
+//! A coarse pass samples the instruction pointer without unwinding. Only the
+//! rows of modules that were sampled and lie in a window around a sample are
+//! loaded afterwards. Frames outside of the kept rows end the unwind.
+//!
+//! The probe applies the nearest row below an address, so every run of kept
+//! rows is followed by an undefined row at the address of the first row that
+//! was dropped. Without it a caller outside of the windows would be unwound
+//! with the rule of unrelated code.
+
+/// Size of the address windows around the samples.
+pub const WINDOW: usize = 2 << 20;
+
+/// Row of the trimmed unwind table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trimmed {
+    /// Row of the table by index.
+    Row(usize),
+    /// Undefined row at the address of the dropped row with the index.
+    End(usize),
+}
+
+/// Returns the rows to keep, sorted like `rows`.
+///
+/// `rows` are the row addresses with the index of their module, `modules`
+/// the address ranges of the modules.
+pub fn hot_rows(
+    rows: &[(usize, usize)],
+    modules: &[(usize, usize)],
+    samples: &[u64],
+) -> Vec<Trimmed> {
+    let mut hot_modules = vec![false; modules.len()];
+    let mut windows = vec![];
+    for sample in samples {
+        let sample = *sample as usize;
+        let module = modules
+            .iter()
+            .position(|(start, end)| *start <= sample && sample < *end);
+        if let Some(module) = module {
+            hot_modules[module] = true;
+            windows.push(sample / WINDOW);
+        }
+    }
+    windows.sort_unstable();
+    windows.dedup();
+    let mut trimmed = vec![];
+    let mut kept = false;
+    for (i, (addr, module)) in rows.iter().enumerate() {
+        if hot_modules[*module] && windows.binary_search(&(addr / WINDOW)).is_ok() {
+            trimmed.push(Trimmed::Row(i));
+            kept = true;
+        } else if kept {
+            trimmed.push(Trimmed::End(i));
+            kept = false;
+        }
+    }
+    trimmed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_sampled_modules_and_windows() {
+        let modules = [(0x1000, 0x80_0000), (0x1000_0000, 0x1100_0000)];
+        let rows = [(0x1000, 0), (0x60_0000, 0), (0x1000_0000, 1)];
+        assert_eq!(
+            hot_rows(&rows, &modules, &[0x2000]),
+            vec![Trimmed::Row(0), Trimmed::End(1)]
+        );
+        assert_eq!(
+            hot_rows(&rows, &modules, &[0x2000, 0x1000_0100]),
+            vec![Trimmed::Row(0), Trimmed::End(1), Trimmed::Row(2)]
+        );
+        assert!(hot_rows(&rows, &modules, &[0x9000_0000]).is_empty());
+    }
+
+    /// Row the probe applies to `pc`, the last one at or below it.
+    fn lookup(rows: &[(usize, usize)], trimmed: &[Trimmed], pc: usize) -> Option<Trimmed> {
+        let addr = |row: &Trimmed| match row {
+            Trimmed::Row(i) | Trimmed::End(i) => rows[*i].0,
+        };
+        let i = trimmed.partition_point(|row| addr(row) <= pc);
+        i.checked_sub(1).map(|i| trimmed[i])
+    }
+
+    #[test]
+    fn callers_outside_the_windows_end_the_unwind() {
+        let modules = [(0x1000, 0x80_0000)];
+        // a hot function followed by a cold caller in the next window.
+        let rows = [(0x1000, 0), (0x1100, 0), (0x20_1000, 0), (0x20_1100, 0)];
+        let trimmed = hot_rows(&rows, &modules, &[0x1080]);
+        assert_eq!(lookup(&rows, &trimmed, 0x1080), Some(Trimmed::Row(0)));
+        assert_eq!(lookup(&rows, &trimmed, 0x1180), Some(Trimmed::Row(1)));
+        assert_eq!(lookup(&rows, &trimmed, 0x20_1080), Some(Trimmed::End(2)));
+        assert_eq!(lookup(&rows, &trimmed, 0x20_1180), Some(Trimmed::End(2)));
+        assert_eq!(lookup(&rows, &trimmed, 0x800), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Unwinding in user space.
+//!
+//! [`backtrace`] unwinds a stack with the unwind table the same way as the
+//! probe does, for stacks captured with ptrace and to test the table against
+//! the reference unwinder of the `ehframe` crate, see the corpus module.
+use crate::Row;
+
+/// Frames of a stack, like the stacks of the probe.
+pub const MAX_STACK_DEPTH: usize = 48;
+
+/// Unwinds a stack the same way as the probe, `register` looks up a dwarf
+/// register of the innermost frame and `read` reads a word of the target's
+/// memory.
+pub fn backtrace(
+    rows: &[Row],
+    mut rip: u64,
+    mut rsp: u64,
+    register: impl Fn(u16) -> Option<u64>,
+    read: impl Fn(u64) -> Option<u64>,
+) -> Vec<u64> {
+    let mut stack = vec![];
+    while rip != 0 && stack.len() < MAX_STACK_DEPTH {
+        stack.push(rip);
+        let row = match rows.binary_search_by_key(&(rip as usize), |row| row.addr) {
+            Ok(i) => &rows[i],
+            Err(0) => break,
+            Err(i) => &rows[i - 1],
+        };
+        // only the registers of the innermost frame are known.
+        let innermost = stack.len() == 1;
+        let register = |reg| if innermost { register(reg) } else { None };
+        let cfa = match row.rsp.decode().execute(rip, rsp, 0, register, &read) {
+            Some(cfa) => cfa,
+            None => break,
+        };
+        rip = row
+            .rip
+            .decode()
+            .execute(rip, rsp, cfa, register, &read)
+            .unwrap_or_default();
+        rsp = cfa;
+    }
+    stack
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_trace_instruction::Instruction;
+
+    #[test]
+    fn unwind_frames() {
+        // cfa is rsp + 8, the return address is stored below the cfa.
+        let row = |addr| Row {
+            addr,
+            module: 0,
+            rip: Instruction::cfa_offset(-8).into(),
+            rsp: Instruction::rsp(8).into(),
+        };
+        let rows = vec![row(0x1000), row(0x1100)];
+        let read = |addr| match addr {
+            0x2000 => Some(0x1110),
+            0x2008 => Some(0),
+            _ => None,
+        };
+        let none = |_| None;
+        assert_eq!(
+            backtrace(&rows, 0x1010, 0x2000, none, read),
+            vec![0x1010, 0x1110]
+        );
+        assert_eq!(backtrace(&rows, 0x10, 0x2000, none, read), vec![0x10]);
+
+        // the cfa of the innermost frame is r13 + 8, rsp points elsewhere.
+        let mut rows = rows;
+        rows[0].rsp = Instruction::register(13, 8).into();
+        let r13 = |reg| if reg == 13 { Some(0x2000) } else { None };
+        assert_eq!(
+            backtrace(&rows, 0x1010, 0x3000, r13, read),
+            vec![0x1010, 0x1110]
+        );
+        assert_eq!(backtrace(&rows, 0x1010, 0x3000, none, read), vec![0x1010]);
+    }
+}
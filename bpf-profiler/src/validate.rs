@@ -0,0 +1,263 @@
+//! Validation of the unwind table before sampling.
+//!
+//! The probe can't report a broken table, it unwinds wrong frames or stops
+//! early. The rows are checked to be strictly sorted, to have valid
+//! instructions with sane offsets and to lie inside their module. Rows with
+//! rules the probe doesn't support, like the undefined return address of
+//! `_start`, aren't problems but end the stacks, they're counted per module
+//! along with the part of the module the rows cover.
+use crate::Row;
+use bpf::utils::BinaryInfo;
+use cargo_trace_instruction::{Invalid, Op};
+use std::ops::Range;
+use std::path::PathBuf;
+
+pub use cargo_trace_instruction::MAX_OFFSET;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Problem {
+    Unsorted {
+        row: usize,
+        addr: usize,
+        next: usize,
+    },
+    InvalidOp {
+        row: usize,
+        op: u64,
+    },
+    Offset {
+        row: usize,
+        offset: i64,
+    },
+    Register {
+        row: usize,
+        reg: u16,
+    },
+    OutsideModule {
+        row: usize,
+        addr: usize,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unsorted { row, addr, next } => write!(
+                f,
+                "row {} at 0x{:x} isn't before the next row at 0x{:x}",
+                row, addr, next
+            ),
+            Self::InvalidOp { row, op } => write!(f, "row {} has the invalid op {}", row, op),
+            Self::Offset { row, offset } => {
+                write!(f, "row {} has the offset {} out of bounds", row, offset)
+            }
+            Self::Register { row, reg } => {
+                write!(f, "row {} has the invalid register {}", row, reg)
+            }
+            Self::OutsideModule { row, addr } => {
+                write!(f, "row {} at 0x{:x} is outside of its module", row, addr)
+            }
+        }
+    }
+}
+
+/// Rows of a module.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Coverage {
+    pub path: PathBuf,
+    pub rows: usize,
+    /// Rows with rules the probe doesn't support.
+    pub unsupported: usize,
+    /// Bytes from the first row to the end of the module.
+    pub covered: usize,
+    pub size: usize,
+}
+
+impl std::fmt::Display for Coverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let percent = if self.size > 0 {
+            self.covered as f64 * 100.0 / self.size as f64
+        } else {
+            0.0
+        };
+        write!(
+            f,
+            "{}: {} rows, {} unsupported, {:.0}% covered",
+            self.path.display(),
+            self.rows,
+            self.unsupported,
+            percent
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub modules: Vec<Coverage>,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for module in &self.modules {
+            writeln!(f, "{}", module)?;
+        }
+        for problem in self.problems.iter().take(10) {
+            writeln!(f, "{}", problem)?;
+        }
+        if self.problems.len() > 10 {
+            writeln!(f, "{} more problems", self.problems.len() - 10)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validates the unwind table `rows` of the modules of `info`.
+pub fn validate(info: &BinaryInfo, rows: &[Row]) -> Report {
+    let ranges: Vec<_> = info
+        .iter()
+        .map(|binary| binary.start_addr..binary.end_addr)
+        .collect();
+    let mut modules: Vec<_> = info
+        .iter()
+        .map(|binary| Coverage {
+            path: binary.elf.path().to_path_buf(),
+            size: binary.end_addr - binary.start_addr,
+            ..Default::default()
+        })
+        .collect();
+    for row in rows {
+        if let Some(module) = modules.get_mut(row.module) {
+            if module.rows == 0 {
+                module.covered = ranges[row.module].end.saturating_sub(row.addr);
+            }
+            module.rows += 1;
+            module.unsupported += !is_supported(row) as usize;
+        }
+    }
+    Report {
+        modules,
+        problems: problems(&ranges, rows),
+    }
+}
+
+/// Whether the probe can compute the cfa and the return address of `row`,
+/// cfas based on registers other than `rip` and `rsp` only in the innermost
+/// frame.
+fn is_supported(row: &Row) -> bool {
+    let cfa = matches!(
+        row.rsp.decode().op(),
+        Some(Op::Rip) | Some(Op::Rsp) | Some(Op::Register)
+    );
+    let rip = matches!(row.rip.decode().op(), Some(op) if op != Op::Undefined);
+    cfa && rip
+}
+
+/// Problems of `rows` in the modules at `ranges`.
+pub fn problems(ranges: &[Range<usize>], rows: &[Row]) -> Vec<Problem> {
+    let mut problems = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(next) = rows.get(i + 1) {
+            if row.addr >= next.addr {
+                problems.push(Problem::Unsorted {
+                    row: i,
+                    addr: row.addr,
+                    next: next.addr,
+                });
+            }
+        }
+        match ranges.get(row.module) {
+            Some(range) if range.contains(&row.addr) => {}
+            _ => problems.push(Problem::OutsideModule {
+                row: i,
+                addr: row.addr,
+            }),
+        }
+        for ins in &[row.rip, row.rsp] {
+            match ins.decode().validate() {
+                Ok(_) => {}
+                Err(Invalid::Op(op)) => problems.push(Problem::InvalidOp { row: i, op }),
+                Err(Invalid::Offset(offset)) => problems.push(Problem::Offset { row: i, offset }),
+                Err(Invalid::Register(reg)) => problems.push(Problem::Register { row: i, reg }),
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction;
+    use bpf::{I64, U64};
+
+    const OP_UNDEFINED: u64 = Op::Undefined as u64;
+    const OP_CFA_OFFSET: u64 = Op::CfaOffset as u64;
+    const OP_RSP: u64 = Op::Rsp as u64;
+    const OP_R13: u64 = Op::Register as u64 | 13 << 32;
+
+    fn row(addr: usize, module: usize, rip: (u64, i64), rsp: (u64, i64)) -> Row {
+        let ins = |(op, offset)| Instruction {
+            op: U64::new(op),
+            offset: I64::new(offset),
+        };
+        Row {
+            addr,
+            module,
+            rip: ins(rip),
+            rsp: ins(rsp),
+        }
+    }
+
+    #[test]
+    fn table_problems() {
+        let ranges = [0x1000..0x2000, 0x3000..0x4000];
+        let ok = [
+            row(0x1000, 0, (OP_CFA_OFFSET, -8), (OP_RSP, 8)),
+            row(0x1010, 0, (OP_UNDEFINED, 0), (OP_UNDEFINED, 0)),
+            row(0x3000, 1, (OP_CFA_OFFSET, -8), (OP_RSP, 16)),
+            row(0x3010, 1, (OP_CFA_OFFSET, -8), (OP_R13, 16)),
+        ];
+        assert_eq!(problems(&ranges, &ok), vec![]);
+        assert!(!is_supported(&ok[1]));
+        assert!(is_supported(&ok[3]));
+
+        let broken = [
+            row(0x1010, 0, (OP_CFA_OFFSET, -8), (OP_RSP, 8)),
+            row(0x1000, 0, (7, 0), (OP_RSP, MAX_OFFSET + 1)),
+            row(0x2000, 0, (OP_CFA_OFFSET, -8), (OP_RSP, 8)),
+            row(
+                0x3000,
+                1,
+                (OP_CFA_OFFSET, -8),
+                (Op::Register as u64 | 40 << 32, 8),
+            ),
+        ];
+        assert_eq!(
+            problems(&ranges, &broken),
+            vec![
+                Problem::Unsorted {
+                    row: 0,
+                    addr: 0x1010,
+                    next: 0x1000
+                },
+                Problem::InvalidOp { row: 1, op: 7 },
+                Problem::Offset {
+                    row: 1,
+                    offset: MAX_OFFSET + 1
+                },
+                Problem::OutsideModule {
+                    row: 2,
+                    addr: 0x2000
+                },
+                Problem::Register { row: 3, reg: 40 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+use std::path::Path;
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BtfKind {
+    Int,
+    Ptr,
+    Array,
+    Struct,
+    Union,
+    Enum,
+    Fwd,
+    Typedef,
+    Volatile,
+    Const,
+    Restrict,
+    Func,
+    FuncProto,
+    Var,
+    Datasec,
+    Float,
+    DeclTag,
+    TypeTag,
+    Enum64,
+}
+
+impl BtfKind {
+    fn from_u32(kind: u32) -> Option<Self> {
+        use BtfKind::*;
+        Some(match kind {
+            1 => Int,
+            2 => Ptr,
+            3 => Array,
+            4 => Struct,
+            5 => Union,
+            6 => Enum,
+            7 => Fwd,
+            8 => Typedef,
+            9 => Volatile,
+            10 => Const,
+            11 => Restrict,
+            12 => Func,
+            13 => FuncProto,
+            14 => Var,
+            15 => Datasec,
+            16 => Float,
+            17 => DeclTag,
+            18 => TypeTag,
+            19 => Enum64,
+            _ => return None,
+        })
+    }
+
+    /// Size of the kind specific data following `struct btf_type`.
+    fn extra_size(&self, vlen: usize) -> usize {
+        use BtfKind::*;
+        match self {
+            Int | Var | DeclTag => 4,
+            Array => 12,
+            Struct | Union | Datasec | Enum64 => vlen * 12,
+            Enum | FuncProto => vlen * 8,
+            _ => 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        use BtfKind::*;
+        match self {
+            Int => "INT",
+            Ptr => "PTR",
+            Array => "ARRAY",
+            Struct => "STRUCT",
+            Union => "UNION",
+            Enum => "ENUM",
+            Fwd => "FWD",
+            Typedef => "TYPEDEF",
+            Volatile => "VOLATILE",
+            Const => "CONST",
+            Restrict => "RESTRICT",
+            Func => "FUNC",
+            FuncProto => "FUNC_PROTO",
+            Var => "VAR",
+            Datasec => "DATASEC",
+            Float => "FLOAT",
+            DeclTag => "DECL_TAG",
+            TypeTag => "TYPE_TAG",
+            Enum64 => "ENUM64",
+        }
+    }
+}
+
+impl std::fmt::Display for BtfKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BtfType {
+    pub id: u32,
+    pub kind: BtfKind,
+    pub name: String,
+    pub vlen: u16,
+    /// Size for sized types or the referenced type id.
+    pub size_or_type: u32,
+}
+
+/// BPF type format.
+///
+/// Only the type table is decoded, which is enough to list types and to
+/// resolve type ids by name.
+#[derive(Clone, Debug, Default)]
+pub struct Btf {
+    types: Vec<BtfType>,
+    strings: Vec<u8>,
+    /// Id of the first type, greater than one for split btf.
+    start_id: u32,
+}
+
+impl Btf {
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn load_vmlinux() -> Result<Self> {
+        Self::load("/sys/kernel/btf/vmlinux")
+    }
+
+    /// Loads the btf of a kernel module, which extends the vmlinux btf.
+    pub fn load_module(name: &str, vmlinux: &Btf) -> Result<Self> {
+        let data = std::fs::read(Path::new("/sys/kernel/btf").join(name))?;
+        Self::parse_split(&data, vmlinux)
+    }
+
+    /// Names of the kernel modules providing btf.
+    pub fn modules() -> Result<Vec<String>> {
+        let mut modules = vec![];
+        for entry in std::fs::read_dir("/sys/kernel/btf")? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name != "vmlinux" {
+                modules.push(name);
+            }
+        }
+        modules.sort();
+        Ok(modules)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_inner(data, None)
+    }
+
+    /// Parses split btf, type ids and string offsets continue where the ones
+    /// of `base` end.
+    pub fn parse_split(data: &[u8], base: &Btf) -> Result<Self> {
+        Self::parse_inner(data, Some(base))
+    }
+
+    fn parse_inner(data: &[u8], base: Option<&Btf>) -> Result<Self> {
+        if data.len() < 24 || read_u16(data, 0)? != BTF_MAGIC {
+            bail!("invalid btf magic");
+        }
+        let hdr_len = read_u32(data, 4)? as usize;
+        let type_off = hdr_len + read_u32(data, 8)? as usize;
+        let type_len = read_u32(data, 12)? as usize;
+        let str_off = hdr_len + read_u32(data, 16)? as usize;
+        let str_len = read_u32(data, 20)? as usize;
+        let types = data
+            .get(type_off..type_off + type_len)
+            .ok_or_else(|| anyhow::anyhow!("btf type section out of bounds"))?;
+        let strings = data
+            .get(str_off..str_off + str_len)
+            .ok_or_else(|| anyhow::anyhow!("btf string section out of bounds"))?;
+
+        let (start_id, base_strings) = match base {
+            Some(base) => (base.start_id + base.types.len() as u32, &base.strings[..]),
+            None => (1, &[][..]),
+        };
+        let mut btf = Self {
+            types: vec![],
+            strings: [base_strings, strings].concat(),
+            start_id,
+        };
+        let mut offset = 0;
+        while offset < types.len() {
+            let name_off = read_u32(types, offset)? as usize;
+            let info = read_u32(types, offset + 4)?;
+            let size_or_type = read_u32(types, offset + 8)?;
+            let vlen = (info & 0xffff) as u16;
+            let kind = match BtfKind::from_u32((info >> 24) & 0x1f) {
+                Some(kind) => kind,
+                None => bail!("unknown btf kind {}", (info >> 24) & 0x1f),
+            };
+            btf.types.push(BtfType {
+                id: start_id + btf.types.len() as u32,
+                kind,
+                name: read_str(&btf.strings, name_off)?.to_string(),
+                vlen,
+                size_or_type,
+            });
+            offset += 12 + kind.extra_size(vlen as usize);
+        }
+        Ok(btf)
+    }
+
+    pub fn types(&self) -> &[BtfType] {
+        &self.types
+    }
+
+    pub fn get(&self, id: u32) -> Option<&BtfType> {
+        self.types.get(id.checked_sub(self.start_id)? as usize)
+    }
+
+    pub fn find(&self, kind: BtfKind, name: &str) -> Option<&BtfType> {
+        self.types
+            .iter()
+            .find(|ty| ty.kind == kind && ty.name == name)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_ne_bytes(bytes.try_into()?)),
+        None => bail!("unexpected end of btf"),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_ne_bytes(bytes.try_into()?)),
+        None => bail!("unexpected end of btf"),
+    }
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<&str> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| anyhow::anyhow!("btf string out of bounds"))?;
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(std::str::from_utf8(&bytes[..len])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn btf_type(name_off: u32, kind: u32, vlen: u32, size_or_type: u32) -> Vec<u8> {
+        let mut ty = vec![];
+        ty.extend_from_slice(&name_off.to_ne_bytes());
+        ty.extend_from_slice(&((kind << 24) | vlen).to_ne_bytes());
+        ty.extend_from_slice(&size_or_type.to_ne_bytes());
+        ty
+    }
+
+    #[test]
+    fn parse_btf() {
+        let strings = b"\0int\0main\0";
+        let mut types = btf_type(1, 1, 0, 4);
+        types.extend_from_slice(&0x20u32.to_ne_bytes());
+        types.extend(btf_type(0, 13, 0, 1));
+        types.extend(btf_type(5, 12, 0, 2));
+
+        let mut data = vec![];
+        data.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+        data.extend_from_slice(&[1, 0]);
+        data.extend_from_slice(&24u32.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+        data.extend_from_slice(&(types.len() as u32).to_ne_bytes());
+        data.extend_from_slice(&(types.len() as u32).to_ne_bytes());
+        data.extend_from_slice(&(strings.len() as u32).to_ne_bytes());
+        data.extend(types);
+        data.extend_from_slice(strings);
+
+        let btf = Btf::parse(&data).unwrap();
+        assert_eq!(btf.types().len(), 3);
+        assert_eq!(btf.find(BtfKind::Int, "int").unwrap().id, 1);
+        let main = btf.find(BtfKind::Func, "main").unwrap();
+        assert_eq!(main.id, 3);
+        assert_eq!(main.size_or_type, 2);
+    }
+}
@@ -3,8 +3,13 @@ use anyhow::Result;
 pub fn online_cpu_ids() -> Result<Vec<u32>> {
     let path = "/sys/devices/system/cpu/online";
     let content = std::fs::read_to_string(&path)?;
-    Ok(content
-        .trim()
+    Ok(parse_cpu_list(&content))
+}
+
+/// Parses a cpu list like `0-3,8`, as in `cpu/online` or the `cpumask` of a
+/// pmu.
+pub fn parse_cpu_list(list: &str) -> Vec<u32> {
+    list.trim()
         .split(',')
         .flat_map(|group| {
             let mut iter = group.split('-');
@@ -12,7 +17,7 @@ pub fn online_cpu_ids() -> Result<Vec<u32>> {
             let end = iter.next().map(|i| i.parse().unwrap()).unwrap_or(start);
             start..=end
         })
-        .collect())
+        .collect()
 }
 
 #[cfg(test)]
@@ -23,4 +28,9 @@ mod tests {
     fn read_cpu_ids() {
         assert!(!online_cpu_ids().unwrap().is_empty());
     }
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(parse_cpu_list("0-2,8\n"), vec![0, 1, 2, 8]);
+    }
 }
@@ -15,7 +15,9 @@ pub struct Binary {
 
 pub struct BinaryInfo {
     map: Vec<Binary>,
-    ptracer: Ptracer,
+    pid: u32,
+    /// Only set for processes spawned by [`BinaryInfo::new`].
+    ptracer: Option<Ptracer>,
 }
 
 impl BinaryInfo {
@@ -35,6 +37,18 @@ impl BinaryInfo {
         log::debug!("loading {}", path.display());
         let mut ptracer = Ptracer::spawn(&path, args)?;
         log::debug!("loaded program with pid {}", ptracer.pid());
+        Self::run_to_start(&mut ptracer)?;
+        let pid = i32::from(ptracer.pid()) as u32;
+        Ok(Self {
+            map: Self::binaries(pid)?,
+            pid,
+            ptracer: Some(ptracer),
+        })
+    }
+
+    /// Runs a stopped process to the `_start` of its binary, by which time
+    /// the dynamic linker loaded its libraries.
+    fn run_to_start(ptracer: &mut Ptracer) -> Result<()> {
         let address_map = AddressMap::load_pid(i32::from(ptracer.pid()) as u32)?;
         let load_addr = address_map[0].start_addr;
         let offset = Elf::open(&address_map[0].path)?
@@ -44,7 +58,20 @@ impl BinaryInfo {
         ptracer.enable_breakpoint(load_addr + offset)?;
         ptracer.cont(ContinueMode::Default)?;
         ptracer.remove_breakpoint(load_addr + offset)?;
-        let address_map = AddressMap::load_pid(i32::from(ptracer.pid()) as u32)?;
+        Ok(())
+    }
+
+    /// Loads the binaries of an already running process.
+    pub fn attach(pid: u32) -> Result<Self> {
+        Ok(Self {
+            map: Self::binaries(pid)?,
+            pid,
+            ptracer: None,
+        })
+    }
+
+    fn binaries(pid: u32) -> Result<Vec<Binary>> {
+        let address_map = AddressMap::load_pid(pid)?;
         let mut map = vec![];
         for entry in address_map.iter() {
             let elf = Elf::open(&entry.path)?;
@@ -56,7 +83,48 @@ impl BinaryInfo {
                 dwarf,
             });
         }
-        Ok(Self { map, ptracer })
+        Ok(map)
+    }
+
+    /// Adds a mapping of `path` at `start..end`, extending the module of
+    /// `path` if it is already mapped. Returns whether the modules changed,
+    /// files that aren't elf modules are ignored.
+    pub fn map_module(&mut self, path: &Path, start: usize, end: usize) -> Result<bool> {
+        if let Some(binary) = self.map.iter_mut().find(|binary| binary.elf.path() == path) {
+            if start >= binary.start_addr && end <= binary.end_addr {
+                return Ok(false);
+            }
+            binary.start_addr = binary.start_addr.min(start);
+            binary.end_addr = binary.end_addr.max(end);
+        } else {
+            let elf = match Elf::open(path) {
+                Ok(elf) => elf,
+                Err(err) => {
+                    log::debug!("ignoring mapping of {}: {}", path.display(), err);
+                    return Ok(false);
+                }
+            };
+            let dwarf = elf.dwarf().ok();
+            self.map.push(Binary {
+                start_addr: start,
+                end_addr: end,
+                elf,
+                dwarf,
+            });
+        }
+        self.map.sort_unstable_by_key(|binary| binary.start_addr);
+        Ok(true)
+    }
+
+    /// Removes the modules inside the unmapped range `start..end`, returning
+    /// whether there were any. The traced binary is never removed.
+    pub fn unmap_modules(&mut self, start: usize, end: usize) -> bool {
+        let path = self.path().to_path_buf();
+        let len = self.map.len();
+        self.map.retain(|binary| {
+            binary.elf.path() == path || binary.start_addr < start || binary.end_addr > end
+        });
+        self.map.len() != len
     }
 
     pub fn path(&self) -> &Path {
@@ -75,46 +143,65 @@ impl BinaryInfo {
         self.map[0].dwarf.as_ref()
     }
 
-    pub fn ptracer(&self) -> &Ptracer {
-        &self.ptracer
+    pub fn ptracer(&self) -> Option<&Ptracer> {
+        self.ptracer.as_ref()
     }
 
     pub fn pid(&self) -> u32 {
-        i32::from(self.ptracer.pid()) as _
+        self.pid
     }
 
+    /// Continues a spawned process, does nothing for attached ones.
     pub fn cont(&mut self) -> Result<()> {
-        self.ptracer.cont(ContinueMode::Default)?;
+        if let Some(ptracer) = self.ptracer.as_mut() {
+            ptracer.cont(ContinueMode::Default)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the process runs another binary than the one its modules
+    /// were loaded for, false once it exited.
+    ///
+    /// A spawned process stops after an exec, see [`BinaryInfo::reload`].
+    pub fn execed(&self) -> bool {
+        match std::fs::read_link(format!("/proc/{}/exe", self.pid)) {
+            Ok(exe) => exe != self.path(),
+            Err(_) => false,
+        }
+    }
+
+    /// Loads the binaries of the process again after an exec. A spawned
+    /// process is run to the `_start` of the new binary first.
+    pub fn reload(&mut self) -> Result<()> {
+        if let Some(ptracer) = self.ptracer.as_mut() {
+            Self::run_to_start(ptracer)?;
+        }
+        self.map = Self::binaries(self.pid)?;
         Ok(())
     }
 
     pub fn binary(&self, ip: usize) -> Option<&Binary> {
+        self.module(ip).map(|i| &self.map[i])
+    }
+
+    /// Index of the binary containing `ip`.
+    pub fn module(&self, ip: usize) -> Option<usize> {
         let i = match self.map.binary_search_by_key(&ip, |entry| entry.start_addr) {
             Ok(i) => i,
             Err(0) => 0,
             Err(i) => i - 1,
         };
-        let entry = &self.map[i];
+        let entry = self.map.get(i)?;
         if ip < entry.start_addr || ip > entry.end_addr {
             None
         } else {
-            Some(entry)
+            Some(i)
         }
     }
 
     pub fn resolve_symbol(&self, ip: usize) -> Result<Option<String>> {
         if let Some(entry) = self.binary(ip) {
-            let offset = ip - entry.start_addr;
-            if let Some(dwarf) = entry.dwarf.as_ref() {
-                if let Some(frame) = dwarf.find_frames(offset)?.next()? {
-                    if let Some(function) = frame.function {
-                        return Ok(Some(function.demangle()?.to_string()));
-                    }
-                }
-            }
-            if let Some(symbol) = entry.elf.resolve_address(offset)? {
-                return Ok(Some(symbol.to_owned()));
-            }
+            return resolve_symbol(&entry.elf, entry.dwarf.as_ref(), ip - entry.start_addr);
         }
         Ok(None)
     }
@@ -160,6 +247,19 @@ impl BinaryInfo {
     }
 }
 
+/// Function at `offset` into `elf`, from the debug info if there is any or
+/// from the symbol table.
+pub fn resolve_symbol(elf: &Elf, dwarf: Option<&Dwarf>, offset: usize) -> Result<Option<String>> {
+    if let Some(dwarf) = dwarf {
+        if let Some(frame) = dwarf.find_frames(offset)?.next()? {
+            if let Some(function) = frame.function {
+                return Ok(Some(function.demangle()?.to_string()));
+            }
+        }
+    }
+    Ok(elf.resolve_address(offset)?.map(|symbol| symbol.to_owned()))
+}
+
 impl std::ops::Deref for BinaryInfo {
     type Target = [Binary];
 
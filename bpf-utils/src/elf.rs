@@ -1,6 +1,6 @@
 use addr2line::{gimli, object, Context, FrameIter, Location};
 use anyhow::Result;
-use ehframe::UnwindTable;
+use ehframe::{UnwindTable, Unwinder};
 use memmap::Mmap;
 use object::elf::FileHeader64;
 use object::read::elf::ElfFile;
@@ -10,6 +10,10 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use thiserror::Error;
 
+/// Directories searched for separate debug info before the default
+/// locations, separated by `:`.
+pub const DEBUG_PATH_ENV: &str = "BPF_DEBUG_PATH";
+
 #[derive(Debug, Error)]
 #[error("Offset `{1}` out of range of `{1}`")]
 pub struct OffsetOutOfRange(String, usize);
@@ -46,10 +50,44 @@ impl Elf {
         if self.0.obj.has_debug_symbols() {
             return Dwarf::new(self.clone());
         }
+        if let Some(debug_path) = self.find_debug_file() {
+            return Dwarf::open(&debug_path);
+        }
         let debug_path = locate_dwarf::locate_debug_symbols(&self.0.obj, self.path())?;
         Dwarf::open(&debug_path)
     }
 
+    /// Looks for `.build-id/<xx>/<rest>.debug` and `<name>.debug` in the
+    /// directories of `BPF_DEBUG_PATH`.
+    fn find_debug_file(&self) -> Option<PathBuf> {
+        let dirs = std::env::var_os(DEBUG_PATH_ENV)?;
+        let name = self.path().file_name()?.to_string_lossy().into_owned();
+        let build_id = self
+            .0
+            .obj
+            .build_id()
+            .ok()
+            .flatten()
+            .filter(|id| id.len() == 20)
+            .map(|id| BuildId::new(id).to_string());
+        for dir in std::env::split_paths(&dirs) {
+            let mut candidates = vec![];
+            if let Some(id) = &build_id {
+                candidates.push(
+                    dir.join(".build-id")
+                        .join(&id[..2])
+                        .join(format!("{}.debug", &id[2..])),
+                );
+            }
+            candidates.push(dir.join(format!("{}.debug", name)));
+            if let Some(path) = candidates.into_iter().find(|path| path.is_file()) {
+                log::debug!("found debug info of {} at {}", name, path.display());
+                return Some(path);
+            }
+        }
+        None
+    }
+
     pub fn build_id(&self) -> Result<BuildId> {
         Ok(BuildId::new(self.0.obj.build_id()?.unwrap()))
     }
@@ -58,6 +96,11 @@ impl Elf {
         UnwindTable::parse(&self.0.obj)
     }
 
+    /// Reference unwinder of the module, see [`Unwinder`].
+    pub fn unwinder(&self) -> Result<Unwinder> {
+        Unwinder::parse(&self.0.obj)
+    }
+
     pub fn resolve_symbol(&self, symbol: &str, offset: usize) -> Result<Option<usize>> {
         for sym in self.0.obj.symbols() {
             if sym.name() == Ok(symbol) {
@@ -1,4 +1,5 @@
 use anyhow::{bail, Result};
+use std::path::Path;
 use std::process::Command;
 
 #[derive(Debug)]
@@ -17,6 +18,7 @@ pub enum FieldFormat {
 #[derive(Debug, Default)]
 pub struct EventFormat {
     fields: Vec<(String, FieldFormat)>,
+    offsets: Vec<usize>,
 }
 
 impl EventFormat {
@@ -24,13 +26,33 @@ impl EventFormat {
         self.fields.iter()
     }
 
-    fn add_field(&mut self, name: String, format: FieldFormat) {
+    /// Offset of the field `name` in the record of the event.
+    pub fn offset(&self, name: &str) -> Option<(usize, &FieldFormat)> {
+        let i = self.fields.iter().position(|field| field.0 == name)?;
+        Some((self.offsets[i], &self.fields[i].1))
+    }
+
+    fn add_field(&mut self, name: String, format: FieldFormat, offset: usize) {
         self.fields.push((name, format));
+        self.offsets.push(offset);
     }
 }
 
+const EVENTS_DIR: &str = "/sys/kernel/debug/tracing/events";
+
+/// Whether the tracepoint exists, false without access to tracefs.
+pub fn event_exists(category: &str, name: &str) -> bool {
+    Path::new(EVENTS_DIR).join(category).join(name).exists()
+}
+
+/// Id of the tracepoint, the `common_type` of its records.
+pub fn event_id(category: &str, name: &str) -> Result<u32> {
+    let path = format!("{}/{}/{}/id", EVENTS_DIR, category, name);
+    Ok(std::fs::read_to_string(path)?.trim().parse()?)
+}
+
 pub fn event_format(category: &str, name: &str) -> Result<EventFormat> {
-    let events_dir = "/sys/kernel/debug/tracing/events";
+    let events_dir = EVENTS_DIR;
     let output = Command::new("sudo")
         .arg("cat")
         .arg(format!("{}/{}/{}/format", events_dir, category, name))
@@ -49,7 +71,7 @@ pub fn event_format(category: &str, name: &str) -> Result<EventFormat> {
         }
         let mut cols = line.split('\t').skip(1);
         let (name, len) = parse_decl(cols.next())?;
-        cols.next();
+        let offset: usize = parse_size(cols.next())?;
         let size: usize = parse_size(cols.next())?;
         let signed: bool = parse_signed(cols.next())?;
         let format = if let Some(len) = len {
@@ -61,7 +83,7 @@ pub fn event_format(category: &str, name: &str) -> Result<EventFormat> {
         } else {
             FieldFormat::Simple { size, signed }
         };
-        event.add_field(name.to_owned(), format);
+        event.add_field(name.to_owned(), format, offset);
     }
     Ok(event)
 }
@@ -0,0 +1,98 @@
+//! Passing file descriptors over unix sockets with `SCM_RIGHTS`.
+use std::io::{Error, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Maximum number of fds received with one message.
+pub const MAX_FDS: usize = 16;
+
+/// Sends `data` together with duplicates of `fds`.
+pub fn send(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<()> {
+    assert!(!data.is_empty() && fds.len() <= MAX_FDS);
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let fds_len = std::mem::size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+    let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives a message into `buf`, returning its length and the received fds.
+pub fn recv(sock: &UnixStream, buf: &mut [u8]) -> Result<(usize, Vec<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) };
+    let mut control = vec![0u8; space as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut _;
+    msg.msg_controllen = control.len() as _;
+    let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut fds = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(data.add(i).read_unaligned());
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((len as usize, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn pass_fd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut r, w) = UnixStream::pair().unwrap();
+        send(&a, b"pipe", &[w.as_raw_fd()]).unwrap();
+        let mut buf = [0; 16];
+        let (len, fds) = recv(&b, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pipe");
+        assert_eq!(fds.len(), 1);
+        let mut passed =
+            unsafe { <UnixStream as std::os::unix::io::FromRawFd>::from_raw_fd(fds[0]) };
+        passed.write_all(b"hi").unwrap();
+        let mut out = [0; 2];
+        r.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"hi");
+    }
+}
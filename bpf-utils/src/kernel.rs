@@ -0,0 +1,61 @@
+//! Version of the running kernel.
+//!
+//! Kernels before 5.0 only load kprobes whose version matches their
+//! `LINUX_VERSION_CODE`, which is computed from the release like the
+//! `KERNEL_VERSION` macro of the kernel headers.
+use anyhow::{anyhow, Result};
+use std::ffi::CStr;
+
+/// Version code of `major.minor.patch`, the patch level saturates at 255.
+pub fn version_code(major: u32, minor: u32, patch: u32) -> u32 {
+    (major << 16) + (minor << 8) + patch.min(255)
+}
+
+/// Version code of a release like `5.4.0-42-generic`.
+pub fn parse_release(release: &str) -> Option<u32> {
+    let mut parts = release.splitn(3, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts
+        .next()
+        .map(|patch| {
+            let len = patch
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(patch.len());
+            patch[..len].parse().unwrap_or(0)
+        })
+        .unwrap_or(0);
+    Some(version_code(major, minor, patch))
+}
+
+/// `LINUX_VERSION_CODE` of the running kernel.
+///
+/// Ubuntu kernels report the abi number as the patch level, the upstream
+/// version is the last field of `/proc/version_signature`.
+pub fn linux_version_code() -> Result<u32> {
+    if let Ok(signature) = std::fs::read_to_string("/proc/version_signature") {
+        if let Some(version) = signature.split_whitespace().last().and_then(parse_release) {
+            return Ok(version);
+        }
+    }
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy();
+    parse_release(&release).ok_or_else(|| anyhow!("unexpected kernel release {}", release))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases() {
+        assert_eq!(parse_release("5.4.0-42-generic"), Some(0x05_04_00));
+        assert_eq!(parse_release("4.19.300"), Some(0x04_13_ff));
+        assert_eq!(parse_release("6.1"), Some(0x06_01_00));
+        assert_eq!(parse_release("5.10.1rc2"), Some(0x05_0a_01));
+        assert_eq!(parse_release("linux"), None);
+    }
+}
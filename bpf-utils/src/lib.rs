@@ -1,9 +1,13 @@
+pub mod btf;
 pub mod cpu;
 pub mod dylibs;
 pub mod elf;
 pub mod event;
+pub mod fdpass;
 pub mod kallsyms;
+pub mod kernel;
 pub mod maps;
 pub mod rlimit;
+pub mod sys;
 pub mod syscall;
 pub use ehframe;
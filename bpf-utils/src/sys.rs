@@ -0,0 +1,605 @@
+//! Raw `bpf(2)` commands that libbpf-rs doesn't expose.
+use std::io::{Error, Result};
+use std::os::unix::io::RawFd;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_GET_FD_BY_ID: libc::c_long = 13;
+const BPF_MAP_GET_FD_BY_ID: libc::c_long = 14;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+const BPF_BTF_GET_FD_BY_ID: libc::c_long = 19;
+const BPF_MAP_FREEZE: libc::c_long = 22;
+const BPF_BTF_GET_NEXT_ID: libc::c_long = 23;
+const BPF_MAP_LOOKUP_BATCH: libc::c_long = 24;
+const BPF_ENABLE_STATS: libc::c_long = 32;
+
+const BPF_STATS_RUN_TIME: u32 = 0;
+
+/// Open flags for [`map_get_fd_by_id`].
+pub const BPF_F_RDONLY: u32 = 1 << 3;
+pub const BPF_F_WRONLY: u32 = 1 << 4;
+
+/// Size of the nul terminated names of maps and programs.
+pub const BPF_OBJ_NAME_LEN: usize = 16;
+
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_CGROUP_ARRAY: u32 = 8;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+const BPF_MAP_TYPE_ARRAY_OF_MAPS: u32 = 12;
+
+/// Kernel internal errno of unsupported operations.
+const ENOTSUPP: i32 = 524;
+
+/// Entries counted by one batch lookup of [`map_len`].
+const LEN_BATCH: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct GetInfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct GetFdByIdAttr {
+    id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MapFdAttr {
+    map_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MapBatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct EnableStatsAttr {
+    ty: u32,
+}
+
+/// `BPF_PROG_LOAD` attributes up to `fd_array` (linux 5.14).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; BPF_OBJ_NAME_LEN],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+    prog_btf_fd: u32,
+    func_info_rec_size: u32,
+    func_info: u64,
+    func_info_cnt: u32,
+    line_info_rec_size: u32,
+    line_info: u64,
+    line_info_cnt: u32,
+    attach_btf_id: u32,
+    attach_prog_fd: u32,
+    core_relo_cnt: u32,
+    fd_array: u64,
+}
+
+/// `struct bpf_btf_info`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct BtfInfo {
+    btf: u64,
+    btf_size: u32,
+    id: u32,
+    name: u64,
+    name_len: u32,
+    kernel_btf: u32,
+}
+
+/// `struct bpf_prog_info` up to `verified_insns`.
+///
+/// Fields unknown to the running kernel are left zeroed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProgInfo {
+    pub ty: u32,
+    pub id: u32,
+    pub tag: [u8; 8],
+    pub jited_prog_len: u32,
+    pub xlated_prog_len: u32,
+    pub jited_prog_insns: u64,
+    pub xlated_prog_insns: u64,
+    pub load_time: u64,
+    pub created_by_uid: u32,
+    pub nr_map_ids: u32,
+    pub map_ids: u64,
+    pub name: [u8; BPF_OBJ_NAME_LEN],
+    pub ifindex: u32,
+    pub gpl_compatible: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub nr_jited_ksyms: u32,
+    pub nr_jited_func_lens: u32,
+    pub jited_ksyms: u64,
+    pub jited_func_lens: u64,
+    pub btf_id: u32,
+    pub func_info_rec_size: u32,
+    pub func_info: u64,
+    pub nr_func_info: u32,
+    pub nr_line_info: u32,
+    pub line_info: u64,
+    pub jited_line_info: u64,
+    pub nr_jited_line_info: u32,
+    pub line_info_rec_size: u32,
+    pub jited_line_info_rec_size: u32,
+    pub nr_prog_tags: u32,
+    pub prog_tags: u64,
+    pub run_time_ns: u64,
+    pub run_cnt: u64,
+    pub recursion_misses: u64,
+    pub verified_insns: u32,
+    _pad: u32,
+}
+
+impl ProgInfo {
+    pub fn name(&self) -> &str {
+        obj_name(&self.name)
+    }
+}
+
+/// `struct bpf_map_info` up to `btf_value_type_id`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapInfo {
+    pub ty: u32,
+    pub id: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub name: [u8; BPF_OBJ_NAME_LEN],
+    pub ifindex: u32,
+    pub btf_vmlinux_value_type_id: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub btf_id: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+    _pad: u32,
+}
+
+impl MapInfo {
+    pub fn name(&self) -> &str {
+        obj_name(&self.name)
+    }
+}
+
+/// Name of a map or program as the kernel stores it, libbpf truncates the
+/// symbol names of the object.
+pub fn kernel_name(name: &str) -> &str {
+    let mut len = name.len().min(BPF_OBJ_NAME_LEN - 1);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    &name[..len]
+}
+
+fn obj_name(name: &[u8]) -> &str {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    std::str::from_utf8(&name[..len]).unwrap_or_default()
+}
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &mut T) -> Result<i32> {
+    let ret =
+        unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, std::mem::size_of::<T>()) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(ret as _)
+}
+
+fn obj_get_info<T>(fd: RawFd, info: &mut T) -> Result<()> {
+    let mut attr = GetInfoAttr {
+        bpf_fd: fd as _,
+        info_len: std::mem::size_of::<T>() as _,
+        info: info as *mut T as u64,
+    };
+    sys_bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr)?;
+    Ok(())
+}
+
+pub fn prog_info(fd: RawFd) -> Result<ProgInfo> {
+    let mut info = ProgInfo::default();
+    obj_get_info(fd, &mut info)?;
+    Ok(info)
+}
+
+pub fn map_info(fd: RawFd) -> Result<MapInfo> {
+    let mut info = MapInfo::default();
+    obj_get_info(fd, &mut info)?;
+    Ok(info)
+}
+
+/// Returns the ids of the maps used by a program.
+pub fn prog_map_ids(fd: RawFd) -> Result<Vec<u32>> {
+    let len = prog_info(fd)?.nr_map_ids;
+    let mut ids = vec![0u32; len as usize];
+    let mut info = ProgInfo {
+        nr_map_ids: len,
+        map_ids: ids.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    obj_get_info(fd, &mut info)?;
+    ids.truncate(info.nr_map_ids as usize);
+    Ok(ids)
+}
+
+/// Returns the program after it was rewritten by the verifier.
+pub fn prog_xlated_insns(fd: RawFd) -> Result<Vec<u8>> {
+    let len = prog_info(fd)?.xlated_prog_len;
+    let mut insns = vec![0u8; len as usize];
+    let mut info = ProgInfo {
+        xlated_prog_len: len,
+        xlated_prog_insns: insns.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    obj_get_info(fd, &mut info)?;
+    insns.truncate(info.xlated_prog_len as usize);
+    Ok(insns)
+}
+
+/// Returns the native code generated by the jit.
+pub fn prog_jited_insns(fd: RawFd) -> Result<Vec<u8>> {
+    let len = prog_info(fd)?.jited_prog_len;
+    let mut insns = vec![0u8; len as usize];
+    let mut info = ProgInfo {
+        jited_prog_len: len,
+        jited_prog_insns: insns.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    obj_get_info(fd, &mut info)?;
+    insns.truncate(info.jited_prog_len as usize);
+    Ok(insns)
+}
+
+/// Opens a loaded program by id. The caller owns the returned fd.
+pub fn prog_get_fd_by_id(id: u32) -> Result<RawFd> {
+    let mut attr = GetFdByIdAttr {
+        id,
+        ..Default::default()
+    };
+    sys_bpf(BPF_PROG_GET_FD_BY_ID, &mut attr)
+}
+
+/// Opens a map by id with the access restricted by `open_flags`. The caller
+/// owns the returned fd.
+pub fn map_get_fd_by_id(id: u32, open_flags: u32) -> Result<RawFd> {
+    let mut attr = GetFdByIdAttr {
+        id,
+        open_flags,
+        ..Default::default()
+    };
+    sys_bpf(BPF_MAP_GET_FD_BY_ID, &mut attr)
+}
+
+/// Ids of the loaded btf objects, including the ones of vmlinux and the
+/// modules.
+pub fn btf_ids() -> Result<Vec<u32>> {
+    let mut ids = vec![];
+    let mut attr = GetFdByIdAttr::default();
+    loop {
+        match sys_bpf(BPF_BTF_GET_NEXT_ID, &mut attr) {
+            Ok(_) => {
+                ids.push(attr.next_id);
+                attr.id = attr.next_id;
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => return Ok(ids),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Opens a btf object by id. The caller owns the returned fd.
+pub fn btf_get_fd_by_id(id: u32) -> Result<RawFd> {
+    let mut attr = GetFdByIdAttr {
+        id,
+        ..Default::default()
+    };
+    sys_bpf(BPF_BTF_GET_FD_BY_ID, &mut attr)
+}
+
+/// Name and raw data of a kernel btf object, `None` for btf loaded from user
+/// space. Kernels before 5.11 don't name their btf objects.
+pub fn kernel_btf(fd: RawFd) -> Result<Option<(String, Vec<u8>)>> {
+    let mut info = BtfInfo::default();
+    obj_get_info(fd, &mut info)?;
+    let mut data = vec![0u8; info.btf_size as usize];
+    // MODULE_NAME_LEN
+    let mut name = [0u8; 64];
+    let mut info = BtfInfo {
+        btf: data.as_mut_ptr() as u64,
+        btf_size: data.len() as _,
+        name: name.as_mut_ptr() as u64,
+        name_len: name.len() as _,
+        ..Default::default()
+    };
+    obj_get_info(fd, &mut info)?;
+    if info.kernel_btf == 0 {
+        return Ok(None);
+    }
+    data.truncate(info.btf_size as usize);
+    Ok(Some((obj_name(&name).to_string(), data)))
+}
+
+/// Loads a program, `fd_array` holds the btf objects of the modules whose
+/// kfuncs the program calls, which libbpf can't pass. The verifier log is
+/// part of the error.
+pub fn prog_load(
+    prog_type: u32,
+    expected_attach_type: u32,
+    name: &str,
+    insns: &[u8],
+    license: &std::ffi::CStr,
+    kern_version: u32,
+    fd_array: &[RawFd],
+) -> Result<RawFd> {
+    let mut attr = ProgLoadAttr {
+        prog_type,
+        expected_attach_type,
+        insn_cnt: (insns.len() / 8) as _,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        kern_version,
+        fd_array: fd_array.as_ptr() as u64,
+        ..Default::default()
+    };
+    let name = kernel_name(name).as_bytes();
+    attr.prog_name[..name.len()].copy_from_slice(name);
+    let err = match sys_bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => return Ok(fd),
+        Err(err) => err,
+    };
+    // loaded again with the verifier log like libbpf does.
+    let mut log = vec![0u8; 1 << 20];
+    attr.log_level = 1;
+    attr.log_size = log.len() as _;
+    attr.log_buf = log.as_mut_ptr() as u64;
+    match sys_bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(fd),
+        Err(_) => {
+            let len = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+            let log = String::from_utf8_lossy(&log[..len]);
+            Err(Error::new(err.kind(), format!("{}\n{}", err, log)))
+        }
+    }
+}
+
+/// Makes the map read-only from user space.
+///
+/// Updates from bpf programs are still allowed unless the map was created
+/// with `BPF_F_RDONLY_PROG`. Freezing can't be undone.
+pub fn map_freeze(fd: RawFd) -> Result<()> {
+    let mut attr = MapFdAttr { map_fd: fd as _ };
+    sys_bpf(BPF_MAP_FREEZE, &mut attr)?;
+    Ok(())
+}
+
+/// Creates an unnamed map, used to probe the map types the kernel supports.
+pub fn map_create(ty: u32, key_size: u32, value_size: u32, max_entries: u32) -> Result<RawFd> {
+    let mut attr = MapCreateAttr {
+        map_type: ty,
+        key_size,
+        value_size,
+        max_entries,
+        map_flags: 0,
+    };
+    sys_bpf(BPF_MAP_CREATE, &mut attr)
+}
+
+/// Looks up `key`, per cpu maps fill `value` with one entry per possible cpu,
+/// each rounded up to 8 bytes. Returns `false` if there is no such entry.
+pub fn map_lookup_elem(fd: RawFd, key: &[u8], value: &mut [u8]) -> Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as _,
+        key: key.as_ptr() as u64,
+        value: value.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_LOOKUP_ELEM, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn map_update_elem(fd: RawFd, key: &[u8], value: &[u8]) -> Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as _,
+        key: key.as_ptr() as u64,
+        value: value.as_ptr() as u64,
+        ..Default::default()
+    };
+    sys_bpf(BPF_MAP_UPDATE_ELEM, &mut attr)?;
+    Ok(())
+}
+
+/// Writes the key following `key` to `next_key`, the first key if `key` is
+/// `None`. Returns `false` after the last key.
+pub fn map_get_next_key(fd: RawFd, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as _,
+        key: key.map(|key| key.as_ptr() as u64).unwrap_or_default(),
+        // `next_key` shares the union field with `value`.
+        value: next_key.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Looks up the entries following the position `in_batch`, from the start
+/// if it's `None`, writing up to `count` keys and values and the position
+/// after them to `out_batch`. The position of an array is the last key read.
+/// Returns the number of entries read and whether it read the last entry.
+///
+/// Needs linux 5.6, older kernels fail with `EINVAL`.
+pub fn map_lookup_batch(
+    fd: RawFd,
+    in_batch: Option<&[u8]>,
+    out_batch: &mut [u8],
+    keys: &mut [u8],
+    values: &mut [u8],
+    count: u32,
+) -> Result<(u32, bool)> {
+    let mut attr = MapBatchAttr {
+        in_batch: in_batch
+            .map(|batch| batch.as_ptr() as u64)
+            .unwrap_or_default(),
+        out_batch: out_batch.as_mut_ptr() as u64,
+        keys: keys.as_mut_ptr() as u64,
+        values: values.as_mut_ptr() as u64,
+        count,
+        map_fd: fd as _,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_LOOKUP_BATCH, &mut attr) {
+        Ok(_) => Ok((attr.count, false)),
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok((attr.count, true)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Counts the entries of a map. Arrays always have `max_entries` entries,
+/// other maps are counted by looking up their entries in batches, which
+/// copies the keys and values of a batch, or by iterating over their keys on
+/// kernels without batch lookups. Entries added or removed concurrently may
+/// be missed or counted twice.
+pub fn map_len(fd: RawFd) -> Result<usize> {
+    let info = map_info(fd)?;
+    match info.ty {
+        BPF_MAP_TYPE_ARRAY
+        | BPF_MAP_TYPE_PROG_ARRAY
+        | BPF_MAP_TYPE_PERF_EVENT_ARRAY
+        | BPF_MAP_TYPE_PERCPU_ARRAY
+        | BPF_MAP_TYPE_CGROUP_ARRAY
+        | BPF_MAP_TYPE_ARRAY_OF_MAPS => return Ok(info.max_entries as usize),
+        _ => {}
+    }
+    match map_len_batched(fd, &info) {
+        // kernels before 5.6 don't know the command, some map types don't
+        // support it.
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(ENOTSUPP)) => {
+            map_len_by_key(fd, &info)
+        }
+        len => len,
+    }
+}
+
+fn map_len_batched(fd: RawFd, info: &MapInfo) -> Result<usize> {
+    let value_size = match info.ty {
+        // per cpu values are rounded up to 8 bytes for each possible cpu.
+        BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_LRU_PERCPU_HASH => {
+            ((info.value_size as usize + 7) & !7) * possible_cpus()?
+        }
+        _ => info.value_size as usize,
+    };
+    let mut keys = vec![0u8; LEN_BATCH * info.key_size as usize];
+    let mut values = vec![0u8; LEN_BATCH * value_size];
+    // the position of a hash map is a bucket index.
+    let mut in_batch: Option<[u8; 4]> = None;
+    let mut out_batch = [0u8; 4];
+    let mut len = 0;
+    loop {
+        let (count, done) = map_lookup_batch(
+            fd,
+            in_batch.as_ref().map(|batch| &batch[..]),
+            &mut out_batch,
+            &mut keys,
+            &mut values,
+            LEN_BATCH as u32,
+        )?;
+        len += count as usize;
+        if done {
+            return Ok(len);
+        }
+        in_batch = Some(out_batch);
+    }
+}
+
+fn map_len_by_key(fd: RawFd, info: &MapInfo) -> Result<usize> {
+    let mut key = vec![0u8; info.key_size as usize];
+    let mut next_key = vec![0u8; info.key_size as usize];
+    if !map_get_next_key(fd, None, &mut key)? {
+        return Ok(0);
+    }
+    let mut len = 1;
+    while map_get_next_key(fd, Some(&key), &mut next_key)? {
+        std::mem::swap(&mut key, &mut next_key);
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn possible_cpus() -> Result<usize> {
+    let cpus = std::fs::read_to_string("/sys/devices/system/cpu/possible")?;
+    Ok(crate::cpu::parse_cpu_list(&cpus).len().max(1))
+}
+
+/// Enables collecting the run time and count of all programs, which is
+/// reported by [`prog_info`]. Stats are collected until the returned fd is
+/// closed.
+pub fn enable_stats() -> Result<RawFd> {
+    let mut attr = EnableStatsAttr {
+        ty: BPF_STATS_RUN_TIME,
+    };
+    sys_bpf(BPF_ENABLE_STATS, &mut attr)
+}
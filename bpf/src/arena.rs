@@ -0,0 +1,76 @@
+//! User space view of arena maps.
+use anyhow::{bail, Result};
+use std::os::unix::io::RawFd;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Shared mapping of an arena.
+///
+/// The kernel fixes the address of an arena on the first mapping, so each
+/// arena should be mapped once.
+pub struct BpfArena {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl BpfArena {
+    pub(crate) fn map(fd: RawFd) -> Result<Self> {
+        let info = bpf_utils::sys::map_info(fd)?;
+        let len = info.max_entries as usize * PAGE_SIZE;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            bail!("mmap arena: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Resolves the lower 32 bits of an arena address as stored by an
+    /// `ArenaPtr` in the probe.
+    pub fn resolve<T>(&self, addr: u32) -> Option<*mut T> {
+        if addr == 0 {
+            return None;
+        }
+        let start = self.ptr as usize;
+        let ptr = (start & !0xffff_ffff) | addr as usize;
+        if ptr < start || ptr + std::mem::size_of::<T>() > start + self.len {
+            return None;
+        }
+        Some(ptr as *mut T)
+    }
+
+    /// Reads the `T` at the arena address `addr`.
+    pub fn get<T: Copy>(&self, addr: u32) -> Option<T> {
+        self.resolve::<T>(addr)
+            .map(|ptr| unsafe { std::ptr::read_volatile(ptr) })
+    }
+}
+
+impl Drop for BpfArena {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut _, self.len) };
+    }
+}
@@ -0,0 +1,254 @@
+//! Audit log of the kernel facing operations performed by the loader.
+//!
+//! libbpf issues the `bpf` syscalls for creating maps and loading programs
+//! itself, so these events are reconstructed from the kernel's view of the
+//! loaded object right after the load succeeded. The kernel hands out ids in
+//! creation order, the maps are logged first sorted by id, followed by the
+//! programs. A failed load, attach, freeze or map update is logged as
+//! `Failed` in place of the event.
+use anyhow::Result;
+use bpf_utils::sys;
+use libbpf_rs::Object;
+use std::os::unix::io::RawFd;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuditEvent {
+    MapCreate {
+        id: u32,
+        name: String,
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+        flags: u32,
+    },
+    ProgLoad {
+        id: u32,
+        name: String,
+        prog_type: u32,
+        tag: [u8; 8],
+        insns: u32,
+        map_ids: Vec<u32>,
+    },
+    Attach {
+        prog_id: u32,
+        probe: String,
+        perf_event_fds: Vec<u32>,
+    },
+    MapFreeze {
+        id: u32,
+        name: String,
+    },
+    MapUpdate {
+        id: u32,
+        name: String,
+    },
+    Failed {
+        op: &'static str,
+        target: String,
+        error: String,
+    },
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MapCreate {
+                id,
+                name,
+                map_type,
+                key_size,
+                value_size,
+                max_entries,
+                flags,
+            } => write!(
+                f,
+                "op=map_create id={} name={} type={} key_size={} value_size={} max_entries={} flags=0x{:x}",
+                id, name, map_type, key_size, value_size, max_entries, flags
+            ),
+            Self::ProgLoad {
+                id,
+                name,
+                prog_type,
+                tag,
+                insns,
+                map_ids,
+            } => {
+                write!(
+                    f,
+                    "op=prog_load id={} name={} type={} insns={} tag=",
+                    id, name, prog_type, insns
+                )?;
+                for byte in tag {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, " map_ids={:?}", map_ids)
+            }
+            Self::Attach {
+                prog_id,
+                probe,
+                perf_event_fds,
+            } => write!(
+                f,
+                "op=attach prog_id={} probe={} perf_event_fds={:?}",
+                prog_id, probe, perf_event_fds
+            ),
+            Self::MapFreeze { id, name } => write!(f, "op=map_freeze id={} name={}", id, name),
+            Self::MapUpdate { id, name } => write!(f, "op=map_update id={} name={}", id, name),
+            Self::Failed { op, target, error } => {
+                write!(f, "op={} target={} failed: {}", op, target, error)
+            }
+        }
+    }
+}
+
+/// Receives every audit event.
+pub type AuditHook = Box<dyn Fn(&AuditEvent)>;
+
+/// Hook writing the events to the `bpf::audit` log target, failures as
+/// warnings and the frequent map updates at the debug level.
+pub fn log_hook() -> AuditHook {
+    Box::new(|event| match event {
+        AuditEvent::Failed { .. } => log::warn!(target: "bpf::audit", "{}", event),
+        AuditEvent::MapUpdate { .. } => log::debug!(target: "bpf::audit", "{}", event),
+        _ => log::info!(target: "bpf::audit", "{}", event),
+    })
+}
+
+/// Reports the outcome of `op` on `target`, `event` is only computed if it
+/// succeeded.
+pub(crate) fn record<T, E: std::fmt::Display>(
+    audit: Option<&AuditHook>,
+    op: &'static str,
+    target: &str,
+    res: std::result::Result<T, E>,
+    event: impl FnOnce(&T) -> Result<AuditEvent>,
+) -> std::result::Result<T, E> {
+    match (audit, &res) {
+        (Some(audit), Ok(value)) => match event(value) {
+            Ok(event) => audit(&event),
+            Err(err) => log::warn!(target: "bpf::audit", "op={} target={}: {}", op, target, err),
+        },
+        (Some(_), Err(err)) => failed(audit, op, target, err),
+        (None, _) => {}
+    }
+    res
+}
+
+/// Reports that `op` on `target` failed.
+pub(crate) fn failed(
+    audit: Option<&AuditHook>,
+    op: &'static str,
+    target: &str,
+    err: &dyn std::fmt::Display,
+) {
+    if let Some(audit) = audit {
+        audit(&AuditEvent::Failed {
+            op,
+            target: target.to_string(),
+            error: err.to_string(),
+        });
+    }
+}
+
+/// Reports the maps and programs created by loading an object, in the order
+/// the kernel created them.
+pub(crate) fn loaded(
+    audit: &AuditHook,
+    obj: &mut Object,
+    maps: &[String],
+    prog_fds: &[RawFd],
+) -> Result<()> {
+    let mut map_ids = vec![];
+    for map in maps {
+        if let Some(map) = obj.map(map)? {
+            map_ids.push(sys::map_info(map.fd())?.id);
+        }
+    }
+    let mut progs = vec![];
+    for fd in prog_fds.iter().copied() {
+        // also covers the maps libbpf creates for globals.
+        map_ids.extend(sys::prog_map_ids(fd)?);
+        progs.push((sys::prog_info(fd)?.id, prog_load(fd)?));
+    }
+    map_ids.sort_unstable();
+    map_ids.dedup();
+    for id in map_ids {
+        audit(&map_create(id)?);
+    }
+    progs.sort_by_key(|(id, _)| *id);
+    for (_, event) in &progs {
+        audit(event);
+    }
+    Ok(())
+}
+
+pub(crate) fn prog_load(fd: RawFd) -> Result<AuditEvent> {
+    let info = sys::prog_info(fd)?;
+    Ok(AuditEvent::ProgLoad {
+        id: info.id,
+        name: info.name().to_string(),
+        prog_type: info.ty,
+        tag: info.tag,
+        insns: info.xlated_prog_len / 8,
+        map_ids: sys::prog_map_ids(fd)?,
+    })
+}
+
+pub(crate) fn map_create(id: u32) -> Result<AuditEvent> {
+    let fd = sys::map_get_fd_by_id(id, sys::BPF_F_RDONLY)?;
+    let info = sys::map_info(fd);
+    unsafe { libc::close(fd) };
+    let info = info?;
+    Ok(AuditEvent::MapCreate {
+        id: info.id,
+        name: info.name().to_string(),
+        map_type: info.ty,
+        key_size: info.key_size,
+        value_size: info.value_size,
+        max_entries: info.max_entries,
+        flags: info.map_flags,
+    })
+}
+
+pub(crate) fn map_freeze(fd: RawFd) -> Result<AuditEvent> {
+    let info = sys::map_info(fd)?;
+    Ok(AuditEvent::MapFreeze {
+        id: info.id,
+        name: info.name().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn failures_are_recorded() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let log = events.clone();
+        let hook: AuditHook = Box::new(move |event| log.borrow_mut().push(event.to_string()));
+        let res: std::result::Result<(), _> = Err("Operation not permitted");
+        assert!(record(Some(&hook), "map_update", "CONFIG", res, |_| unreachable!()).is_err());
+        let res: std::result::Result<_, &str> = Ok(7);
+        let event = |id: &u32| {
+            Ok(AuditEvent::MapUpdate {
+                id: *id,
+                name: "CONFIG".into(),
+            })
+        };
+        assert_eq!(
+            record(Some(&hook), "map_update", "CONFIG", res, event),
+            Ok(7)
+        );
+        assert_eq!(
+            *events.borrow(),
+            [
+                "op=map_update target=CONFIG failed: Operation not permitted",
+                "op=map_update id=7 name=CONFIG",
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Minimal parsing of 64 bit little endian bpf objects.
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+
+pub const SHT_SYMTAB: u32 = 2;
+
+pub const SHDR_SIZE: usize = 64;
+pub const SYM_SIZE: usize = 24;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Section {
+    pub header: usize,
+    pub ty: u32,
+    pub flags: u64,
+    pub offset: usize,
+    pub size: usize,
+    pub link: u32,
+    pub info: u32,
+}
+
+pub fn sections(elf: &[u8]) -> Result<Vec<Section>> {
+    if elf.get(..4) != Some(b"\x7fELF") || read_u8(elf, 4)? != 2 || read_u8(elf, 5)? != 1 {
+        bail!("expected a 64 bit little endian elf");
+    }
+    let shoff = read_u64(elf, 0x28)? as usize;
+    let shnum = read_u16(elf, 0x3c)? as usize;
+    (0..shnum)
+        .map(|i| {
+            let header = shoff + i * SHDR_SIZE;
+            Ok(Section {
+                header,
+                ty: read_u32(elf, header + 4)?,
+                flags: read_u64(elf, header + 8)?,
+                offset: read_u64(elf, header + 24)? as usize,
+                size: read_u64(elf, header + 32)? as usize,
+                link: read_u32(elf, header + 40)?,
+                info: read_u32(elf, header + 44)?,
+            })
+        })
+        .collect()
+}
+
+pub fn section(sections: &[Section], index: u32) -> Result<Section> {
+    match sections.get(index as usize) {
+        Some(section) => Ok(*section),
+        None => bail!("section index {} out of bounds", index),
+    }
+}
+
+pub fn read_u8(elf: &[u8], offset: usize) -> Result<u8> {
+    match elf.get(offset) {
+        Some(byte) => Ok(*byte),
+        None => bail!("unexpected end of elf"),
+    }
+}
+
+pub fn read_u16(elf: &[u8], offset: usize) -> Result<u16> {
+    match elf.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_le_bytes(bytes.try_into()?)),
+        None => bail!("unexpected end of elf"),
+    }
+}
+
+pub fn read_u32(elf: &[u8], offset: usize) -> Result<u32> {
+    match elf.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into()?)),
+        None => bail!("unexpected end of elf"),
+    }
+}
+
+pub fn read_u64(elf: &[u8], offset: usize) -> Result<u64> {
+    match elf.get(offset..offset + 8) {
+        Some(bytes) => Ok(u64::from_le_bytes(bytes.try_into()?)),
+        None => bail!("unexpected end of elf"),
+    }
+}
+
+pub fn read_str(elf: &[u8], offset: usize) -> Result<&str> {
+    let bytes = match elf.get(offset..) {
+        Some(bytes) => bytes,
+        None => bail!("unexpected end of elf"),
+    };
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(std::str::from_utf8(&bytes[..len])?)
+}
+
+pub fn section_name(elf: &[u8], sections: &[Section], section: &Section) -> Result<String> {
+    let shstrtab = self::section(sections, read_u16(elf, 0x3e)? as u32)?;
+    let name = read_u32(elf, section.header)? as usize;
+    Ok(read_str(elf, shstrtab.offset + name)?.to_string())
+}
+
+/// Overrides the `max_entries` of the `bpf_map_def` of `map`.
+pub fn set_max_entries(elf: &mut [u8], map: &str, max_entries: u32) -> Result<()> {
+    let sections = sections(elf)?;
+    let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+        Some(symtab) => *symtab,
+        None => bail!("missing symbol table"),
+    };
+    let strtab = section(&sections, symtab.link)?;
+    for sym in (symtab.offset..symtab.offset + symtab.size).step_by(SYM_SIZE) {
+        let name = read_str(elf, strtab.offset + read_u32(elf, sym)? as usize)?;
+        if name != map {
+            continue;
+        }
+        let shndx = read_u16(elf, sym + 6)? as u32;
+        let maps = match sections.get(shndx as usize) {
+            Some(maps) => *maps,
+            None => continue,
+        };
+        if section_name(elf, &sections, &maps)? != "maps" {
+            continue;
+        }
+        // max_entries follows the type, key size and value size.
+        let offset = maps.offset + read_u64(elf, sym + 8)? as usize + 12;
+        read_u32(elf, offset)?;
+        elf[offset..offset + 4].copy_from_slice(&max_entries.to_le_bytes());
+        return Ok(());
+    }
+    bail!("map `{}` not found", map)
+}
+
+/// Names of the symbols in the `maps` section, which libbpf names the maps
+/// after.
+pub fn map_names(elf: &[u8]) -> Result<Vec<String>> {
+    let sections = sections(elf)?;
+    let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+        Some(symtab) => *symtab,
+        None => bail!("missing symbol table"),
+    };
+    let strtab = section(&sections, symtab.link)?;
+    let mut names = vec![];
+    for sym in (symtab.offset..symtab.offset + symtab.size).step_by(SYM_SIZE) {
+        let shndx = read_u16(elf, sym + 6)? as u32;
+        let maps = match sections.get(shndx as usize) {
+            Some(maps) => *maps,
+            None => continue,
+        };
+        if section_name(elf, &sections, &maps)? != "maps" {
+            continue;
+        }
+        let name = read_str(elf, strtab.offset + read_u32(elf, sym)? as usize)?;
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Initializes the global `name` in `.rodata` to `value`, which libbpf
+/// freezes before the programs are loaded.
+pub fn set_global(elf: &mut [u8], name: &str, value: &[u8]) -> Result<()> {
+    let sections = sections(elf)?;
+    let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+        Some(symtab) => *symtab,
+        None => bail!("missing symbol table"),
+    };
+    let strtab = section(&sections, symtab.link)?;
+    for sym in (symtab.offset..symtab.offset + symtab.size).step_by(SYM_SIZE) {
+        if read_str(elf, strtab.offset + read_u32(elf, sym)? as usize)? != name {
+            continue;
+        }
+        let shndx = read_u16(elf, sym + 6)? as u32;
+        let rodata = match sections.get(shndx as usize) {
+            Some(rodata) => *rodata,
+            None => continue,
+        };
+        if section_name(elf, &sections, &rodata)? != ".rodata" {
+            continue;
+        }
+        let size = read_u64(elf, sym + 16)? as usize;
+        if size != value.len() {
+            bail!("global `{}` has {} bytes, not {}", name, size, value.len());
+        }
+        let offset = rodata.offset + read_u64(elf, sym + 8)? as usize;
+        match elf.get_mut(offset..offset + size) {
+            Some(bytes) => bytes.copy_from_slice(value),
+            None => bail!("unexpected end of elf"),
+        }
+        return Ok(());
+    }
+    bail!("global `{}` not found", name)
+}
+
+/// Sets the contents of the `version` section to the result of `version` if
+/// it is zero, returning the version the object is loaded with. `version` is
+/// only called for a zero version.
+pub fn set_version(elf: &mut [u8], version: impl FnOnce() -> Result<u32>) -> Result<Option<u32>> {
+    let sections = sections(elf)?;
+    for section in &sections {
+        if section_name(elf, &sections, section)? != "version" {
+            continue;
+        }
+        let current = read_u32(elf, section.offset)?;
+        if current != 0 {
+            return Ok(Some(current));
+        }
+        let version = version()?;
+        elf[section.offset..section.offset + 4].copy_from_slice(&version.to_le_bytes());
+        return Ok(Some(version));
+    }
+    Ok(None)
+}
@@ -0,0 +1,88 @@
+//! Decoding of events prefixed with `bpf_helpers::event::EventHeader`.
+use std::convert::TryInto;
+use std::time::Duration;
+
+pub const TASK_COMM_LEN: usize = 16;
+/// Size of the header in the event.
+pub const HEADER_SIZE: usize = 24 + TASK_COMM_LEN;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EventHeader {
+    pub ktime_ns: u64,
+    /// Thread id.
+    pub pid: u32,
+    /// Process id.
+    pub tgid: u32,
+    pub cpu: u32,
+    pub comm: [u8; TASK_COMM_LEN],
+}
+
+impl EventHeader {
+    /// Splits an event into the header and the payload.
+    pub fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_ne_bytes(data[i..(i + 4)].try_into().unwrap());
+        let header = Self {
+            ktime_ns: u64::from_ne_bytes(data[..8].try_into().unwrap()),
+            pid: u32_at(8),
+            tgid: u32_at(12),
+            cpu: u32_at(16),
+            comm: data[24..HEADER_SIZE].try_into().unwrap(),
+        };
+        Some((header, &data[HEADER_SIZE..]))
+    }
+
+    /// Name of the task, truncated by the kernel to 15 bytes.
+    pub fn comm(&self) -> String {
+        let len = self
+            .comm
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(TASK_COMM_LEN);
+        String::from_utf8_lossy(&self.comm[..len]).into_owned()
+    }
+
+    /// Time since boot, not counting suspend.
+    pub fn ktime(&self) -> Duration {
+        Duration::from_nanos(self.ktime_ns)
+    }
+}
+
+impl std::fmt::Display for EventHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.6} cpu{} {} {}/{}",
+            self.ktime().as_secs_f64(),
+            self.cpu,
+            self.comm(),
+            self.tgid,
+            self.pid
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header() {
+        let mut data = vec![];
+        data.extend_from_slice(&1_500_000_000u64.to_ne_bytes());
+        data.extend_from_slice(&43u32.to_ne_bytes());
+        data.extend_from_slice(&42u32.to_ne_bytes());
+        data.extend_from_slice(&3u32.to_ne_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"cargo-trace\0\0\0\0\0");
+        data.extend_from_slice(&7u32.to_ne_bytes());
+        let (header, payload) = EventHeader::parse(&data).unwrap();
+        assert_eq!(header.comm(), "cargo-trace");
+        assert_eq!((header.pid, header.tgid, header.cpu), (43, 42, 3));
+        assert_eq!(payload, &7u32.to_ne_bytes());
+        assert_eq!(header.to_string(), "1.500000 cpu3 cargo-trace 42/43");
+        assert!(EventHeader::parse(&data[..20]).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! Kernel features and their fallbacks.
+//!
+//! LTS kernels like 4.19 and 5.4 lack some of the features the probes
+//! prefer, each has a fallback that works on older kernels:
+//!
+//! | feature      | since | fallback                        |
+//! |--------------|-------|---------------------------------|
+//! | ring buffers | 5.8   | perf buffers, unrolled search   |
+//! | kprobe pmu   | 4.17  | tracefs `kprobe_events`         |
+//! | uprobe pmu   | 4.17  | tracefs `uprobe_events`         |
+//!
+//! Ring buffers are probed by creating one, the kernel version decides
+//! without the privileges to do so. Probes are built a second time for
+//! kernels without ring buffers, writing to perf buffers and with the
+//! unwind table search and the backtrace unrolled, as these kernels may also
+//! predate bounded loops (5.3). Newer kernels search with a bounded loop.
+use anyhow::Result;
+use bpf_utils::kernel::{linux_version_code, version_code};
+use bpf_utils::sys;
+
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Features {
+    /// `LINUX_VERSION_CODE` of the running kernel.
+    pub version: u32,
+    pub ringbuf: bool,
+    pub kprobe_pmu: bool,
+    pub uprobe_pmu: bool,
+}
+
+impl Features {
+    pub fn detect() -> Result<Self> {
+        let version = linux_version_code()?;
+        Ok(Self {
+            version,
+            ringbuf: probe_map(BPF_MAP_TYPE_RINGBUF, 4096)
+                .unwrap_or(version >= version_code(5, 8, 0)),
+            kprobe_pmu: bpf_probes::pmu::exists("kprobe"),
+            uprobe_pmu: bpf_probes::pmu::exists("uprobe"),
+        })
+    }
+
+    /// Whether the probes built for kernels without ring buffers are loaded.
+    pub fn is_compat(&self) -> bool {
+        !self.ringbuf
+    }
+}
+
+/// Whether the kernel creates maps of type `ty`, unknown without the
+/// privileges to create maps.
+fn probe_map(ty: u32, max_entries: u32) -> Option<bool> {
+    match sys::map_create(ty, 0, 0, max_entries) {
+        Ok(fd) => {
+            unsafe { libc::close(fd) };
+            Some(true)
+        }
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => None,
+        Err(err) => {
+            log::debug!("map type {} unsupported: {}", ty, err);
+            Some(false)
+        }
+    }
+}
+
+impl std::fmt::Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "kernel {}.{}.{}",
+            self.version >> 16,
+            (self.version >> 8) & 0xff,
+            self.version & 0xff
+        )?;
+        let pick = |available: bool, yes: &'static str, no: &'static str| {
+            if available {
+                yes
+            } else {
+                no
+            }
+        };
+        let rows = [
+            (
+                "ring buffers",
+                "5.8",
+                self.ringbuf,
+                pick(
+                    self.ringbuf,
+                    "ring buffers, bounded loop search",
+                    "perf buffers, unrolled search",
+                ),
+            ),
+            (
+                "kprobe pmu",
+                "4.17",
+                self.kprobe_pmu,
+                pick(self.kprobe_pmu, "kprobe pmu", "tracefs kprobe_events"),
+            ),
+            (
+                "uprobe pmu",
+                "4.17",
+                self.uprobe_pmu,
+                pick(self.uprobe_pmu, "uprobe pmu", "tracefs uprobe_events"),
+            ),
+        ];
+        writeln!(
+            f,
+            "{:14} {:6} {:10} {}",
+            "feature", "since", "available", "active"
+        )?;
+        for (name, since, available, active) in &rows {
+            let available = pick(*available, "yes", "no");
+            writeln!(f, "{:14} {:6} {:10} {}", name, since, available, active)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lts_report() {
+        let features = Features {
+            version: version_code(4, 19, 0),
+            ringbuf: false,
+            kprobe_pmu: true,
+            uprobe_pmu: false,
+        };
+        assert!(features.is_compat());
+        let report = features.to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "kernel 4.19.0");
+        assert_eq!(lines.len(), 5);
+        assert!(lines[2].ends_with("no         perf buffers, unrolled search"));
+        assert!(lines[3].ends_with("yes        kprobe pmu"));
+        assert!(lines[4].ends_with("no         tracefs uprobe_events"));
+    }
+}
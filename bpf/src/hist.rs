@@ -0,0 +1,162 @@
+//! Log2 histograms recorded with `bpf_helpers::hist`.
+//!
+//! Percentiles are interpolated linearly within the bucket that holds them,
+//! so they are estimates within the bounds of that bucket.
+
+/// Number of buckets, bucket `i` counts values in `[2^(i-1), 2^i)`.
+pub const LOG2_BUCKETS: usize = 65;
+
+/// Percentiles printed by [`Percentiles`].
+pub const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Log2Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Default for Log2Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LOG2_BUCKETS],
+        }
+    }
+}
+
+impl Log2Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bucket(value: u64) -> usize {
+        64 - value.leading_zeros() as usize
+    }
+
+    /// Bounds of a bucket, the lower one is inclusive.
+    pub fn bounds(bucket: usize) -> (f64, f64) {
+        match bucket {
+            0 => (0.0, 1.0),
+            i => (2f64.powi(i as i32 - 1), 2f64.powi(i as i32)),
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.add(Self::bucket(value), 1);
+    }
+
+    pub fn add(&mut self, bucket: usize, count: u64) {
+        if let Some(b) = self.buckets.get_mut(bucket) {
+            *b += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Estimates the value below which `p` percent of the values are.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = p.clamp(0.0, 100.0) / 100.0 * total as f64;
+        let mut seen = 0.0;
+        let mut last = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let count = *count as f64;
+            if seen + count >= rank {
+                let (lo, hi) = Self::bounds(i);
+                return Some(lo + (rank - seen) / count * (hi - lo));
+            }
+            seen += count;
+            last = i;
+        }
+        Some(Self::bounds(last).1)
+    }
+
+    pub fn percentiles(&self) -> Percentiles<'_> {
+        Percentiles(self)
+    }
+}
+
+/// Formats p50, p90, p99 and p999.
+pub struct Percentiles<'a>(&'a Log2Histogram);
+
+impl<'a> std::fmt::Display for Percentiles<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, p) in PERCENTILES.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            let name = format!("p{}", p).replace('.', "");
+            match self.0.percentile(*p) {
+                Some(value) => write!(f, "{} {:.0}", name, value)?,
+                None => write!(f, "{} -", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Log2Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let max = self
+            .buckets
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        let first = self.buckets.iter().position(|count| *count > 0);
+        let last = self.buckets.iter().rposition(|count| *count > 0);
+        if let (Some(first), Some(last)) = (first, last) {
+            for i in first..=last {
+                let (lo, hi) = Self::bounds(i);
+                let bar = "@".repeat((self.buckets[i] * 40 / max) as usize);
+                let range = format!("[{}, {})", lo, hi);
+                writeln!(f, "{:<24} {:>10} |{:<40}|", range, self.buckets[i], bar)?;
+            }
+        }
+        write!(f, "{}", self.percentiles())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(Log2Histogram::bucket(0), 0);
+        assert_eq!(Log2Histogram::bucket(1), 1);
+        assert_eq!(Log2Histogram::bucket(3), 2);
+        assert_eq!(Log2Histogram::bucket(4), 3);
+        assert_eq!(Log2Histogram::bucket(u64::MAX), 64);
+        assert_eq!(Log2Histogram::bounds(3), (4.0, 8.0));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut hist = Log2Histogram::new();
+        assert_eq!(hist.percentile(50.0), None);
+        // 100 values in [4, 8) and 100 in [64, 128).
+        hist.add(3, 100);
+        hist.add(7, 100);
+        assert_eq!(hist.percentile(25.0), Some(6.0));
+        assert_eq!(hist.percentile(50.0), Some(8.0));
+        assert_eq!(hist.percentile(75.0), Some(96.0));
+        assert_eq!(hist.percentile(100.0), Some(128.0));
+        assert_eq!(
+            hist.percentiles().to_string(),
+            "p50 8 p90 115 p99 127 p999 128"
+        );
+        assert!(hist.to_string().contains("[4, 8)"));
+    }
+}
@@ -0,0 +1,440 @@
+//! Kfunc call relocation.
+//!
+//! Calls to kernel functions are emitted as calls to undefined symbols. The
+//! libbpf version used doesn't resolve them, so before the object is opened
+//! each such call is rewritten into a `BPF_PSEUDO_KFUNC_CALL` with the btf id
+//! of the function, and the relocation is removed.
+//!
+//! Kfuncs defined in modules, like the conntrack lookups of `nf_conntrack`,
+//! are looked up in the btf objects listed by `BPF_BTF_GET_NEXT_ID`. Their
+//! calls refer to the module btf by its index in the `fd_array` of the
+//! program load, which libbpf doesn't pass. The programs calling them are
+//! hidden from libbpf and loaded by [`ModulePrograms::load`] with the maps
+//! libbpf created, which relocates maps and global data but not calls to
+//! other bpf functions.
+use crate::elf::*;
+use anyhow::{bail, Context, Result};
+use bpf_utils::btf::{Btf, BtfKind};
+use bpf_utils::sys;
+use libbpf_rs::Object;
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+
+const SHT_REL: u32 = 9;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+const BPF_LD_IMM64: u8 = 0x18;
+const BPF_CALL: u8 = 0x85;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_PSEUDO_MAP_VALUE: u8 = 2;
+const BPF_PSEUDO_KFUNC_CALL: u8 = 2;
+
+const BPF_PROG_TYPE_TRACING: u32 = 26;
+
+const REL_SIZE: usize = 16;
+
+struct KfuncCall {
+    /// Offset of the call instruction in the file.
+    insn: usize,
+    /// Offset of the relocation entry in the file.
+    rel: usize,
+    sym: usize,
+    /// Index of the section containing the call.
+    section: u32,
+    name: String,
+}
+
+/// A kfunc call resolved to a kernel btf id.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Kfunc {
+    pub name: String,
+    pub btf_id: u32,
+    /// Module defining the kfunc, `None` for vmlinux.
+    pub module: Option<String>,
+}
+
+/// Names of the kfuncs called by a bpf object.
+pub fn kfunc_names(elf: &[u8]) -> Result<Vec<String>> {
+    let mut names: Vec<_> = kfunc_calls(elf)?
+        .into_iter()
+        .map(|call| call.name)
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Btf of a kernel module, the fd is closed on drop.
+struct ModuleBtf {
+    name: String,
+    fd: RawFd,
+    btf: Btf,
+}
+
+impl Drop for ModuleBtf {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn module_btfs(vmlinux: &Btf) -> Result<Vec<ModuleBtf>> {
+    let mut modules = vec![];
+    for id in sys::btf_ids()? {
+        let fd = match sys::btf_get_fd_by_id(id) {
+            Ok(fd) => fd,
+            // unloaded since it was listed.
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let mut module = ModuleBtf {
+            name: String::new(),
+            fd,
+            btf: Btf::default(),
+        };
+        let (name, data) = match sys::kernel_btf(module.fd)? {
+            Some((name, data)) if !name.is_empty() && name != "vmlinux" => (name, data),
+            _ => continue,
+        };
+        module.btf = Btf::parse_split(&data, vmlinux)
+            .with_context(|| format!("parsing the btf of module `{}`", name))?;
+        module.name = name;
+        modules.push(module);
+    }
+    Ok(modules)
+}
+
+/// Btf id of a kfunc and the index of the module defining it, the module btf
+/// is listed on first use.
+fn find_kfunc(
+    vmlinux: &Btf,
+    modules: &mut Option<Vec<ModuleBtf>>,
+    name: &str,
+) -> Result<(u32, Option<usize>)> {
+    if let Some(ty) = vmlinux.find(BtfKind::Func, name) {
+        return Ok((ty.id, None));
+    }
+    if modules.is_none() {
+        *modules = Some(module_btfs(vmlinux).context("listing the module btf (linux 5.11+)")?);
+    }
+    for (i, module) in modules.iter().flatten().enumerate() {
+        if let Some(ty) = module.btf.find(BtfKind::Func, name) {
+            return Ok((ty.id, Some(i)));
+        }
+    }
+    bail!("kfunc `{}` not found in the kernel btf", name)
+}
+
+/// An object with its kfunc calls resolved.
+pub struct Resolved {
+    pub elf: Vec<u8>,
+    pub kfuncs: Vec<Kfunc>,
+    pub modules: ModulePrograms,
+}
+
+/// Resolves the kfunc calls of a bpf object.
+///
+/// Returns `None` if the object doesn't call any kfuncs, in which case the
+/// kernel btf isn't loaded.
+pub fn resolve_kfuncs(elf: &[u8]) -> Result<Option<Resolved>> {
+    let calls = kfunc_calls(elf)?;
+    if calls.is_empty() {
+        return Ok(None);
+    }
+    let btf = Btf::load_vmlinux()
+        .context("kfuncs need the kernel btf of /sys/kernel/btf/vmlinux (linux 5.4+)")?;
+    let mut modules = None;
+    // indices of the modules in the fd array, the first entry is reserved.
+    let mut fd_array: Vec<usize> = vec![];
+    let mut hidden = vec![];
+    let mut elf = elf.to_vec();
+    let mut removed = vec![];
+    let mut kfuncs: Vec<Kfunc> = vec![];
+    for call in &calls {
+        let (id, module) = find_kfunc(&btf, &mut modules, &call.name)?;
+        let off = match module {
+            Some(module) => {
+                if !hidden.contains(&call.section) {
+                    hidden.push(call.section);
+                }
+                match fd_array.iter().position(|i| *i == module) {
+                    Some(pos) => pos + 1,
+                    None => {
+                        fd_array.push(module);
+                        fd_array.len()
+                    }
+                }
+            }
+            None => 0,
+        };
+        if !kfuncs.iter().any(|kfunc| kfunc.name == call.name) {
+            kfuncs.push(Kfunc {
+                name: call.name.clone(),
+                btf_id: id,
+                module: module.map(|i| modules.iter().flatten().nth(i).unwrap().name.clone()),
+            });
+        }
+        log::debug!("resolved kfunc {} to btf id {}", call.name, id);
+        elf[call.insn + 1] = (BPF_PSEUDO_KFUNC_CALL << 4) | (elf[call.insn + 1] & 0x0f);
+        elf[call.insn + 2..call.insn + 4].copy_from_slice(&(off as i16).to_le_bytes());
+        elf[call.insn + 4..call.insn + 8].copy_from_slice(&id.to_le_bytes());
+        // libbpf ignores absolute symbols, so it doesn't treat the kfunc as
+        // an unresolved extern.
+        elf[call.sym + 6..call.sym + 8].copy_from_slice(&SHN_ABS.to_le_bytes());
+        removed.push(call.rel);
+    }
+    remove_relocations(&mut elf, &removed)?;
+    let mut modules: Vec<_> = modules.unwrap_or_default().into_iter().map(Some).collect();
+    let btfs = fd_array
+        .iter()
+        .map(|i| modules[*i].take().unwrap())
+        .collect();
+    let modules = hide_programs(&mut elf, &hidden, btfs)?;
+    Ok(Some(Resolved {
+        elf,
+        kfuncs,
+        modules,
+    }))
+}
+
+struct Relocation {
+    /// Offset of the instruction in the program.
+    insn: usize,
+    symbol: String,
+    /// Name of the section the symbol is defined in.
+    section: String,
+    value: u64,
+}
+
+struct ModuleProgram {
+    name: String,
+    insns: Vec<u8>,
+    relocations: Vec<Relocation>,
+}
+
+/// Programs calling kfuncs of modules, loaded once libbpf created the maps.
+#[derive(Default)]
+pub struct ModulePrograms {
+    btfs: Vec<ModuleBtf>,
+    progs: Vec<ModuleProgram>,
+    license: CString,
+    version: u32,
+}
+
+impl ModulePrograms {
+    pub fn contains(&self, entry: &str) -> bool {
+        self.progs.iter().any(|prog| prog.name == entry)
+    }
+
+    /// Loads `entry` with the maps of `obj`, the caller owns the returned fd.
+    pub fn load(
+        &self,
+        obj: &mut Object,
+        entry: &str,
+        prog_type: u32,
+        attach_type: u32,
+    ) -> Result<RawFd> {
+        let prog = match self.progs.iter().find(|prog| prog.name == entry) {
+            Some(prog) => prog,
+            None => bail!("no program `{}`", entry),
+        };
+        if prog_type == BPF_PROG_TYPE_TRACING {
+            bail!(
+                "`{}` calls module kfuncs, which isn't supported for fentry/fexit programs",
+                entry
+            );
+        }
+        let mut insns = prog.insns.clone();
+        for reloc in &prog.relocations {
+            let insn = reloc.insn;
+            match read_u8(&insns, insn)? {
+                BPF_LD_IMM64 => {}
+                BPF_CALL => bail!(
+                    "`{}` calls the bpf function `{}`, which isn't supported in programs calling module kfuncs",
+                    entry,
+                    reloc.symbol
+                ),
+                _ => bail!("unsupported relocation of `{}` in `{}`", reloc.symbol, entry),
+            }
+            let (src, map) = match reloc.section.as_str() {
+                "maps" => (BPF_PSEUDO_MAP_FD, reloc.symbol.clone()),
+                // libbpf names the maps of global data after the object.
+                ".data" | ".rodata" | ".bss" => {
+                    (BPF_PSEUDO_MAP_VALUE, format!("bpf{}", reloc.section))
+                }
+                section => bail!(
+                    "unsupported relocation of `{}` in `{}` to section `{}`",
+                    reloc.symbol,
+                    entry,
+                    section
+                ),
+            };
+            let fd = match obj.map(&map)? {
+                Some(map) => map.fd(),
+                None => bail!("map `{}` used by `{}` not found", map, entry),
+            };
+            if src == BPF_PSEUDO_MAP_VALUE {
+                // the offset into the value is the addend plus the symbol value.
+                let offset = read_u32(&insns, insn + 4)?.wrapping_add(reloc.value as u32);
+                insns[insn + 12..insn + 16].copy_from_slice(&offset.to_le_bytes());
+            }
+            insns[insn + 1] = (src << 4) | (insns[insn + 1] & 0x0f);
+            insns[insn + 4..insn + 8].copy_from_slice(&fd.to_le_bytes());
+        }
+        let mut fd_array = vec![0];
+        fd_array.extend(self.btfs.iter().map(|btf| btf.fd));
+        let fd = sys::prog_load(
+            prog_type,
+            attach_type,
+            entry,
+            &insns,
+            &self.license,
+            self.version,
+            &fd_array,
+        )
+        .with_context(|| format!("loading `{}`", entry))?;
+        Ok(fd)
+    }
+}
+
+/// Moves the programs of the `hidden` sections out of the sight of libbpf,
+/// which skips sections that aren't executable along with their relocations.
+fn hide_programs(elf: &mut [u8], hidden: &[u32], btfs: Vec<ModuleBtf>) -> Result<ModulePrograms> {
+    let sections = sections(elf)?;
+    let mut modules = ModulePrograms {
+        btfs,
+        ..Default::default()
+    };
+    for section in &sections {
+        match section_name(elf, &sections, section)?.as_str() {
+            "license" => modules.license = CString::new(read_str(elf, section.offset)?)?,
+            "version" => modules.version = read_u32(elf, section.offset)?,
+            _ => {}
+        }
+    }
+    if hidden.is_empty() {
+        return Ok(modules);
+    }
+    let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+        Some(symtab) => *symtab,
+        None => bail!("missing symbol table"),
+    };
+    let strtab = section(&sections, symtab.link)?;
+    let symbol = |sym: usize| -> Result<(String, u16, u64)> {
+        let name = read_str(elf, strtab.offset + read_u32(elf, sym)? as usize)?;
+        Ok((
+            name.to_string(),
+            read_u16(elf, sym + 6)?,
+            read_u64(elf, sym + 8)?,
+        ))
+    };
+    for index in hidden {
+        let text = section(&sections, *index)?;
+        let mut name = None;
+        for sym in (symtab.offset..symtab.offset + symtab.size).step_by(SYM_SIZE) {
+            let (sym_name, shndx, value) = symbol(sym)?;
+            if shndx as u32 == *index && value == 0 && read_u8(elf, sym + 4)? & 0xf == STT_FUNC {
+                name = Some(sym_name);
+            }
+        }
+        let mut prog = ModuleProgram {
+            name: name.context("program without a symbol")?,
+            insns: elf[text.offset..text.offset + text.size].to_vec(),
+            relocations: vec![],
+        };
+        for rel in sections
+            .iter()
+            .filter(|s| s.ty == SHT_REL && s.info == *index)
+        {
+            for entry in (rel.offset..rel.offset + rel.size).step_by(REL_SIZE) {
+                let r_sym = (read_u64(elf, entry + 8)? >> 32) as usize;
+                let (symbol, shndx, value) = symbol(symtab.offset + r_sym * SYM_SIZE)?;
+                let section = match sections.get(shndx as usize) {
+                    Some(section) if shndx != SHN_UNDEF => section_name(elf, &sections, section)?,
+                    _ => String::new(),
+                };
+                prog.relocations.push(Relocation {
+                    insn: read_u64(elf, entry)? as usize,
+                    symbol,
+                    section,
+                    value,
+                });
+            }
+        }
+        log::debug!(
+            "loading `{}` without libbpf, it calls module kfuncs",
+            prog.name
+        );
+        modules.progs.push(prog);
+    }
+    for index in hidden {
+        let text = section(&sections, *index)?;
+        let flags = text.flags & !SHF_EXECINSTR;
+        elf[text.header + 8..text.header + 16].copy_from_slice(&flags.to_le_bytes());
+    }
+    Ok(modules)
+}
+
+fn kfunc_calls(elf: &[u8]) -> Result<Vec<KfuncCall>> {
+    let sections = sections(elf)?;
+    let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+        Some(symtab) => *symtab,
+        None => return Ok(vec![]),
+    };
+    let strtab = section(&sections, symtab.link)?;
+    let mut calls = vec![];
+    for rel in sections.iter().filter(|s| s.ty == SHT_REL) {
+        let target = section(&sections, rel.info)?;
+        if target.flags & SHF_EXECINSTR == 0 {
+            continue;
+        }
+        for entry in (rel.offset..rel.offset + rel.size).step_by(REL_SIZE) {
+            let r_offset = read_u64(elf, entry)? as usize;
+            let r_sym = (read_u64(elf, entry + 8)? >> 32) as usize;
+            let sym = symtab.offset + r_sym * SYM_SIZE;
+            let st_type = read_u8(elf, sym + 4)? & 0xf;
+            if read_u16(elf, sym + 6)? != SHN_UNDEF || !matches!(st_type, STT_NOTYPE | STT_FUNC) {
+                continue;
+            }
+            let insn = target.offset + r_offset;
+            if read_u8(elf, insn)? != BPF_CALL {
+                continue;
+            }
+            let name = read_str(elf, strtab.offset + read_u32(elf, sym)? as usize)?;
+            calls.push(KfuncCall {
+                insn,
+                rel: entry,
+                sym,
+                section: rel.info,
+                name: name.to_string(),
+            });
+        }
+    }
+    Ok(calls)
+}
+
+/// Removes relocation entries by moving the remaining entries of their
+/// section to the front and shrinking the section.
+fn remove_relocations(elf: &mut [u8], removed: &[usize]) -> Result<()> {
+    for rel in sections(elf)?.into_iter().filter(|s| s.ty == SHT_REL) {
+        let end = rel.offset + rel.size;
+        if !removed
+            .iter()
+            .any(|entry| (rel.offset..end).contains(entry))
+        {
+            continue;
+        }
+        let kept: Vec<u8> = (rel.offset..end)
+            .step_by(REL_SIZE)
+            .filter(|entry| !removed.contains(entry))
+            .flat_map(|entry| elf[entry..entry + REL_SIZE].to_vec())
+            .collect();
+        elf[rel.offset..rel.offset + kept.len()].copy_from_slice(&kept);
+        let size = (kept.len() as u64).to_le_bytes();
+        elf[rel.header + 32..rel.header + 40].copy_from_slice(&size);
+    }
+    Ok(())
+}
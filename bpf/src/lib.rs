@@ -1,9 +1,33 @@
-use anyhow::Result;
+use crate::arena::BpfArena;
+use crate::audit::{AuditEvent, AuditHook};
+use crate::perf::{PerfBuffer, PerfBufferOptions};
+use crate::records::Records;
+use crate::ringbuf::RingBuffer;
+use crate::stats::{ProgramStats, StatsGuard, StatsLogger};
+use anyhow::{bail, Result};
 pub use bpf_probes::*;
 use libbpf_rs::{Map, MapFlags, Object, ObjectBuilder, OpenObject};
+use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
 use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
 
+pub mod arena;
+pub mod audit;
+mod elf;
+pub mod event;
+pub mod features;
+pub mod hist;
+pub mod kfunc;
+pub mod memory;
+pub mod perf;
+pub mod records;
+pub mod ringbuf;
+pub mod seccomp;
+pub mod stats;
+
 pub type I16 = zerocopy::byteorder::I16<byteorder::NativeEndian>;
 pub type I32 = zerocopy::byteorder::I32<byteorder::NativeEndian>;
 pub type I64 = zerocopy::byteorder::I64<byteorder::NativeEndian>;
@@ -12,11 +36,15 @@ pub type U32 = zerocopy::byteorder::U32<byteorder::NativeEndian>;
 pub type U64 = zerocopy::byteorder::U64<byteorder::NativeEndian>;
 
 pub mod utils {
-    pub use bpf_utils::dylibs::BinaryInfo;
+    pub use bpf_utils::dylibs::{resolve_symbol, BinaryInfo};
     pub use bpf_utils::ehframe;
-    pub use bpf_utils::elf::{Dwarf, Elf};
+    pub use bpf_utils::elf::{Dwarf, Elf, DEBUG_PATH_ENV};
+    pub use bpf_utils::event;
+    pub use bpf_utils::fdpass;
     pub use bpf_utils::kallsyms::{KernelSymbol, KernelSymbolTable};
+    pub use bpf_utils::kernel::linux_version_code;
     pub use bpf_utils::maps::{AddressEntry, AddressMap};
+    pub use bpf_utils::sys::{self, kernel_name, MapInfo, ProgInfo};
     pub use bpf_utils::syscall::syscall_table;
     pub use sudo;
 }
@@ -24,50 +52,220 @@ pub mod utils {
 pub struct BpfBuilder {
     child_pid: Option<u32>,
     probes: Vec<(Probe, &'static str)>,
+    xdp: Vec<(String, &'static str, u32)>,
     new_obj: OpenObject,
+    maps: Vec<String>,
+    audit: Option<AuditHook>,
+    kfuncs: Vec<kfunc::Kfunc>,
+    modules: kfunc::ModulePrograms,
+    stats: bool,
+    perf: PerfOptions,
+}
+
+#[derive(Clone, Debug, Default)]
+struct PerfOptions {
+    default: PerfBufferOptions,
+    maps: HashMap<String, PerfBufferOptions>,
 }
 
 impl BpfBuilder {
     pub fn new(prog: &[u8]) -> Result<Self> {
+        Self::with_max_entries(prog, &[])
+    }
+
+    /// Opens the object with the `max_entries` of some maps overridden, which
+    /// sizes them to the data at run time. A zero version of the object is set
+    /// to the version of the running kernel.
+    pub fn with_max_entries(prog: &[u8], max_entries: &[(&str, u32)]) -> Result<Self> {
+        Self::with_globals(prog, max_entries, &[])
+    }
+
+    /// Like `with_max_entries`, also initializing read-only `globals` by
+    /// name. Their values are constants to the verifier, which prunes the
+    /// branches they decide.
+    pub fn with_globals(
+        prog: &[u8],
+        max_entries: &[(&str, u32)],
+        globals: &[(&str, &[u8])],
+    ) -> Result<Self> {
         bpf_utils::rlimit::increase_memlock_rlimit()?;
+        let mut prog = prog.to_vec();
+        for (map, max_entries) in max_entries {
+            elf::set_max_entries(&mut prog, map, *max_entries)?;
+        }
+        for (name, value) in globals {
+            elf::set_global(&mut prog, name, value)?;
+        }
+        if let Some(version) = elf::set_version(&mut prog, bpf_utils::kernel::linux_version_code)? {
+            log::debug!("loading with kernel version 0x{:x}", version);
+        }
+        let (prog, kfuncs, modules) = match kfunc::resolve_kfuncs(&prog)? {
+            Some(resolved) => (resolved.elf, resolved.kfuncs, resolved.modules),
+            None => (prog, vec![], Default::default()),
+        };
+        let maps = elf::map_names(&prog)?;
+        check_kernel_names("maps", maps.iter().map(String::as_str))?;
         let new_obj = ObjectBuilder::default()
             .relaxed_maps(true)
-            .open_memory("bpf", prog)?;
+            .open_memory("bpf", &prog)?;
         Ok(Self {
             child_pid: None,
             probes: Default::default(),
+            xdp: Default::default(),
             new_obj,
+            maps,
+            audit: None,
+            kfuncs,
+            modules,
+            stats: false,
+            perf: Default::default(),
         })
     }
 
+    /// Kfuncs called by the object and their kernel btf ids.
+    pub fn kfuncs(&self) -> &[kfunc::Kfunc] {
+        &self.kfuncs
+    }
+
     pub fn set_child_pid<T: Into<u32>>(&mut self, pid: T) {
         self.child_pid = Some(pid.into());
     }
 
+    /// Records the maps created, programs loaded and probes attached.
+    pub fn set_audit_hook<F: Fn(&AuditEvent) + 'static>(&mut self, hook: F) {
+        self.audit = Some(Box::new(hook));
+    }
+
+    /// Collects the run time of the programs, see `Bpf::program_stats`.
+    ///
+    /// This adds overhead to every program run on the system while enabled.
+    pub fn enable_stats(&mut self) {
+        self.stats = true;
+    }
+
+    /// Sets the buffer size and wakeup threshold of the perf buffers.
+    pub fn set_perf_options(&mut self, options: PerfBufferOptions) {
+        self.perf.default = options;
+    }
+
+    /// Overrides the perf buffer options of `map`.
+    pub fn set_perf_map_options(&mut self, map: &str, options: PerfBufferOptions) {
+        self.perf.maps.insert(map.to_string(), options);
+    }
+
     pub fn attach_probe_str(&mut self, probe: &str, entry: &'static str) -> Result<()> {
         self.attach_probe(probe.parse()?, entry)
     }
 
     pub fn attach_probe(&mut self, probe: Probe, entry: &'static str) -> Result<()> {
-        let new_prog = self.new_obj.prog(entry)?.unwrap();
-        new_prog.set_prog_type(probe.prog_type());
-        if let Some(attach_type) = probe.attach_type() {
-            new_prog.set_attach_type(attach_type);
+        // programs calling module kfuncs are loaded without libbpf.
+        if !self.modules.contains(entry) {
+            let new_prog = self.new_obj.prog(entry)?.unwrap();
+            new_prog.set_prog_type(probe.prog_type());
+            if let Some(attach_type) = probe.attach_type() {
+                new_prog.set_attach_type(attach_type);
+            }
         }
         self.probes.push((probe, entry));
         Ok(())
     }
 
+    /// Attaches the xdp program `entry` to `interface`, see `xdp::XDP_FLAGS_*`.
+    pub fn attach_xdp(&mut self, interface: &str, entry: &'static str, flags: u32) -> Result<()> {
+        if !self.modules.contains(entry) {
+            let new_prog = self.new_obj.prog(entry)?.unwrap();
+            new_prog.set_prog_type(ProgramType::Xdp);
+        }
+        self.xdp.push((interface.to_string(), entry, flags));
+        Ok(())
+    }
+
     pub fn load(self) -> Result<Bpf> {
-        let mut obj = self.new_obj.load()?;
+        let audit = self.audit;
+        let mut obj = self.new_obj.load().map_err(|err| {
+            audit::failed(audit.as_ref(), "load", "bpf", &err);
+            err
+        })?;
+        let stats = if self.stats {
+            Some(StatsGuard::enable()?)
+        } else {
+            None
+        };
+        let mut entries: Vec<_> = self.probes.iter().map(|(_, entry)| *entry).collect();
+        entries.extend(self.xdp.iter().map(|(_, entry, _)| *entry));
+        entries.sort_unstable();
+        entries.dedup();
+        check_kernel_names("programs", entries.iter().copied())?;
+        let mut module_progs = HashMap::new();
+        let types = self
+            .probes
+            .iter()
+            .map(|(probe, entry)| (*entry, probe.prog_type(), probe.attach_type()))
+            .chain(
+                self.xdp
+                    .iter()
+                    .map(|(_, entry, _)| (*entry, ProgramType::Xdp, None)),
+            );
+        for (entry, prog_type, attach_type) in types {
+            if self.modules.contains(entry) && !module_progs.contains_key(entry) {
+                let attach_type = attach_type.map(|ty| ty as u32).unwrap_or_default();
+                let fd = self
+                    .modules
+                    .load(&mut obj, entry, prog_type as u32, attach_type)
+                    .map_err(|err| {
+                        audit::failed(audit.as_ref(), "load", entry, &err);
+                        err
+                    })?;
+                module_progs.insert(entry, ProgramFd(fd));
+            }
+        }
+        if let Some(audit) = audit.as_ref() {
+            let mut fds = vec![];
+            for entry in &entries {
+                fds.push(prog_fd(&mut obj, &module_progs, entry)?);
+            }
+            audit::loaded(audit, &mut obj, &self.maps, &fds)?;
+        }
         let mut probes = vec![];
         for (probe, entry) in self.probes {
-            let prog = obj.prog(entry)?.unwrap();
-            probes.extend(probe.attach(prog, self.child_pid)?);
+            let fd = prog_fd(&mut obj, &module_progs, entry)?;
+            let name = probe.to_string();
+            let attached = probe.attach(fd, self.child_pid);
+            let attached = audit::record(audit.as_ref(), "attach", &name, attached, |attached| {
+                Ok(AuditEvent::Attach {
+                    prog_id: bpf_utils::sys::prog_info(fd)?.id,
+                    probe: name.clone(),
+                    perf_event_fds: attached.iter().map(|probe| probe.fd()).collect(),
+                })
+            })?;
+            probes.extend(attached);
+        }
+        let mut xdp = vec![];
+        for (interface, entry, flags) in self.xdp {
+            let fd = prog_fd(&mut obj, &module_progs, entry)?;
+            let name = format!("xdp:{}", interface);
+            let attached = AttachedXdp::attach(fd, &interface, flags);
+            let attached = audit::record(audit.as_ref(), "attach", &name, attached, |_| {
+                Ok(AuditEvent::Attach {
+                    prog_id: bpf_utils::sys::prog_info(fd)?.id,
+                    probe: name.clone(),
+                    perf_event_fds: vec![],
+                })
+            })?;
+            xdp.push(attached);
         }
         Ok(Bpf {
             obj,
             _probes: probes,
+            _xdp: xdp,
+            module_progs,
+            audit,
+            map_info: HashMap::new(),
+            maps: self.maps,
+            entries,
+            _stats: stats,
+            stats_logger: None,
+            perf: self.perf,
         })
     }
 }
@@ -75,6 +273,16 @@ impl BpfBuilder {
 pub struct Bpf {
     obj: Object,
     _probes: Vec<AttachedProbe>,
+    _xdp: Vec<AttachedXdp>,
+    module_progs: HashMap<&'static str, ProgramFd>,
+    audit: Option<AuditHook>,
+    /// Id and kernel name of the audited maps by symbol.
+    map_info: HashMap<String, (u32, String)>,
+    maps: Vec<String>,
+    entries: Vec<&'static str>,
+    _stats: Option<StatsGuard>,
+    stats_logger: Option<StatsLogger>,
+    perf: PerfOptions,
 }
 
 impl Bpf {
@@ -83,23 +291,186 @@ impl Bpf {
         K: AsBytes + FromBytes + Unaligned + Clone,
         V: AsBytes + FromBytes + Unaligned + Clone,
     {
-        Ok(BpfHashMap::new(self.obj.map(map)?.unwrap()))
+        self.map_with_audit(map)
     }
 
     pub fn array<V>(&mut self, map: &str) -> Result<BpfHashMap<'_, U32, V>>
     where
         V: AsBytes + FromBytes + Unaligned + Clone,
     {
-        Ok(BpfHashMap::new(self.obj.map(map)?.unwrap()))
+        self.map_with_audit(map)
+    }
+
+    /// Handle of `map`, the id and kernel name of an audited map are only
+    /// queried for the first handle.
+    fn map_with_audit<K, V>(&mut self, map: &str) -> Result<BpfHashMap<'_, K, V>>
+    where
+        K: AsBytes + FromBytes + Unaligned + Clone,
+        V: AsBytes + FromBytes + Unaligned + Clone,
+    {
+        let handle = BpfHashMap::new(self.obj.map(map)?.unwrap());
+        let audit = match self.audit.as_ref() {
+            Some(audit) => audit,
+            None => return Ok(handle),
+        };
+        if !self.map_info.contains_key(map) {
+            let info = bpf_utils::sys::map_info(handle.map.fd())?;
+            self.map_info
+                .insert(map.to_string(), (info.id, info.name().to_string()));
+        }
+        let (id, name) = &self.map_info[map];
+        Ok(BpfHashMap {
+            audit: Some((audit, *id, name)),
+            ..handle
+        })
     }
 
     pub fn stack_trace(&mut self, map: &str) -> Result<BpfStackTrace<'_>> {
         Ok(BpfStackTrace::new(self.obj.map(map)?.unwrap()))
     }
+
+    /// Opens a consumer of the perf event array `map`.
+    pub fn perf_buffer(&mut self, map: &str) -> Result<PerfBuffer<'_>> {
+        let options = self.perf.maps.get(map).unwrap_or(&self.perf.default);
+        PerfBuffer::new(self.obj.map(map)?.unwrap().fd(), options)
+    }
+
+    /// Opens a consumer of the ring buffer `map`.
+    pub fn ring_buffer(&mut self, map: &str) -> Result<RingBuffer<'_>> {
+        RingBuffer::new(self.obj.map(map)?.unwrap().fd())
+    }
+
+    /// Opens a consumer of `map`, a ring buffer or a perf event array.
+    pub fn records(&mut self, map: &str) -> Result<Records<'_>> {
+        let fd = self.obj.map(map)?.unwrap().fd();
+        if bpf_utils::sys::map_info(fd)?.ty == BPF_MAP_TYPE_PERF_EVENT_ARRAY {
+            let options = self.perf.maps.get(map).unwrap_or(&self.perf.default);
+            Ok(Records::Perf(PerfBuffer::new(fd, options)?))
+        } else {
+            Ok(Records::Ring(RingBuffer::new(fd)?))
+        }
+    }
+
+    /// Maps the arena `map` into the address space of the process.
+    pub fn arena(&mut self, map: &str) -> Result<BpfArena> {
+        BpfArena::map(self.obj.map(map)?.unwrap().fd())
+    }
+
+    /// Fd of `map`, valid as long as `self` is alive.
+    pub fn map_fd(&mut self, map: &str) -> Result<RawFd> {
+        Ok(self.obj.map(map)?.unwrap().fd())
+    }
+
+    /// Drops write access to `map` from user space once it was initialized.
+    pub fn freeze(&mut self, map: &str) -> Result<()> {
+        let fd = self.obj.map(map)?.unwrap().fd();
+        let frozen = bpf_utils::sys::map_freeze(fd);
+        audit::record(self.audit.as_ref(), "map_freeze", map, frozen, |_| {
+            audit::map_freeze(fd)
+        })?;
+        Ok(())
+    }
+
+    /// Kernel memory used by the maps of the attached programs.
+    pub fn map_memory(&mut self) -> Result<Vec<memory::MapMemory>> {
+        let mut ids = vec![];
+        for entry in self.entries.clone() {
+            let fd = prog_fd(&mut self.obj, &self.module_progs, entry)?;
+            ids.extend(bpf_utils::sys::prog_map_ids(fd)?);
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        let mut maps = vec![];
+        for id in ids {
+            let fd = bpf_utils::sys::map_get_fd_by_id(id, bpf_utils::sys::BPF_F_RDONLY)?;
+            let map = memory::MapMemory::new(fd);
+            unsafe { libc::close(fd) };
+            maps.push(map?);
+        }
+        Ok(maps)
+    }
+
+    /// Run count and time of the attached programs, requires
+    /// `BpfBuilder::enable_stats`.
+    pub fn program_stats(&mut self) -> Result<Vec<ProgramStats>> {
+        let mut stats = vec![];
+        for entry in self.entries.clone() {
+            let fd = prog_fd(&mut self.obj, &self.module_progs, entry)?;
+            stats.push(ProgramStats::new(entry, fd)?);
+        }
+        Ok(stats)
+    }
+
+    /// Logs the program stats to the `bpf::stats` target every `interval`
+    /// until `Bpf` is dropped, replacing the logger of a previous call.
+    pub fn log_stats(&mut self, interval: Duration) -> Result<()> {
+        let mut progs = vec![];
+        for entry in self.entries.clone() {
+            let fd = prog_fd(&mut self.obj, &self.module_progs, entry)?;
+            progs.push((entry.to_string(), bpf_utils::sys::prog_info(fd)?.id));
+        }
+        self.stats_logger = None;
+        self.stats_logger = Some(StatsLogger::spawn(progs, interval)?);
+        Ok(())
+    }
+
+    /// Lines counted by `bpf_helpers::hit!()`, empty if the probe wasn't built
+    /// with hit counters.
+    pub fn hit_counts(&mut self) -> Result<Vec<(u32, u64)>> {
+        match self.obj.map(stats::HITS_MAP)? {
+            Some(map) => stats::hit_counts(map.fd()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Map errors counted by probes built with the `debug-counters` feature
+    /// of `bpf_helpers`, empty otherwise.
+    pub fn diag_counts(&mut self) -> Result<Vec<(&'static str, u64)>> {
+        let counts = match self.obj.map(stats::DIAG_MAP)? {
+            Some(map) => stats::hit_counts(map.fd())?,
+            None => return Ok(vec![]),
+        };
+        Ok(counts
+            .into_iter()
+            .filter_map(|(i, count)| Some((*stats::DIAG_COUNTERS.get(i as usize)?, count)))
+            .collect())
+    }
+
+    /// Kernel side information about a loaded program.
+    pub fn program_info(&mut self, entry: &str) -> Result<utils::ProgInfo> {
+        let fd = prog_fd(&mut self.obj, &self.module_progs, entry)?;
+        Ok(bpf_utils::sys::prog_info(fd)?)
+    }
+
+    /// Kernel side information about a map.
+    pub fn map_info(&mut self, map: &str) -> Result<utils::MapInfo> {
+        let fd = self.obj.map(map)?.unwrap().fd();
+        Ok(bpf_utils::sys::map_info(fd)?)
+    }
+
+    /// Maps of the object, their kernel names are truncated by
+    /// `utils::kernel_name`.
+    pub fn map_names(&self) -> &[String] {
+        &self.maps
+    }
+
+    /// Attached programs, their kernel names are truncated by
+    /// `utils::kernel_name`.
+    pub fn program_names(&self) -> &[&'static str] {
+        &self.entries
+    }
+
+    /// Instructions of a loaded program as rewritten by the verifier.
+    pub fn xlated_insns(&mut self, entry: &str) -> Result<Vec<u8>> {
+        let fd = prog_fd(&mut self.obj, &self.module_progs, entry)?;
+        Ok(bpf_utils::sys::prog_xlated_insns(fd)?)
+    }
 }
 
 pub struct BpfHashMap<'a, K, V> {
     map: &'a mut Map,
+    /// Hook, id and kernel name of the map when inserts are audited.
+    audit: Option<(&'a AuditHook, u32, &'a str)>,
     _marker: PhantomData<(K, V)>,
 }
 
@@ -111,6 +482,7 @@ where
     pub fn new(map: &'a mut Map) -> Self {
         Self {
             map,
+            audit: None,
             _marker: PhantomData,
         }
     }
@@ -125,11 +497,38 @@ where
     }
 
     pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
-        self.map
-            .update(key.as_bytes(), value.as_bytes(), MapFlags::empty())?;
+        let res = self
+            .map
+            .update(key.as_bytes(), value.as_bytes(), MapFlags::empty());
+        if let Some((audit, id, name)) = self.audit.as_ref() {
+            audit::record(Some(*audit), "map_update", name, res, |_| {
+                Ok(AuditEvent::MapUpdate {
+                    id: *id,
+                    name: name.to_string(),
+                })
+            })?;
+        } else {
+            res?;
+        }
         Ok(())
     }
 
+    /// Number of entries, see `bpf_utils::sys::map_len`. Arrays always have
+    /// `capacity` entries, hash maps copy their entries in batches.
+    pub fn approx_len(&self) -> Result<usize> {
+        Ok(bpf_utils::sys::map_len(self.map.fd())?)
+    }
+
+    /// Maximum number of entries.
+    pub fn capacity(&self) -> Result<usize> {
+        Ok(bpf_utils::sys::map_info(self.map.fd())?.max_entries as usize)
+    }
+
+    /// Inserts of new keys fail once the map is full.
+    pub fn is_full(&self) -> Result<bool> {
+        Ok(self.approx_len()? >= self.capacity()?)
+    }
+
     pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
         self.map.keys().filter_map(|bytes| {
             LayoutVerified::<_, K>::new_unaligned(bytes.as_slice())
@@ -147,6 +546,115 @@ where
     }
 }
 
+/// Entries read by one batch lookup of [`BpfHashMap::range`].
+const BATCH: usize = 4096;
+
+impl<'a, V> BpfHashMap<'a, U32, V>
+where
+    V: AsBytes + FromBytes + Unaligned + Clone,
+{
+    /// Values of the keys in `range` of an array, read in batches or one by
+    /// one on kernels without batch lookups. Stops at the end of the array,
+    /// per cpu arrays aren't supported.
+    pub fn range(&self, range: Range<u32>) -> Result<Vec<V>> {
+        let fd = self.map.fd();
+        let value_size = std::mem::size_of::<V>();
+        let mut values = Vec::with_capacity(range.len());
+        // the batch starts after the key `in_batch`.
+        let mut prev = range.start.checked_sub(1).map(U32::new);
+        let mut next = U32::new(0);
+        let mut keys = vec![0; BATCH * std::mem::size_of::<U32>()];
+        let mut buf = vec![0; BATCH * value_size];
+        while values.len() < range.len() {
+            let count = (range.len() - values.len()).min(BATCH) as u32;
+            let in_batch = prev.as_ref().map(|key| key.as_bytes());
+            let out_batch = next.as_bytes_mut();
+            let (n, done) = match bpf_utils::sys::map_lookup_batch(
+                fd, in_batch, out_batch, &mut keys, &mut buf, count,
+            ) {
+                Ok(res) => res,
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) && values.is_empty() => {
+                    return self.range_by_key(range);
+                }
+                Err(err) => return Err(err.into()),
+            };
+            for value in buf[..n as usize * value_size].chunks(value_size) {
+                if let Some(layout) = LayoutVerified::<_, V>::new_unaligned(value) {
+                    values.push(layout.into_ref().clone());
+                }
+            }
+            if done || n == 0 {
+                break;
+            }
+            prev = Some(next);
+        }
+        Ok(values)
+    }
+
+    fn range_by_key(&self, range: Range<u32>) -> Result<Vec<V>> {
+        let mut values = Vec::with_capacity(range.len());
+        for key in range {
+            match self.get(&U32::new(key))? {
+                Some(value) => values.push(value),
+                None => break,
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Index of the first value that isn't smaller than the next one, `None` if
+/// `values` is strictly sorted.
+///
+/// Tables the probes binary search, like unwind tables, must be strictly
+/// sorted, otherwise the search silently finds the wrong entries.
+pub fn first_unsorted<T: PartialOrd>(values: &[T]) -> Option<usize> {
+    values.windows(2).position(|pair| pair[0] >= pair[1])
+}
+
+/// Program loaded without libbpf, closed on drop.
+struct ProgramFd(RawFd);
+
+impl Drop for ProgramFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn prog_fd(
+    obj: &mut Object,
+    module_progs: &HashMap<&'static str, ProgramFd>,
+    entry: &str,
+) -> Result<RawFd> {
+    match module_progs.get(entry) {
+        Some(prog) => Ok(prog.0),
+        None => Ok(obj.prog(entry)?.unwrap().fd()),
+    }
+}
+
+/// Fails if two maps or programs are indistinguishable by the names
+/// `bpftool` and the verifier log show.
+fn check_kernel_names<'a>(kind: &str, names: impl Iterator<Item = &'a str>) -> Result<()> {
+    let mut seen = HashMap::new();
+    for name in names {
+        let kernel_name = bpf_utils::sys::kernel_name(name);
+        if let Some(other) = seen.insert(kernel_name, name) {
+            if other != name {
+                bail!(
+                    "{} `{}` and `{}` share the kernel name `{}`",
+                    kind,
+                    other,
+                    name,
+                    kernel_name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+
 const BPF_MAX_STACK_DEPTH: usize = 127;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -179,3 +687,26 @@ impl<'a> BpfStackTrace<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strictly_sorted() {
+        assert_eq!(first_unsorted(&[1, 2, 3]), None);
+        assert_eq!(first_unsorted::<u64>(&[]), None);
+        assert_eq!(first_unsorted(&[1, 3, 3, 4]), Some(1));
+        assert_eq!(first_unsorted(&[1, 2, 0]), Some(1));
+    }
+
+    #[test]
+    fn kernel_names() {
+        assert_eq!(utils::kernel_name("USER_STACK"), "USER_STACK");
+        assert_eq!(utils::kernel_name("USER_STACKS_BUILDID"), "USER_STACKS_BUI");
+        let names = ["USER_STACKS_BUILDID", "USER_STACK", "USER_STACKS_BUILDID"];
+        assert!(check_kernel_names("maps", names.iter().copied()).is_ok());
+        let names = ["USER_STACKS_BUILDID", "USER_STACKS_BUILDID_OLD"];
+        assert!(check_kernel_names("maps", names.iter().copied()).is_err());
+    }
+}
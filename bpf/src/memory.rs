@@ -0,0 +1,138 @@
+//! Kernel memory used by maps.
+//!
+//! The kernel reports the memory charged for a map in its fdinfo. Older
+//! kernels report a rough size, so the estimate from the map definition is
+//! kept alongside.
+use anyhow::Result;
+use bpf_utils::sys::{self, MapInfo};
+use std::os::unix::io::RawFd;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+
+/// Size of `struct htab_elem` without the key and value.
+const HTAB_ELEM_SIZE: u64 = 48;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MapMemory {
+    pub id: u32,
+    pub name: String,
+    pub max_entries: u32,
+    /// Estimate from the entry count, key and value sizes.
+    pub estimate: u64,
+    /// Bytes charged by the kernel, if reported.
+    pub memlock: Option<u64>,
+}
+
+impl MapMemory {
+    pub fn new(fd: RawFd) -> Result<Self> {
+        let info = sys::map_info(fd)?;
+        Ok(Self {
+            id: info.id,
+            name: info.name().to_string(),
+            max_entries: info.max_entries,
+            estimate: estimate(&info),
+            memlock: memlock(fd)?,
+        })
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.memlock.unwrap_or(self.estimate)
+    }
+}
+
+fn round_up(size: u32) -> u64 {
+    (size as u64 + 7) & !7
+}
+
+fn possible_cpus() -> u64 {
+    unsafe { libbpf_sys::libbpf_num_possible_cpus() }.max(1) as u64
+}
+
+/// Estimates the size of the preallocated entries of a map.
+pub fn estimate(info: &MapInfo) -> u64 {
+    let entries = info.max_entries as u64;
+    let key = round_up(info.key_size);
+    let value = round_up(info.value_size);
+    match info.ty {
+        BPF_MAP_TYPE_HASH | BPF_MAP_TYPE_LRU_HASH => entries * (HTAB_ELEM_SIZE + key + value),
+        BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_LRU_PERCPU_HASH => {
+            entries * (HTAB_ELEM_SIZE + key + value * possible_cpus())
+        }
+        BPF_MAP_TYPE_PERCPU_ARRAY => entries * value * possible_cpus(),
+        _ => entries * (key + value),
+    }
+}
+
+/// Reads the `memlock` field of the fdinfo of a map.
+fn memlock(fd: RawFd) -> Result<Option<u64>> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))?;
+    for line in fdinfo.lines() {
+        if let Some(value) = line.strip_prefix("memlock:") {
+            return Ok(Some(value.trim().parse()?));
+        }
+    }
+    Ok(None)
+}
+
+/// Table of the memory used by maps with a total.
+pub struct Report<'a>(pub &'a [MapMemory]);
+
+impl<'a> std::fmt::Display for Report<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:16} {:>12} {:>12} {:>12}",
+            "map", "entries", "estimate", "memlock"
+        )?;
+        for map in self.0 {
+            writeln!(
+                f,
+                "{:16} {:>12} {:>12} {:>12}",
+                map.name,
+                map.max_entries,
+                Bytes(map.estimate),
+                map.memlock
+                    .map(|bytes| Bytes(bytes).to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            )?;
+        }
+        let total: u64 = self.0.iter().map(MapMemory::bytes).sum();
+        writeln!(f, "total {}", Bytes(total))
+    }
+}
+
+struct Bytes(u64);
+
+impl std::fmt::Display for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let units = ["B", "KiB", "MiB", "GiB"];
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        let size = if unit == 0 {
+            format!("{}{}", self.0, units[0])
+        } else {
+            format!("{:.1}{}", size, units[unit])
+        };
+        f.pad(&size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        assert_eq!(Bytes(512).to_string(), "512B");
+        assert_eq!(Bytes(1536).to_string(), "1.5KiB");
+        assert_eq!(Bytes(3 << 30).to_string(), "3.0GiB");
+    }
+}
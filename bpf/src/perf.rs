@@ -0,0 +1,267 @@
+//! Consumer of perf event arrays.
+//!
+//! Every cpu gets a ring buffer, which wakes up the consumer after a number
+//! of events or once a number of bytes is pending. Batching the wakeups
+//! reduces the cpu usage of high throughput probes at the cost of latency.
+use anyhow::Result;
+use bpf_utils::sys;
+use perf_event_open_sys::bindings::{self as perf, perf_event_attr, perf_event_mmap_page};
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Wakeup {
+    /// Wakes up after this many events.
+    Events(u32),
+    /// Wakes up once this many bytes are pending.
+    Watermark(u32),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PerfBufferOptions {
+    /// Data pages per cpu, rounded up to a power of two.
+    pub pages: usize,
+    pub wakeup: Wakeup,
+}
+
+impl Default for PerfBufferOptions {
+    fn default() -> Self {
+        Self {
+            pages: 8,
+            wakeup: Wakeup::Events(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PerfEvent<'a> {
+    Sample { cpu: u32, data: &'a [u8] },
+    Lost { cpu: u32, count: u64 },
+}
+
+struct Ring {
+    cpu: u32,
+    fd: RawFd,
+    base: *mut u8,
+    /// Size of the data area.
+    size: usize,
+    page_size: usize,
+}
+
+impl Ring {
+    fn open(cpu: u32, options: &PerfBufferOptions) -> Result<Self> {
+        let mut attr: perf_event_attr = unsafe { std::mem::zeroed() };
+        attr.size = std::mem::size_of::<perf_event_attr>() as _;
+        attr.type_ = perf::perf_type_id_PERF_TYPE_SOFTWARE;
+        attr.config = perf::perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as _;
+        attr.sample_type = perf::perf_event_sample_format_PERF_SAMPLE_RAW as _;
+        attr.__bindgen_anon_1 = perf::perf_event_attr__bindgen_ty_1 { sample_period: 1 };
+        attr.__bindgen_anon_2 = match options.wakeup {
+            Wakeup::Events(events) => perf::perf_event_attr__bindgen_ty_2 {
+                wakeup_events: events.max(1),
+            },
+            Wakeup::Watermark(bytes) => {
+                attr.set_watermark(1);
+                perf::perf_event_attr__bindgen_ty_2 {
+                    wakeup_watermark: bytes.max(1),
+                }
+            }
+        };
+        let fd = unsafe {
+            perf_event_open_sys::perf_event_open(
+                &mut attr,
+                -1,
+                cpu as _,
+                -1,
+                perf::PERF_FLAG_FD_CLOEXEC as _,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = options.pages.max(1).next_power_of_two() * page_size;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size + page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err.into());
+        }
+        Ok(Self {
+            cpu,
+            fd,
+            base: base as *mut u8,
+            size,
+            page_size,
+        })
+    }
+
+    fn header(&self) -> *mut perf_event_mmap_page {
+        self.base as *mut _
+    }
+
+    /// Calls `f` with the pending records, returning their number.
+    fn consume(&mut self, buf: &mut Vec<u8>, f: &mut impl FnMut(PerfEvent)) -> usize {
+        let header = self.header();
+        let head = unsafe { std::ptr::read_volatile(&(*header).data_head) };
+        fence(Ordering::Acquire);
+        let mut tail = unsafe { (*header).data_tail };
+        let data = unsafe { self.base.add(self.page_size) };
+        let mut count = 0;
+        while tail < head {
+            let offset = tail as usize % self.size;
+            // records can wrap around the end of the ring.
+            let read = |buf: &mut Vec<u8>, offset: usize, len: usize| {
+                buf.clear();
+                let first = len.min(self.size - offset);
+                unsafe {
+                    buf.extend_from_slice(std::slice::from_raw_parts(data.add(offset), first));
+                    buf.extend_from_slice(std::slice::from_raw_parts(data, len - first));
+                }
+            };
+            read(buf, offset, 8);
+            let ty = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let len = u16::from_ne_bytes([buf[6], buf[7]]) as usize;
+            read(buf, offset, len);
+            match ty {
+                PERF_RECORD_SAMPLE if len >= 12 => {
+                    let size = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+                    let end = (12 + size).min(len);
+                    f(PerfEvent::Sample {
+                        cpu: self.cpu,
+                        data: &buf[12..end],
+                    });
+                }
+                PERF_RECORD_LOST if len >= 24 => {
+                    let mut lost = [0; 8];
+                    lost.copy_from_slice(&buf[16..24]);
+                    f(PerfEvent::Lost {
+                        cpu: self.cpu,
+                        count: u64::from_ne_bytes(lost),
+                    });
+                }
+                _ => {}
+            }
+            count += 1;
+            tail += len.max(8) as u64;
+        }
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(&mut (*header).data_tail, tail) };
+        count
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            perf_event_open_sys::ioctls::DISABLE(self.fd, 0);
+            libc::munmap(self.base as *mut _, self.size + self.page_size);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Reads the events of a perf event array, valid as long as the map is.
+pub struct PerfBuffer<'a> {
+    rings: Vec<Ring>,
+    epoll: RawFd,
+    buf: Vec<u8>,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> PerfBuffer<'a> {
+    pub(crate) fn new(map_fd: RawFd, options: &PerfBufferOptions) -> Result<Self> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut buffer = Self {
+            rings: vec![],
+            epoll,
+            buf: vec![],
+            _marker: PhantomData,
+        };
+        for cpu in bpf_utils::cpu::online_cpu_ids()? {
+            let ring = Ring::open(cpu, options)?;
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: buffer.rings.len() as u64,
+            };
+            if unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, ring.fd, &mut event) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            sys::map_update_elem(map_fd, &cpu.to_ne_bytes(), &ring.fd.to_ne_bytes())?;
+            if unsafe { perf_event_open_sys::ioctls::ENABLE(ring.fd, 0) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            buffer.rings.push(ring);
+        }
+        Ok(buffer)
+    }
+
+    /// Waits up to `timeout` for a wakeup and passes the pending events to
+    /// `f`, returning the number of events.
+    ///
+    /// Events below the wakeup threshold stay pending until the next wakeup
+    /// or a call to [`PerfBuffer::consume`].
+    pub fn poll(&mut self, timeout: Duration, mut f: impl FnMut(PerfEvent)) -> Result<usize> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; self.rings.len()];
+        let ret = unsafe {
+            libc::epoll_wait(
+                self.epoll,
+                events.as_mut_ptr(),
+                events.len() as _,
+                epoll_timeout(timeout),
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err.into());
+        }
+        let mut count = 0;
+        for event in &events[..ret as usize] {
+            let ring = &mut self.rings[event.u64 as usize];
+            count += ring.consume(&mut self.buf, &mut f);
+        }
+        Ok(count)
+    }
+
+    /// Passes the pending events of all cpus to `f` without waiting.
+    pub fn consume(&mut self, mut f: impl FnMut(PerfEvent)) -> usize {
+        let buf = &mut self.buf;
+        self.rings
+            .iter_mut()
+            .map(|ring| ring.consume(buf, &mut f))
+            .sum()
+    }
+}
+
+impl<'a> Drop for PerfBuffer<'a> {
+    fn drop(&mut self) {
+        self.rings.clear();
+        unsafe { libc::close(self.epoll) };
+    }
+}
+
+/// `timeout` in milliseconds for `epoll_wait`, long timeouts are clamped
+/// instead of wrapping to a negative value, which waits forever.
+pub(crate) fn epoll_timeout(timeout: Duration) -> i32 {
+    timeout.as_millis().min(i32::MAX as u128) as i32
+}
@@ -0,0 +1,39 @@
+//! Consumer of the records of a ring buffer or a perf event array.
+//!
+//! Probes built for kernels without ring buffers write their records to a
+//! perf event array of the same name instead, see the features module.
+use crate::perf::{PerfBuffer, PerfEvent};
+use crate::ringbuf::RingBuffer;
+use anyhow::Result;
+use std::time::Duration;
+
+pub enum Records<'a> {
+    Ring(RingBuffer<'a>),
+    Perf(PerfBuffer<'a>),
+}
+
+impl<'a> Records<'a> {
+    /// Waits up to `timeout` for a wakeup and passes the pending records to
+    /// `f`, returning the number of records.
+    pub fn poll(&mut self, timeout: Duration, mut f: impl FnMut(&[u8])) -> Result<usize> {
+        match self {
+            Self::Ring(ring) => ring.poll(timeout, f),
+            Self::Perf(perf) => perf.poll(timeout, |event| sample(event, &mut f)),
+        }
+    }
+
+    /// Passes the pending records to `f` without waiting.
+    pub fn consume(&mut self, mut f: impl FnMut(&[u8])) -> usize {
+        match self {
+            Self::Ring(ring) => ring.consume(f),
+            Self::Perf(perf) => perf.consume(|event| sample(event, &mut f)),
+        }
+    }
+}
+
+fn sample(event: PerfEvent, f: &mut impl FnMut(&[u8])) {
+    match event {
+        PerfEvent::Sample { data, .. } => f(data),
+        PerfEvent::Lost { cpu, count } => log::warn!("lost {} records on cpu {}", count, cpu),
+    }
+}
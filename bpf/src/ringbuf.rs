@@ -0,0 +1,147 @@
+//! Consumer of ring buffer maps.
+//!
+//! Unlike perf event arrays all cpus share one buffer, so records arrive in
+//! the order they were reserved. The data pages are mapped twice in a row by
+//! the kernel, records wrapping around the end of the buffer are contiguous.
+use anyhow::Result;
+use bpf_utils::sys;
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+const BUSY_BIT: u32 = 1 << 31;
+const DISCARD_BIT: u32 = 1 << 30;
+const HEADER_SIZE: usize = 8;
+
+/// Reads the records of a ring buffer, valid as long as the map is.
+pub struct RingBuffer<'a> {
+    epoll: RawFd,
+    consumer: *mut u8,
+    producer: *mut u8,
+    /// Size of the data area.
+    size: usize,
+    page_size: usize,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> RingBuffer<'a> {
+    pub(crate) fn new(map_fd: RawFd) -> Result<Self> {
+        let size = sys::map_info(map_fd)?.max_entries as usize;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let consumer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                map_fd,
+                0,
+            )
+        };
+        if consumer == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let producer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size + 2 * size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                map_fd,
+                page_size as _,
+            )
+        };
+        if producer == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::munmap(consumer, page_size) };
+            return Err(err.into());
+        }
+        let buffer = Self {
+            epoll: unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) },
+            consumer: consumer as *mut u8,
+            producer: producer as *mut u8,
+            size,
+            page_size,
+            _marker: PhantomData,
+        };
+        if buffer.epoll < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: 0,
+        };
+        if unsafe { libc::epoll_ctl(buffer.epoll, libc::EPOLL_CTL_ADD, map_fd, &mut event) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(buffer)
+    }
+
+    fn position(ptr: *mut u8) -> &'a AtomicU64 {
+        unsafe { &*(ptr as *const AtomicU64) }
+    }
+
+    /// Waits up to `timeout` for a wakeup and passes the pending records to
+    /// `f`, returning the number of records.
+    pub fn poll(&mut self, timeout: Duration, f: impl FnMut(&[u8])) -> Result<usize> {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        let ret = unsafe {
+            libc::epoll_wait(
+                self.epoll,
+                &mut event,
+                1,
+                crate::perf::epoll_timeout(timeout),
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err.into());
+        }
+        Ok(self.consume(f))
+    }
+
+    /// Passes the pending records to `f` without waiting.
+    pub fn consume(&mut self, mut f: impl FnMut(&[u8])) -> usize {
+        let consumer = Self::position(self.consumer);
+        let producer = Self::position(self.producer);
+        let data = unsafe { self.producer.add(self.page_size) };
+        let mut pos = consumer.load(Ordering::Acquire);
+        let mut count = 0;
+        loop {
+            let head = producer.load(Ordering::Acquire);
+            if pos >= head {
+                break;
+            }
+            while pos < head {
+                let record = unsafe { data.add(pos as usize & (self.size - 1)) };
+                let len = unsafe { &*(record as *const AtomicU32) }.load(Ordering::Acquire);
+                // the producer hasn't committed the record yet.
+                if len & BUSY_BIT != 0 {
+                    return count;
+                }
+                let data_len = (len & !(BUSY_BIT | DISCARD_BIT)) as usize;
+                pos += ((data_len + HEADER_SIZE + 7) & !7) as u64;
+                if len & DISCARD_BIT == 0 {
+                    f(unsafe { std::slice::from_raw_parts(record.add(HEADER_SIZE), data_len) });
+                    count += 1;
+                }
+                consumer.store(pos, Ordering::Release);
+            }
+        }
+        count
+    }
+}
+
+impl<'a> Drop for RingBuffer<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.consumer as *mut _, self.page_size);
+            libc::munmap(self.producer as *mut _, self.page_size + 2 * self.size);
+            libc::close(self.epoll);
+        }
+    }
+}
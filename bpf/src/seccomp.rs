@@ -0,0 +1,327 @@
+//! Seccomp filters with user notifications.
+//!
+//! Seccomp filters are classic bpf programs generated from a list of rules.
+//! Syscalls with the [`Action::Notify`] action are passed to a [`Notifier`],
+//! so a supervisor can inspect them next to the data collected by the ebpf
+//! probes. Syscalls are named with the same table as the tracepoints.
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+#[cfg(target_arch = "x86_64")]
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets in `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// Bit set in the numbers of x32 syscalls, which share the x86_64 arch.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_long = 1 << 3;
+const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xc018_2101;
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Log,
+    Errno(u16),
+    Trap,
+    /// Passes the syscall to the [`Notifier`].
+    Notify,
+    KillThread,
+    KillProcess,
+}
+
+impl Action {
+    fn ret(self) -> u32 {
+        match self {
+            Self::Allow => 0x7fff_0000,
+            Self::Log => 0x7ffc_0000,
+            Self::Errno(errno) => 0x0005_0000 | errno as u32,
+            Self::Trap => 0x0003_0000,
+            Self::Notify => 0x7fc0_0000,
+            Self::KillThread => 0,
+            Self::KillProcess => 0x8000_0000,
+        }
+    }
+}
+
+/// `struct sock_filter`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl SockFilter {
+    fn stmt(code: u16, k: u32) -> Self {
+        Self {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+#[derive(Clone, Debug)]
+pub struct SeccompFilter {
+    default: Action,
+    rules: Vec<(u32, Action)>,
+}
+
+impl SeccompFilter {
+    pub fn new(default: Action) -> Self {
+        Self {
+            default,
+            rules: vec![],
+        }
+    }
+
+    pub fn rule(&mut self, nr: u32, action: Action) -> &mut Self {
+        self.rules.push((nr, action));
+        self
+    }
+
+    /// Adds a rule for a syscall named in `table`.
+    pub fn rule_by_name(
+        &mut self,
+        table: &BTreeMap<u32, String>,
+        name: &str,
+        action: Action,
+    ) -> Result<&mut Self> {
+        match table.iter().find(|(_, syscall)| *syscall == name) {
+            Some((nr, _)) => Ok(self.rule(*nr, action)),
+            None => bail!("unknown syscall {}", name),
+        }
+    }
+
+    fn notifies(&self) -> bool {
+        self.default == Action::Notify || self.rules.iter().any(|(_, a)| *a == Action::Notify)
+    }
+
+    /// Generates the filter, syscalls of other architectures and x32
+    /// syscalls kill the process.
+    pub fn compile(&self) -> Vec<SockFilter> {
+        let mut prog = vec![
+            SockFilter::stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+            SockFilter::jump(BPF_JEQ_K, AUDIT_ARCH, 1, 0),
+            SockFilter::stmt(BPF_RET_K, Action::KillProcess.ret()),
+            SockFilter::stmt(BPF_LD_W_ABS, NR_OFFSET),
+        ];
+        // x32 syscalls would otherwise bypass rules on the x86_64 numbers.
+        #[cfg(target_arch = "x86_64")]
+        prog.extend_from_slice(&[
+            SockFilter::jump(BPF_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            SockFilter::stmt(BPF_RET_K, Action::KillProcess.ret()),
+        ]);
+        for (nr, action) in &self.rules {
+            prog.push(SockFilter::jump(BPF_JEQ_K, *nr, 0, 1));
+            prog.push(SockFilter::stmt(BPF_RET_K, action.ret()));
+        }
+        prog.push(SockFilter::stmt(BPF_RET_K, self.default.ret()));
+        prog
+    }
+
+    /// Installs the filter on the calling thread, which also sets
+    /// `no_new_privs`. Returns a notifier if any rule notifies.
+    ///
+    /// The filter is inherited by children and kept across `exec`. When the
+    /// filtered process isn't the supervisor, the notifier can be passed to it
+    /// with `bpf::utils::fdpass`.
+    pub fn install(&self) -> Result<Option<Notifier>> {
+        let prog = self.compile();
+        if prog.len() > u16::MAX as usize {
+            bail!("seccomp filter too large");
+        }
+        let fprog = SockFprog {
+            len: prog.len() as u16,
+            filter: prog.as_ptr(),
+        };
+        let flags = if self.notifies() {
+            SECCOMP_FILTER_FLAG_NEW_LISTENER
+        } else {
+            0
+        };
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let ret = libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                flags,
+                &fprog as *const SockFprog,
+            );
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(if flags != 0 {
+                Some(Notifier(ret as RawFd))
+            } else {
+                None
+            })
+        }
+    }
+}
+
+/// `struct seccomp_notif`
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Notification {
+    pub id: u64,
+    pub pid: u32,
+    pub flags: u32,
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+impl Notification {
+    pub fn syscall<'a>(&self, table: &'a BTreeMap<u32, String>) -> Option<&'a str> {
+        table.get(&(self.nr as u32)).map(|name| name.as_str())
+    }
+}
+
+/// `struct seccomp_notif_resp`
+#[repr(C)]
+struct NotificationResponse {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+/// Receives the syscalls of the [`Action::Notify`] rules.
+pub struct Notifier(RawFd);
+
+impl Notifier {
+    pub fn from_raw_fd(fd: RawFd) -> Self {
+        Self(fd)
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+
+    /// Blocks until a syscall is trapped.
+    pub fn recv(&self) -> Result<Notification> {
+        let mut notif = Notification::default();
+        if unsafe { libc::ioctl(self.0, SECCOMP_IOCTL_NOTIF_RECV, &mut notif as *mut _) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(notif)
+    }
+
+    fn send(&self, resp: NotificationResponse) -> Result<()> {
+        if unsafe { libc::ioctl(self.0, SECCOMP_IOCTL_NOTIF_SEND, &resp as *const _) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Lets the kernel run the syscall.
+    ///
+    /// The syscall runs with the arguments in the memory of the caller at that
+    /// time, which another thread may have changed since they were inspected.
+    /// Allowing a syscall based on pointer arguments, like a path, isn't a
+    /// security check, use [`deny`](Self::deny) or [`reply`](Self::reply)
+    /// after emulating the syscall instead.
+    pub fn allow(&self, notif: &Notification) -> Result<()> {
+        self.send(NotificationResponse {
+            id: notif.id,
+            val: 0,
+            error: 0,
+            flags: SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+        })
+    }
+
+    /// Fails the syscall with `errno`.
+    pub fn deny(&self, notif: &Notification, errno: i32) -> Result<()> {
+        self.send(NotificationResponse {
+            id: notif.id,
+            val: 0,
+            error: -errno,
+            flags: 0,
+        })
+    }
+
+    /// Skips the syscall and returns `val` to the caller.
+    pub fn reply(&self, notif: &Notification, val: i64) -> Result<()> {
+        self.send(NotificationResponse {
+            id: notif.id,
+            val,
+            error: 0,
+            flags: 0,
+        })
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_filter() {
+        let mut filter = SeccompFilter::new(Action::Allow);
+        filter.rule(59, Action::Notify).rule(62, Action::Errno(1));
+        let prog = filter.compile();
+        let rules = prog
+            .iter()
+            .position(|ins| *ins == SockFilter::jump(BPF_JEQ_K, 59, 0, 1))
+            .unwrap();
+        assert_eq!(prog.len(), rules + 5);
+        assert_eq!(prog[rules + 1].k, 0x7fc0_0000);
+        assert_eq!(prog[rules + 3].k, 0x0005_0001);
+        assert_eq!(prog[rules + 4].k, Action::Allow.ret());
+        assert!(filter.notifies());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn kill_x32_syscalls() {
+        let prog = SeccompFilter::new(Action::Allow).compile();
+        assert_eq!(prog[3], SockFilter::stmt(BPF_LD_W_ABS, NR_OFFSET));
+        assert_eq!(prog[4], SockFilter::jump(BPF_JGE_K, 0x4000_0000, 0, 1));
+        assert_eq!(prog[5].k, Action::KillProcess.ret());
+        assert_eq!(prog[6].k, Action::Allow.ret());
+    }
+
+    #[test]
+    fn struct_sizes() {
+        assert_eq!(std::mem::size_of::<Notification>(), 80);
+        assert_eq!(std::mem::size_of::<NotificationResponse>(), 24);
+    }
+}
@@ -0,0 +1,159 @@
+//! Run time statistics of the loaded programs.
+use anyhow::{bail, Result};
+use bpf_utils::sys;
+use std::convert::TryInto;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Keeps stats collection enabled while alive.
+pub(crate) struct StatsGuard(RawFd);
+
+impl StatsGuard {
+    pub fn enable() -> Result<Self> {
+        Ok(Self(sys::enable_stats()?))
+    }
+}
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProgramStats {
+    pub name: String,
+    pub run_cnt: u64,
+    pub run_time_ns: u64,
+}
+
+impl ProgramStats {
+    pub fn new(name: &str, fd: RawFd) -> Result<Self> {
+        let info = sys::prog_info(fd)?;
+        Ok(Self {
+            name: name.to_string(),
+            run_cnt: info.run_cnt,
+            run_time_ns: info.run_time_ns,
+        })
+    }
+
+    /// Average run time in nanoseconds.
+    pub fn avg_ns(&self) -> u64 {
+        self.run_time_ns
+            .checked_div(self.run_cnt)
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: runs={} total={}ns avg={}ns",
+            self.name,
+            self.run_cnt,
+            self.run_time_ns,
+            self.avg_ns()
+        )
+    }
+}
+
+/// Background thread logging the stats of the programs, stopped on drop.
+pub(crate) struct StatsLogger {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatsLogger {
+    /// Logs the stats of `progs` every `interval`.
+    ///
+    /// The programs are reopened by id, so the thread doesn't borrow the
+    /// loaded object.
+    pub fn spawn(progs: Vec<(String, u32)>, interval: Duration) -> Result<Self> {
+        let mut fds = vec![];
+        for (name, id) in progs {
+            match sys::prog_get_fd_by_id(id) {
+                Ok(fd) => fds.push((name, fd)),
+                Err(err) => {
+                    close(&fds);
+                    return Err(err.into());
+                }
+            }
+        }
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                for (name, fd) in &fds {
+                    match ProgramStats::new(name, *fd) {
+                        Ok(stats) => log::info!(target: "bpf::stats", "{}", stats),
+                        Err(err) => log::warn!(target: "bpf::stats", "{}: {}", name, err),
+                    }
+                }
+            }
+            close(&fds);
+        });
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for StatsLogger {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn close(fds: &[(String, RawFd)]) {
+    for (_, fd) in fds {
+        unsafe { libc::close(*fd) };
+    }
+}
+
+/// Name of the map defined by `bpf_helpers::hit_counters!()`.
+pub const HITS_MAP: &str = "BPF_HITS";
+
+/// Sums the per cpu counts of a `BPF_HITS` or `BPF_DIAG` map, returning the
+/// nonzero ones.
+pub(crate) fn hit_counts(fd: RawFd) -> Result<Vec<(u32, u64)>> {
+    let cpus = unsafe { libbpf_sys::libbpf_num_possible_cpus() };
+    if cpus < 0 {
+        bail!("failed to get the number of cpus");
+    }
+    let max_entries = sys::map_info(fd)?.max_entries;
+    let mut value = vec![0u8; cpus as usize * 8];
+    let mut hits = vec![];
+    for line in 0..max_entries {
+        if !sys::map_lookup_elem(fd, &line.to_ne_bytes(), &mut value)? {
+            continue;
+        }
+        let count: u64 = value
+            .chunks_exact(8)
+            .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+            .sum();
+        if count > 0 {
+            hits.push((line, count));
+        }
+    }
+    Ok(hits)
+}
+
+/// Name of the map of `bpf_helpers::diag`.
+pub const DIAG_MAP: &str = "BPF_DIAG";
+
+/// Names of the counters of `bpf_helpers::diag` by index.
+pub const DIAG_COUNTERS: &[&str] = &[
+    "update_full",
+    "update_nomem",
+    "update_failed",
+    "lookup_miss",
+    "delete_miss",
+    "recursion",
+];
@@ -0,0 +1,406 @@
+//! Remote profiling agent.
+//!
+//! `cargo trace serve --listen <addr>` serves a small HTTP API to profile
+//! running processes:
+//!
+//! - `POST /profile/<pid>` starts profiling, the body optionally contains the
+//!   probe and defaults to `profile:hz:99`.
+//! - `GET /profile/<pid>` returns the collapsed stacks sampled so far.
+//! - `DELETE /profile/<pid>` stops profiling and returns the collapsed stacks.
+//! - `GET /profiles` lists the profiled pids and cgroups.
+//!
+//! `/profile/cgroup/<path>` profiles the processes of the cgroup `<path>`
+//! below `/sys/fs/cgroup` instead. They are looked up when profiling starts,
+//! processes added to the cgroup later aren't profiled. The stacks of all
+//! processes are merged.
+//!
+//! Requests are authenticated by an [`Authenticate`] hook, by default a
+//! bearer token read from `CARGO_TRACE_AGENT_TOKEN`. Without a token the
+//! agent only listens on loopback addresses.
+//!
+//! Requests are served one at a time, so a client that stops sending is cut
+//! off after [`TIMEOUT`] and the size of the request is capped.
+use anyhow::{bail, Result};
+use bpf_profiler::Profiler;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_PROBE: &str = "profile:hz:99";
+pub const TOKEN_ENV: &str = "CARGO_TRACE_AGENT_TOKEN";
+/// Mount point of the cgroup v2 hierarchy.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Maximum size of a request body.
+const MAX_BODY: usize = 4096;
+/// Maximum size of the request line and of each header.
+const MAX_LINE: usize = 8192;
+/// Maximum number of headers.
+const MAX_HEADERS: usize = 64;
+/// Read and write timeout of a connection.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn read(stream: &mut impl BufRead) -> Result<Self> {
+        let mut line = String::new();
+        read_line(stream, &mut line)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            if read_line(stream, &mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                bail!("too many request headers");
+            }
+            if let Some(i) = line.find(':') {
+                let (key, value) = line.split_at(i);
+                headers.push((key.trim().to_string(), value[1..].trim().to_string()));
+            }
+        }
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: String::new(),
+        };
+        let len: usize = request
+            .header("content-length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or_default();
+        if len > MAX_BODY {
+            bail!("request body too large");
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body)?;
+        request.body = String::from_utf8(body)?;
+        Ok(request)
+    }
+}
+
+/// Reads a line of at most `MAX_LINE` bytes.
+fn read_line(stream: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let len = stream.take(MAX_LINE as u64).read_line(line)?;
+    if len == MAX_LINE && !line.ends_with('\n') {
+        bail!("request line too long");
+    }
+    Ok(len)
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: body.into(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            409 => "Conflict",
+            500 => "Internal Server Error",
+            _ => "",
+        }
+    }
+
+    fn write(&self, stream: &mut impl Write) -> Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.reason(),
+            self.body.len(),
+            self.body
+        )?;
+        Ok(())
+    }
+}
+
+/// Decides if a request is allowed.
+pub trait Authenticate {
+    fn authenticate(&self, request: &Request) -> bool;
+}
+
+/// Accepts requests with an `Authorization: Bearer <token>` header.
+pub struct BearerToken(pub String);
+
+impl Authenticate for BearerToken {
+    fn authenticate(&self, request: &Request) -> bool {
+        request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| constant_time_eq(token.trim().as_bytes(), self.0.as_bytes()))
+            .unwrap_or_default()
+    }
+}
+
+/// Compares `a` and `b` in a time that only depends on their lengths, so the
+/// token can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Accepts all requests.
+pub struct AllowAll;
+
+impl Authenticate for AllowAll {
+    fn authenticate(&self, _: &Request) -> bool {
+        true
+    }
+}
+
+/// Uses a [`BearerToken`] if `CARGO_TRACE_AGENT_TOKEN` is set, otherwise
+/// accepts all requests if the agent only listens on `addrs` that are
+/// loopback addresses.
+pub fn auth_from_env(addrs: &[SocketAddr]) -> Result<Box<dyn Authenticate>> {
+    match std::env::var(TOKEN_ENV) {
+        Ok(token) if !token.is_empty() => Ok(Box::new(BearerToken(token))),
+        _ => {
+            if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+                bail!(
+                    "{} has to be set to listen on {}, which isn't a loopback address",
+                    TOKEN_ENV,
+                    addr
+                );
+            }
+            log::warn!("{} not set, accepting unauthenticated requests", TOKEN_ENV);
+            Ok(Box::new(AllowAll))
+        }
+    }
+}
+
+/// What a profile is taken of.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Target {
+    Pid(u32),
+    /// A cgroup path relative to [`CGROUP_ROOT`].
+    Cgroup(PathBuf),
+}
+
+impl Target {
+    /// Parses the segments of a path following `/profile/`.
+    fn parse(segments: &[&str]) -> Option<Self> {
+        match segments {
+            [pid] => pid.parse().ok().map(Self::Pid),
+            ["cgroup", path @ ..] => {
+                // the path may not leave the cgroup hierarchy.
+                let valid = |segment: &&str| !matches!(*segment, "" | "." | "..");
+                if path.is_empty() || !path.iter().all(valid) {
+                    return None;
+                }
+                Some(Self::Cgroup(path.iter().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Pids of the processes of the target.
+    fn pids(&self) -> Result<Vec<u32>> {
+        match self {
+            Self::Pid(pid) => Ok(vec![*pid]),
+            Self::Cgroup(path) => cgroup_pids(&Path::new(CGROUP_ROOT).join(path)),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Pid(pid) => write!(f, "{}", pid),
+            Self::Cgroup(path) => write!(f, "cgroup/{}", path.display()),
+        }
+    }
+}
+
+/// Pids listed in the `cgroup.procs` of the cgroup `dir`.
+fn cgroup_pids(dir: &Path) -> Result<Vec<u32>> {
+    let procs = std::fs::read_to_string(dir.join("cgroup.procs"))?;
+    let mut pids = vec![];
+    for line in procs.lines() {
+        pids.push(line.trim().parse()?);
+    }
+    Ok(pids)
+}
+
+pub struct Agent {
+    auth: Box<dyn Authenticate>,
+    profiles: HashMap<Target, Vec<Profiler>>,
+}
+
+impl Agent {
+    pub fn new(auth: Box<dyn Authenticate>) -> Self {
+        Self {
+            auth,
+            profiles: Default::default(),
+        }
+    }
+
+    /// Serves requests one at a time, the loaded probes aren't `Send`.
+    pub fn listen(&mut self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("agent listening on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            if let Err(err) = self.serve(stream?) {
+                log::warn!("{}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let request = Request::read(&mut BufReader::new(&mut stream))?;
+        log::debug!("{} {}", request.method, request.path);
+        let response = if self.auth.authenticate(&request) {
+            self.handle(&request)
+                .unwrap_or_else(|err| Response::new(500, format!("{}\n", err)))
+        } else {
+            Response::new(401, "unauthorized\n")
+        };
+        response.write(&mut stream)
+    }
+
+    pub fn handle(&mut self, request: &Request) -> Result<Response> {
+        let segments: Vec<_> = request.path.trim_matches('/').split('/').collect();
+        let target = match segments.as_slice() {
+            ["profiles"] if request.method == "GET" => {
+                let mut targets: Vec<_> = self.profiles.keys().collect();
+                targets.sort();
+                let mut targets: Vec<_> = targets.iter().map(ToString::to_string).collect();
+                targets.push(String::new());
+                return Ok(Response::new(200, targets.join("\n")));
+            }
+            ["profile", target @ ..] => match Target::parse(target) {
+                Some(target) => target,
+                None => return Ok(Response::new(400, "invalid pid or cgroup\n")),
+            },
+            _ => return Ok(Response::new(404, "not found\n")),
+        };
+        Ok(match request.method.as_str() {
+            "POST" => {
+                if self.profiles.contains_key(&target) {
+                    return Ok(Response::new(409, "already profiling\n"));
+                }
+                let probe = match request.body.trim() {
+                    "" => DEFAULT_PROBE,
+                    probe => probe,
+                };
+                let pids = target.pids()?;
+                if pids.is_empty() {
+                    return Ok(Response::new(404, "no processes to profile\n"));
+                }
+                let mut profiles = vec![];
+                for pid in pids {
+                    profiles.push(Profiler::attach(pid, probe)?);
+                }
+                log::info!("started profiling {} with {}", target, probe);
+                self.profiles.insert(target, profiles);
+                Response::new(200, "")
+            }
+            "GET" => match self.profiles.get_mut(&target) {
+                Some(profiles) => Response::new(200, collapsed(profiles)?),
+                None => Response::new(404, "not profiling\n"),
+            },
+            "DELETE" => match self.profiles.remove(&target) {
+                Some(mut profiles) => {
+                    log::info!("stopped profiling {}", target);
+                    Response::new(200, collapsed(&mut profiles)?)
+                }
+                None => Response::new(404, "not profiling\n"),
+            },
+            _ => Response::new(404, "not found\n"),
+        })
+    }
+}
+
+/// Collapsed stacks of all `profilers`, equal stacks are merged.
+fn collapsed(profilers: &mut [Profiler]) -> Result<String> {
+    let mut lines = vec![];
+    for profiler in profilers {
+        lines.extend(profiler.collapsed()?);
+    }
+    let mut collapsed = bpf_profiler::symbols::fold(lines).join("\n");
+    collapsed.push('\n');
+    Ok(collapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_request() {
+        let raw = "POST /profile/42 HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 13\r\n\r\nprofile:hz:99";
+        let request = Request::read(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/profile/42");
+        assert_eq!(request.body, "profile:hz:99");
+        assert!(BearerToken("secret".into()).authenticate(&request));
+        assert!(!BearerToken("other".into()).authenticate(&request));
+        assert!(!BearerToken("secre".into()).authenticate(&request));
+    }
+
+    #[test]
+    fn reject_oversized_requests() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert!(Request::read(&mut long.as_bytes()).is_err());
+        let many = format!("GET /profiles HTTP/1.1\r\n{}\r\n", "a: b\r\n".repeat(100));
+        assert!(Request::read(&mut many.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_target() {
+        assert_eq!(Target::parse(&["42"]), Some(Target::Pid(42)));
+        assert_eq!(
+            Target::parse(&["cgroup", "system.slice", "app.service"]),
+            Some(Target::Cgroup("system.slice/app.service".into()))
+        );
+        assert_eq!(Target::parse(&["cgroup"]), None);
+        assert_eq!(Target::parse(&["cgroup", "..", "etc"]), None);
+        assert_eq!(Target::parse(&["cgroup", "a", "", "b"]), None);
+        assert_eq!(Target::parse(&["a42"]), None);
+    }
+
+    #[test]
+    fn token_required_off_loopback() {
+        std::env::remove_var(TOKEN_ENV);
+        let loopback = [
+            "127.0.0.1:7878".parse().unwrap(),
+            "[::1]:7878".parse().unwrap(),
+        ];
+        assert!(auth_from_env(&loopback).is_ok());
+        assert!(auth_from_env(&["0.0.0.0:7878".parse().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Command line of cargo-trace.
+//!
+//! The subcommands, their flags and the files they write are a stable
+//! contract, incompatible changes to the outputs bump
+//! [`OUTPUT_SCHEMA_VERSION`].
+use crate::compress::Compression;
+use crate::config::Config;
+use crate::filter::Rule;
+use crate::pod::Pod;
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::clap::{AppSettings, ArgMatches, Shell};
+use structopt::StructOpt;
+
+/// Version of the formats of `collapsed.txt`, `trace.profile`,
+/// `memory-collapsed.txt` and the responses of `serve`.
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "cargo-trace",
+    bin_name = "cargo trace",
+    about = "Oscilloscope for rust programs.",
+    global_settings = &[AppSettings::VersionlessSubcommands],
+)]
+pub struct Cli {
+    /// Prints the version of the output formats and exits.
+    #[structopt(long)]
+    pub output_schema_version: bool,
+    /// Prints the kernel features the probes use or fall back from and exits.
+    #[structopt(long)]
+    pub min_kernel_report: bool,
+    #[structopt(subcommand)]
+    pub cmd: Option<Cmd>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    /// Builds and traces a cargo target, cargo options follow `--`.
+    Run {
+        #[structopt(flatten)]
+        opts: TraceOpts,
+        /// Probe like `profile:hz:99`, defaults to the config.
+        probe: Option<String>,
+        #[structopt(last = true)]
+        cargo_args: Vec<String>,
+    },
+    /// Traces a running process until enter is pressed.
+    Attach {
+        #[structopt(flatten)]
+        opts: TraceOpts,
+        #[structopt(flatten)]
+        target: Target,
+        /// Probe like `profile:hz:99`, defaults to the config.
+        probe: Option<String>,
+    },
+    /// Writes the collapsed stacks and the flamegraph of a profile.
+    Report {
+        profile: PathBuf,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
+    /// Writes a differential flamegraph of two profiles to `diff.svg`.
+    Diff {
+        before: PathBuf,
+        after: PathBuf,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
+    /// Sums the profiles of several runs or hosts.
+    Merge {
+        #[structopt(required = true)]
+        profiles: Vec<PathBuf>,
+        #[structopt(short, long, default_value = "merged.profile")]
+        output: PathBuf,
+    },
+    /// Prints the stack of every thread of a running process once.
+    Snapshot {
+        #[structopt(flatten)]
+        target: Target,
+        /// Saves the registers and stacks to the directory instead, for
+        /// `check-unwind`.
+        #[structopt(long, value_name = "dir")]
+        save: Option<PathBuf>,
+    },
+    /// Compares the stacks of saved snapshots unwound with the unwind table
+    /// and with a reference unwinder.
+    CheckUnwind {
+        #[structopt(required = true)]
+        snapshots: Vec<PathBuf>,
+    },
+    /// Shows the functions a running process spends its time in, see the top
+    /// module.
+    Top {
+        #[structopt(flatten)]
+        target: Target,
+        /// Probe like `profile:hz:99`, defaults to the config.
+        probe: Option<String>,
+        /// Time between redraws.
+        #[structopt(long, default_value = "2s", parse(try_from_str = crate::stall::parse_duration))]
+        interval: Duration,
+        /// Number of functions shown.
+        #[structopt(short, default_value = "20")]
+        n: usize,
+    },
+    /// Reports threads blocked for longer than the threshold.
+    Stall {
+        #[structopt(flatten)]
+        target: Target,
+        #[structopt(long, default_value = "5s", parse(try_from_str = crate::stall::parse_duration))]
+        threshold: Duration,
+    },
+    /// Controls profiling over http, see the agent module.
+    #[structopt(alias = "agent")]
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+    },
+    /// Prints the completions of a shell.
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct TraceOpts {
+    /// Logs the run time of the probe every second.
+    #[structopt(long)]
+    pub probe_stats: bool,
+    /// Prints the kernel memory used by the unwind tables.
+    #[structopt(long)]
+    pub show_memory: bool,
+    /// Samples for the given number of milliseconds before loading the
+    /// unwind table of the code that ran.
+    #[structopt(long, value_name = "ms")]
+    pub trim: Option<u64>,
+    /// Only loads the probe as root, see the privsep module.
+    #[structopt(long)]
+    pub privsep: bool,
+    /// Logs the maps created, programs loaded and probes attached to the
+    /// `bpf::audit` log target.
+    #[structopt(long)]
+    pub audit: bool,
+    /// Only samples between calls to the start and stop marker functions.
+    #[structopt(long, require_equals = true, value_name = "start,stop")]
+    pub markers: Option<Option<String>>,
+    /// Reports the time spent in direct reclaim and major faults.
+    #[structopt(long)]
+    pub memory: bool,
+    /// Records the gpu jobs of the target on driver tracepoints and writes
+    /// them to `gpu-timeline.json`, see the gpu module of bpf-profiler.
+    #[structopt(long, require_equals = true, value_name = "tracepoints")]
+    pub gpu: Option<Option<String>>,
+    /// Splits the stacks by numa node and counts cpu migrations.
+    #[structopt(long)]
+    pub numa: bool,
+    /// Reloads the unwind table when the target loads or unloads modules or
+    /// execs another binary.
+    #[structopt(long)]
+    pub follow_mappings: bool,
+    /// Counts the package energy and writes the stacks weighted by their
+    /// estimated share of it.
+    #[structopt(long)]
+    pub energy: bool,
+    #[structopt(flatten)]
+    pub output: OutputOpts,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct OutputOpts {
+    /// Compresses the outputs with gzip or zstd.
+    #[structopt(long)]
+    pub compress: Option<Compression>,
+    /// Reads the defaults from a config other than `trace.toml`.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+    /// Removes the matching frames from the flamegraph.
+    #[structopt(long, number_of_values = 1, value_name = "pattern")]
+    pub hide: Vec<String>,
+    /// Merges runs of matching frames into one frame.
+    #[structopt(long, number_of_values = 1, value_name = "pattern")]
+    pub collapse: Vec<String>,
+    /// Starts the stacks at the outermost matching frame.
+    #[structopt(long, number_of_values = 1, value_name = "pattern")]
+    pub root: Vec<String>,
+}
+
+impl OutputOpts {
+    pub fn compression(&self, config: &Config) -> Result<Compression> {
+        match self.compress {
+            Some(compression) => Ok(compression),
+            None => config.compression(),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Target {
+    #[structopt(long, required_unless = "pod")]
+    pub pid: Option<u32>,
+    /// Kubernetes pod as `<namespace>/<name>`.
+    #[structopt(long)]
+    pub pod: Option<Pod>,
+    /// Container of a pod with several containers.
+    #[structopt(long, requires = "pod")]
+    pub container: Option<String>,
+}
+
+impl Target {
+    pub fn pid(&self) -> Result<u32> {
+        match (&self.pod, self.pid) {
+            (Some(pod), _) => {
+                let pid = pod.pid(self.container.as_deref())?;
+                log::info!("tracing pod {} with pid {}", pod, pid);
+                Ok(pid)
+            }
+            (None, Some(pid)) => Ok(pid),
+            (None, None) => anyhow::bail!("needs --pid <pid> or --pod"),
+        }
+    }
+}
+
+/// Arguments without the `trace` cargo passes to subcommands.
+pub fn args() -> Vec<String> {
+    let mut args: Vec<_> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("trace") {
+        args.remove(1);
+    }
+    args
+}
+
+/// Filter rules of the subcommand in the order they were given.
+pub fn rules(matches: &ArgMatches) -> Vec<Rule> {
+    let matches = match matches.subcommand() {
+        (_, Some(matches)) => matches,
+        _ => return vec![],
+    };
+    let mut rules = vec![];
+    for flag in &["hide", "collapse", "root"] {
+        if let (Some(indices), Some(values)) = (matches.indices_of(flag), matches.values_of(flag)) {
+            for (i, pattern) in indices.zip(values) {
+                let rule = Rule::from_flag(&format!("--{}", flag), pattern);
+                rules.extend(rule.map(|rule| (i, rule)));
+            }
+        }
+    }
+    rules.sort_by_key(|(i, _)| *i);
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> (Cli, Vec<Rule>) {
+        let matches = Cli::clap().get_matches_from(args);
+        (Cli::from_clap(&matches), rules(&matches))
+    }
+
+    #[test]
+    fn run_args() {
+        let (cli, rules) = parse(&[
+            "cargo-trace",
+            "run",
+            "--root",
+            "main",
+            "--markers",
+            "--hide=std::*",
+            "--collapse",
+            "tokio::*",
+            "profile:hz:99",
+            "--",
+            "--release",
+        ]);
+        assert_eq!(
+            rules,
+            vec![
+                Rule::Root("main".into()),
+                Rule::Hide("std::*".into()),
+                Rule::Collapse("tokio::*".into()),
+            ]
+        );
+        match cli.cmd {
+            Some(Cmd::Run {
+                opts,
+                probe,
+                cargo_args,
+            }) => {
+                assert_eq!(opts.markers, Some(None));
+                assert_eq!(probe.as_deref(), Some("profile:hz:99"));
+                assert_eq!(cargo_args, vec!["--release"]);
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn target_args() {
+        assert!(Cli::clap()
+            .get_matches_from_safe(&["cargo-trace", "snapshot"])
+            .is_err());
+        let (cli, _) = parse(&["cargo-trace", "stall", "--pid", "42", "--threshold", "2s"]);
+        match cli.cmd {
+            Some(Cmd::Stall { target, threshold }) => {
+                assert_eq!(target.pid, Some(42));
+                assert_eq!(threshold, Duration::from_secs(2));
+            }
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Compressed output files.
+//!
+//! The compression is chosen by the file extension, `.gz` and `.svgz` are
+//! gzip and `.zst` is zstd.
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") | Some("svgz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// Appends the extension of the compression to `path`.
+    pub fn path(self, path: &str) -> PathBuf {
+        match self {
+            Self::None => path.into(),
+            Self::Gzip => format!("{}.gz", path).into(),
+            Self::Zstd => format!("{}.zst", path).into(),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "gzip" | "gz" => Self::Gzip,
+            "zstd" | "zst" => Self::Zstd,
+            _ => anyhow::bail!("unknown compression {}", s),
+        })
+    }
+}
+
+/// Compressed output file.
+///
+/// Errors of the last writes only surface in [`finish`](Self::finish), the
+/// file is truncated if it's dropped without.
+pub enum Writer {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Writer {
+    /// Writes the end of the compressed stream and flushes the file.
+    pub fn finish(self) -> Result<()> {
+        let mut f = match self {
+            Self::Plain(f) => f,
+            Self::Gzip(gz) => gz.finish()?,
+            Self::Zstd(zstd) => zstd.finish()?,
+        };
+        f.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(f) => f.write(buf),
+            Self::Gzip(gz) => gz.write(buf),
+            Self::Zstd(zstd) => zstd.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(f) => f.flush(),
+            Self::Gzip(gz) => gz.flush(),
+            Self::Zstd(zstd) => zstd.flush(),
+        }
+    }
+}
+
+/// Creates `path`, compressing writes according to its extension.
+pub fn create(path: &Path) -> Result<Writer> {
+    let f = BufWriter::new(File::create(path)?);
+    Ok(match Compression::from_path(path) {
+        Compression::None => Writer::Plain(f),
+        Compression::Gzip => Writer::Gzip(flate2::write::GzEncoder::new(
+            f,
+            flate2::Compression::default(),
+        )),
+        Compression::Zstd => Writer::Zstd(zstd::Encoder::new(f, 0)?),
+    })
+}
+
+/// Opens `path`, decompressing reads according to its extension.
+pub fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let f = BufReader::new(File::open(path)?);
+    Ok(match Compression::from_path(path) {
+        Compression::None => Box::new(f),
+        Compression::Gzip => Box::new(BufReader::new(flate2::bufread::GzDecoder::new(f))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(f)?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_from_path() {
+        let path = Compression::Gzip.path("collapsed.txt");
+        assert_eq!(path, Path::new("collapsed.txt.gz"));
+        assert_eq!(Compression::from_path(&path), Compression::Gzip);
+        assert_eq!(
+            Compression::from_path(Path::new("flamegraph.svgz")),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("collapsed.txt")),
+            Compression::None
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Default options from `trace.toml`.
+//!
+//! The config is read from `--config=<path>` or from `trace.toml` in the
+//! current directory, flags given on the command line take precedence.
+//!
+//! ```toml
+//! frequency = 99
+//! # probe = "kprobe:finish_task_switch"
+//! compress = "zstd"
+//! filters = ["root=main", "collapse=tokio::runtime::*", "hide=std::*"]
+//! outputs = ["flamegraph", "profile"]
+//! symbol-paths = ["/usr/lib/debug"]
+//! ```
+use crate::compress::Compression;
+use crate::filter::Rule;
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PATH: &str = "trace.toml";
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// `collapsed.txt`
+    Collapsed,
+    /// `flamegraph.svg`
+    Flamegraph,
+    /// `trace.profile`
+    Profile,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Sampling frequency in hertz when no probe is given.
+    pub frequency: u64,
+    /// Probe used when none is given, overrides the frequency.
+    pub probe: Option<String>,
+    pub compress: Option<String>,
+    /// Rules like `hide=<pattern>`, applied before the rules of the command
+    /// line.
+    pub filters: Vec<String>,
+    pub outputs: Vec<Output>,
+    /// Directories searched for separate debug info.
+    pub symbol_paths: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frequency: 99,
+            probe: None,
+            compress: None,
+            filters: vec![],
+            outputs: vec![Output::Collapsed, Output::Flamegraph, Output::Profile],
+            symbol_paths: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or `trace.toml` if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_PATH).exists() => Path::new(DEFAULT_PATH),
+            None => return Ok(Self::default()),
+        };
+        let s = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+        let config = s
+            .parse()
+            .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+        log::debug!("loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    pub fn probe(&self) -> String {
+        match &self.probe {
+            Some(probe) => probe.clone(),
+            None => format!("profile:hz:{}", self.frequency),
+        }
+    }
+
+    pub fn compression(&self) -> Result<Compression> {
+        match &self.compress {
+            Some(compress) => compress.parse(),
+            None => Ok(Compression::None),
+        }
+    }
+
+    pub fn rules(&self) -> Result<Vec<Rule>> {
+        self.filters
+            .iter()
+            .map(|filter| {
+                let mut iter = filter.splitn(2, '=');
+                let flag = format!("--{}", iter.next().unwrap_or_default());
+                iter.next()
+                    .and_then(|pattern| Rule::from_flag(&flag, pattern))
+                    .ok_or_else(|| anyhow::anyhow!("invalid filter {}", filter))
+            })
+            .collect()
+    }
+
+    /// Makes the symbol paths visible to the symbolization of `bpf`.
+    pub fn set_symbol_paths(&self) -> Result<()> {
+        if !self.symbol_paths.is_empty() {
+            let paths = std::env::join_paths(&self.symbol_paths)?;
+            std::env::set_var(bpf::utils::DEBUG_PATH_ENV, paths);
+        }
+        Ok(())
+    }
+
+    pub fn has_output(&self, output: Output) -> bool {
+        self.outputs.contains(&output)
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = r#"
+            frequency = 999
+            compress = "zstd"
+            filters = ["root=main", "hide=std::*"]
+            outputs = ["flamegraph"]
+            symbol-paths = ["/usr/lib/debug"]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.probe(), "profile:hz:999");
+        assert_eq!(config.compression().unwrap(), Compression::Zstd);
+        assert_eq!(
+            config.rules().unwrap(),
+            vec![Rule::Root("main".into()), Rule::Hide("std::*".into())]
+        );
+        assert!(config.has_output(Output::Flamegraph));
+        assert!(!config.has_output(Output::Profile));
+        assert_eq!(config.symbol_paths, vec![PathBuf::from("/usr/lib/debug")]);
+
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config, Config::default());
+        assert!("frequncy = 99".parse::<Config>().is_err());
+        assert!(Config {
+            filters: vec!["drop=main".into()],
+            ..Default::default()
+        }
+        .rules()
+        .is_err());
+    }
+}
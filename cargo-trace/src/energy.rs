@@ -0,0 +1,133 @@
+//! Energy attributed to the stacks of the target.
+//!
+//! The package energy of the `power` pmu, the rapl counters of intel and amd
+//! cpus, is counted system wide while the target is sampled. It can't be
+//! sampled, so the target is charged the share of the energy that matches
+//! its share of the busy cpu time, the sampled time over the busy time of all
+//! cpus in `/proc/stat`, and every stack the share of the samples it has.
+//! It's an estimate, the power drawn by a core depends on the code it runs.
+use crate::compress::{self, Compression};
+use crate::profile::split_line;
+use anyhow::{Context, Result};
+use bpf::pmu::Counter;
+use inferno::flamegraph::{self, Options};
+use std::io::Write;
+
+pub const DEVICE: &str = "power";
+pub const EVENT: &str = "energy-pkg";
+
+pub struct Energy {
+    counter: Counter,
+    joules: f64,
+    busy: f64,
+}
+
+impl Energy {
+    /// Starts counting the package energy.
+    pub fn start() -> Result<Self> {
+        let counter = Counter::open(DEVICE, EVENT)
+            .context("--energy needs the rapl counters of the power pmu")?;
+        Ok(Self {
+            joules: counter.read()?,
+            busy: busy_seconds()?,
+            counter,
+        })
+    }
+
+    /// Prints the energy of the package and the target and writes the stacks
+    /// weighted by microjoules to `energy-collapsed.txt` and the energy
+    /// flamegraph if `flamegraph` is set.
+    pub fn report(
+        self,
+        lines: &[String],
+        frequency: f64,
+        flamegraph: bool,
+        compression: Compression,
+    ) -> Result<()> {
+        let joules = self.counter.read()? - self.joules;
+        let busy = busy_seconds()? - self.busy;
+        let samples: f64 = lines
+            .iter()
+            .filter_map(|line| split_line(line))
+            .map(|(_, n)| n)
+            .sum();
+        let share = if busy > 0.0 {
+            (samples / frequency / busy).min(1.0)
+        } else {
+            0.0
+        };
+        let target = joules * share;
+        println!(
+            "energy: {:.2}J package, {:.2}J target ({:.0}% of the busy cpu time)",
+            joules,
+            target,
+            share * 100.0
+        );
+        let microjoules = if samples > 0.0 {
+            target * 1e6 / samples
+        } else {
+            0.0
+        };
+        let lines: Vec<_> = lines
+            .iter()
+            .filter_map(|line| split_line(line))
+            .map(|(stack, n)| format!("{} {}", stack, (n * microjoules).round() as u64))
+            .collect();
+
+        let mut f = compress::create(&compression.path("energy-collapsed.txt"))?;
+        for line in &lines {
+            writeln!(f, "{}", line)?;
+        }
+        f.finish()?;
+        if !flamegraph {
+            return Ok(());
+        }
+        let svg_path = match compression {
+            Compression::Gzip => "energy.svgz".into(),
+            compression => compression.path("energy.svg"),
+        };
+        let mut f = compress::create(&svg_path)?;
+        let mut options = Options::default();
+        options.title = "energy".into();
+        options.count_name = "uJ".into();
+        flamegraph::from_lines(&mut options, lines.iter().map(|s| s.as_str()), &mut f)?;
+        f.finish()
+    }
+}
+
+/// Busy time of all cpus in seconds.
+fn busy_seconds() -> Result<f64> {
+    let stat = std::fs::read_to_string("/proc/stat")?;
+    let ticks = busy_ticks(&stat).context("invalid /proc/stat")?;
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    Ok(ticks as f64 / hz as f64)
+}
+
+/// Sums the `user`, `nice`, `system`, `irq`, `softirq` and `steal` ticks of
+/// the `cpu` line, `idle` and `iowait` aren't busy.
+fn busy_ticks(stat: &str) -> Option<u64> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let ticks: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    Some(
+        [0, 1, 2, 5, 6, 7]
+            .iter()
+            .filter_map(|i| ticks.get(*i))
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_stat_busy_ticks() {
+        let stat = "cpu  10 2 30 400 50 6 7 8 0 0\ncpu0 5 1 15 200 25 3 3 4 0 0\n";
+        assert_eq!(busy_ticks(stat), Some(10 + 2 + 30 + 6 + 7 + 8));
+        assert_eq!(busy_ticks("intr 1"), None);
+    }
+}
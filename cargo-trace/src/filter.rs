@@ -0,0 +1,132 @@
+//! Rules hiding and merging frames of the symbolized stacks.
+//!
+//! Patterns match whole symbols, `*` matches any number of characters. The
+//! rules apply in the order given on the command line.
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Rule {
+    /// Removes the matching frames.
+    Hide(String),
+    /// Replaces runs of matching frames with the pattern.
+    Collapse(String),
+    /// Removes the frames above the outermost matching frame.
+    Root(String),
+}
+
+impl Rule {
+    pub fn from_flag(flag: &str, pattern: &str) -> Option<Self> {
+        let pattern = pattern.to_string();
+        Some(match flag {
+            "--hide" => Self::Hide(pattern),
+            "--collapse" => Self::Collapse(pattern),
+            "--root" => Self::Root(pattern),
+            _ => return None,
+        })
+    }
+
+    /// Applies the rule to the frames of a stack, outermost frame first.
+    pub fn apply(&self, frames: &mut Vec<String>) {
+        match self {
+            Self::Hide(pattern) => frames.retain(|frame| !glob(pattern, frame)),
+            Self::Collapse(pattern) => {
+                frames.dedup_by(|frame, prev| glob(pattern, frame) && glob(pattern, prev));
+                for frame in frames.iter_mut() {
+                    if glob(pattern, frame) {
+                        *frame = pattern.clone();
+                    }
+                }
+            }
+            Self::Root(pattern) => {
+                if let Some(i) = frames.iter().position(|frame| glob(pattern, frame)) {
+                    frames.drain(..i);
+                }
+            }
+        }
+    }
+}
+
+pub fn glob(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !s.starts_with(first) {
+        return false;
+    }
+    let mut rest = &s[first.len()..];
+    let parts: Vec<_> = parts.collect();
+    if let Some((last, middle)) = parts.split_last() {
+        for part in middle {
+            match rest.find(part) {
+                Some(i) => rest = &rest[(i + part.len())..],
+                None => return false,
+            }
+        }
+        rest.len() >= last.len() && rest.ends_with(last)
+    } else {
+        rest.is_empty()
+    }
+}
+
+/// Applies the rules to collapsed stacks, summing the stacks that became
+/// equal.
+pub fn filter_lines(rules: &[Rule], lines: Vec<String>) -> Vec<String> {
+    if rules.is_empty() {
+        return lines;
+    }
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for line in &lines {
+        let mut iter = line.rsplitn(2, ' ');
+        let count: u64 = match iter.next().and_then(|count| count.parse().ok()) {
+            Some(count) => count,
+            None => continue,
+        };
+        let mut frames: Vec<_> = iter
+            .next()
+            .unwrap_or_default()
+            .split(';')
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| frame.to_string())
+            .collect();
+        for rule in rules {
+            rule.apply(&mut frames);
+        }
+        *stacks.entry(frames.join(";")).or_default() += count;
+    }
+    stacks
+        .into_iter()
+        .map(|(stack, count)| format!("{} {}", stack, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob("std::*", "std::thread::spawn"));
+        assert!(!glob("std::*", "core::ptr::drop"));
+        assert!(glob("*::poll", "tokio::task::poll"));
+        assert!(glob("tokio::*::run*", "tokio::runtime::run_task"));
+        assert!(glob("main", "main"));
+        assert!(!glob("main", "main2"));
+        assert!(!glob("a*a", "a"));
+    }
+
+    #[test]
+    fn filter_rules() {
+        let lines = vec![
+            "_start;main;tokio::runtime::a;tokio::runtime::b;app::work;std::alloc 3".to_string(),
+            "_start;main;tokio::runtime::c;app::work 2".to_string(),
+        ];
+        let rules = vec![
+            Rule::Root("main".into()),
+            Rule::Collapse("tokio::runtime::*".into()),
+            Rule::Hide("std::*".into()),
+        ];
+        assert_eq!(
+            filter_lines(&rules, lines),
+            vec!["main;tokio::runtime::*;app::work 5"]
+        );
+    }
+}
@@ -1,152 +1,405 @@
+use crate::cli::Cmd;
+use crate::compress::Compression;
 use anyhow::Result;
-use bpf::utils::{ehframe, sudo, BinaryInfo};
-use bpf::{BpfBuilder, Probe, ProgramType, I64, U32, U64};
+use bpf::utils::{sudo, BinaryInfo};
+use bpf_profiler::{
+    collapse, gpu, grow, marker_probes, numa, privsep, symbols, trace_trimmed, unwind_rows,
+    validate, Trace, DEFAULT_MARKERS,
+};
 use cargo_subcommand::Subcommand;
+use inferno::differential;
 use inferno::flamegraph::{self, Options};
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
+use std::net::ToSocketAddrs;
 use std::process::Command;
-use zerocopy::{AsBytes, FromBytes, Unaligned};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
 
-static PROBE: &[u8] = include_bytes!(concat!(
-    env!("OUT_DIR"),
-    "/target/bpf/programs/cargo-trace-probe/cargo-trace-probe.elf",
-));
+mod agent;
+mod cli;
+mod compress;
+mod config;
+mod energy;
+mod filter;
+mod memory;
+mod pod;
+mod profile;
+mod snapshot;
+mod stall;
+mod timeline;
+mod top;
 
-#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
-#[repr(C)]
-pub struct Instruction {
-    op: U64,
-    offset: I64,
-}
-
-impl From<ehframe::Instruction> for Instruction {
-    fn from(ins: ehframe::Instruction) -> Self {
-        Self {
-            op: U64::new(match (ins.op(), ins.reg()) {
-                (ehframe::Op::CfaOffset, None) => 1,
-                (ehframe::Op::Register, Some(ehframe::Reg::Rip)) => 2,
-                (ehframe::Op::Register, Some(ehframe::Reg::Rsp)) => 3,
-                _ => 0,
-            }),
-            offset: I64::new(ins.offset().unwrap_or_default()),
+fn main() -> Result<()> {
+    env_logger::init();
+    privsep::run_if_helper();
+    let matches = cli::Cli::clap().get_matches_from(cli::args());
+    let rules = cli::rules(&matches);
+    let cli = cli::Cli::from_clap(&matches);
+    if cli.output_schema_version {
+        println!("{}", cli::OUTPUT_SCHEMA_VERSION);
+        return Ok(());
+    }
+    if cli.min_kernel_report {
+        print!("{}", bpf::features::Features::detect()?);
+        return Ok(());
+    }
+    let cmd = match cli.cmd {
+        Some(cmd) => cmd,
+        None => anyhow::bail!("missing subcommand, see `cargo trace --help`"),
+    };
+    match cmd {
+        Cmd::Run {
+            opts,
+            probe,
+            cargo_args,
+        } => {
+            let config = config::Config::load(opts.output.config.as_deref())?;
+            let probe = probe.unwrap_or_else(|| config.probe());
+            let args = vec!["cargo-trace".to_string(), "trace".to_string(), probe];
+            let cmd =
+                Subcommand::new(args.into_iter().chain(cargo_args), "trace", |_, _| Ok(true))?;
+            if sudo::check() == sudo::RunningAs::User {
+                let status = Command::new("cargo")
+                    .arg("build")
+                    .args(cmd.args())
+                    .spawn()?
+                    .wait()?;
+                if !status.success() {
+                    std::process::exit(status.code().unwrap());
+                }
+            }
+            let uid = unsafe { libc::getuid() };
+            if !opts.privsep {
+                sudo::with_env(&["RUST_LOG"]).unwrap();
+            }
+            config.set_symbol_paths()?;
+            let info = BinaryInfo::from_cargo_subcommand(&cmd)?;
+            trace(info, cmd.cmd(), &opts, &config, rules, uid)
+        }
+        Cmd::Attach {
+            opts,
+            target,
+            probe,
+        } => {
+            let config = config::Config::load(opts.output.config.as_deref())?;
+            let probe = probe.unwrap_or_else(|| config.probe());
+            let uid = unsafe { libc::getuid() };
+            if !opts.privsep {
+                sudo::with_env(&["RUST_LOG", "CONTAINER_RUNTIME_ENDPOINT"]).unwrap();
+            }
+            config.set_symbol_paths()?;
+            let info = BinaryInfo::attach(target.pid()?)?;
+            trace(info, &probe, &opts, &config, rules, uid)
+        }
+        Cmd::Report { profile, output } => {
+            let config = config::Config::load(output.config.as_deref())?;
+            let compression = output.compression(&config)?;
+            let lines = profile::Profile::read(&profile)?.lines();
+            let rules = [config.rules()?, rules].concat();
+            let lines = filter::filter_lines(&rules, lines);
+            let title = profile.display().to_string();
+            write_outputs(&config, &lines, title, compression)
+        }
+        Cmd::Diff {
+            before,
+            after,
+            output,
+        } => {
+            let config = config::Config::load(output.config.as_deref())?;
+            let compression = output.compression(&config)?;
+            let rules = [config.rules()?, rules].concat();
+            let collapsed = |path: &std::path::Path| -> Result<Vec<u8>> {
+                let lines = profile::Profile::read(path)?.lines();
+                Ok(filter::filter_lines(&rules, lines).join("\n").into_bytes())
+            };
+            let mut folded = vec![];
+            let mut options = differential::Options::default();
+            // the runs usually differ in length.
+            options.normalize = true;
+            differential::from_readers(
+                options,
+                &collapsed(&before)?[..],
+                &collapsed(&after)?[..],
+                &mut folded,
+            )?;
+            let svg_path = match compression {
+                Compression::Gzip => "diff.svgz".into(),
+                compression => compression.path("diff.svg"),
+            };
+            let mut options = Options::default();
+            options.title = format!("{} vs {}", before.display(), after.display());
+            let lines = String::from_utf8(folded)?;
+            let mut f = compress::create(&svg_path)?;
+            flamegraph::from_lines(&mut options, lines.lines(), &mut f)?;
+            f.finish()
+        }
+        Cmd::Merge { profiles, output } => {
+            let profiles = profiles
+                .iter()
+                .map(|path| profile::Profile::read(path))
+                .collect::<Result<Vec<_>>>()?;
+            let merged = profile::merge(&profiles);
+            merged.write(&output)?;
+            println!(
+                "merged {} profiles into {}",
+                profiles.len(),
+                output.display()
+            );
+            Ok(())
+        }
+        Cmd::Snapshot { target, save } => {
+            sudo::with_env(&["RUST_LOG", "CONTAINER_RUNTIME_ENDPOINT"]).unwrap();
+            let pid = target.pid()?;
+            let info = BinaryInfo::attach(pid)?;
+            if let Some(dir) = save {
+                return snapshot::save(&info, pid, &dir);
+            }
+            let rows = unwind_rows(&info)?;
+            snapshot::print(&info, &snapshot::capture(pid, &rows)?)
+        }
+        Cmd::CheckUnwind { snapshots } => snapshot::check(&snapshots),
+        Cmd::Top {
+            target,
+            probe,
+            interval,
+            n,
+        } => {
+            let config = config::Config::load(None)?;
+            let probe = probe.unwrap_or_else(|| config.probe());
+            sudo::with_env(&["RUST_LOG", "CONTAINER_RUNTIME_ENDPOINT"]).unwrap();
+            config.set_symbol_paths()?;
+            top::run(target.pid()?, &probe, interval, n)
+        }
+        Cmd::Stall { target, threshold } => {
+            sudo::with_env(&["RUST_LOG", "CONTAINER_RUNTIME_ENDPOINT"]).unwrap();
+            let info = BinaryInfo::attach(target.pid()?)?;
+            let rows = unwind_rows(&info)?;
+            stall::watch(&info, &rows, threshold)
+        }
+        Cmd::Serve { listen } => {
+            sudo::with_env(&["RUST_LOG", agent::TOKEN_ENV]).unwrap();
+            let addrs: Vec<_> = listen.to_socket_addrs()?.collect();
+            agent::Agent::new(agent::auth_from_env(&addrs)?).listen(&addrs[..])
+        }
+        Cmd::Completions { shell } => {
+            cli::Cli::clap().gen_completions_to("cargo-trace", shell, &mut std::io::stdout());
+            Ok(())
         }
     }
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let args = std::env::args();
-    let cmd = Subcommand::new(args, "trace", |_, _| Ok(true))?;
-    if sudo::check() == sudo::RunningAs::User {
-        let status = Command::new("cargo")
-            .arg("build")
-            .args(cmd.args())
-            .spawn()?
-            .wait()?;
-        if !status.success() {
-            std::process::exit(status.code().unwrap());
-        }
-    }
-    let uid = unsafe { libc::getuid() };
-    sudo::with_env(&["RUST_LOG"]).unwrap();
+/// Traces `info` with `probe` and writes the outputs as the user `uid`.
+fn trace(
+    mut info: BinaryInfo,
+    probe: &str,
+    opts: &cli::TraceOpts,
+    config: &config::Config,
+    rules: Vec<filter::Rule>,
+    uid: u32,
+) -> Result<()> {
+    let compression = opts.output.compression(config)?;
+    let rules = [config.rules()?, rules].concat();
+    let title = probe.to_string();
 
-    let mut info = BinaryInfo::from_cargo_subcommand(&cmd)?;
+    let mut trace = Trace::new(probe, &info)?;
+    trace.probe_stats = opts.probe_stats;
+    trace.show_memory = opts.show_memory;
+    trace.numa = opts.numa;
+    trace.audit = opts.audit;
+    if let Some(markers) = &opts.markers {
+        let markers = markers.as_deref().unwrap_or(DEFAULT_MARKERS);
+        trace.markers = Some(marker_probes(markers, info.path())?);
+    }
+    let pid = info.pid();
+    let path = info.path().to_path_buf();
+    let rows = unwind_rows(&info)?;
+    let report = validate::validate(&info, &rows);
+    for module in &report.modules {
+        log::info!("{}", module);
+    }
+    if !report.is_ok() {
+        anyhow::bail!("invalid unwind table:\n{}", report);
+    }
 
-    // TODO more convenience:
-    // uprobes: find path from libname
-    // tracepoint: convert to kprobes on syscalls
-    let mut probe: Probe = cmd.cmd().parse()?;
-    let entry = match probe.prog_type() {
-        ProgramType::Kprobe => "kprobe",
-        ProgramType::PerfEvent => "perf_event",
-        _ => return Err(anyhow::anyhow!("unsupported probe {}", probe)),
+    if opts.memory && opts.privsep {
+        anyhow::bail!("--memory isn't supported with --privsep");
+    }
+    if opts.numa && opts.privsep {
+        anyhow::bail!("--numa isn't supported with --privsep");
+    }
+    if opts.follow_mappings && (opts.privsep || opts.trim.is_some()) {
+        anyhow::bail!("--follow-mappings isn't supported with --privsep or --trim");
+    }
+    let frequency = profile::frequency(&trace.probe);
+    if opts.energy && (opts.privsep || frequency.is_none()) {
+        anyhow::bail!(
+            "--energy needs a profile or interval probe and isn't supported with --privsep"
+        );
+    }
+    if opts.gpu.is_some() && opts.privsep {
+        anyhow::bail!("--gpu isn't supported with --privsep");
+    }
+    let mut memory = if opts.memory {
+        Some(memory::load(pid, opts.audit)?)
+    } else {
+        None
     };
-    log::debug!("setting default path to {}", info.path().display());
-    probe.set_default_path(info.path());
-    let mut builder = BpfBuilder::new(PROBE)?;
-    if let ProgramType::PerfEvent = probe.prog_type() {
-        // without this we will get kernel regs instead of user regs.
-        builder.set_child_pid(info.pid());
-    }
-    builder.attach_probe(probe, entry)?;
-    let mut bpf = builder.load()?;
-    log::debug!("loaded bpf program");
-
-    let mut i = 0;
-    for binary in info.iter() {
-        let table = binary.elf.unwind_table()?;
-        for row in table.rows.iter() {
-            let addr = binary.start_addr + row.start_address;
-            let mut pc = bpf.array::<U64>("PC")?;
-            pc.insert(&U32::new(i as _), &U64::new(addr as _))?;
 
-            let mut rip = bpf.array::<Instruction>("RIP")?;
-            rip.insert(&U32::new(i as _), &row.rip.into())?;
+    let gpu = match &opts.gpu {
+        Some(tracepoints) => {
+            let tracepoints = tracepoints.as_deref().unwrap_or(gpu::DEFAULT_TRACEPOINTS);
+            Some(gpu::Recorder::spawn(
+                pid,
+                gpu::parse_tracepoints(tracepoints)?,
+                opts.audit,
+            )?)
+        }
+        None => None,
+    };
+    let energy = if opts.energy {
+        Some(energy::Energy::start()?)
+    } else {
+        None
+    };
 
-            let mut rsp = bpf.array::<Instruction>("RSP")?;
-            rsp.insert(&U32::new(i as _), &row.rsp.into())?;
+    let probe = trace.probe.clone();
+    let start = Instant::now();
+    let trim = opts.trim.map(Duration::from_millis);
+    let (stacks, migrations) = match trim {
+        Some(_) if opts.privsep => anyhow::bail!("--trim isn't supported with --privsep"),
+        Some(delay) => trace_trimmed(&mut info, trace, rows, delay, |info| run(info, false))?,
+        None if opts.privsep => {
+            let mut helper = privsep::Helper::spawn()?;
+            let fd = helper.load(&trace, &rows)?;
+            run(&mut info, false)?;
+            helper.stop()?;
+            let stacks = privsep::read_stacks(fd)?;
+            unsafe { libc::close(fd) };
+            (stacks, Default::default())
+        }
+        None => {
+            let mut bpf = trace.load(&rows)?;
+            let (stacks_fd, config_fd) = (bpf.map_fd("USER_STACK")?, bpf.map_fd("CONFIG")?);
+            let watcher = if opts.follow_mappings {
+                grow::Watcher::spawn_following_mappings(trace.clone(), rows, stacks_fd, config_fd)
+            } else {
+                grow::Watcher::spawn(trace.clone(), rows, stacks_fd, config_fd)
+            };
+            run(&mut info, opts.follow_mappings)?;
+            let (mut stacks, mut migrations) = watcher.finish()?;
+            let first = trace.collect(&mut bpf)?;
+            // the first probe only sampled the binary before the exec, the
+            // watcher dropped those stacks of its probes as well.
+            if info.path() == path {
+                stacks.extend(first);
+            }
+            migrations += trace.migrations(&mut bpf)?;
+            (grow::merge(stacks), migrations)
+        }
+    };
+    let duration = start.elapsed();
 
-            i += 1;
+    // the stacks of the gpu jobs and the memory events span the exec.
+    let execed = info.path() != path;
+    if let Some(recorder) = gpu {
+        let jobs = recorder.finish()?;
+        if execed {
+            log::warn!("skipping the gpu timeline, the target execed");
+        } else {
+            timeline::write(&info, &jobs, compression)?;
         }
     }
-    let mut len = bpf.array::<U32>("CONFIG")?;
-    len.insert(&U32::new(0), &U32::new(i as _))?;
-    len.insert(&U32::new(1), &U32::new(info.pid()))?;
-
-    log::debug!("running program");
-    info.cont()?;
-
+    if let Some(bpf) = memory.as_mut() {
+        if execed {
+            log::warn!("skipping the memory report, the target execed");
+        } else {
+            memory::report(&info, bpf, compression)?;
+        }
+    }
+    let lines = if opts.numa {
+        println!("{}", migrations);
+        for (node, samples) in numa::node_samples(&stacks) {
+            println!("node {}: {} samples", node, samples);
+        }
+        symbols::fold(numa::collapse_by_node(&info, stacks)?)
+    } else {
+        symbols::fold(collapse(&info, stacks.into_iter())?)
+    };
     unsafe { libc::setuid(uid) };
-    let user_stack = bpf.hash_map::<[U64; 48], U32>("USER_STACK")?;
+    if config.has_output(config::Output::Profile) {
+        let profile = profile::Profile::new(&info, &probe, duration, &lines);
+        profile.write(&compression.path("trace.profile"))?;
+    }
+    let lines = filter::filter_lines(&rules, lines);
+    if let (Some(energy), Some(frequency)) = (energy, frequency) {
+        let flamegraph = config.has_output(config::Output::Flamegraph);
+        energy.report(&lines, frequency, flamegraph, compression)?;
+    }
+    write_outputs(config, &lines, title, compression)
+}
 
-    write_flamegraph(&info, user_stack.iter(), cmd.cmd().to_string())?;
+/// Runs a spawned program to completion, attached programs are traced until
+/// enter is pressed.
+///
+/// A program that execs another binary, like a script starting the real
+/// program, continues with the modules of the new binary if `follow_exec`
+/// is set. Otherwise tracing fails, the probe unwinds the new binary with
+/// the unwind table of the old one.
+fn run(info: &mut BinaryInfo, follow_exec: bool) -> Result<()> {
+    if info.ptracer().is_some() {
+        log::debug!("running program");
+        info.cont()?;
+        while info.execed() {
+            reload_execed(info, follow_exec)?;
+            info.cont()?;
+        }
+        return Ok(());
+    }
+    println!("tracing pid {}, press enter to stop", info.pid());
+    std::io::stdin().read_line(&mut String::new())?;
+    if info.execed() {
+        reload_execed(info, follow_exec)?;
+    }
+    Ok(())
+}
 
+fn reload_execed(info: &mut BinaryInfo, follow_exec: bool) -> Result<()> {
+    if !follow_exec {
+        let exe = std::fs::read_link(format!("/proc/{}/exe", info.pid()))?;
+        anyhow::bail!(
+            "the target execed {}, which is only traced with --follow-mappings",
+            exe.display()
+        );
+    }
+    info.reload()?;
+    log::info!("target execed {}", info.path().display());
     Ok(())
 }
 
-fn write_flamegraph(
-    info: &BinaryInfo,
-    iter: impl Iterator<Item = ([U64; 48], U32)>,
+/// Writes the collapsed stacks and the flamegraph if the config selects them.
+fn write_outputs(
+    config: &config::Config,
+    lines: &[String],
     title: String,
+    compression: Compression,
 ) -> Result<()> {
-    let mut f = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open("collapsed.txt")?;
-
-    let mut symbols = Vec::with_capacity(48);
-    for (stack, count) in iter {
-        symbols.clear();
-        for ip in stack.iter() {
-            let ip = ip.get() as usize;
-            if ip == 0 {
-                break;
-            }
-            if let Some(symbol) = info.resolve_symbol(ip)? {
-                symbols.push(symbol);
-            } else {
-                break;
-            }
+    if config.has_output(config::Output::Collapsed) {
+        let mut f = compress::create(&compression.path("collapsed.txt"))?;
+        for line in lines {
+            writeln!(f, "{}", line)?;
         }
-        symbols.reverse();
-        let mut collapsed = symbols.join(";");
-        collapsed.push(' ');
-        collapsed.push_str(&count.to_string());
-        writeln!(f, "{}", collapsed)?;
+        f.finish()?;
     }
-
-    let collapsed = BufReader::new(File::open("collapsed.txt")?)
-        .lines()
-        .collect::<Result<Vec<_>, _>>()?;
-    let f = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open("flamegraph.svg")?;
+    if !config.has_output(config::Output::Flamegraph) {
+        return Ok(());
+    }
+    let svg_path = match compression {
+        Compression::Gzip => "flamegraph.svgz".into(),
+        compression => compression.path("flamegraph.svg"),
+    };
+    let mut f = compress::create(&svg_path)?;
     let mut options = Options::default();
     options.title = title;
-    flamegraph::from_lines(&mut options, collapsed.iter().map(|s| s.as_str()), f)?;
-    Ok(())
+    flamegraph::from_lines(&mut options, lines.iter().map(|s| s.as_str()), &mut f)?;
+    f.finish()
 }
@@ -0,0 +1,107 @@
+//! Time the target spends waiting for memory.
+//!
+//! Direct reclaim is timed with the `vmscan` tracepoints and major faults
+//! with a kprobe and a kretprobe on `handle_mm_fault`. The time is summed by
+//! the user stack, which the kernel unwinds with frame pointers.
+use crate::compress::{self, Compression};
+use anyhow::Result;
+use bpf::hist::{Log2Histogram, LOG2_BUCKETS};
+use bpf::utils::BinaryInfo;
+use bpf::{Bpf, U32, U64};
+use bpf_profiler::{collapse, probe_builder, symbols};
+use std::io::Write;
+use zerocopy::{AsBytes, FromBytes, Unaligned};
+
+pub const KINDS: [&str; 2] = ["direct reclaim", "major fault"];
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct MemoryKey {
+    kind: U64,
+    stack: [U64; 48],
+}
+
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct MemoryTime {
+    count: U64,
+    time_ns: U64,
+}
+
+/// Loads the memory programs of the probe for `pid`, independent of the
+/// sampling probe so they keep running when it's reloaded. `audit` logs the
+/// setup like `Trace::audit`.
+pub fn load(pid: u32, audit: bool) -> Result<Bpf> {
+    let empty = [("PC", 1), ("RIP", 1), ("RSP", 1), ("USER_STACK", 1)];
+    let mut builder = probe_builder(pid, &empty)?;
+    if audit {
+        builder.set_audit_hook(bpf::audit::log_hook());
+    }
+    builder.attach_probe_str(
+        "tracepoint:vmscan:mm_vmscan_direct_reclaim_begin",
+        "reclaim_begin",
+    )?;
+    builder.attach_probe_str(
+        "tracepoint:vmscan:mm_vmscan_direct_reclaim_end",
+        "reclaim_end",
+    )?;
+    builder.attach_probe_str("kprobe:handle_mm_fault", "fault_begin")?;
+    builder.attach_probe_str("kretprobe:handle_mm_fault", "fault_end")?;
+    let mut bpf = builder.load()?;
+    let mut config = bpf.array::<U32>("CONFIG")?;
+    config.insert(&U32::new(1), &U32::new(pid))?;
+    Ok(bpf)
+}
+
+/// Prints the time per kind and writes the stacks weighted by microseconds
+/// to `memory-collapsed.txt`.
+pub fn report(info: &BinaryInfo, bpf: &mut Bpf, compression: Compression) -> Result<()> {
+    let entries: Vec<_> = bpf
+        .hash_map::<MemoryKey, MemoryTime>("MEMORY")?
+        .iter()
+        .collect();
+    let mut totals = [(0, 0); 2];
+    for (key, time) in &entries {
+        if let Some(total) = totals.get_mut(key.kind.get() as usize) {
+            total.0 += time.count.get();
+            total.1 += time.time_ns.get();
+        }
+    }
+    let buckets: Vec<_> = bpf.array::<U64>("MEMORY_HIST")?.iter().collect();
+    for (i, (kind, (count, time_ns))) in KINDS.iter().zip(totals.iter()).enumerate() {
+        let mut hist = Log2Histogram::new();
+        for (key, count) in &buckets {
+            let key = key.get() as usize;
+            if key / LOG2_BUCKETS == i {
+                hist.add(key % LOG2_BUCKETS, count.get());
+            }
+        }
+        println!(
+            "{}: {} times, {:.1}ms, latency ns {}",
+            kind,
+            count,
+            *time_ns as f64 / 1e6,
+            hist.percentiles()
+        );
+    }
+
+    let (kinds, stacks): (Vec<_>, Vec<_>) = entries
+        .iter()
+        .filter_map(|(key, time)| {
+            let kind = KINDS.get(key.kind.get() as usize)?;
+            let micros = (time.time_ns.get() / 1000).max(1).min(u32::MAX as u64);
+            Some((kind, (key.stack, U32::new(micros as u32))))
+        })
+        .unzip();
+    let lines = collapse(info, stacks.into_iter())?;
+    let lines = kinds.iter().zip(lines).map(|(kind, line)| {
+        // stacks without frame pointers are empty.
+        let sep = if line.starts_with(' ') { "" } else { ";" };
+        format!("{}{}{}", kind, sep, line)
+    });
+    let mut f = compress::create(&compression.path("memory-collapsed.txt"))?;
+    for line in symbols::fold(lines) {
+        writeln!(f, "{}", line)?;
+    }
+    f.finish()
+}
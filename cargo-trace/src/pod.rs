@@ -0,0 +1,132 @@
+//! Resolution of kubernetes pods to host pids.
+//!
+//! The container runtime is queried with `crictl`, which talks to the CRI
+//! socket of containerd or cri-o. The endpoint can be set with
+//! `CONTAINER_RUNTIME_ENDPOINT`.
+use anyhow::{bail, Result};
+use std::process::Command;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pod {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for Pod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (namespace, name) = match s.find('/') {
+            Some(i) => (&s[..i], &s[(i + 1)..]),
+            None => ("default", s),
+        };
+        if namespace.is_empty() || name.is_empty() || name.contains('/') {
+            bail!("invalid pod {}, expected <namespace>/<name>", s);
+        }
+        Ok(Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Pod {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+fn crictl(args: &[&str]) -> Result<String> {
+    let output = Command::new("crictl").args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "crictl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl Pod {
+    /// Host pids of the running containers of the pod, optionally only of
+    /// the container named `container`.
+    pub fn pids(&self, container: Option<&str>) -> Result<Vec<u32>> {
+        let name = format!("^{}$", self.name);
+        let pods = crictl(&[
+            "pods",
+            "--namespace",
+            &self.namespace,
+            "--name",
+            &name,
+            "--state",
+            "ready",
+            "-q",
+        ])?;
+        let pod = match pods.lines().next() {
+            Some(pod) => pod.trim().to_string(),
+            None => bail!("pod {} not found", self),
+        };
+        let mut args = vec!["ps", "--pod", &pod, "--state", "running", "-q"];
+        let name;
+        if let Some(container) = container {
+            name = format!("^{}$", container);
+            args.extend_from_slice(&["--name", &name]);
+        }
+        let mut pids = vec![];
+        for id in crictl(&args)?.lines() {
+            pids.push(inspect_pid(&crictl(&["inspect", id.trim()])?)?);
+        }
+        if pids.is_empty() {
+            bail!("no running containers in pod {}", self);
+        }
+        Ok(pids)
+    }
+
+    /// The host pid of the single container to trace.
+    pub fn pid(&self, container: Option<&str>) -> Result<u32> {
+        let pids = self.pids(container)?;
+        if pids.len() > 1 {
+            bail!(
+                "pod {} has {} containers, select one with --container",
+                self,
+                pids.len()
+            );
+        }
+        Ok(pids[0])
+    }
+}
+
+/// Extracts the host pid from the output of `crictl inspect`.
+fn inspect_pid(json: &str) -> Result<u32> {
+    let i = match json.find("\"pid\":") {
+        Some(i) => i + "\"pid\":".len(),
+        None => bail!("no pid in container info"),
+    };
+    let pid: String = json[i..]
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    Ok(pid.parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pod() {
+        let pod: Pod = "kube-system/coredns-1".parse().unwrap();
+        assert_eq!(pod.namespace, "kube-system");
+        assert_eq!(pod.name, "coredns-1");
+        assert_eq!("web".parse::<Pod>().unwrap().namespace, "default");
+        assert!("a/b/c".parse::<Pod>().is_err());
+    }
+
+    #[test]
+    fn pid_from_inspect() {
+        let json = r#"{"status": {}, "info": {"sandboxID": "ab", "pid": 4242, "removing": false}}"#;
+        assert_eq!(inspect_pid(json).unwrap(), 4242);
+    }
+}
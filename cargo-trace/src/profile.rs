@@ -0,0 +1,254 @@
+//! The `.profile` format and merging of profiles of several runs.
+//!
+//! A profile is a header followed by the collapsed stacks:
+//!
+//! ```text
+//! cargo-trace profile 1
+//! duration 12.5
+//! frequency 99
+//! build-id target/release/app 3f2a...
+//! app::main;app::run;app::work 120
+//! ```
+//!
+//! The duration is in seconds, the frequency in hertz is missing for probes
+//! that don't sample at a fixed rate. The first build id is the traced binary.
+use crate::compress;
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use bpf::{Interval, Probe};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub const HEADER: &str = "cargo-trace profile 1";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub duration: f64,
+    pub frequency: Option<f64>,
+    /// Path and build id of every module.
+    pub build_ids: Vec<(String, String)>,
+    pub stacks: BTreeMap<String, f64>,
+}
+
+impl Profile {
+    pub fn new(info: &BinaryInfo, probe: &Probe, duration: Duration, lines: &[String]) -> Self {
+        let frequency = frequency(probe);
+        let mut build_ids: Vec<_> = info
+            .iter()
+            .filter_map(|binary| {
+                let build_id = binary.elf.build_id().ok()?;
+                Some((
+                    binary.elf.path().display().to_string(),
+                    build_id.to_string(),
+                ))
+            })
+            .collect();
+        // the libraries are in the order of their randomized addresses.
+        if build_ids.len() > 1 {
+            build_ids[1..].sort();
+        }
+        let mut stacks = BTreeMap::new();
+        for line in lines {
+            if let Some((stack, count)) = split_line(line) {
+                *stacks.entry(stack.to_string()).or_default() += count;
+            }
+        }
+        Self {
+            duration: duration.as_secs_f64(),
+            frequency,
+            build_ids,
+            stacks,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut s = String::new();
+        compress::open(path)?.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut f = compress::create(path)?;
+        write!(f, "{}", self)?;
+        f.finish()
+    }
+
+    /// Build id of the traced binary.
+    pub fn build_id(&self) -> Option<&str> {
+        self.build_ids.first().map(|(_, id)| id.as_str())
+    }
+
+    /// Collapsed stacks with the weights rounded to whole samples.
+    pub fn lines(&self) -> Vec<String> {
+        self.stacks
+            .iter()
+            .map(|(stack, weight)| format!("{} {}", stack, weight.round() as u64))
+            .collect()
+    }
+}
+
+/// Sample rate in hertz of probes that sample at a fixed rate.
+pub fn frequency(probe: &Probe) -> Option<f64> {
+    match probe {
+        Probe::Profile { interval } | Probe::Interval { interval } => Some(match interval {
+            Interval::Hz(hz) => *hz as f64,
+            Interval::Seconds(d) | Interval::Millis(d) | Interval::Micros(d) => {
+                1.0 / d.as_secs_f64()
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Splits a collapsed line at the last space, symbols can contain spaces.
+pub fn split_line(line: &str) -> Option<(&str, f64)> {
+    let mut iter = line.rsplitn(2, ' ');
+    let count = iter.next()?.parse().ok()?;
+    Some((iter.next()?, count))
+}
+
+impl std::str::FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            anyhow::bail!("not a cargo-trace profile");
+        }
+        let mut profile = Self::default();
+        for line in lines {
+            let mut fields = line.splitn(2, ' ');
+            match (fields.next(), fields.next()) {
+                (Some("duration"), Some(duration)) => profile.duration = duration.parse()?,
+                (Some("frequency"), Some(frequency)) => {
+                    profile.frequency = Some(frequency.parse()?)
+                }
+                (Some("build-id"), Some(build_id)) => {
+                    let mut fields = build_id.rsplitn(2, ' ');
+                    let id = fields.next().unwrap_or_default();
+                    let path = fields.next().unwrap_or_default();
+                    profile.build_ids.push((path.to_string(), id.to_string()));
+                }
+                _ => {
+                    let (stack, count) =
+                        split_line(line).ok_or_else(|| anyhow::anyhow!("invalid line {}", line))?;
+                    *profile.stacks.entry(stack.to_string()).or_default() += count;
+                }
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "duration {}", self.duration)?;
+        if let Some(frequency) = self.frequency {
+            writeln!(f, "frequency {}", frequency)?;
+        }
+        for (path, id) in &self.build_ids {
+            writeln!(f, "build-id {} {}", path, id)?;
+        }
+        for (stack, weight) in &self.stacks {
+            writeln!(f, "{} {}", stack, weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sums the stacks of several runs.
+///
+/// Every run is scaled to the mean duration and the highest frequency of the
+/// runs first, so long runs and high frequencies don't dominate. Symbols are
+/// matched by name, if the runs traced different builds of the binary the
+/// stacks of every build get its build id as the root frame.
+pub fn merge(profiles: &[Profile]) -> Profile {
+    let mut merged = Profile::default();
+    if profiles.is_empty() {
+        return merged;
+    }
+    let mean_duration = profiles.iter().map(|p| p.duration).sum::<f64>() / profiles.len() as f64;
+    let max_frequency = profiles
+        .iter()
+        .filter_map(|p| p.frequency)
+        .fold(None, |max: Option<f64>, f| {
+            Some(max.map_or(f, |max| max.max(f)))
+        });
+    let builds: BTreeSet<_> = profiles.iter().map(|p| p.build_id()).collect();
+    if builds.len() > 1 {
+        log::warn!("merging profiles of {} different builds", builds.len());
+    }
+    for profile in profiles {
+        let mut scale = 1.0;
+        if profile.duration > 0.0 {
+            scale *= mean_duration / profile.duration;
+        }
+        if let (Some(max), Some(frequency)) = (max_frequency, profile.frequency) {
+            scale *= max / frequency;
+        }
+        for (stack, weight) in &profile.stacks {
+            let stack = match profile.build_id() {
+                Some(id) if builds.len() > 1 => format!("{};{}", &id[..id.len().min(12)], stack),
+                _ => stack.clone(),
+            };
+            *merged.stacks.entry(stack).or_default() += weight * scale;
+        }
+        for build_id in &profile.build_ids {
+            if !merged.build_ids.contains(build_id) {
+                merged.build_ids.push(build_id.clone());
+            }
+        }
+    }
+    merged.duration = mean_duration * profiles.len() as f64;
+    merged.frequency = max_frequency;
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(duration: f64, frequency: f64, build_id: &str, stacks: &[(&str, f64)]) -> Profile {
+        Profile {
+            duration,
+            frequency: Some(frequency),
+            build_ids: vec![("app".into(), build_id.into())],
+            stacks: stacks.iter().map(|(s, w)| (s.to_string(), *w)).collect(),
+        }
+    }
+
+    #[test]
+    fn profile_roundtrip() {
+        let p = profile(2.5, 99.0, "3f2a", &[("main;<impl Foo for Bar>::run", 12.0)]);
+        let s = p.to_string();
+        assert!(s.starts_with(HEADER));
+        assert_eq!(s.parse::<Profile>().unwrap(), p);
+        assert!("main 1".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn merge_runs() {
+        let a = profile(10.0, 100.0, "aa", &[("main;work", 100.0)]);
+        let b = profile(
+            30.0,
+            50.0,
+            "aa",
+            &[("main;work", 75.0), ("main;idle", 25.0)],
+        );
+        let merged = merge(&[a.clone(), b]);
+        // a is scaled by 20 / 10, b by 20 / 30 * 100 / 50.
+        assert!((merged.stacks["main;work"] - 300.0).abs() < 1e-9);
+        assert!((merged.stacks["main;idle"] - 100.0 / 3.0).abs() < 1e-9);
+        assert_eq!(merged.duration, 40.0);
+        assert_eq!(merged.frequency, Some(100.0));
+
+        let c = profile(10.0, 100.0, "bb", &[("main;work", 10.0)]);
+        let merged = merge(&[a, c]);
+        assert_eq!(merged.stacks["aa;main;work"], 100.0);
+        assert_eq!(merged.stacks["bb;main;work"], 10.0);
+        assert_eq!(merged.lines(), vec!["aa;main;work 100", "bb;main;work 10"]);
+    }
+}
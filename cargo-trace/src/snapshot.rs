@@ -0,0 +1,255 @@
+//! On demand snapshot of the stacks of all threads.
+//!
+//! Sampling only sees threads while they run. A snapshot interrupts every
+//! thread of the target once with `PTRACE_INTERRUPT` and unwinds its user
+//! stack with the unwind table of the probe, so threads blocked in the kernel
+//! show where they wait.
+//!
+//! Snapshots can also be saved with the registers and a copy of the stack,
+//! to check the unwind table against a reference unwinder later.
+use anyhow::Result;
+use bpf::utils::ehframe::Regs;
+use bpf::utils::BinaryInfo;
+use bpf_profiler::corpus::{self, Module, Snapshot};
+use bpf_profiler::unwind::backtrace;
+use bpf_profiler::Row;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes of the stack saved in a snapshot.
+const SNAPSHOT_STACK: usize = 64 * 1024;
+/// Event of the stop caused by `PTRACE_INTERRUPT`.
+const PTRACE_EVENT_STOP: i32 = 128;
+
+pub struct ThreadStack {
+    pub tid: u32,
+    pub comm: String,
+    pub stack: Vec<u64>,
+}
+
+pub fn threads(pid: u32) -> Result<Vec<u32>> {
+    let mut tids = vec![];
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Stops the threads of `pid` one at a time and unwinds their stacks.
+/// Threads that exit or can't be stopped are skipped.
+pub fn capture(pid: u32, rows: &[Row]) -> Result<Vec<ThreadStack>> {
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let mut stacks = vec![];
+    for tid in threads(pid)? {
+        match capture_thread(&mem, pid, tid, rows) {
+            Ok(stack) => stacks.push(stack),
+            Err(err) => log::warn!("skipping thread {}: {}", tid, err),
+        }
+    }
+    Ok(stacks)
+}
+
+/// Unwinds the stack of a single thread, `mem` is `/proc/<pid>/mem`.
+pub fn capture_thread(mem: &File, pid: u32, tid: u32, rows: &[Row]) -> Result<ThreadStack> {
+    let (regs, signal) = interrupt(tid)?;
+    let stack = backtrace(
+        rows,
+        regs.rip,
+        regs.rsp,
+        |reg| dwarf_register(&regs, reg),
+        |addr| {
+            let mut word = [0; 8];
+            mem.read_exact_at(&mut word, addr).ok()?;
+            Some(u64::from_ne_bytes(word))
+        },
+    );
+    detach(tid, signal);
+    let comm =
+        std::fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)).unwrap_or_default();
+    Ok(ThreadStack {
+        tid,
+        comm: comm.trim_end().to_string(),
+        stack,
+    })
+}
+
+/// Saves the registers and the stack of every thread of `pid` to `dir` as
+/// unwind snapshots, see the corpus module of bpf-profiler.
+pub fn save(info: &BinaryInfo, pid: u32, dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let modules: Vec<_> = info
+        .iter()
+        .map(|binary| Module {
+            start: binary.start_addr,
+            end: binary.end_addr,
+            path: binary.elf.path().to_path_buf(),
+        })
+        .collect();
+    for tid in threads(pid)? {
+        let (regs, signal) = match interrupt(tid) {
+            Ok(stopped) => stopped,
+            Err(err) => {
+                log::warn!("skipping thread {}: {}", tid, err);
+                continue;
+            }
+        };
+        let mut stack = vec![];
+        let mut page = [0; 4096];
+        while stack.len() < SNAPSHOT_STACK
+            && mem
+                .read_exact_at(&mut page, regs.rsp + stack.len() as u64)
+                .is_ok()
+        {
+            stack.extend_from_slice(&page);
+        }
+        detach(tid, signal);
+        let snapshot = Snapshot {
+            regs: Regs {
+                rip: regs.rip,
+                rsp: regs.rsp,
+                rbp: regs.rbp,
+            },
+            modules: modules.clone(),
+            stack_addr: regs.rsp,
+            stack,
+        };
+        let path = dir.join(format!("{}-{}.snapshot", pid, tid));
+        snapshot.write(&path)?;
+        println!("saved {}", path.display());
+    }
+    Ok(())
+}
+
+/// Unwinds the snapshots at `paths` with the unwind table and the reference
+/// unwinder, directories are searched for `.snapshot` files. Fails if any
+/// stacks differ.
+pub fn check(paths: &[PathBuf]) -> Result<()> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                if path.extension().and_then(|ext| ext.to_str()) == Some("snapshot") {
+                    files.push(path);
+                }
+            }
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files.sort();
+    let mut mismatches = 0;
+    for path in &files {
+        let outcome = corpus::check(&Snapshot::read(path)?)?;
+        if outcome.is_match() {
+            println!("ok {} ({} frames)", path.display(), outcome.probe.len());
+            continue;
+        }
+        mismatches += 1;
+        println!("mismatch {}", path.display());
+        let len = outcome.probe.len().max(outcome.reference.len());
+        for i in 0..len {
+            let frame = |stack: &[u64]| match stack.get(i) {
+                Some(ip) => format!("0x{:x}", ip),
+                None => "-".to_string(),
+            };
+            let (probe, reference) = (frame(&outcome.probe), frame(&outcome.reference));
+            let marker = if probe == reference { " " } else { "!" };
+            println!("{} {:4}: {:18} {}", marker, i, probe, reference);
+        }
+    }
+    if mismatches > 0 {
+        anyhow::bail!("{} of {} snapshots differ", mismatches, files.len());
+    }
+    Ok(())
+}
+
+pub fn print(info: &BinaryInfo, stacks: &[ThreadStack]) -> Result<()> {
+    for thread in stacks {
+        println!("thread {} ({})", thread.tid, thread.comm);
+        for (i, ip) in thread.stack.iter().enumerate() {
+            info.print_frame(i, *ip as usize)?;
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Register of a ptrace stopped thread by its dwarf number.
+fn dwarf_register(regs: &libc::user_regs_struct, reg: u16) -> Option<u64> {
+    Some(match reg {
+        0 => regs.rax,
+        1 => regs.rdx,
+        2 => regs.rcx,
+        3 => regs.rbx,
+        4 => regs.rsi,
+        5 => regs.rdi,
+        6 => regs.rbp,
+        7 => regs.rsp,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        15 => regs.r15,
+        16 => regs.rip,
+        _ => return None,
+    })
+}
+
+fn ptrace(request: libc::c_uint, tid: u32, data: *mut libc::c_void) -> Result<()> {
+    let null = std::ptr::null_mut::<libc::c_void>();
+    if unsafe { libc::ptrace(request, tid as libc::pid_t, null, data) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Stops a thread without sending it a signal and reads its registers.
+///
+/// A signal arriving before the interrupt stops the thread instead, it's
+/// returned to be delivered again by [`detach`].
+fn interrupt(tid: u32) -> Result<(libc::user_regs_struct, i32)> {
+    ptrace(libc::PTRACE_SEIZE, tid, std::ptr::null_mut())?;
+    let mut signal = 0;
+    let res = (|| -> Result<libc::user_regs_struct> {
+        ptrace(libc::PTRACE_INTERRUPT, tid, std::ptr::null_mut())?;
+        let mut status = 0;
+        if unsafe { libc::waitpid(tid as _, &mut status, libc::__WALL) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if !libc::WIFSTOPPED(status) {
+            anyhow::bail!("thread exited");
+        }
+        if status >> 16 != PTRACE_EVENT_STOP {
+            signal = libc::WSTOPSIG(status);
+        }
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        ptrace(
+            libc::PTRACE_GETREGS,
+            tid,
+            &mut regs as *mut libc::user_regs_struct as *mut _,
+        )?;
+        Ok(regs)
+    })();
+    match res {
+        Ok(regs) => Ok((regs, signal)),
+        Err(err) => {
+            detach(tid, signal);
+            Err(err)
+        }
+    }
+}
+
+/// Resumes a thread stopped by [`interrupt`], delivering the `signal` it
+/// stopped for.
+fn detach(tid: u32, signal: i32) {
+    ptrace(libc::PTRACE_DETACH, tid, signal as usize as *mut _).ok();
+}
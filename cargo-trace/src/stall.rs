@@ -0,0 +1,247 @@
+//! Detection of threads that stay blocked.
+//!
+//! The run time of every thread of the target is polled from
+//! `/proc/<pid>/task/<tid>/schedstat`. A thread that is off cpu without
+//! running for longer than the threshold is reported once, with its stack
+//! from a snapshot and the futex or file descriptor of the syscall it waits
+//! in.
+use crate::snapshot;
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use bpf_profiler::Row;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+const SYS_FUTEX: u32 = 202;
+/// Syscalls blocking on the file descriptor in their first argument.
+const FD_SYSCALLS: &[u32] = &[0, 1, 42, 43, 44, 45, 46, 47, 232, 281, 288];
+
+/// Parses durations like `500ms`, `5s` or `2m`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value.parse()?;
+    Ok(match unit {
+        "ms" => Duration::from_millis(value),
+        "s" | "" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value * 60),
+        _ => anyhow::bail!("invalid duration {}", s),
+    })
+}
+
+/// What a blocked thread waits on, from `/proc/<pid>/task/<tid>/syscall`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Wait {
+    Running,
+    /// Blocked outside of a syscall, like in a page fault.
+    NoSyscall,
+    Futex(u64),
+    Fd {
+        syscall: u32,
+        fd: u64,
+    },
+    Syscall(u32),
+}
+
+impl Wait {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut fields = s.split_whitespace();
+        let nr = fields.next()?;
+        if nr == "running" {
+            return Some(Self::Running);
+        }
+        let nr: i64 = nr.parse().ok()?;
+        if nr < 0 {
+            return Some(Self::NoSyscall);
+        }
+        let nr = nr as u32;
+        let arg0 = fields
+            .next()
+            .and_then(|arg| u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok());
+        Some(match arg0 {
+            Some(addr) if nr == SYS_FUTEX => Self::Futex(addr),
+            Some(fd) if FD_SYSCALLS.contains(&nr) => Self::Fd { syscall: nr, fd },
+            _ => Self::Syscall(nr),
+        })
+    }
+
+    fn describe(&self, pid: u32, syscalls: &BTreeMap<u32, String>) -> String {
+        let name = |nr: &u32| {
+            syscalls
+                .get(nr)
+                .cloned()
+                .unwrap_or_else(|| format!("syscall {}", nr))
+        };
+        match self {
+            Self::Running => "nothing".to_string(),
+            Self::NoSyscall => "no syscall".to_string(),
+            Self::Futex(addr) => format!("futex 0x{:x}", addr),
+            Self::Fd { syscall, fd } => {
+                let target = std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd))
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default();
+                format!("{} on fd {} {}", name(syscall), fd, target)
+            }
+            Self::Syscall(nr) => name(nr),
+        }
+    }
+}
+
+/// Tracks since when the threads haven't run.
+pub struct Detector {
+    threshold: Duration,
+    /// Run time, since when it is unchanged and whether the thread was
+    /// reported.
+    threads: HashMap<u32, (u64, Instant, bool)>,
+}
+
+impl Detector {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Records the run time of a thread, returning how long it has been
+    /// blocked the first time it passes the threshold.
+    pub fn update(
+        &mut self,
+        tid: u32,
+        run_ns: u64,
+        running: bool,
+        now: Instant,
+    ) -> Option<Duration> {
+        let entry = self.threads.entry(tid).or_insert((run_ns, now, false));
+        if running || entry.0 != run_ns {
+            *entry = (run_ns, now, false);
+            return None;
+        }
+        let blocked = now.duration_since(entry.1);
+        if entry.2 || blocked < self.threshold {
+            return None;
+        }
+        entry.2 = true;
+        Some(blocked)
+    }
+
+    pub fn retain(&mut self, tids: &[u32]) {
+        self.threads.retain(|tid, _| tids.contains(tid));
+    }
+}
+
+fn thread_stat(pid: u32, tid: u32) -> Result<(char, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/task/{}/stat", pid, tid))?;
+    let state = stat
+        .rfind(')')
+        .and_then(|i| stat[i + 1..].trim_start().chars().next())
+        .unwrap_or('?');
+    let schedstat = std::fs::read_to_string(format!("/proc/{}/task/{}/schedstat", pid, tid))?;
+    let run_ns = schedstat
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .parse()?;
+    Ok((state, run_ns))
+}
+
+/// Polls the threads of `pid` until it exits, reporting stalled threads.
+pub fn watch(info: &BinaryInfo, rows: &[Row], threshold: Duration) -> Result<()> {
+    let pid = info.pid();
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let syscalls = bpf::utils::syscall_table().unwrap_or_default();
+    let mut detector = Detector::new(threshold);
+    let interval = (threshold / 4).max(Duration::from_millis(100));
+    println!(
+        "watching pid {} for threads blocked longer than {:?}",
+        pid, threshold
+    );
+    while let Ok(tids) = snapshot::threads(pid) {
+        let now = Instant::now();
+        for tid in &tids {
+            let (state, run_ns) = match thread_stat(pid, *tid) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            // the run time of a running thread is only updated when it's
+            // scheduled out.
+            let blocked = match detector.update(*tid, run_ns, state == 'R', now) {
+                Some(blocked) => blocked,
+                None => continue,
+            };
+            let wait = std::fs::read_to_string(format!("/proc/{}/task/{}/syscall", pid, tid))
+                .ok()
+                .and_then(|s| Wait::parse(&s))
+                .map(|wait| wait.describe(pid, &syscalls))
+                .unwrap_or_default();
+            let thread = match snapshot::capture_thread(&mem, pid, *tid, rows) {
+                Ok(thread) => thread,
+                Err(err) => {
+                    log::warn!("failed to capture thread {}: {}", tid, err);
+                    continue;
+                }
+            };
+            println!(
+                "thread {} ({}) blocked for {:.1}s in state {} waiting on {}",
+                tid,
+                thread.comm,
+                blocked.as_secs_f64(),
+                state,
+                wait
+            );
+            for (i, ip) in thread.stack.iter().enumerate() {
+                info.print_frame(i, *ip as usize)?;
+            }
+            println!();
+        }
+        detector.retain(&tids);
+        std::thread::sleep(interval);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_wait() {
+        let futex = "202 0x7f3a2c000b40 0x80 0x0 0x0 0x0 0x0 0x7ffd 0x7f3a";
+        assert_eq!(Wait::parse(futex), Some(Wait::Futex(0x7f3a2c000b40)));
+        let read = "0 0x3 0x7f3a 0x2000 0x0 0x0 0x0 0x7ffd 0x7f3a";
+        assert_eq!(Wait::parse(read), Some(Wait::Fd { syscall: 0, fd: 3 }));
+        assert_eq!(Wait::parse("35 0x7ffd 0x0"), Some(Wait::Syscall(35)));
+        assert_eq!(Wait::parse("running"), Some(Wait::Running));
+        assert_eq!(Wait::parse("-1 0x7ffd 0x7f3a"), Some(Wait::NoSyscall));
+    }
+
+    #[test]
+    fn detect_stall() {
+        let start = Instant::now();
+        let mut detector = Detector::new(Duration::from_secs(5));
+        assert_eq!(detector.update(1, 10, false, start), None);
+        assert_eq!(
+            detector.update(1, 10, false, start + Duration::from_secs(4)),
+            None
+        );
+        let blocked = detector.update(1, 10, false, start + Duration::from_secs(6));
+        assert_eq!(blocked, Some(Duration::from_secs(6)));
+        // reported once until the thread runs again.
+        assert_eq!(
+            detector.update(1, 10, false, start + Duration::from_secs(7)),
+            None
+        );
+        assert_eq!(
+            detector.update(1, 20, false, start + Duration::from_secs(8)),
+            None
+        );
+        assert_eq!(detector.update(2, 0, true, start), None);
+        assert_eq!(
+            detector.update(2, 0, true, start + Duration::from_secs(9)),
+            None
+        );
+        assert_eq!(parse_duration("5s").unwrap(), Duration::from_secs(5));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("5h").is_err());
+    }
+}
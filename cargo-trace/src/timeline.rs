@@ -0,0 +1,101 @@
+//! Timeline of the gpu jobs of the target.
+//!
+//! `gpu-timeline.json` is in the trace event format of chrome://tracing and
+//! perfetto. Every job is a slice on the `gpu` track from its submission to
+//! its completion and an instant on the submitting thread, both carrying the
+//! collapsed stack of the submission. Times are in microseconds since the
+//! first submission.
+use crate::compress::{self, Compression};
+use anyhow::Result;
+use bpf::utils::BinaryInfo;
+use bpf_profiler::gpu::GpuJob;
+use std::io::Write;
+
+/// Thread id of the `gpu` track, which isn't a thread of the target.
+const GPU_TID: u32 = 0;
+
+pub fn write(info: &BinaryInfo, jobs: &[GpuJob], compression: Compression) -> Result<()> {
+    let pid = info.pid();
+    let start = jobs
+        .iter()
+        .map(|job| job.submit_ns)
+        .min()
+        .unwrap_or_default();
+    let micros = |ns: u64| (ns - start) as f64 / 1000.0;
+    let mut events = vec![format!(
+        r#"{{"name":"thread_name","ph":"M","pid":{},"tid":{},"args":{{"name":"gpu"}}}}"#,
+        pid, GPU_TID
+    )];
+    for job in jobs {
+        let frames = job
+            .stack
+            .iter()
+            .rev()
+            .map(|ip| {
+                Ok(info
+                    .resolve_symbol(*ip as usize)?
+                    .unwrap_or_else(|| format!("0x{:x}", ip)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let name = frames.last().map(String::as_str).unwrap_or("gpu job");
+        let args = format!(
+            r#"{{"tracepoint":{},"stack":{}}}"#,
+            json_string(&job.tracepoint),
+            json_string(&frames.join(";"))
+        );
+        events.push(format!(
+            r#"{{"name":{},"cat":"submit","ph":"i","s":"t","ts":{:.3},"pid":{},"tid":{},"args":{}}}"#,
+            json_string(name),
+            micros(job.submit_ns),
+            pid,
+            job.tid,
+            args
+        ));
+        // jobs that didn't complete while tracing only get the instant.
+        if let Some(complete_ns) = job.complete_ns {
+            events.push(format!(
+                r#"{{"name":{},"cat":"gpu","ph":"X","ts":{:.3},"dur":{:.3},"pid":{},"tid":{},"args":{}}}"#,
+                json_string(name),
+                micros(job.submit_ns),
+                complete_ns.saturating_sub(job.submit_ns) as f64 / 1000.0,
+                pid,
+                GPU_TID,
+                args
+            ));
+        }
+    }
+    let completed = jobs.iter().filter(|job| job.complete_ns.is_some()).count();
+    println!("gpu: {} jobs, {} completed", jobs.len(), completed);
+
+    let mut f = compress::create(&compression.path("gpu-timeline.json"))?;
+    writeln!(f, "{{\"traceEvents\":[")?;
+    writeln!(f, "{}", events.join(",\n"))?;
+    writeln!(f, "]}}")?;
+    f.finish()
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+        assert_eq!(json_string("<T as Trait>::f"), r#""<T as Trait>::f""#);
+    }
+}
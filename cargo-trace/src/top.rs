@@ -0,0 +1,116 @@
+//! Live view of the functions a running process spends its time in.
+//!
+//! `cargo trace top --pid <pid>` profiles the process like `attach` and
+//! redraws the functions with the most samples every interval until the
+//! process exits. Self counts the samples a function was the innermost frame
+//! of, total the samples it was anywhere on the stack. Counts are summed
+//! since the start.
+use crate::profile::split_line;
+use anyhow::Result;
+use bpf_profiler::Profiler;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub self_samples: u64,
+    pub total_samples: u64,
+}
+
+/// The `n` functions with the most self samples in collapsed `lines`.
+pub fn hottest(lines: &[String], n: usize) -> Vec<Function> {
+    let mut functions: HashMap<&str, (u64, u64)> = HashMap::new();
+    for (stack, count) in lines.iter().filter_map(|line| split_line(line)) {
+        let count = count as u64;
+        let frames: Vec<_> = stack.split(';').collect();
+        let mut seen = HashSet::new();
+        for frame in &frames {
+            if seen.insert(*frame) {
+                functions.entry(*frame).or_default().1 += count;
+            }
+        }
+        if let Some(leaf) = frames.last() {
+            functions.entry(*leaf).or_default().0 += count;
+        }
+    }
+    let mut functions: Vec<_> = functions
+        .into_iter()
+        .map(|(name, (self_samples, total_samples))| Function {
+            name: name.to_string(),
+            self_samples,
+            total_samples,
+        })
+        .collect();
+    functions.sort_by(|a, b| {
+        (b.self_samples, b.total_samples, &a.name).cmp(&(a.self_samples, a.total_samples, &b.name))
+    });
+    functions.truncate(n);
+    functions
+}
+
+/// Profiles `pid` with `probe`, printing the `n` hottest functions every
+/// `interval`.
+pub fn run(pid: u32, probe: &str, interval: Duration, n: usize) -> Result<()> {
+    let mut profiler = Profiler::attach(pid, probe)?;
+    let proc = format!("/proc/{}", pid);
+    while Path::new(&proc).exists() {
+        std::thread::sleep(interval);
+        let lines = profiler.collapsed()?;
+        let samples: f64 = lines
+            .iter()
+            .filter_map(|line| split_line(line))
+            .map(|(_, count)| count)
+            .sum();
+        // clears the terminal and moves the cursor home.
+        print!("\x1b[2J\x1b[H");
+        println!("pid {}, {} samples", pid, samples);
+        println!("{:>7} {:>7}  function", "self", "total");
+        for function in hottest(&lines, n) {
+            let percent = |count: u64| 100.0 * count as f64 / samples.max(1.0);
+            println!(
+                "{:>6.1}% {:>6.1}%  {}",
+                percent(function.self_samples),
+                percent(function.total_samples),
+                function.name
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_and_total() {
+        let lines = vec![
+            "main;work;hash 5".to_string(),
+            "main;work 3".to_string(),
+            "main;recurse;recurse 2".to_string(),
+        ];
+        let top = hottest(&lines, 2);
+        assert_eq!(
+            top,
+            vec![
+                Function {
+                    name: "hash".into(),
+                    self_samples: 5,
+                    total_samples: 5,
+                },
+                Function {
+                    name: "work".into(),
+                    self_samples: 3,
+                    total_samples: 8,
+                },
+            ]
+        );
+        let recurse = hottest(&lines, 10)
+            .into_iter()
+            .find(|function| function.name == "recurse")
+            .unwrap();
+        assert_eq!((recurse.self_samples, recurse.total_samples), (2, 2));
+    }
+}
@@ -108,19 +108,26 @@ pub enum Op {
 
 /// Dwarf register.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-#[repr(u8)]
 pub enum Reg {
-    Rip = libc::REG_RIP as u8,
-    Rsp = libc::REG_RSP as u8,
+    Rip,
+    Rsp,
+    /// Any other register by its dwarf number.
+    Other(u16),
 }
 
+/// Names of the x86_64 registers by dwarf number.
+const REGISTER_NAMES: [&str; 16] = [
+    "rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
 impl Reg {
-    fn from_gimli(reg: gimli::Register) -> Option<Self> {
-        Some(match reg {
+    fn from_gimli(reg: gimli::Register) -> Self {
+        match reg {
             gimli::X86_64::RA => Self::Rip,
             gimli::X86_64::RSP => Self::Rsp,
-            _ => return None,
-        })
+            gimli::Register(reg) => Self::Other(reg),
+        }
     }
 }
 
@@ -129,6 +136,10 @@ impl std::fmt::Display for Reg {
         match self {
             Self::Rip => write!(f, "rip"),
             Self::Rsp => write!(f, "rsp"),
+            Self::Other(reg) => match REGISTER_NAMES.get(*reg as usize) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "reg{}", reg),
+            },
         }
     }
 }
@@ -164,12 +175,7 @@ impl UnwindTableRow {
             },
             rsp: match row.cfa() {
                 CfaRule::RegisterAndOffset { register, offset } => {
-                    if let Some(reg) = Reg::from_gimli(*register) {
-                        Instruction::reg_offset(reg, *offset)
-                    } else {
-                        log::debug!("unimpl rsp {:?}", row.cfa());
-                        Instruction::unimpl()
-                    }
+                    Instruction::reg_offset(Reg::from_gimli(*register), *offset)
                 }
                 _ => {
                     log::debug!("unimpl cfa {:?}", row.cfa());
@@ -250,3 +256,77 @@ impl std::fmt::Display for UnwindTable {
         Ok(())
     }
 }
+
+/// Registers of a frame, the `rbp` of callers is only known when a frame
+/// saved it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Regs {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+}
+
+/// Reference unwinder evaluating the rows of `.eh_frame` with gimli.
+///
+/// It doesn't go through the encoded rows of an [`UnwindTable`] and handles
+/// more rules, like a cfa based on `rbp` and a saved `rbp`, so unwinding the
+/// same stack with both tests the encoding.
+pub struct Unwinder {
+    data: Vec<u8>,
+    bases: gimli::BaseAddresses,
+}
+
+impl Unwinder {
+    pub fn parse<'a, O: Object<'a, 'a>>(file: &'a O) -> Result<Self> {
+        let section = match file.section_by_name(".eh_frame") {
+            Some(section) => section,
+            None => anyhow::bail!("missing .eh_frame section"),
+        };
+        let mut bases = gimli::BaseAddresses::default().set_eh_frame(section.address());
+        if let Some(section) = file.section_by_name(".eh_frame_hdr") {
+            bases = bases.set_eh_frame_hdr(section.address());
+        }
+        if let Some(section) = file.section_by_name(".text") {
+            bases = bases.set_text(section.address());
+        }
+        if let Some(section) = file.section_by_name(".got") {
+            bases = bases.set_got(section.address());
+        }
+        Ok(Self {
+            data: section.uncompressed_data()?.into_owned(),
+            bases,
+        })
+    }
+
+    /// Unwinds the caller of `regs` with the row of `addr`, the address of
+    /// the instruction relative to the module. Returns `None` at the end of
+    /// the stack, `read` reads a word of the memory of the process.
+    pub fn step(&self, addr: u64, regs: &Regs, read: impl Fn(u64) -> Option<u64>) -> Option<Regs> {
+        let mut eh_frame = gimli::EhFrame::new(&self.data, NativeEndian);
+        eh_frame.set_address_size(std::mem::size_of::<usize>() as _);
+        let mut ctx = UninitializedUnwindContext::new();
+        let row = eh_frame
+            .unwind_info_for_address(&self.bases, &mut ctx, addr, gimli::EhFrame::cie_from_offset)
+            .ok()?;
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                let value = match *register {
+                    gimli::X86_64::RSP => regs.rsp,
+                    gimli::X86_64::RBP => regs.rbp,
+                    _ => return None,
+                };
+                (value as i64 + offset) as u64
+            }
+            _ => return None,
+        };
+        let rip = match row.register(gimli::X86_64::RA) {
+            RegisterRule::Offset(offset) => read((cfa as i64 + offset) as u64)?,
+            _ => return None,
+        };
+        let rbp = match row.register(gimli::X86_64::RBP) {
+            RegisterRule::Offset(offset) => read((cfa as i64 + offset) as u64)?,
+            _ => regs.rbp,
+        };
+        Some(Regs { rip, rsp: cfa, rbp })
+    }
+}
@@ -3,7 +3,7 @@
 
 use bpf_helpers::{entry, map, program, sys, Array, HashMap, StackTrace};
 
-program!(0xFFFF_FFFE, b"GPL");
+program!(b"GPL");
 
 #[map]
 static PROBE_COUNT: HashMap<u32, u32> = HashMap::with_max_entries(13);
@@ -1,9 +1,10 @@
 #![no_std]
 #![no_main]
 
+use bpf_helpers::hist::log2_bucket;
 use bpf_helpers::{entry, map, program, HashMap, Instant, PidTgid};
 
-program!(0xFFFF_FFFE, b"GPL");
+program!(b"GPL");
 
 const FILTER_PID: Option<u32> = None;
 const FILTER_FAILED: bool = true;
@@ -14,6 +15,9 @@ const BY_PROCESS: bool = false;
 static START: HashMap<PidTgid, Instant> = HashMap::with_max_entries(1024);
 #[map]
 static DATA: HashMap<u32, SyscallInfo> = HashMap::with_max_entries(1024);
+/// Log2 histograms of the syscall latency in ns.
+#[map]
+static LATENCY: HashMap<LatencyBucket, u64> = HashMap::with_max_entries(4096);
 
 #[derive(Clone, Copy, Default)]
 #[repr(C)]
@@ -22,6 +26,13 @@ pub struct SyscallInfo {
     pub time_ns: u64,
 }
 
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct LatencyBucket {
+    pub key: u32,
+    pub bucket: u32,
+}
+
 #[entry("raw_syscalls:sys_enter")]
 fn sys_enter(_args: &SysEnter) {
     let pid_tgid = PidTgid::current();
@@ -58,9 +69,16 @@ fn sys_exit(args: &SysExit) {
         args.id as u32
     };
     if let Some(start) = START.get(&pid_tgid) {
+        let elapsed = start.elapsed().as_nanos();
         let mut entry = DATA.get(&key).unwrap_or_default();
         entry.count += 1;
-        entry.time_ns += start.elapsed().as_nanos();
+        entry.time_ns += elapsed;
         DATA.insert(&key, &entry);
+        let bucket = LatencyBucket {
+            key,
+            bucket: log2_bucket(elapsed),
+        };
+        let count = LATENCY.get(&bucket).unwrap_or_default();
+        LATENCY.insert(&bucket, &(count + 1));
     }
 }
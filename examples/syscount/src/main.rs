@@ -1,5 +1,7 @@
 use anyhow::Result;
+use bpf::hist::Log2Histogram;
 use bpf::{BpfBuilder, U32, U64};
+use std::collections::HashMap;
 use std::time::Duration;
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
@@ -15,24 +17,44 @@ pub struct SyscallInfo {
     pub time_ns: U64,
 }
 
+#[derive(Clone, Copy, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct LatencyBucket {
+    pub key: U32,
+    pub bucket: U32,
+}
+
 fn main() -> Result<()> {
     bpf::utils::sudo::escalate_if_needed().unwrap();
     let mut builder = BpfBuilder::new(PROBE)?;
     builder.attach_probe_str("tracepoint:raw_syscalls:sys_enter", "sys_enter")?;
     builder.attach_probe_str("tracepoint:raw_syscalls:sys_exit", "sys_exit")?;
     let mut bpf = builder.load()?;
-    let data = bpf.hash_map::<U32, SyscallInfo>("DATA")?;
     let table = bpf::utils::syscall_table()?;
-    println!("{:10} {:6} {:10}", "SYSCALL", "COUNT", "NS");
+    println!("{:10} {:6} {:10} LATENCY NS", "SYSCALL", "COUNT", "NS");
     loop {
         std::thread::sleep(Duration::from_millis(250));
 
-        for (syscall, info) in data.iter() {
+        let mut hists: HashMap<u32, Log2Histogram> = HashMap::new();
+        for (bucket, count) in bpf.hash_map::<LatencyBucket, U64>("LATENCY")?.iter() {
+            hists
+                .entry(bucket.key.get())
+                .or_default()
+                .add(bucket.bucket.get() as usize, count.get());
+        }
+        for (syscall, info) in bpf.hash_map::<U32, SyscallInfo>("DATA")?.iter() {
             let name = table
                 .get(&syscall.get())
                 .cloned()
                 .unwrap_or_else(|| syscall.to_string());
-            println!("{:10} {:6} {:10}", name, info.count, info.time_ns);
+            let hist = hists.remove(&syscall.get()).unwrap_or_default();
+            println!(
+                "{:10} {:6} {:10} {}",
+                name,
+                info.count,
+                info.time_ns,
+                hist.percentiles()
+            );
         }
     }
 }
@@ -0,0 +1,15 @@
+#![no_std]
+#![no_main]
+
+use bpf_helpers::{entry, map, program, DevMap};
+
+program!(b"GPL");
+
+#[map]
+static PORTS: DevMap = DevMap::with_max_entries(64);
+
+/// Floods every packet to all ports except the one it arrived on.
+#[entry("xdp")]
+fn flood(_ctx: &xdp_md) -> XdpAction {
+    PORTS.redirect(0, DevMap::BROADCAST | DevMap::EXCLUDE_INGRESS)
+}
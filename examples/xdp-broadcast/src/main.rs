@@ -0,0 +1,59 @@
+use anyhow::Result;
+use bpf::{xdp, BpfBuilder, U32};
+
+static PROBE: &[u8] = include_bytes!(concat!(
+    env!("OUT_DIR"),
+    "/target/bpf/programs/xdp-broadcast-probe/xdp-broadcast-probe.elf",
+));
+
+fn main() -> Result<()> {
+    let interfaces: Vec<String> = std::env::args().skip(1).collect();
+    if interfaces.len() < 2 {
+        eprintln!("usage: xdp-broadcast <interface> <interface>...");
+        std::process::exit(1);
+    }
+    bpf::utils::sudo::escalate_if_needed().unwrap();
+    // blocked before attaching, so ctrl-c always detaches the program.
+    let signals = block_signals()?;
+    let mut builder = BpfBuilder::new(PROBE)?;
+    for interface in &interfaces {
+        builder.attach_xdp(interface, "flood", xdp::XDP_FLAGS_SKB_MODE)?;
+    }
+    let mut bpf = builder.load()?;
+    let mut ports = bpf.array::<U32>("PORTS")?;
+    for (i, interface) in interfaces.iter().enumerate() {
+        let ifindex = xdp::ifindex(interface)?;
+        ports.insert(&U32::new(i as _), &U32::new(ifindex as _))?;
+    }
+    println!(
+        "flooding between {}, press ctrl-c to exit",
+        interfaces.join(", ")
+    );
+    wait_for(&signals)?;
+    // dropping `bpf` detaches the program from the interfaces.
+    drop(bpf);
+    Ok(())
+}
+
+fn block_signals() -> Result<libc::sigset_t> {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret).into());
+        }
+        Ok(set)
+    }
+}
+
+fn wait_for(signals: &libc::sigset_t) -> Result<()> {
+    let mut signal = 0;
+    let ret = unsafe { libc::sigwait(signals, &mut signal) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret).into());
+    }
+    Ok(())
+}